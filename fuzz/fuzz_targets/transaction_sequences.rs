@@ -0,0 +1,136 @@
+//! Feeds arbitrary sequences of transactions to a `TxProcessor`, under arbitrary dispute and
+//! locked account policies, and checks that it doesn't panic or fail and that balances stay
+//! consistent. Clients and transaction ids are drawn from small ranges, so that disputes,
+//! resolves, chargebacks and refunds hit earlier transactions, in any order.
+//!
+//! Run with `cargo +nightly fuzz run transaction_sequences`, from the repository root.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tx_processor::amount::Amount;
+use tx_processor::error::TxProcessorError;
+use tx_processor::model::{ClientBalance, Transaction, TxAmount, TxType, AMOUNT_DECIMALS};
+use tx_processor::tx_processor::{DisputeFundsPolicy, LockedAccountPolicy, ProcessorConfig, TxOutcome, TxProcessor};
+use tx_processor::verify::trial_balance;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    dispute_withdrawals: bool,
+    cap_disputes: bool,
+    reject_disputes: bool,
+    queue_locked: bool,
+    ops: Vec<Op>,
+}
+
+#[derive(Debug, Arbitrary)]
+enum Kind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Hold,
+    Release,
+    Capture,
+    Refund,
+    Settle,
+    Freeze,
+    Unfreeze,
+}
+
+#[derive(Debug, Arbitrary)]
+struct Op {
+    kind: Kind,
+    client: u8,
+    tx: u8,
+    /// In ten-thousandths, zero and negative amounts included.
+    amount: Option<i32>,
+    /// Retries of a transaction reuse its key.
+    idempotency_key: Option<u8>,
+}
+
+impl Op {
+    fn transaction(&self) -> Transaction {
+        let tx_type = match self.kind {
+            Kind::Deposit => TxType::Deposit,
+            Kind::Withdrawal => TxType::Withdrawal,
+            Kind::Dispute => TxType::Dispute,
+            Kind::Resolve => TxType::Resolve,
+            Kind::Chargeback => TxType::Chargeback,
+            Kind::Hold => TxType::Hold,
+            Kind::Release => TxType::Release,
+            Kind::Capture => TxType::Capture,
+            Kind::Refund => TxType::Refund,
+            Kind::Settle => TxType::Settle,
+            Kind::Freeze => TxType::Freeze,
+            Kind::Unfreeze => TxType::Unfreeze,
+        };
+        let amount = self.amount.map(|amount| TxAmount::from_f64(f64::from(amount) / 10_000.0));
+        Transaction {
+            idempotency_key: self.idempotency_key.map(|key| format!("key-{}", key % 8)),
+            ..Transaction::new(tx_type, u16::from(self.client % 4), u32::from(self.tx % 16), amount)
+        }
+    }
+}
+
+fn rounded(balance: &ClientBalance) -> [f64; 3] {
+    [balance.available, balance.held, balance.total].map(|amount: TxAmount| amount.round_dp(AMOUNT_DECIMALS))
+}
+
+fuzz_target!(|input: Input| {
+    let dispute_funds_policy = match (input.cap_disputes, input.reject_disputes) {
+        (true, _) => DisputeFundsPolicy::Cap,
+        (false, true) => DisputeFundsPolicy::Reject,
+        (false, false) => DisputeFundsPolicy::AllowNegative,
+    };
+    let locked_account_policy = match input.queue_locked {
+        true => LockedAccountPolicy::Queue,
+        false => LockedAccountPolicy::Reject,
+    };
+    let mut processor = TxProcessor::with_config(ProcessorConfig {
+        record_ledger: true,
+        dispute_withdrawals: input.dispute_withdrawals,
+        dispute_funds_policy,
+        locked_account_policy,
+        ..Default::default()
+    });
+
+    for op in &input.ops {
+        let mut tx = op.transaction();
+        let empty = ClientBalance::new_empty(tx.client);
+        let before = processor.balance_of(tx.client).cloned().unwrap_or_else(|| empty.clone());
+        let outcome = match processor.process_transaction(&mut tx) {
+            Ok(outcome) => outcome,
+            // Processing stops there, as it does for such a CSV record.
+            Err(TxProcessorError::MissingAmount(_)) if tx.amount.is_none() => return,
+            Err(err) => panic!("{tx:?} failed: {err}"),
+        };
+        let after = processor.balance_of(tx.client).cloned().unwrap_or(empty);
+
+        if tx.tx_type != TxType::Settle {
+            if outcome != TxOutcome::Applied {
+                assert_eq!(before, after, "{tx:?} was not applied, but changed the balance");
+            }
+            if before.locked {
+                assert!(after.locked, "{tx:?} unlocked the account");
+                let moves_funds = matches!(
+                    tx.tx_type,
+                    TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Capture | TxType::Refund
+                );
+                // An idempotent retry may still report the outcome of the first attempt.
+                assert!(!moves_funds || before == after, "{tx:?} applied to a locked account");
+            }
+            if before.frozen && matches!(tx.tx_type, TxType::Withdrawal | TxType::Hold | TxType::Capture) {
+                assert_eq!(before, after, "{tx:?} took funds from a frozen account");
+            }
+        }
+        for balance in processor.balances() {
+            let [available, held, total] = rounded(balance);
+            assert_eq!(total, (available + held).round_dp(AMOUNT_DECIMALS), "{balance:?}");
+        }
+    }
+
+    let trial = trial_balance(&processor);
+    assert!(trial.is_balanced(), "{:?}", trial.discrepancies);
+});
@@ -0,0 +1,112 @@
+//! Listing client accounts a page at a time, for servers with too many clients to list at once.
+//! Pages are ordered by client id and end with a cursor, the last client listed, that the next
+//! page starts after, so that clients added between pages don't shift the pages that follow.
+//! Listing a page scans all accounts, but only sorts and copies the ones on the page.
+
+use crate::model::{ClientBalance, ClientId};
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+
+/// Which accounts are listed: those with the given `locked` and `frozen` flags, all if not set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct AccountFilter {
+    pub locked: Option<bool>,
+    pub frozen: Option<bool>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, balance: &ClientBalance) -> bool {
+        self.locked.is_none_or(|locked| balance.locked == locked)
+            && self.frozen.is_none_or(|frozen| balance.frozen == frozen)
+    }
+}
+
+/// A page of accounts, see `account_page`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct AccountPage {
+    #[serde(serialize_with = "crate::output::serialize_rounded")]
+    pub clients: Vec<ClientBalance>,
+    /// Cursor to list the next page after, if there are more accounts.
+    pub next_cursor: Option<ClientId>,
+}
+
+/// The first `limit` (at least one) accounts of `balances` after the client `after`, that match
+/// `filter`.
+pub fn account_page<'a>(
+    balances: impl Iterator<Item = &'a ClientBalance>,
+    after: Option<ClientId>,
+    limit: usize,
+    filter: &AccountFilter,
+) -> AccountPage {
+    let limit = limit.max(1);
+    let mut page: Vec<_> = balances
+        .filter(|balance| after.is_none_or(|after| balance.client > after) && filter.matches(balance))
+        .collect();
+    let more = page.len() > limit;
+    if more {
+        page.select_nth_unstable_by_key(limit, |balance| balance.client);
+        page.truncate(limit);
+    }
+    page.sort_unstable_by_key(|balance| balance.client);
+    AccountPage {
+        next_cursor: page.last().filter(|_| more).map(|balance| balance.client),
+        clients: page.into_iter().cloned().collect(),
+    }
+}
+
+impl<S: Stores> TxProcessor<S> {
+    /// A page of the accounts, see `account_page`.
+    pub fn account_page(&self, after: Option<ClientId>, limit: usize, filter: &AccountFilter) -> AccountPage {
+        account_page(self.clients_balance.values(), after, limit, filter)
+    }
+
+    /// All accounts matching `filter`, `limit` at a time.
+    pub fn account_pages(&self, limit: usize, filter: AccountFilter) -> impl Iterator<Item = AccountPage> + '_ {
+        let first = self.account_page(None, limit, &filter);
+        std::iter::successors(Some(first), move |page| {
+            page.next_cursor.map(|cursor| self.account_page(Some(cursor), limit, &filter))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    fn clients(page: &AccountPage) -> Vec<ClientId> {
+        page.clients.iter().map(|balance| balance.client).collect()
+    }
+
+    #[test]
+    fn test_account_pages() {
+        let mut scenario = Scenario::new();
+        for client in [5, 3, 9, 1, 7] {
+            scenario = scenario.tx(deposit(client, client.into(), 1.0));
+        }
+        let processor = scenario.tx(freeze(3)).tx(freeze(9)).into_processor();
+
+        let all = AccountFilter::default();
+        let page = processor.account_page(None, 2, &all);
+        assert_eq!((clients(&page), page.next_cursor), (vec![1, 3], Some(3)));
+        let page = processor.account_page(Some(3), 2, &all);
+        assert_eq!((clients(&page), page.next_cursor), (vec![5, 7], Some(7)));
+        let page = processor.account_page(Some(7), 2, &all);
+        assert_eq!((clients(&page), page.next_cursor), (vec![9], None));
+        let page = processor.account_page(None, 5, &all);
+        assert_eq!((clients(&page), page.next_cursor), (vec![1, 3, 5, 7, 9], None));
+
+        let frozen = AccountFilter {
+            frozen: Some(true),
+            ..Default::default()
+        };
+        let pages: Vec<_> = processor.account_pages(1, frozen).map(|page| clients(&page)).collect();
+        assert_eq!(pages, [vec![3], vec![9]]);
+        let active = AccountFilter {
+            locked: Some(false),
+            frozen: Some(false),
+        };
+        let pages: Vec<_> = processor.account_pages(10, active).map(|page| clients(&page)).collect();
+        assert_eq!(pages, [vec![1, 5, 7]]);
+    }
+}
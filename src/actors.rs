@@ -0,0 +1,304 @@
+//! Actor-per-client processing, for async services: each client seen gets a task of its own that
+//! applies the client's transactions to the client's state, a `TxProcessor` that only ever sees
+//! that client's transactions. A `ClientRouter` dispatches transactions by client id over a
+//! channel to the client's task, spawning it on the client's first transaction, and hands back the
+//! outcome. Transactions of a client are applied in the order they were submitted, while different
+//! clients never wait on each other: there is no global lock.
+//!
+//! The router works with any executor, it is given a function to spawn tasks with (ie
+//! `tokio::spawn`). As with `sharding`, this gives the same balances as a single processor as long
+//! as disputes, resolves and chargebacks are sent by the client of the transaction they reference.
+//! Transaction ids and idempotency keys are only deduplicated per client. A settlement marker
+//! settles every client. Client states are made from the router's `ProcessorConfig` and share its
+//! notifier, there are no per-client validators, journals nor audit logs.
+//!
+//! The task of a client that had no transactions for a while is ended with `end_idle_tasks`, which
+//! keeps its state, to spawn a task again with the client's next transaction. The other tasks end
+//! when the router is dropped.
+
+use crate::error::TxProcessorError;
+use crate::model::{ClientBalance, ClientId, Transaction, TxType};
+use crate::server::lock;
+use crate::tx_processor::{Notification, NotificationSink, ProcessorConfig, TxOutcome, TxProcessor};
+use crate::GResult;
+use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Requests queued per client task before submitters wait, so a busy client applies back-pressure
+/// to its own submitters only.
+const CLIENT_QUEUE: usize = 64;
+
+/// How long a client task is kept without transactions by servers, see `end_idle_tasks`.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Request {
+    tx: Transaction,
+    reply: oneshot::Sender<GResult<TxOutcome>>,
+}
+
+type Spawn = Box<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>;
+
+struct Client {
+    /// Only changed by the client's task, read directly for balances.
+    state: Arc<Mutex<TxProcessor>>,
+    /// The channel to the client's task, `None` once the task ended for being idle.
+    sender: Option<mpsc::Sender<Request>>,
+    /// Submissions waiting on the task, which isn't ended while there are any.
+    submitting: Arc<AtomicUsize>,
+    last_used: Instant,
+}
+
+/// Counts a submission in `Client::submitting` until dropped.
+struct Submitting(Arc<AtomicUsize>);
+
+impl Submitting {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(count))
+    }
+}
+
+impl Drop for Submitting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Shares the router's notifier between the client states.
+struct SharedNotifier(Arc<Mutex<Box<dyn NotificationSink>>>);
+
+impl NotificationSink for SharedNotifier {
+    fn notify(&mut self, notification: &Notification) -> GResult<()> {
+        lock_notifier(&self.0).notify(notification)
+    }
+
+    fn balance_changes(&self) -> bool {
+        lock_notifier(&self.0).balance_changes()
+    }
+}
+
+fn lock_notifier(notifier: &Mutex<Box<dyn NotificationSink>>) -> MutexGuard<'_, Box<dyn NotificationSink>> {
+    notifier.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Dispatches transactions to client tasks, see the module docs.
+pub struct ClientRouter {
+    clients: Mutex<HashMap<ClientId, Client>>,
+    config: ProcessorConfig,
+    run_id: Option<String>,
+    notifier: Option<Arc<Mutex<Box<dyn NotificationSink>>>>,
+    spawn: Spawn,
+}
+
+impl ClientRouter {
+    /// A router spawning client tasks with `spawn`, with client states made from `config`.
+    pub fn new<F>(config: ProcessorConfig, spawn: F) -> Self
+    where
+        F: Fn(BoxFuture<'static, ()>) + Send + Sync + 'static,
+    {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+            config,
+            run_id: None,
+            notifier: None,
+            spawn: Box::new(spawn),
+        }
+    }
+
+    /// Sets the run id of the client states, see `TxProcessor::run_id`.
+    pub fn run_id(mut self, run_id: impl Into<String>) -> Self {
+        self.run_id = Some(run_id.into());
+        self
+    }
+
+    /// Sets the notifier that all client states notify.
+    pub fn notifier<N: NotificationSink + 'static>(mut self, notifier: N) -> Self {
+        self.notifier = Some(Arc::new(Mutex::new(Box::new(notifier))));
+        self
+    }
+
+    fn new_state(&self) -> TxProcessor {
+        let mut state = TxProcessor::with_config(self.config.clone());
+        state.run_id = self.run_id.clone();
+        if let Some(notifier) = &self.notifier {
+            state.notifier = Some(Box::new(SharedNotifier(Arc::clone(notifier))));
+        }
+        state
+    }
+
+    fn lock_clients(&self) -> MutexGuard<'_, HashMap<ClientId, Client>> {
+        // Never held across an await, only to look up, add or end clients.
+        self.clients.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The channel to the task of `client`, spawning it if needed, counted as submitting until the
+    /// `Submitting` is dropped.
+    fn client(&self, client: ClientId) -> (mpsc::Sender<Request>, Submitting) {
+        let mut clients = self.lock_clients();
+        let client = clients.entry(client).or_insert_with(|| Client {
+            state: Arc::new(Mutex::new(self.new_state())),
+            sender: None,
+            submitting: Arc::default(),
+            last_used: Instant::now(),
+        });
+        client.last_used = Instant::now();
+        let sender = client.sender.get_or_insert_with(|| {
+            let (sender, requests) = mpsc::channel(CLIENT_QUEUE);
+            (self.spawn)(Box::pin(run_client(Arc::clone(&client.state), requests)));
+            sender
+        });
+        (sender.clone(), Submitting::new(&client.submitting))
+    }
+
+    fn states(&self) -> Vec<Arc<Mutex<TxProcessor>>> {
+        self.lock_clients().values().map(|client| Arc::clone(&client.state)).collect()
+    }
+
+    /// Processes a transaction in the task of its client, see `TxProcessor::process_transaction`.
+    pub async fn submit(&self, tx: Transaction) -> GResult<TxOutcome> {
+        if tx.tx_type == TxType::Settle {
+            let clients: Vec<_> = self.lock_clients().keys().copied().collect();
+            for client in clients {
+                submit_to(self.client(client), tx.clone()).await?;
+            }
+            return Ok(TxOutcome::Applied);
+        }
+        submit_to(self.client(tx.client), tx).await
+    }
+
+    /// The balance of `client`, with the transactions applied so far.
+    pub fn balance_of(&self, client: ClientId) -> Option<ClientBalance> {
+        self.with_client(client, |state| state.balance_of(client).cloned()).flatten()
+    }
+
+    /// Balances of all clients, sorted by client.
+    pub fn balances(&self) -> Vec<ClientBalance> {
+        let mut balances: Vec<_> = self.states().iter().filter_map(|state| lock(state).balances().next().cloned()).collect();
+        balances.sort_by_key(|balance| balance.client);
+        balances
+    }
+
+    /// Runs `f` on the state of `client`, if the client was seen.
+    pub fn with_client<R>(&self, client: ClientId, f: impl FnOnce(&TxProcessor) -> R) -> Option<R> {
+        let state = self.lock_clients().get(&client).map(|client| Arc::clone(&client.state))?;
+        let result = f(&lock(&state));
+        Some(result)
+    }
+
+    /// Ends the tasks of clients that had no transactions for `idle`, keeping their states.
+    /// Returns the number of tasks ended.
+    pub fn end_idle_tasks(&self, idle: Duration) -> usize {
+        let mut ended = 0;
+        for client in self.lock_clients().values_mut() {
+            let is_idle = client.submitting.load(Ordering::SeqCst) == 0 && client.last_used.elapsed() >= idle;
+            // Dropping the only sender ends the task once it applied what was queued.
+            if is_idle && client.sender.take().is_some() {
+                ended += 1;
+            }
+        }
+        ended
+    }
+
+    /// Number of clients seen.
+    pub fn client_count(&self) -> usize {
+        self.lock_clients().len()
+    }
+
+    /// Number of client tasks running.
+    pub fn task_count(&self) -> usize {
+        self.lock_clients().values().filter(|client| client.sender.is_some()).count()
+    }
+}
+
+fn client_stopped() -> TxProcessorError {
+    TxProcessorError::Io(std::io::Error::other("client task stopped"))
+}
+
+async fn submit_to((mut sender, _submitting): (mpsc::Sender<Request>, Submitting), tx: Transaction) -> GResult<TxOutcome> {
+    let (reply, outcome) = oneshot::channel();
+    sender.send(Request { tx, reply }).await.map_err(|_| client_stopped())?;
+    outcome.await.map_err(|_| client_stopped())?
+}
+
+async fn run_client(state: Arc<Mutex<TxProcessor>>, mut requests: mpsc::Receiver<Request>) {
+    while let Some(Request { mut tx, reply }) = requests.next().await {
+        // The submitter may have given up waiting, the outcome is then dropped.
+        let outcome = lock(&state).process_transaction(&mut tx);
+        let _ = reply.send(outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_client_router() -> GResult<()> {
+        // A thread per task, as a stand-in for a multi-threaded executor.
+        let router = ClientRouter::new(ProcessorConfig::default(), |task| {
+            std::thread::spawn(move || block_on(task));
+        });
+
+        // All submissions run concurrently, the tasks of each client apply them in order.
+        let submissions = (1..=3u16).flat_map(|client| {
+            let base = u32::from(client) * 100;
+            [
+                deposit(client, base, 10.0),
+                withdrawal(client, base + 1, 4.0),
+                withdrawal(client, base + 2, 7.0),
+                dispute(client, base),
+            ]
+        });
+        let outcomes = block_on(futures::future::join_all(submissions.map(|tx| router.submit(tx))));
+        let outcomes = outcomes.into_iter().collect::<GResult<Vec<_>>>()?;
+        assert_eq!(outcomes[0..4], [
+            TxOutcome::Applied,
+            TxOutcome::Applied,
+            TxOutcome::Rejected(crate::error::RejectReason::InsufficientFunds),
+            TxOutcome::Applied,
+        ]);
+        assert_eq!(router.client_count(), 3);
+
+        let balances = router.balances();
+        let totals: Vec<_> = balances.iter().map(|balance| (balance.client, balance.available, balance.held)).collect();
+        assert_eq!(totals, vec![(1, amount(-4.0), amount(10.0)), (2, amount(-4.0), amount(10.0)), (3, amount(-4.0), amount(10.0))]);
+        assert_eq!(router.balance_of(4), None);
+
+        // A settlement goes to every client.
+        let outcome = block_on(router.submit(settle()))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        assert_eq!(router.client_count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_end_idle_tasks() -> GResult<()> {
+        let router = ClientRouter::new(ProcessorConfig::default(), |task| {
+            std::thread::spawn(move || block_on(task));
+        });
+        block_on(router.submit(deposit(1, 1, 10.0)))?;
+        block_on(router.submit(deposit(2, 2, 5.0)))?;
+        assert_eq!(router.end_idle_tasks(Duration::from_secs(60)), 0);
+        assert_eq!(router.task_count(), 2);
+
+        // Ended tasks keep their client's state, and are spawned again for the next transaction.
+        assert_eq!(router.end_idle_tasks(Duration::ZERO), 2);
+        assert_eq!((router.task_count(), router.client_count()), (0, 2));
+        assert_eq!(router.balance_of(1).map(|balance| balance.available), Some(amount(10.0)));
+        let outcome = block_on(router.submit(withdrawal(1, 3, 4.0)))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        let outcome = block_on(router.submit(dispute(1, 1)))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        assert_eq!(router.task_count(), 1);
+        let balance = router.balance_of(1).map(|balance| (balance.available, balance.held));
+        assert_eq!(balance, Some((amount(-4.0), amount(10.0))));
+        Ok(())
+    }
+}
@@ -0,0 +1,560 @@
+//! Amount backends: the `Amount` trait is what balances need of an amount type, and is
+//! implemented for `f64`, `MinorUnits` and, with the `decimal` feature, `rust_decimal::Decimal`.
+//! `Transaction`, `ClientBalance` and `TxProcessor` are generic over it, so that processors with
+//! different backends coexist in one build, ie `TxProcessor<HashStores, f64>` next to
+//! `TxProcessor<HashStores, MinorUnits>`. The type parameter defaults to `model::TxAmount`, the
+//! backend selected at build time, and the parts that read or write amounts in a fixed encoding
+//! use it only: the CSV and Parquet inputs, disk-backed stores (`TxStore<TxAmount>`), journal
+//! replay and snapshots. In-memory stores take any backend.
+//!
+//! Integer amounts: `MinorUnits` holds an amount as a whole number of ten-thousandths in an
+//! `i64`, so that sums are exact and as fast as integer arithmetic, without a decimal dependency.
+//! With the `minor-units` feature, `model::TxAmount` is `MinorUnits` instead of `f64`.
+//!
+//! `MinorUnits` mirrors the parts of the `f64` API the engine uses (ie `is_finite`, `abs`), so that
+//! the same code builds with either backend. Arithmetic saturates, and a saturated amount is not
+//! finite, which is how the engine detects an overflow with `f64`.
+//! It serializes as the integer number of minor units, so that snapshots and stores are exact,
+//! while transaction inputs and balances outputs stay in currency units (see `units`).
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// An amount type: arithmetic, comparisons, parsing (`FromStr`) and formatting (`Display`), and
+/// serialization for outputs and snapshots. `Default` is zero.
+pub trait Amount:
+    Copy
+    + Debug
+    + Default
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Neg<Output = Self>
+    + Display
+    + FromStr<Err: Display>
+    + Serialize
+    + DeserializeOwned
+    + Send
+    + Sync
+    + 'static
+{
+    /// False once an operation overflowed.
+    fn is_finite(self) -> bool;
+
+    /// Rounds to `decimals` decimal places, half away from zero, without a negative zero.
+    fn round_dp(self, decimals: u32) -> Self;
+
+    fn to_f64(self) -> f64;
+
+    /// The nearest amount to `amount`.
+    fn from_f64(amount: f64) -> Self;
+
+    /// The largest finite amount, that balances saturate to (see `model::OverflowPolicy`). Its
+    /// negation is the smallest.
+    fn max_finite() -> Self;
+
+    /// The sum, or `None` if it overflows.
+    fn checked_add(self, other: Self) -> Option<Self> {
+        let sum = self + other;
+        sum.is_finite().then_some(sum)
+    }
+
+    /// The larger of `self` and zero.
+    fn max_zero(self) -> Self {
+        if self > Self::default() {
+            self
+        } else {
+            Self::default()
+        }
+    }
+}
+
+impl Amount for f64 {
+    fn is_finite(self) -> bool {
+        f64::is_finite(self)
+    }
+
+    fn round_dp(self, decimals: u32) -> Self {
+        let scale = 10f64.powi(decimals as i32);
+        let rounded = (self * scale).round() / scale;
+        rounded + 0.0
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn from_f64(amount: f64) -> Self {
+        amount
+    }
+
+    fn max_finite() -> Self {
+        f64::MAX
+    }
+}
+
+/// Decimal places of `MinorUnits`.
+pub const MINOR_UNIT_DECIMALS: u32 = 4;
+const MINOR_UNITS_PER_UNIT: i64 = 10_i64.pow(MINOR_UNIT_DECIMALS);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MinorUnits(pub i64);
+
+impl MinorUnits {
+    pub const ZERO: MinorUnits = MinorUnits(0);
+
+    /// The nearest amount to `amount`. Out of range amounts saturate.
+    pub fn from_f64(amount: f64) -> Self {
+        MinorUnits((amount * MINOR_UNITS_PER_UNIT as f64).round() as i64)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / MINOR_UNITS_PER_UNIT as f64
+    }
+
+    /// False once an operation overflowed (saturated).
+    pub fn is_finite(self) -> bool {
+        self.0 != i64::MAX && self.0 != i64::MIN
+    }
+
+    pub fn abs(self) -> Self {
+        MinorUnits(self.0.saturating_abs())
+    }
+
+    /// Same layout as `f64::to_le_bytes`, so that stores keep 8 bytes per amount.
+    pub fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 8]) -> Self {
+        MinorUnits(i64::from_le_bytes(bytes))
+    }
+
+    /// Rounds to `decimals` decimal places, half away from zero.
+    pub fn round(self, decimals: u32) -> Self {
+        if decimals >= MINOR_UNIT_DECIMALS {
+            return self;
+        }
+        let step = 10_i64.pow(MINOR_UNIT_DECIMALS - decimals);
+        let half = step / 2 * self.0.signum();
+        MinorUnits(self.0.saturating_add(half) / step * step)
+    }
+}
+
+impl Amount for MinorUnits {
+    fn is_finite(self) -> bool {
+        MinorUnits::is_finite(self)
+    }
+
+    fn round_dp(self, decimals: u32) -> Self {
+        self.round(decimals)
+    }
+
+    fn to_f64(self) -> f64 {
+        MinorUnits::to_f64(self)
+    }
+
+    fn from_f64(amount: f64) -> Self {
+        MinorUnits::from_f64(amount)
+    }
+
+    fn max_finite() -> Self {
+        MinorUnits(i64::MAX - 1)
+    }
+}
+
+/// Decimal arithmetic panics on overflow rather than saturating, so a `Decimal` is always finite,
+/// and overflows are checked before adding.
+#[cfg(feature = "decimal")]
+impl Amount for rust_decimal::Decimal {
+    fn is_finite(self) -> bool {
+        true
+    }
+
+    fn round_dp(self, decimals: u32) -> Self {
+        let rounded = self.round_dp_with_strategy(decimals, rust_decimal::RoundingStrategy::MidpointAwayFromZero);
+        // A negative amount rounded to zero keeps its sign.
+        if rounded.is_zero() {
+            rust_decimal::Decimal::ZERO
+        } else {
+            rounded
+        }
+    }
+
+    fn to_f64(self) -> f64 {
+        rust_decimal::prelude::ToPrimitive::to_f64(&self).unwrap_or_default()
+    }
+
+    /// Amounts out of the `Decimal` range (or not finite) are zero.
+    fn from_f64(amount: f64) -> Self {
+        rust_decimal::prelude::FromPrimitive::from_f64(amount).unwrap_or_default()
+    }
+
+    fn max_finite() -> Self {
+        rust_decimal::Decimal::MAX
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        rust_decimal::Decimal::checked_add(self, other)
+    }
+}
+
+impl Add for MinorUnits {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        MinorUnits(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for MinorUnits {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        MinorUnits(self.0.saturating_sub(other.0))
+    }
+}
+
+impl AddAssign for MinorUnits {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for MinorUnits {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for MinorUnits {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        MinorUnits(self.0.saturating_neg())
+    }
+}
+
+impl std::iter::Sum for MinorUnits {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(MinorUnits::ZERO, Add::add)
+    }
+}
+
+impl From<f64> for MinorUnits {
+    fn from(amount: f64) -> Self {
+        MinorUnits::from_f64(amount)
+    }
+}
+
+impl From<MinorUnits> for f64 {
+    fn from(amount: MinorUnits) -> Self {
+        amount.to_f64()
+    }
+}
+
+/// Shortest form by default (`127.9`, `0`), or exactly the formatter's precision (`127.90`).
+impl Display for MinorUnits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let decimals = f.precision().map_or(MINOR_UNIT_DECIMALS, |precision| precision as u32);
+        let amount = self.round(decimals);
+        let sign = if amount.0 < 0 { "-" } else { "" };
+        let units = amount.0.unsigned_abs() / MINOR_UNITS_PER_UNIT as u64;
+        let fraction = format!("{:04}", amount.0.unsigned_abs() % MINOR_UNITS_PER_UNIT as u64);
+        let fraction = match f.precision() {
+            Some(precision) => format!("{fraction:0<precision$}")[..precision].to_string(),
+            None => fraction.trim_end_matches('0').to_string(),
+        };
+        match fraction.is_empty() {
+            true => write!(f, "{sign}{units}"),
+            false => write!(f, "{sign}{units}.{fraction}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid decimal amount `{0}`")]
+pub struct ParseAmountError(String);
+
+/// Parses a decimal number exactly (ie `-12.5`, `.25`, `3.`). Further decimal places are rounded.
+impl FromStr for MinorUnits {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseAmountError(s.to_string());
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (units, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if (units.is_empty() && fraction.is_empty()) || !all_digits(units) || !all_digits(fraction) {
+            return Err(invalid());
+        }
+
+        let units: i64 = if units.is_empty() { 0 } else { units.parse().map_err(|_| invalid())? };
+        let mut minor = units.checked_mul(MINOR_UNITS_PER_UNIT).ok_or_else(invalid)?;
+        let mut scale = MINOR_UNITS_PER_UNIT;
+        for digit in fraction.bytes().take(MINOR_UNIT_DECIMALS as usize) {
+            scale /= 10;
+            minor = minor.checked_add((digit - b'0') as i64 * scale).ok_or_else(invalid)?;
+        }
+        if fraction.as_bytes().get(MINOR_UNIT_DECIMALS as usize).is_some_and(|digit| *digit >= b'5') {
+            minor = minor.checked_add(1).ok_or_else(invalid)?;
+        }
+        // The saturation sentinels would read as an overflowed amount.
+        if minor > MinorUnits::max_finite().0 {
+            return Err(invalid());
+        }
+        Ok(MinorUnits(if negative { -minor } else { minor }))
+    }
+}
+
+/// Serialized as the integer number of ten-thousandths, so that snapshots restore amounts
+/// exactly. Snapshots of `f64` builds therefore don't load into `minor-units` builds, and back.
+impl Serialize for MinorUnits {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for MinorUnits {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        i64::deserialize(deserializer).map(MinorUnits)
+    }
+}
+
+/// Serde `with` module for amounts in currency units (ie `1.5`) whatever the amount type, for
+/// transaction inputs and balances outputs, that are read and written by other programs. Amounts
+/// are written as the string of their `Display`, and read from a string or a number with
+/// `FromStr`, so that they are exact whatever their number of digits. A number token may have been
+/// parsed as an `f64` by the format already (ie by `serde_json`, or by `csv` for a field that looks
+/// like a number), and is then only exact for up to 15 significant digits.
+pub mod units {
+    use super::Amount;
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt::{self, Display, Formatter};
+    use std::marker::PhantomData;
+
+    pub fn serialize<A: Amount, S: Serializer>(amount: &A, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(amount)
+    }
+
+    pub fn deserialize<'de, A: Amount, D: Deserializer<'de>>(deserializer: D) -> Result<A, D::Error> {
+        deserializer.deserialize_any(UnitsVisitor(PhantomData))
+    }
+
+    struct UnitsVisitor<A>(PhantomData<A>);
+
+    impl<A: Amount> UnitsVisitor<A> {
+        fn parse<E: de::Error>(amount: impl Display) -> Result<A, E> {
+            let amount = amount.to_string();
+            amount.trim().parse().map_err(|err| E::custom(format!("invalid amount `{amount}`: {err}")))
+        }
+    }
+
+    impl<A: Amount> Visitor<'_> for UnitsVisitor<A> {
+        type Value = A;
+
+        fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+            f.write_str("an amount, as a number or a string")
+        }
+
+        fn visit_str<E: de::Error>(self, amount: &str) -> Result<A, E> {
+            Self::parse(amount)
+        }
+
+        fn visit_i64<E: de::Error>(self, amount: i64) -> Result<A, E> {
+            Self::parse(amount)
+        }
+
+        fn visit_u64<E: de::Error>(self, amount: u64) -> Result<A, E> {
+            Self::parse(amount)
+        }
+
+        fn visit_i128<E: de::Error>(self, amount: i128) -> Result<A, E> {
+            Self::parse(amount)
+        }
+
+        fn visit_u128<E: de::Error>(self, amount: u128) -> Result<A, E> {
+            Self::parse(amount)
+        }
+
+        /// `f64` displays as the shortest decimal that parses back to it, ie `0.1` rather than
+        /// `0.1000000000000000055511151231257827`.
+        fn visit_f64<E: de::Error>(self, amount: f64) -> Result<A, E> {
+            Self::parse(amount)
+        }
+    }
+
+    /// `units` for an optional amount.
+    pub mod option {
+        use super::Amount;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<A: Amount, S: Serializer>(amount: &Option<A>, serializer: S) -> Result<S::Ok, S::Error> {
+            match amount {
+                Some(amount) => serializer.serialize_some(&Units(*amount)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, A: Amount, D: Deserializer<'de>>(deserializer: D) -> Result<Option<A>, D::Error> {
+            Ok(Option::<Units<A>>::deserialize(deserializer)?.map(|Units(amount)| amount))
+        }
+
+        /// An amount (de)serialized with `units`.
+        struct Units<A>(A);
+
+        impl<A: Amount> serde::Serialize for Units<A> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(&self.0, serializer)
+            }
+        }
+
+        impl<'de, A: Amount> Deserialize<'de> for Units<A> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                super::deserialize(deserializer).map(Units)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(s: &str) -> MinorUnits {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_format() {
+        assert_eq!(amount("127.9"), MinorUnits(1_279_000));
+        assert_eq!(amount("-0.0001"), MinorUnits(-1));
+        assert_eq!(amount(".25"), MinorUnits(2_500));
+        assert_eq!(amount("1.23456"), MinorUnits(12_346));
+        assert!("1e3".parse::<MinorUnits>().is_err());
+        assert!(".".parse::<MinorUnits>().is_err());
+        assert!("99999999999999999".parse::<MinorUnits>().is_err());
+        // Overflows in the fraction, and the saturation sentinels.
+        assert!("922337203685477.9999".parse::<MinorUnits>().is_err());
+        assert!("922337203685477.5807".parse::<MinorUnits>().is_err());
+        assert!("-922337203685477.5807".parse::<MinorUnits>().is_err());
+        assert_eq!(amount("922337203685477.5806"), MinorUnits::max_finite());
+
+        assert_eq!(amount("127.9").to_string(), "127.9");
+        assert_eq!(amount("-3").to_string(), "-3");
+        assert_eq!(MinorUnits::ZERO.to_string(), "0");
+        assert_eq!(format!("{:.2}", amount("1.005")), "1.01");
+        assert_eq!(format!("{:.6}", amount("-1.5")), "-1.500000");
+        assert_eq!(serde_json::to_string(&amount("1.5")).unwrap(), "15000");
+        assert_eq!(serde_json::from_str::<MinorUnits>("-1").unwrap(), amount("-0.0001"));
+
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Row {
+            #[serde(with = "units")]
+            amount: MinorUnits,
+        }
+        assert_eq!(serde_json::to_string(&Row { amount: amount("1.5") }).unwrap(), r#"{"amount":"1.5"}"#);
+        assert_eq!(serde_json::from_str::<Row>(r#"{"amount":4}"#).unwrap(), Row { amount: amount("4") });
+        assert_eq!(serde_json::from_str::<Row>(r#"{"amount":0.0003}"#).unwrap(), Row { amount: amount("0.0003") });
+        assert!(serde_json::from_str::<Row>(r#"{"amount":"1.x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_units_round_trip() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Row {
+            #[serde(with = "units")]
+            amount: MinorUnits,
+            #[serde(with = "units::option")]
+            fee: Option<MinorUnits>,
+        }
+        // 10^k and 0.0001 together need more significant digits than an `f64` has from k = 12.
+        for k in 0..15 {
+            let amount = MinorUnits(10i64.pow(k + 4) + 1);
+            let row = Row { amount, fee: Some(-amount) };
+            let json = serde_json::to_string(&row).unwrap();
+            assert_eq!(serde_json::from_str::<Row>(&json).unwrap(), row, "{json}");
+            assert_eq!(MinorUnits::from_f64(amount.to_f64()) == amount, k < 12, "{amount}");
+        }
+        let none = Row { amount: MinorUnits::ZERO, fee: None };
+        assert_eq!(serde_json::to_string(&none).unwrap(), r#"{"amount":"0","fee":null}"#);
+        assert_eq!(serde_json::from_str::<Row>(r#"{"amount":0,"fee":null}"#).unwrap(), none);
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        let sum: MinorUnits = std::iter::repeat_n(amount("0.1"), 10).sum();
+        assert_eq!(sum, amount("1"));
+        assert_eq!(amount("0.1") + amount("0.2"), amount("0.3"));
+        assert!(amount("0.3") - amount("0.1") > MinorUnits::ZERO);
+
+        let overflowed = MinorUnits(i64::MAX - 1) + amount("1");
+        assert!(!overflowed.is_finite());
+        assert_eq!(amount("-2.55").round(1), amount("-2.6"));
+    }
+
+    /// Runs the same transactions through a processor with amounts of type `A`.
+    fn processed<A: Amount>() -> Vec<(u16, f64, f64, bool)> {
+        use crate::model::{Transaction, TxType};
+        use crate::store::HashStores;
+        use crate::tx_processor::{ProcessorConfig, TxProcessor};
+
+        let mut processor: TxProcessor<HashStores, A> = TxProcessor::with_stores(ProcessorConfig::default());
+        let amount = |amount: &str| amount.parse::<A>().ok();
+        let transactions = [
+            Transaction::new(TxType::Deposit, 1, 1, amount("0.1")),
+            Transaction::new(TxType::Deposit, 1, 2, amount("0.2")),
+            Transaction::new(TxType::Withdrawal, 1, 3, amount("0.3")),
+            Transaction::new(TxType::Deposit, 2, 4, amount("12.5")),
+            Transaction::new(TxType::Dispute, 2, 4, None),
+            Transaction::new(TxType::Deposit, 3, 5, amount("7")),
+            Transaction::new(TxType::Dispute, 3, 5, None),
+            Transaction::new(TxType::Chargeback, 3, 5, None),
+        ];
+        for mut tx in transactions {
+            processor.process_transaction(&mut tx).unwrap();
+        }
+        let mut balances: Vec<_> = processor
+            .balances()
+            .map(|balance| (balance.client, balance.available.to_f64(), balance.held.to_f64(), balance.locked))
+            .collect();
+        balances.sort_by_key(|balance| balance.0);
+        balances
+    }
+
+    #[test]
+    fn test_processor_backends() {
+        let expected = vec![(1, 0.0, 0.0, false), (2, 0.0, 12.5, false), (3, 0.0, 0.0, true)];
+        assert_eq!(processed::<MinorUnits>(), expected);
+        let rounded: Vec<_> = processed::<f64>()
+            .into_iter()
+            .map(|(client, available, held, locked)| (client, available.round_dp(4), held, locked))
+            .collect();
+        assert_eq!(rounded, expected);
+        #[cfg(feature = "decimal")]
+        assert_eq!(processed::<rust_decimal::Decimal>(), expected);
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_decimal_amount() {
+        use crate::model::{ClientBalance, OverflowPolicy};
+        use rust_decimal::Decimal;
+
+        let mut balance: ClientBalance<Decimal> = ClientBalance::new_empty(1);
+        balance.add_funds("0.1".parse().unwrap(), OverflowPolicy::Reject).unwrap();
+        balance.add_funds("0.2".parse().unwrap(), OverflowPolicy::Reject).unwrap();
+        assert_eq!(balance.total.to_string(), "0.3");
+        assert_eq!(Amount::round_dp(Decimal::new(-25, 1), 0), Decimal::new(-3, 0));
+        assert_eq!(Amount::round_dp(Decimal::new(-1, 5), 4), Decimal::ZERO);
+    }
+}
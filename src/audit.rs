@@ -0,0 +1,178 @@
+//! Audit trail: an ordered log of every transaction given to a `TxProcessor`, applied or not,
+//! with the balance deltas it caused. Written while processing, as CSV or JSON lines, when an
+//! `AuditLog` (or another `AuditSink`) is set with `TxProcessorBuilder::audit`.
+
+pub use crate::tx_processor::AuditRecord;
+use crate::tx_processor::AuditSink;
+use crate::GResult;
+use std::io::{self, Write};
+use strum_macros::EnumString;
+
+const CSV_HEADER: [&str; 13] = [
+    "sequence",
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "outcome",
+    "reason_code",
+    "reason",
+    "available_delta",
+    "held_delta",
+    "total",
+    "tags",
+    "run_id",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum AuditFormat {
+    #[default]
+    #[strum(serialize = "csv")]
+    Csv,
+    /// One JSON object per line.
+    #[strum(serialize = "jsonl")]
+    JsonLines,
+}
+
+/// Where a processor writes its audit trail. Output is buffered, call `flush` to make sure what
+/// was processed so far is written.
+pub enum AuditLog {
+    Csv(Box<csv::Writer<Box<dyn Write + Send>>>),
+    JsonLines(io::BufWriter<Box<dyn Write + Send>>),
+}
+
+impl AuditLog {
+    /// Writes the audit trail to `out`, starting with a header line for CSV.
+    pub fn new<OUT: Write + Send + 'static>(out: OUT, format: AuditFormat) -> GResult<Self> {
+        Self::with_header(Box::new(out), format, true)
+    }
+
+    /// Writes the audit trail to a file at `path`. With `append`, records are added to an existing
+    /// file, ie when resuming from a checkpoint.
+    pub fn create(path: &str, format: AuditFormat, append: bool) -> GResult<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(append)
+            .write(true)
+            .truncate(!append)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        Self::with_header(Box::new(file), format, is_empty)
+    }
+
+    fn with_header(out: Box<dyn Write + Send>, format: AuditFormat, header: bool) -> GResult<Self> {
+        Ok(match format {
+            AuditFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                if header {
+                    writer.write_record(CSV_HEADER)?;
+                }
+                AuditLog::Csv(Box::new(writer))
+            }
+            AuditFormat::JsonLines => AuditLog::JsonLines(io::BufWriter::new(out)),
+        })
+    }
+
+}
+
+impl AuditSink for AuditLog {
+    fn append(&mut self, record: &AuditRecord) -> GResult<()> {
+        match self {
+            AuditLog::Csv(writer) => {
+                writer.write_record([
+                    record.sequence.to_string(),
+                    record.tx_type.to_string(),
+                    record.client.to_string(),
+                    record.tx_id.to_string(),
+                    record.amount.map(|amount| amount.to_string()).unwrap_or_default(),
+                    record.outcome.clone(),
+                    record.reason_code.clone().unwrap_or_default(),
+                    record.reason.clone().unwrap_or_default(),
+                    record.available_delta.to_string(),
+                    record.held_delta.to_string(),
+                    record.total.to_string(),
+                    record.tags.join(";"),
+                    record.run_id.clone().unwrap_or_default(),
+                ])?;
+            }
+            AuditLog::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        match self {
+            AuditLog::Csv(writer) => writer.flush()?,
+            AuditLog::JsonLines(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+    use crate::test_support::amount;
+    use crate::tx_processor::TxProcessor;
+    use std::sync::{Arc, Mutex};
+
+    /// Output shared with the test, since the processor owns the log.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn audit_trail(input: &str, format: AuditFormat) -> GResult<String> {
+        let output = SharedOutput::default();
+        let mut processor = TxProcessor::builder()
+            .audit(AuditLog::new(output.clone(), format)?)
+            .run_id("run-1")
+            .build();
+        processor.process_input(read_transactions_file(input)?)?;
+        processor.audit.as_mut().unwrap().flush()?;
+        let trail = output.0.lock().unwrap().clone();
+        Ok(String::from_utf8(trail).unwrap())
+    }
+
+    #[test]
+    fn test_audit_csv() -> GResult<()> {
+        let trail = audit_trail("tests/example.csv", AuditFormat::Csv)?;
+        let lines: Vec<_> = trail.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "sequence,type,client,tx,amount,outcome,reason_code,reason,available_delta,held_delta,total,tags,run_id"
+        );
+        assert_eq!(lines[1], "1,deposit,1,1,100,applied,,,100,0,100,,run-1");
+        assert_eq!(lines[5], "5,dispute,2,4,,applied,,,-80,80,80,,run-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_json_lines() -> GResult<()> {
+        let trail = audit_trail("tests/rejections.csv", AuditFormat::JsonLines)?;
+        let records: Vec<AuditRecord> = trail.lines().map(serde_json::from_str).collect::<Result<_, _>>()?;
+        let rejected: Vec<_> = records.iter().filter(|record| record.outcome == "rejected").collect();
+        assert!(!rejected.is_empty());
+        for record in rejected {
+            assert!(record.reason_code.is_some());
+            assert_eq!((record.available_delta, record.held_delta), (amount(0.0), amount(0.0)));
+        }
+        assert!(records.windows(2).all(|pair| pair[0].sequence + 1 == pair[1].sequence));
+        assert!(records.iter().all(|record| record.run_id.as_deref() == Some("run-1")));
+        Ok(())
+    }
+}
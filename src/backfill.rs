@@ -0,0 +1,196 @@
+//! Backfill from a corrected feed: compares the state produced by an original feed with the state
+//! produced by its corrected version, and generates the compensating transactions that move the
+//! committed (original) state to the corrected one.
+//!
+//! Available funds are compensated with deposits and withdrawals, and additional held funds with
+//! a deposit that is immediately disputed. Differences that can't be expressed with transactions
+//! (released holds, lock changes) are reported for manual review.
+
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::tx_processor::{TxOutcome, TxProcessor};
+use crate::{process_file_with, GResult};
+use std::collections::BTreeSet;
+use std::io;
+
+/// Float rounding noise, ignored when comparing balances.
+#[cfg(not(feature = "minor-units"))]
+const TOLERANCE: TxAmount = 1e-9;
+#[cfg(feature = "minor-units")]
+const TOLERANCE: TxAmount = TxAmount::ZERO;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackfillPlan {
+    /// Compensating transactions, in the order they must be applied after the original feed.
+    pub compensations: Vec<Transaction>,
+    /// Clients whose corrected state can't be reached, with the reason.
+    pub unresolved: Vec<(ClientId, String)>,
+}
+
+fn new_tx(tx_type: TxType, client: ClientId, tx_id: TxId, amount: Option<TxAmount>) -> Transaction {
+    Transaction {
+        tags: vec!["backfill".to_string()],
+        ..Transaction::new(tx_type, client, tx_id, amount)
+    }
+}
+
+fn same_balance(a: &ClientBalance, b: &ClientBalance) -> bool {
+    (a.available - b.available).abs() <= TOLERANCE
+        && (a.held - b.held).abs() <= TOLERANCE
+        && (a.total - b.total).abs() <= TOLERANCE
+        && a.locked == b.locked
+}
+
+/// Plans the compensations, and checks them by applying them to `original`. New transaction ids
+/// are allocated after `max_tx_id`.
+pub fn plan_backfill(original: &mut TxProcessor, corrected: &TxProcessor, max_tx_id: TxId) -> GResult<BackfillPlan> {
+    let mut plan = BackfillPlan {
+        compensations: vec![],
+        unresolved: vec![],
+    };
+    let mut next_tx_id = max_tx_id;
+    let mut allocate_tx_id = || {
+        next_tx_id = next_tx_id.checked_add(1).ok_or(crate::error::TxProcessorError::Overflow("tx id"))?;
+        GResult::Ok(next_tx_id)
+    };
+
+    let clients: BTreeSet<ClientId> = original
+        .balances()
+        .chain(corrected.balances())
+        .map(|balance| balance.client)
+        .collect();
+    for client in clients {
+        let from = original
+            .balance_of(client)
+            .cloned()
+            .unwrap_or_else(|| ClientBalance::new_empty(client));
+        let to = corrected
+            .balance_of(client)
+            .cloned()
+            .unwrap_or_else(|| ClientBalance::new_empty(client));
+        if same_balance(&from, &to) {
+            continue;
+        }
+        if to.held < from.held - TOLERANCE {
+            plan.unresolved.push((client, "held funds decreased, resolve or chargeback needed".to_string()));
+            continue;
+        }
+        if to.locked != from.locked {
+            plan.unresolved.push((client, "locked status differs".to_string()));
+            continue;
+        }
+
+        let mut compensations = vec![];
+        let held_delta = to.held - from.held;
+        if held_delta > TOLERANCE {
+            let tx_id = allocate_tx_id()?;
+            compensations.push(new_tx(TxType::Deposit, client, tx_id, Some(held_delta)));
+            compensations.push(new_tx(TxType::Dispute, client, tx_id, None));
+        }
+        let available_delta = to.available - from.available;
+        if available_delta > TOLERANCE {
+            compensations.push(new_tx(TxType::Deposit, client, allocate_tx_id()?, Some(available_delta)));
+        } else if available_delta < -TOLERANCE {
+            compensations.push(new_tx(TxType::Withdrawal, client, allocate_tx_id()?, Some(-available_delta)));
+        }
+
+        let mut rejection = None;
+        for tx in &mut compensations {
+            if let TxOutcome::Rejected(reason) = original.process_transaction(tx)? {
+                rejection.get_or_insert(format!("{} {} rejected: {reason}", tx.tx_type, tx.tx_id));
+            }
+        }
+        let reached = original.balance_of(client).is_some_and(|balance| same_balance(balance, &to));
+        plan.compensations.extend(compensations);
+        if !reached {
+            let reason = rejection.unwrap_or_else(|| "balance could not be reconciled".to_string());
+            plan.unresolved.push((client, reason));
+        }
+    }
+    Ok(plan)
+}
+
+/// Processes both feeds and plans the backfill from `original_path` to `corrected_path`.
+pub fn backfill_files(original_path: &str, corrected_path: &str) -> GResult<BackfillPlan> {
+    let mut max_tx_id = 0;
+    let mut track_tx_id = |tx: &Transaction, _: &TxOutcome| {
+        max_tx_id = max_tx_id.max(tx.tx_id);
+        Ok(())
+    };
+    let mut original = process_file_with(original_path, &mut track_tx_id)?;
+    let corrected = process_file_with(corrected_path, &mut track_tx_id)?;
+    plan_backfill(&mut original, &corrected, max_tx_id)
+}
+
+/// Writes the compensating transactions as an input CSV, so the batch can be reviewed and applied
+/// after the original feed.
+pub fn write_compensations_csv<OUT: io::Write>(out: OUT, compensations: &[Transaction]) -> GResult<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["type", "client", "tx", "amount"])?;
+    for tx in compensations {
+        let amount = tx.amount.map(|amount| amount.to_string()).unwrap_or_default();
+        writer.write_record([tx.tx_type.to_string(), tx.client.to_string(), tx.tx_id.to_string(), amount])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    fn processor(transactions: Vec<Transaction>) -> GResult<TxProcessor> {
+        let mut tx_processor = TxProcessor::new();
+        tx_processor.process_input(transactions.into_iter().map(Ok))?;
+        Ok(tx_processor)
+    }
+
+    #[test]
+    fn test_plan_backfill() -> GResult<()> {
+        use TxType::*;
+
+        let mut original = processor(vec![
+            deposit(1, 1, 100.0),
+            deposit(2, 2, 50.0),
+            deposit(3, 3, 20.0),
+            dispute(3, 3),
+            deposit(5, 6, 10.0),
+        ])?;
+        let corrected = processor(vec![
+            // Client 1 amount corrected down, client 2 up and with a dispute.
+            deposit(1, 1, 80.0),
+            deposit(2, 2, 70.0),
+            dispute(2, 2),
+            // Client 3 dispute was actually resolved.
+            deposit(3, 3, 20.0),
+            // New client 4.
+            deposit(4, 4, 5.0),
+            deposit(5, 6, 10.0),
+        ])?;
+
+        let plan = plan_backfill(&mut original, &corrected, 6)?;
+
+        assert_eq!(plan.compensations, vec![
+            new_tx(Withdrawal, 1, 7, Some(amount(20.0))),
+            new_tx(Deposit, 2, 8, Some(amount(70.0))),
+            new_tx(Dispute, 2, 8, None),
+            new_tx(Withdrawal, 2, 9, Some(amount(50.0))),
+            new_tx(Deposit, 4, 10, Some(amount(5.0))),
+        ]);
+        assert_eq!(plan.unresolved, vec![(
+            3,
+            "held funds decreased, resolve or chargeback needed".to_string()
+        )]);
+        for client in [1, 2, 4] {
+            assert_eq!(original.balance_of(client), corrected.balance_of(client));
+        }
+
+        let mut output = vec![];
+        write_compensations_csv(&mut output, &plan.compensations[..3])?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount\nwithdrawal,1,7,20\ndeposit,2,8,70\ndispute,2,8,\n"
+        );
+        Ok(())
+    }
+}
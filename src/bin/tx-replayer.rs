@@ -0,0 +1,34 @@
+//! Replays a transactions CSV against a running `tx_processor serve --http` server, and reports
+//! throughput and latency.
+//!
+//! Usage: `tx-replayer --addr host:port [--rate 500/s] <transactions.csv>`
+
+use std::error::Error;
+use tx_processor::read_transactions_file;
+use tx_processor::replay::{replay_http, ReplayConfig};
+use tx_processor::soak::parse_rate;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let mut addr = None;
+    let mut rate = None;
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--addr" => addr = Some(value()?),
+            "--rate" => rate = Some(parse_rate(&value()?)?),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or("Missing transactions file")?;
+    let config = ReplayConfig {
+        addr: addr.ok_or("Missing --addr of the server")?,
+        rate,
+    };
+    let report = replay_http(read_transactions_file(&path)?, &config)?;
+    println!("{report}");
+    Ok(())
+}
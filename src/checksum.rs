@@ -0,0 +1,63 @@
+//! CRC-32 checksums of the state files, so that a snapshot or journal that was truncated or
+//! corrupted on disk is reported when it is loaded, instead of restoring wrong balances.
+//!
+//! A snapshot starts with a `crc32 <checksum> <length>` line, followed by its JSON. Each journal
+//! entry is prefixed with the checksum of its JSON and a space. Files saved before checksums were
+//! added start directly with JSON, and are loaded unchecked.
+
+use crate::error::TxProcessorError;
+use crate::GResult;
+
+/// CRC-32 (IEEE) lookup table, one entry per byte value.
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+/// The CRC-32 checksum of `bytes`, as zlib and gzip compute it.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Fails with a `TxProcessorError::Corrupted` error if `bytes` don't have the `expected` checksum
+/// in hex.
+pub(crate) fn verify(what: &'static str, expected: &str, bytes: &[u8]) -> GResult<()> {
+    let actual = crc32(bytes);
+    match u32::from_str_radix(expected, 16) {
+        Ok(expected) if expected == actual => Ok(()),
+        _ => Err(TxProcessorError::Corrupted {
+            what,
+            message: format!("checksum is {actual:08x}, expected {expected}"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() -> GResult<()> {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        verify("journal", "cbf43926", b"123456789")?;
+        let err = verify("journal", "cbf43926", b"123456780").unwrap_err();
+        assert!(err.to_string().starts_with("corrupted journal: checksum is "));
+        assert!(verify("journal", "not hex", b"").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! Provisional deposits: a deposit with the `pending` marker (an optional `pending` column) is
+//! credited to `held` rather than `available`, and counted in `ClientBalance::pending`, until it
+//! clears. A `clear` record with the deposit's id clears it, and with
+//! `ProcessorConfig::clearing_period` set, deposits still pending after that period clear on
+//! their own. Until then, their funds can't be withdrawn, nor the deposits disputed.
+//!
+//! The period is counted in transactions, like `ProcessorConfig::hold_expiry`, or in time, from
+//! the deposit's timestamp to the latest timestamp seen (see `ordering::parse_timestamp`), in
+//! milliseconds. With a period in time, pending deposits without a timestamp are rejected.
+
+use crate::amount::Amount;
+use crate::error::{RejectReason, TxProcessorError};
+use crate::model::{ClientId, Transaction, TxAmount, TxType};
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long deposits stay pending unless a `clear` record clears them first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClearingPeriod {
+    /// Cleared once this many further transactions have been processed.
+    Records(u64),
+    /// Cleared by the first transaction this long after the deposit, by their timestamps.
+    Elapsed(Duration),
+}
+
+/// An integer is a number of transactions, a duration with a unit (`90s`, `15m`, `24h` or `2d`)
+/// a time.
+impl FromStr for ClearingPeriod {
+    type Err = TxProcessorError;
+
+    fn from_str(period: &str) -> GResult<Self> {
+        let period = period.trim();
+        if period.ends_with(['s', 'm', 'h', 'd']) {
+            return crate::soak::parse_duration(period).map(ClearingPeriod::Elapsed);
+        }
+        period.parse().map(ClearingPeriod::Records).map_err(|_| TxProcessorError::Parse {
+            field: "clearing_period",
+            message: format!("`{period}` is neither a number of transactions nor a duration"),
+        })
+    }
+}
+
+impl fmt::Display for ClearingPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClearingPeriod::Records(records) => write!(f, "{records}"),
+            ClearingPeriod::Elapsed(elapsed) => write!(f, "{}s", elapsed.as_secs_f64()),
+        }
+    }
+}
+
+/// A deposit that hasn't cleared yet, in `TxProcessor::pending_deposits` by its id.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct PendingDeposit<A = TxAmount> {
+    pub client: ClientId,
+    pub amount: A,
+    /// Sequence number, or timestamp with `ClearingPeriod::Elapsed`, from which the deposit
+    /// clears, if deposits clear on their own.
+    pub clears_at: Option<i64>,
+}
+
+/// Parses the `pending` column: `true`, `yes` or `1` mark a pending deposit, and `false`, `no`,
+/// `0` or an empty field a deposit that is available right away.
+pub fn parse_pending(value: &str) -> Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" | "" => Ok(false),
+        _ => Err(format!("`{value}` is not a boolean")),
+    }
+}
+
+/// When the pending deposit `tx`, processed at `sequence`, clears on its own, see
+/// `PendingDeposit::clears_at`.
+pub(crate) fn clears_at<A: Amount>(
+    period: Option<ClearingPeriod>,
+    sequence: u64,
+    tx: &Transaction<A>,
+) -> Result<Option<i64>, RejectReason> {
+    match period {
+        None => Ok(None),
+        Some(ClearingPeriod::Records(records)) => {
+            let clears_at = sequence.saturating_add(records).saturating_add(1);
+            Ok(Some(i64::try_from(clears_at).unwrap_or(i64::MAX)))
+        }
+        Some(ClearingPeriod::Elapsed(elapsed)) => {
+            let timestamp = tx.timestamp.ok_or(RejectReason::MissingTimestamp(tx.tx_id))?;
+            Ok(Some(timestamp.saturating_add(i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX))))
+        }
+    }
+}
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Clears the pending deposits whose period elapsed by the current transaction, which
+    /// happened at `timestamp`, if it has one.
+    pub(crate) fn clear_due_deposits(&mut self, timestamp: Option<i64>) -> GResult<()> {
+        let now = match self.config.clearing_period {
+            None => return Ok(()),
+            Some(ClearingPeriod::Records(_)) => i64::try_from(self.counters.sequence).unwrap_or(i64::MAX),
+            Some(ClearingPeriod::Elapsed(_)) => {
+                self.latest_timestamp = self.latest_timestamp.max(timestamp);
+                match self.latest_timestamp {
+                    Some(latest) => latest,
+                    None => return Ok(()),
+                }
+            }
+        };
+        while let Some(&(clears_at, tx_id)) = self.clearings.first() {
+            if clears_at > now {
+                break;
+            }
+            self.clearings.pop_first();
+            // Deposits cleared by a `clear` record leave their entry behind, as may a deposit
+            // whose id was reused since.
+            let due = self.pending_deposits.get(&tx_id).is_some_and(|deposit| deposit.clears_at == Some(clears_at));
+            if !due {
+                continue;
+            }
+            let deposit = self.pending_deposits.remove(&tx_id).expect("deposit was just found");
+            let before = self.ledger_balance(deposit.client);
+            if let Some(balance) = self.clients_balance.get_mut(&deposit.client) {
+                balance.clear_funds(deposit.amount, self.config.overflow_policy.unrejectable())?;
+            }
+            if let Some(before) = before {
+                self.post_ledger(TxType::Clear, tx_id, vec!["elapsed".to_string()], &before);
+            }
+            self.notify_balance_changed(deposit.client, tx_id)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the clearing schedule from the pending deposits, ie after loading them from a
+    /// snapshot.
+    pub(crate) fn rebuild_clearings(&mut self) {
+        self.clearings = self
+            .pending_deposits
+            .iter()
+            .filter_map(|(tx_id, deposit)| Some((deposit.clears_at?, *tx_id)))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::tx_processor::{ProcessorConfig, TxOutcome};
+
+    #[test]
+    fn test_clear_record() {
+        let scenario = Scenario::new()
+            .tx(pending_deposit(1, 1, 100.0))
+            .tx(deposit(1, 2, 10.0))
+            .balance(1, 10.0, 100.0, false)
+            .pending(1, 100.0)
+            // Pending funds can't be withdrawn or disputed, and only their client clears them.
+            .rejected(withdrawal(1, 3, 20.0), "insufficient_funds")
+            .rejected(dispute(1, 1), "pending_deposit")
+            .rejected(clear(2, 1), "unknown_tx_reference")
+            .tx(clear(1, 1))
+            .balance(1, 110.0, 0.0, false)
+            .pending(1, 0.0)
+            .rejected(clear(1, 1), "unknown_tx_reference")
+            .tx(dispute(1, 1))
+            .balance(1, 10.0, 100.0, false)
+            .pending(1, 0.0);
+        assert!(scenario.processor().pending_deposits.is_empty());
+    }
+
+    #[test]
+    fn test_clearing_period_in_records() -> GResult<()> {
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            clearing_period: Some(ClearingPeriod::Records(2)),
+            ..Default::default()
+        });
+        processor.process_transaction(&mut pending_deposit(1, 1, 100.0))?;
+        processor.process_transaction(&mut deposit(2, 2, 1.0))?;
+        processor.process_transaction(&mut deposit(2, 3, 1.0))?;
+        assert_eq!(processor.clients_balance[&1].pending, amount(100.0));
+
+        let outcome = processor.process_transaction(&mut withdrawal(1, 4, 100.0))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        assert_eq!(processor.clients_balance[&1].available, amount(0.0));
+        assert_eq!(processor.clients_balance[&1].pending, amount(0.0));
+        assert!(processor.pending_deposits.is_empty() && processor.clearings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clearing_period_in_time() -> GResult<()> {
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            clearing_period: Some("1h".parse()?),
+            ..Default::default()
+        });
+        let at = |mut tx: Transaction, timestamp: &str| {
+            tx.timestamp = Some(crate::ordering::parse_timestamp(timestamp).unwrap());
+            tx
+        };
+        processor.process_transaction(&mut at(pending_deposit(1, 1, 100.0), "2024-05-01T09:00:00Z"))?;
+        processor.process_transaction(&mut at(pending_deposit(1, 2, 50.0), "2024-05-01T09:30:00Z"))?;
+        // Transactions without a timestamp don't move the clock.
+        processor.process_transaction(&mut deposit(2, 3, 1.0))?;
+        processor.process_transaction(&mut at(deposit(2, 4, 1.0), "2024-05-01T09:59:59Z"))?;
+        assert_eq!(processor.clients_balance[&1].pending, amount(150.0));
+
+        processor.process_transaction(&mut at(deposit(2, 5, 1.0), "2024-05-01T10:00:00Z"))?;
+        assert_eq!(processor.clients_balance[&1].pending, amount(50.0));
+        assert_eq!(processor.clients_balance[&1].available, amount(100.0));
+        // The clock doesn't go back with an earlier timestamp.
+        processor.process_transaction(&mut at(deposit(2, 6, 1.0), "2024-05-01T08:00:00Z"))?;
+        processor.process_transaction(&mut clear(1, 2))?;
+        assert_eq!(processor.clients_balance[&1].available, amount(150.0));
+        processor.process_transaction(&mut at(deposit(2, 7, 1.0), "2024-05-01T11:00:00Z"))?;
+        assert_eq!(processor.clients_balance[&1].available, amount(150.0));
+
+        let outcome = processor.process_transaction(&mut pending_deposit(1, 8, 1.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::MissingTimestamp(8)));
+        assert_eq!(processor.clients_balance[&1].pending, amount(0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_column() -> GResult<()> {
+        let input = "type,client,tx,amount,pending\ndeposit,1,1,100,true\ndeposit,1,2,5,\nclear,1,1,,\n";
+        let mut processor = TxProcessor::new();
+        let outcomes: Vec<_> = crate::read_transactions_csv(input.as_bytes())
+            .map(|tx| processor.process_transaction(&mut tx?))
+            .collect::<GResult<_>>()?;
+        assert_eq!(outcomes, vec![TxOutcome::Applied; 3]);
+        assert_eq!(processor.clients_balance[&1].available, amount(105.0));
+
+        assert_eq!("12".parse::<ClearingPeriod>()?, ClearingPeriod::Records(12));
+        assert_eq!("2d".parse::<ClearingPeriod>()?.to_string(), "172800s");
+        assert!("soon".parse::<ClearingPeriod>().is_err());
+        assert_eq!(parse_pending(" Yes"), Ok(true));
+        assert!(parse_pending("maybe").is_err());
+        assert_eq!(RejectReason::PendingDeposit(1).to_string(), "deposit 1 has not cleared");
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! Transaction ids that repeat across input files, ie daily files that each number their
+//! transactions from 1. A deposit, withdrawal or hold whose id was already used by one of the
+//! previous files collides with it, and is handled according to the `TxIdCollisionPolicy`, so
+//! that disputes and other references resolve to the transaction they mean.
+//!
+//! Ids repeated within a file are not collisions, they are left to the processor.
+
+use crate::input::TransactionIter;
+use crate::model::{Transaction, TxId, TxType};
+use crate::ordering::{OrderCheck, OrderingPolicy};
+use crate::validation::{Finding, Severity};
+use crate::GResult;
+use std::collections::{HashMap, HashSet};
+use strum_macros::EnumString;
+
+/// What to do with a transaction whose id collides with one of a previous input file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum TxIdCollisionPolicy {
+    /// Ids are taken as they are: the later transaction replaces the earlier one as the target of
+    /// references.
+    #[default]
+    Ignore,
+    /// Ids are namespaced by file: a colliding transaction gets a new id, counting down from
+    /// `TxId::MAX`, and references to its id in the same or later files are renumbered along. It is
+    /// tagged `renumbered`, with an `Info` finding giving the new id.
+    Namespace,
+    /// The colliding transaction is rejected, and references keep resolving to the earlier one.
+    Reject,
+    /// The colliding transaction is taken as a repeat of the earlier one, and skipped.
+    Duplicate,
+}
+
+/// Where an id was last introduced, and the id it was given.
+#[derive(Debug, Clone, Copy)]
+struct Introduced {
+    file: usize,
+    tx_id: TxId,
+}
+
+/// Checks the transactions of input files, in order, for id collisions.
+#[derive(Debug)]
+pub struct CollisionCheck {
+    policy: TxIdCollisionPolicy,
+    paths: Vec<String>,
+    introduced: HashMap<TxId, Introduced>,
+    /// Ids given to renumbered transactions.
+    renumbered: HashSet<TxId>,
+    next_renumbered: TxId,
+}
+
+pub(crate) fn introduces_id(tx_type: TxType) -> bool {
+    matches!(tx_type, TxType::Deposit | TxType::Withdrawal | TxType::Hold)
+}
+
+pub(crate) fn references_id(tx_type: TxType) -> bool {
+    matches!(
+        tx_type,
+        TxType::Dispute
+            | TxType::Resolve
+            | TxType::Chargeback
+            | TxType::Release
+            | TxType::Capture
+            | TxType::Refund
+            | TxType::Clear
+    )
+}
+
+impl CollisionCheck {
+    pub fn new(policy: TxIdCollisionPolicy) -> Self {
+        Self {
+            policy,
+            paths: vec![],
+            introduced: HashMap::new(),
+            renumbered: HashSet::new(),
+            next_renumbered: TxId::MAX,
+        }
+    }
+
+    /// Starts checking the transactions of the file at `path`.
+    pub fn start_file(&mut self, path: &str) {
+        self.paths.push(path.to_string());
+    }
+
+    /// Checks the next transaction of the current file, returning it as it is to be processed, or
+    /// `None` if it is skipped.
+    pub fn check(&mut self, mut tx: Transaction) -> Option<Transaction> {
+        if self.policy == TxIdCollisionPolicy::Ignore || self.paths.is_empty() {
+            return Some(tx);
+        }
+        let file = self.paths.len() - 1;
+        if references_id(tx.tx_type) {
+            if let Some(introduced) = self.introduced.get(&tx.tx_id) {
+                if introduced.tx_id != tx.tx_id {
+                    tx.tx_id = introduced.tx_id;
+                    tx.tag("renumbered");
+                }
+            }
+            return Some(tx);
+        }
+        if !introduces_id(tx.tx_type) {
+            return Some(tx);
+        }
+
+        let earlier = match self.introduced.get(&tx.tx_id) {
+            Some(introduced) if introduced.file == file => {
+                tx.tx_id = introduced.tx_id;
+                return Some(tx);
+            }
+            Some(introduced) => Some(introduced.file),
+            // An id given to a renumbered transaction is taken too.
+            None if self.renumbered.contains(&tx.tx_id) => None,
+            None => {
+                self.introduced.insert(tx.tx_id, Introduced { file, tx_id: tx.tx_id });
+                return Some(tx);
+            }
+        };
+        let used_by = earlier.map_or("a renumbered transaction".to_string(), |earlier| format!("`{}`", self.paths[earlier]));
+        match self.policy {
+            TxIdCollisionPolicy::Ignore => Some(tx),
+            TxIdCollisionPolicy::Namespace => {
+                let tx_id = self.renumber();
+                let message = format!("transaction id {} is used by {used_by}, renumbered {tx_id}", tx.tx_id);
+                self.introduced.insert(tx.tx_id, Introduced { file, tx_id });
+                tx.tx_id = tx_id;
+                tx.findings.push(Finding::new(Severity::Info, message));
+                tx.tag("renumbered");
+                Some(tx)
+            }
+            TxIdCollisionPolicy::Reject => {
+                let message = format!("transaction id {} is already used by {used_by}", tx.tx_id);
+                tx.findings.push(Finding::new(Severity::Error, message));
+                Some(tx)
+            }
+            TxIdCollisionPolicy::Duplicate => None,
+        }
+    }
+
+    /// The next id that no transaction has.
+    fn renumber(&mut self) -> TxId {
+        while self.introduced.contains_key(&self.next_renumbered) || self.renumbered.contains(&self.next_renumbered) {
+            self.next_renumbered -= 1;
+        }
+        let tx_id = self.next_renumbered;
+        self.renumbered.insert(tx_id);
+        tx_id
+    }
+}
+
+/// Chains the transactions that `read_file` reads from each of `paths`, checked for id
+/// collisions across files.
+pub fn read_files_checked<F>(paths: Vec<String>, policy: TxIdCollisionPolicy, read_file: F) -> CheckedFiles<F>
+where
+    F: FnMut(&str) -> TransactionIter,
+{
+    CheckedFiles {
+        paths: paths.into_iter(),
+        read_file,
+        current: None,
+        check: CollisionCheck::new(policy),
+        order: OrderCheck::new(OrderingPolicy::Ignore),
+    }
+}
+
+/// See `read_files_checked`.
+pub struct CheckedFiles<F> {
+    paths: std::vec::IntoIter<String>,
+    read_file: F,
+    current: Option<TransactionIter>,
+    check: CollisionCheck,
+    order: OrderCheck,
+}
+
+impl<F> CheckedFiles<F> {
+    /// Also checks the time order of the transactions across files, see `ordering`. Ids are
+    /// checked first, so that re-sorting doesn't change which transaction an id refers to.
+    pub fn ordered(self, policy: OrderingPolicy) -> Self {
+        Self {
+            order: OrderCheck::new(policy),
+            ..self
+        }
+    }
+}
+
+impl<F: FnMut(&str) -> TransactionIter> Iterator for CheckedFiles<F> {
+    type Item = GResult<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(tx) = self.order.pop() {
+                return Some(tx);
+            }
+            if let Some(current) = &mut self.current {
+                match current.next() {
+                    Some(Ok(tx)) => {
+                        match self.check.check(tx) {
+                            Some(tx) => self.order.push(tx),
+                            None => self.order.skip(),
+                        }
+                        continue;
+                    }
+                    Some(Err(err)) => {
+                        self.order.skip();
+                        return Some(Err(err));
+                    }
+                    None => self.current = None,
+                }
+            }
+            let Some(path) = self.paths.next() else {
+                self.order.finish();
+                return self.order.pop();
+            };
+            self.check.start_file(&path);
+            self.order.start_file(&path);
+            self.current = Some((self.read_file)(&path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_csv;
+    use crate::test_support::amount;
+    use crate::tx_processor::{TxOutcome, TxProcessor};
+
+    const DAY_1: &str = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n";
+    const DAY_2: &str = "type,client,tx,amount\ndeposit,2,1,7\ndispute,2,1,\ndispute,1,2,\n";
+
+    fn read(paths: &[String], policy: TxIdCollisionPolicy) -> GResult<Vec<Transaction>> {
+        read_files_checked(paths.to_vec(), policy, |path| match path {
+            "day-1.csv" => read_transactions_csv(DAY_1.as_bytes()),
+            _ => read_transactions_csv(DAY_2.as_bytes()),
+        })
+        .collect()
+    }
+
+    fn ids(transactions: &[Transaction]) -> Vec<(TxType, TxId)> {
+        transactions.iter().map(|tx| (tx.tx_type, tx.tx_id)).collect()
+    }
+
+    #[test]
+    fn test_collision_policies() -> GResult<()> {
+        let paths = ["day-1.csv".to_string(), "day-2.csv".to_string()];
+        let (deposit, dispute) = (TxType::Deposit, TxType::Dispute);
+
+        let ignored = read(&paths, TxIdCollisionPolicy::Ignore)?;
+        assert_eq!(ids(&ignored), [(deposit, 1), (deposit, 2), (deposit, 1), (dispute, 1), (dispute, 2)]);
+
+        let namespaced = read(&paths, TxIdCollisionPolicy::Namespace)?;
+        assert_eq!(ids(&namespaced), [(deposit, 1), (deposit, 2), (deposit, TxId::MAX), (dispute, TxId::MAX), (dispute, 2)]);
+        assert_eq!(namespaced[2].findings[0].message, "transaction id 1 is used by `day-1.csv`, renumbered 4294967295");
+        assert!(namespaced[3].has_tag("renumbered") && !namespaced[4].has_tag("renumbered"));
+        let mut processor = TxProcessor::new();
+        let outcomes: Vec<_> = namespaced.into_iter().map(|mut tx| processor.process_transaction(&mut tx)).collect::<GResult<_>>()?;
+        assert!(outcomes.iter().all(|outcome| *outcome == TxOutcome::Applied));
+        assert_eq!(processor.balance_of(2).map(|balance| balance.held), Some(amount(7.0)));
+
+        let rejected = read(&paths, TxIdCollisionPolicy::Reject)?;
+        assert_eq!(ids(&rejected), ids(&ignored));
+        let mut processor = TxProcessor::new();
+        let outcomes: Vec<_> = rejected.into_iter().map(|mut tx| processor.process_transaction(&mut tx)).collect::<GResult<_>>()?;
+        let reason = "transaction id 1 is already used by `day-1.csv`";
+        assert_eq!(outcomes[2], TxOutcome::Rejected(crate::error::RejectReason::Invalid(reason.to_string())));
+        // The dispute references the deposit of the first file, of another client.
+        let unknown = crate::error::RejectReason::UnknownTxReference(1);
+        assert_eq!(outcomes[3], TxOutcome::Rejected(unknown));
+        assert_eq!(processor.balance_of(2).map(|balance| balance.held), Some(amount(0.0)));
+
+        let duplicates = read(&paths, TxIdCollisionPolicy::Duplicate)?;
+        assert_eq!(ids(&duplicates), [(deposit, 1), (deposit, 2), (dispute, 1), (dispute, 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_renumbered_ids_collide() {
+        let mut check = CollisionCheck::new(TxIdCollisionPolicy::Namespace);
+        let deposit = |tx_id| crate::test_support::deposit(1, tx_id, 1.0);
+        check.start_file("a.csv");
+        check.check(deposit(1));
+        check.start_file("b.csv");
+        assert_eq!(check.check(deposit(1)).map(|tx| tx.tx_id), Some(TxId::MAX));
+        check.start_file("c.csv");
+        // The id given to the renumbered deposit is taken, and the next one too.
+        assert_eq!(check.check(deposit(TxId::MAX)).map(|tx| tx.tx_id), Some(TxId::MAX - 1));
+        check.check(deposit(TxId::MAX - 2));
+        assert_eq!(check.check(deposit(1)).map(|tx| tx.tx_id), Some(TxId::MAX - 3));
+    }
+}
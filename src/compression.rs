@@ -0,0 +1,91 @@
+//! Transparent decompression of gzip and zstd input, detected from the stream's magic bytes so
+//! compressed dumps can be processed without temporary files.
+
+use crate::error::TxProcessorError;
+use crate::GResult;
+use std::io::{self, BufRead, BufReader, Read};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Wraps `input` in a decoder if it starts with gzip or zstd magic bytes, otherwise returns it
+/// as is.
+pub fn decompressed<R: Read + 'static>(input: R) -> GResult<Box<dyn Read>> {
+    let mut input = BufReader::new(input);
+    let header = fill_header(&mut input)?;
+
+    if header.starts_with(GZIP_MAGIC) {
+        #[cfg(feature = "gzip")]
+        return Ok(Box::new(flate2::read::MultiGzDecoder::new(input)));
+        #[cfg(not(feature = "gzip"))]
+        return Err(unsupported("gzip"));
+    }
+    if header.starts_with(ZSTD_MAGIC) {
+        #[cfg(feature = "zstd")]
+        return Ok(Box::new(zstd::stream::read::Decoder::with_buffer(input)?));
+        #[cfg(not(feature = "zstd"))]
+        return Err(unsupported("zstd"));
+    }
+    Ok(Box::new(input))
+}
+
+/// Peeks at the first bytes of the stream without consuming them.
+fn fill_header<R: Read>(input: &mut BufReader<R>) -> io::Result<Vec<u8>> {
+    loop {
+        match input.fill_buf() {
+            Ok(buffer) => return Ok(buffer[..buffer.len().min(ZSTD_MAGIC.len())].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn unsupported(format: &str) -> TxProcessorError {
+    TxProcessorError::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{format} input detected, but {format} support is not enabled in this build"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+
+    fn read_all(mut reader: Box<dyn Read>) -> String {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_plain_input() -> GResult<()> {
+        assert_eq!(read_all(decompressed(CSV.as_bytes())?), CSV);
+        assert_eq!(read_all(decompressed("".as_bytes())?), "");
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_input() -> GResult<()> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(CSV.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        assert_eq!(read_all(decompressed(io::Cursor::new(compressed))?), CSV);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_input() -> GResult<()> {
+        let compressed = zstd::encode_all(CSV.as_bytes(), 0)?;
+
+        assert_eq!(read_all(decompressed(io::Cursor::new(compressed))?), CSV);
+        Ok(())
+    }
+}
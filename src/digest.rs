@@ -0,0 +1,157 @@
+//! SHA-256 digests of the files a run reads and of the output it writes, for the manifest (see
+//! `manifest`) and the run summary (see `summary`).
+
+use crate::encryption::encode_hex;
+use crate::GResult;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// Size and SHA-256 of a file, or of the output stream.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileDigest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+/// Passes writes on to `inner`, hashing them.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// The digest of what was written.
+    pub fn finish(self) -> FileDigest {
+        FileDigest {
+            path: None,
+            sha256: encode_hex(&self.hasher.finalize()),
+            bytes: self.bytes,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads on from `inner`, hashing them. The digest is added to its `InputDigests` once the
+/// file is read to the end.
+pub struct HashingReader<R> {
+    inner: R,
+    path: String,
+    hasher: Option<Sha256>,
+    bytes: u64,
+    digests: InputDigests,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            if let Some(hasher) = self.hasher.take() {
+                self.digests.lock().push(FileDigest {
+                    path: Some(self.path.clone()),
+                    sha256: encode_hex(&hasher.finalize()),
+                    bytes: self.bytes,
+                });
+            }
+        } else if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..read]);
+            self.bytes += read as u64;
+        }
+        Ok(read)
+    }
+}
+
+/// Digests of the input files of a run, hashed as they are read rather than read again.
+#[derive(Debug, Clone, Default)]
+pub struct InputDigests(Arc<Mutex<Vec<FileDigest>>>);
+
+impl InputDigests {
+    /// Hashes `file`, the file at `path`, as it is read.
+    pub fn hashing<R: Read>(&self, path: &str, file: R) -> HashingReader<R> {
+        HashingReader {
+            inner: file,
+            path: path.to_string(),
+            hasher: Some(Sha256::new()),
+            bytes: 0,
+            digests: self.clone(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<FileDigest>> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The digests of the files at `paths`, in order. Files that weren't read to the end through
+    /// `hashing` (ie Parquet files, or when processing stopped early) are read again to hash them.
+    pub fn finish(&self, paths: &[String]) -> GResult<Vec<FileDigest>> {
+        let mut hashed = std::mem::take(&mut *self.lock());
+        let mut digest = |path: &String| match hashed.iter().position(|digest| digest.path.as_ref() == Some(path)) {
+            Some(index) => Ok(hashed.remove(index)),
+            None => digest_file(path),
+        };
+        paths.iter().map(&mut digest).collect()
+    }
+}
+
+/// The digest of the file at `path`.
+pub fn digest_file(path: &str) -> GResult<FileDigest> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => {
+                hasher.update(&buffer[..read]);
+                bytes += read as u64;
+            }
+        }
+    }
+    Ok(FileDigest {
+        path: Some(path.to_string()),
+        sha256: encode_hex(&hasher.finalize()),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_digests() -> GResult<()> {
+        let digests = InputDigests::default();
+        let paths = ["tests/example.csv".to_string(), "tests/disputes.csv".to_string()];
+        io::copy(&mut digests.hashing(&paths[0], std::fs::File::open(&paths[0])?), &mut io::sink())?;
+        // Not read to the end, so hashed again.
+        digests.hashing(&paths[1], std::fs::File::open(&paths[1])?).read_exact(&mut [0; 4])?;
+
+        let expected = paths.iter().map(|path| digest_file(path)).collect::<GResult<Vec<_>>>()?;
+        assert_eq!(digests.finish(&paths)?, expected);
+        Ok(())
+    }
+}
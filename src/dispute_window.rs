@@ -0,0 +1,102 @@
+//! Eviction of stored deposits and withdrawals that can no longer be disputed, so that the
+//! transaction store of an endless streaming run stays bounded.
+//!
+//! With `ProcessorConfig::dispute_window` set, a deposit or withdrawal can only be disputed (or
+//! refunded) within that many further transactions. Past its window, it is removed from the store,
+//! and references to it are rejected as `RejectReason::UnknownTxReference`, as if it had never been
+//! stored. A transaction still under dispute when its window ends is kept for another window, so
+//! that the dispute can be resolved or charged back.
+//!
+//! Windows are counted in transactions, like `ProcessorConfig::hold_expiry`, not in time: unlike
+//! clearing periods (see `clearing`), they must also work for inputs without a `timestamp` column.
+
+use crate::amount::Amount;
+use crate::model::TxId;
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Schedules the eviction of the deposit or withdrawal `tx_id` stored by the current
+    /// transaction, if there is a dispute window.
+    pub(crate) fn schedule_eviction(&mut self, tx_id: TxId) {
+        if let Some(window) = self.config.dispute_window {
+            let expires_at = self.counters.sequence.saturating_add(window);
+            self.tx_expiries.push_back((expires_at, tx_id));
+            self.tx_expires_at.insert(tx_id, expires_at);
+        }
+    }
+
+    /// Evicts the stored transactions whose window ended before the current sequence number.
+    pub(crate) fn evict_undisputable_transactions(&mut self) -> GResult<()> {
+        while let Some(&(expires_at, tx_id)) = self.tx_expiries.front() {
+            if expires_at >= self.counters.sequence {
+                break;
+            }
+            self.tx_expiries.pop_front();
+            // A transaction whose id was stored again since expires later.
+            if self.tx_expires_at.get(&tx_id) != Some(&expires_at) {
+                continue;
+            }
+            if self.open_disputes.contains_key(&tx_id) {
+                self.schedule_eviction(tx_id);
+                continue;
+            }
+            self.tx_expires_at.remove(&tx_id);
+            self.account_transactions.remove(tx_id)?;
+            self.refunded.remove(&tx_id);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the eviction schedule, ie after loading it from a snapshot.
+    pub(crate) fn rebuild_tx_expiries(&mut self, expiries: Vec<(u64, TxId)>) {
+        self.tx_expires_at = expiries.iter().map(|(expires_at, tx_id)| (*tx_id, *expires_at)).collect();
+        self.tx_expiries = expiries.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::*;
+    use crate::tx_processor::{ProcessorConfig, TxProcessor};
+    use crate::GResult;
+
+    #[test]
+    fn test_dispute_window() -> GResult<()> {
+        let config = ProcessorConfig {
+            dispute_window: Some(2),
+            dispute_withdrawals: true,
+            ..Default::default()
+        };
+        let processor = Scenario::with_config(config)
+            .tx(deposit(1, 1, 10.0))
+            .tx(deposit(1, 2, 5.0))
+            .tx(dispute(1, 1))
+            // The window of deposit 1 has ended, but it is kept for its open dispute.
+            .tx(resolve(1, 1))
+            .rejected(dispute(1, 2), "unknown_tx_reference")
+            .tx(withdrawal(1, 3, 1.0))
+            .tx(deposit(1, 4, 1.0))
+            .tx(deposit(1, 5, 1.0))
+            .tx(dispute(1, 4))
+            .rejected(dispute(1, 3), "unknown_tx_reference")
+            .balance(1, 15.0, 1.0, false)
+            .into_processor();
+        let mut stored: Vec<_> =
+            processor.stored_transactions().map(|entry| entry.map(|(tx_id, _)| tx_id)).collect::<GResult<_>>()?;
+        stored.sort();
+        assert_eq!(stored, [4, 5]);
+
+        // The schedule is restored with the state.
+        let mut snapshot = vec![];
+        processor.save_snapshot(&mut snapshot)?;
+        let mut restored = TxProcessor::builder().config(processor.config.clone()).build();
+        restored.load_snapshot(&snapshot[..])?;
+        Scenario::from_processor(restored)
+            .tx(deposit(1, 6, 1.0))
+            .tx(deposit(1, 7, 1.0))
+            .rejected(dispute(1, 5), "unknown_tx_reference");
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Character encoding of the input. Input is UTF-8 by default, and the byte order mark that
+//! Windows tools start their exports with is skipped. With the `encoding` feature, input in
+//! another encoding (ie Latin-1 exports, `windows-1252`) is converted to UTF-8 as it is read,
+//! using `encoding_rs`. A byte order mark then takes precedence over the encoding given, so UTF-16
+//! files are read as well.
+//!
+//! CRLF line endings need no conversion: the CSV and fixed-width readers take both.
+
+use crate::error::TxProcessorError;
+use crate::GResult;
+use std::io::{self, BufRead, BufReader, Read};
+use std::str::FromStr;
+
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+
+/// Character encoding of the input, parsed from a label like `utf-8` or, with the `encoding`
+/// feature, any WHATWG label (`latin1`, `windows-1252`, `utf-16le`...).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputEncoding {
+    #[default]
+    Utf8,
+    #[cfg(feature = "encoding")]
+    Other(&'static encoding_rs::Encoding),
+}
+
+impl FromStr for InputEncoding {
+    type Err = TxProcessorError;
+
+    fn from_str(label: &str) -> GResult<Self> {
+        let label = label.trim();
+        if label.eq_ignore_ascii_case("utf-8") || label.eq_ignore_ascii_case("utf8") {
+            return Ok(InputEncoding::Utf8);
+        }
+        #[cfg(feature = "encoding")]
+        if let Some(encoding) = encoding_rs::Encoding::for_label(label.as_bytes()) {
+            return Ok(match encoding == encoding_rs::UTF_8 {
+                true => InputEncoding::Utf8,
+                false => InputEncoding::Other(encoding),
+            });
+        }
+        let message = match cfg!(feature = "encoding") {
+            true => format!("unknown encoding `{label}`"),
+            false => format!("unsupported encoding `{label}`, only UTF-8 is supported without the `encoding` feature"),
+        };
+        Err(TxProcessorError::Parse {
+            field: "encoding",
+            message,
+        })
+    }
+}
+
+/// Wraps `input` so that it reads as UTF-8 without byte order mark, see the module docs. Bytes
+/// that aren't valid in `encoding` are replaced, except for UTF-8 input, where they are left for
+/// the parser to report.
+pub fn decoded<'a, R: Read + 'a>(input: R, encoding: InputEncoding) -> GResult<Box<dyn Read + 'a>> {
+    match encoding {
+        InputEncoding::Utf8 => {
+            let mut input = BufReader::new(input);
+            if starts_with_bom(&mut input)? {
+                input.consume(UTF8_BOM.len());
+            }
+            Ok(Box::new(input))
+        }
+        #[cfg(feature = "encoding")]
+        InputEncoding::Other(encoding) => Ok(Box::new(
+            encoding_rs_io::DecodeReaderBytesBuilder::new()
+                .encoding(Some(encoding))
+                .bom_override(true)
+                .strip_bom(true)
+                .build(input),
+        )),
+    }
+}
+
+fn starts_with_bom<R: Read>(input: &mut BufReader<R>) -> io::Result<bool> {
+    loop {
+        match input.fill_buf() {
+            Ok(buffer) => return Ok(buffer.starts_with(UTF8_BOM)),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut reader: Box<dyn Read + '_>) -> io::Result<String> {
+        let mut output = String::new();
+        reader.read_to_string(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_utf8_input() -> GResult<()> {
+        let input = b"\xef\xbb\xbftype,client\r\ndeposit,1\r\n";
+        assert_eq!(read_all(decoded(&input[..], InputEncoding::Utf8)?)?, "type,client\r\ndeposit,1\r\n");
+        assert_eq!(read_all(decoded(&b"type"[..], InputEncoding::Utf8)?)?, "type");
+        assert_eq!(read_all(decoded(&b""[..], InputEncoding::Utf8)?)?, "");
+        // Invalid bytes are left for the CSV parser to report, at their record.
+        assert!(read_all(decoded(&b"caf\xe9"[..], InputEncoding::Utf8)?).is_err());
+
+        assert_eq!("UTF-8".parse::<InputEncoding>()?, InputEncoding::Utf8);
+        #[cfg(not(feature = "encoding"))]
+        assert!("latin1".parse::<InputEncoding>().unwrap_err().to_string().contains("`encoding` feature"));
+        Ok(())
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn test_encoded_input() -> GResult<()> {
+        let latin1: InputEncoding = "latin1".parse()?;
+        assert_eq!(latin1, InputEncoding::Other(encoding_rs::WINDOWS_1252));
+        assert_eq!(read_all(decoded(&b"caf\xe9,1\r\n"[..], latin1)?)?, "caf\u{e9},1\r\n");
+        // A byte order mark takes precedence.
+        assert_eq!(read_all(decoded(&b"\xef\xbb\xbfcaf\xc3\xa9"[..], latin1)?)?, "caf\u{e9}");
+        assert_eq!(read_all(decoded(&b"\xff\xfet\0x\0"[..], latin1)?)?, "tx");
+        assert!("klingon".parse::<InputEncoding>().unwrap_err().to_string().contains("unknown encoding `klingon`"));
+        Ok(())
+    }
+}
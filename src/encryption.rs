@@ -0,0 +1,301 @@
+//! Encryption of the state files at rest. With a `StateKey` set on the processor, snapshots and
+//! journal entries are encrypted with ChaCha20-Poly1305, which also authenticates them: a file
+//! that was tampered with, or is read with another key, fails to load instead of restoring wrong
+//! balances. Requires the `encryption` feature.
+//!
+//! An encrypted snapshot is an `encrypted chacha20poly1305` line followed by the nonce and the
+//! ciphertext of the checksummed snapshot. An encrypted journal entry is a line with `encrypted`,
+//! its sequence number, and the hex of the nonce and ciphertext of its JSON. The sequence number is
+//! authenticated along, so that entries can't be reordered or replayed.
+//!
+//! With a key, unencrypted snapshots and journal entries are rejected, since anyone who can write
+//! the state files could otherwise swap in a plaintext one. Existing state is migrated by loading
+//! and saving it again with `StateEncryption::migrate_plaintext` set.
+//!
+//! The processor itself knows nothing of this: the key is given to the functions reading and
+//! writing the state files, as a `StateEncryption`.
+
+use crate::error::TxProcessorError;
+use crate::GResult;
+use std::fmt;
+
+/// First line of encrypted snapshots.
+const ENCRYPTED_SNAPSHOT: &[u8] = b"encrypted chacha20poly1305\n";
+/// Prefix of encrypted journal entries.
+pub(crate) const ENCRYPTED_ENTRY: &str = "encrypted ";
+
+const KEY_LEN: usize = 32;
+/// ChaCha20-Poly1305 nonces are 96 bits, random for each file or entry.
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key to encrypt the state files with, given as 64 hex digits.
+#[derive(Clone, PartialEq, Eq)]
+pub struct StateKey([u8; KEY_LEN]);
+
+impl fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StateKey(..)")
+    }
+}
+
+fn invalid_key(message: String) -> TxProcessorError {
+    TxProcessorError::Parse {
+        field: "state_key",
+        message,
+    }
+}
+
+impl StateKey {
+    pub fn from_hex(hex: &str) -> GResult<Self> {
+        if !cfg!(feature = "encryption") {
+            return Err(invalid_key("encrypting state requires the `encryption` feature".to_string()));
+        }
+        let bytes = decode_hex(hex.trim()).filter(|bytes| bytes.len() == KEY_LEN);
+        let bytes = bytes.ok_or_else(|| invalid_key(format!("a key is {} hex digits", KEY_LEN * 2)))?;
+        Ok(Self(bytes.try_into().expect("the key length was checked")))
+    }
+
+    /// Reads the key from the file at `path`, which should only be readable by the processor.
+    pub fn from_file(path: &str) -> GResult<Self> {
+        Self::from_hex(&std::fs::read_to_string(path)?)
+    }
+
+    /// Reads the key from the environment variable `name`.
+    pub fn from_env(name: &str) -> GResult<Self> {
+        let hex = std::env::var(name).map_err(|_| invalid_key(format!("environment variable `{name}` is not set")))?;
+        Self::from_hex(&hex)
+    }
+
+    /// Encrypts `plaintext` of the state file `what`, returning the nonce followed by the
+    /// ciphertext. `what` and `context` are authenticated along, so that a journal entry can't
+    /// pass for a snapshot, nor for another entry.
+    pub(crate) fn seal(&self, what: &'static str, context: &str, plaintext: &[u8]) -> GResult<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        {
+            use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+
+            let nonce = chacha20poly1305::ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let aad = associated_data(what, context);
+            let payload = Payload {
+                msg: plaintext,
+                aad: &aad,
+            };
+            let ciphertext = self.cipher().encrypt(&nonce, payload).map_err(|_| TxProcessorError::Corrupted {
+                what,
+                message: "it can't be encrypted".to_string(),
+            })?;
+            Ok([nonce.as_slice(), &ciphertext].concat())
+        }
+        #[cfg(not(feature = "encryption"))]
+        {
+            let _ = (self.0, what, context, plaintext);
+            unreachable!("keys can't be created without the `encryption` feature")
+        }
+    }
+
+    /// Decrypts what `seal` returned for the state file `what` and `context`.
+    pub(crate) fn open(&self, what: &'static str, context: &str, sealed: &[u8]) -> GResult<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        {
+            use chacha20poly1305::aead::{Aead, Payload};
+
+            let failed = || TxProcessorError::Corrupted {
+                what,
+                message: "it can't be decrypted with the state key, or it was tampered with".to_string(),
+            };
+            if sealed.len() < NONCE_LEN {
+                return Err(failed());
+            }
+            let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+            let aad = associated_data(what, context);
+            let payload = Payload {
+                msg: ciphertext,
+                aad: &aad,
+            };
+            self.cipher().decrypt(chacha20poly1305::Nonce::from_slice(nonce), payload).map_err(|_| failed())
+        }
+        #[cfg(not(feature = "encryption"))]
+        {
+            let _ = (self.0, what, context, sealed);
+            unreachable!("keys can't be created without the `encryption` feature")
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn cipher(&self) -> chacha20poly1305::ChaCha20Poly1305 {
+        use chacha20poly1305::KeyInit;
+        chacha20poly1305::ChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn associated_data(what: &str, context: &str) -> Vec<u8> {
+    format!("{what} {context}").into_bytes()
+}
+
+/// How the state files are written and read. The default neither encrypts them nor reads
+/// encrypted ones.
+#[derive(Debug, Clone, Default)]
+pub struct StateEncryption {
+    /// If set, snapshots and journal entries are encrypted with it.
+    pub key: Option<StateKey>,
+    /// With a `key`, still read unencrypted snapshots and journal entries, so that existing state
+    /// can be encrypted by loading and saving it again.
+    pub migrate_plaintext: bool,
+}
+
+impl StateEncryption {
+    pub fn new(key: StateKey) -> Self {
+        Self {
+            key: Some(key),
+            migrate_plaintext: false,
+        }
+    }
+
+    /// Fails if the state file `what` is encrypted but there is no key to decrypt it.
+    pub(crate) fn key_for(&self, what: &str) -> GResult<&StateKey> {
+        self.key.as_ref().ok_or_else(|| TxProcessorError::Parse {
+            field: "state_key",
+            message: format!("the {what} is encrypted, a state key is needed to read it"),
+        })
+    }
+
+    /// Fails if the state file `what` is unencrypted while there is a key, unless migrating.
+    pub(crate) fn check_plaintext(&self, what: &'static str) -> GResult<()> {
+        if self.key.is_some() && !self.migrate_plaintext {
+            return Err(TxProcessorError::Corrupted {
+                what,
+                message: "it isn't encrypted, though there is a state key".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The snapshot file for the checksummed snapshot `checked`, encrypted if there is a key.
+    pub(crate) fn seal_snapshot(&self, checked: Vec<u8>) -> GResult<Vec<u8>> {
+        match &self.key {
+            Some(key) => Ok([ENCRYPTED_SNAPSHOT, &key.seal("snapshot", "", &checked)?].concat()),
+            None => Ok(checked),
+        }
+    }
+
+    /// The checksummed snapshot in the snapshot file `bytes`, decrypted if it is encrypted.
+    pub(crate) fn open_snapshot(&self, bytes: Vec<u8>) -> GResult<Vec<u8>> {
+        match bytes.strip_prefix(ENCRYPTED_SNAPSHOT) {
+            Some(sealed) => self.key_for("snapshot")?.open("snapshot", "", sealed),
+            None => {
+                self.check_plaintext("snapshot")?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(decode_hex(&encode_hex(&[0, 0xab, 0xff])), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn test_no_encryption() {
+        assert!(StateKey::from_hex(KEY).unwrap_err().to_string().contains("`encryption` feature"));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_seal_and_open() -> GResult<()> {
+        let key = StateKey::from_hex(KEY)?;
+        assert!(StateKey::from_hex("0011").is_err());
+        assert_eq!(format!("{key:?}"), "StateKey(..)");
+
+        let sealed = key.seal("snapshot", "", b"balances")?;
+        assert_eq!(key.open("snapshot", "", &sealed)?, b"balances");
+        assert_ne!(key.seal("snapshot", "", b"balances")?, sealed);
+        assert!(key.open("journal", "", &sealed).is_err());
+        assert!(key.open("snapshot", "1", &sealed).is_err());
+        let other = StateKey::from_hex(&KEY.replace("1f", "20"))?;
+        let err = other.open("snapshot", "", &sealed).unwrap_err().to_string();
+        assert!(err.starts_with("corrupted snapshot: it can't be decrypted"));
+        assert!(key.open("snapshot", "", &sealed[..4]).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_state() -> GResult<()> {
+        use crate::journal::Journal;
+        use crate::read_transactions_file;
+        use crate::tx_processor::TxProcessor;
+
+        let path = std::env::temp_dir().join(format!("tx_encrypted_journal_test_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let key = StateKey::from_hex(KEY)?;
+
+        let (plaintext, mut encrypted) = (StateEncryption::default(), StateEncryption::new(key.clone()));
+
+        let mut processor = TxProcessor::builder().journal(Journal::open(&path)?.encrypted(key)).build();
+        processor.process_input(read_transactions_file("tests/example.csv")?)?;
+        let mut snapshot = vec![];
+        processor.save_snapshot_with(&mut snapshot, &encrypted)?;
+        assert!(snapshot.starts_with(ENCRYPTED_SNAPSHOT));
+        let journal = std::fs::read_to_string(&path)?;
+        assert!(journal.lines().all(|line| line.starts_with(ENCRYPTED_ENTRY)) && !journal.contains("deposit"));
+
+        let mut restored = TxProcessor::new();
+        let err = restored.load_snapshot(&snapshot[..]).unwrap_err().to_string();
+        assert!(err.contains("the snapshot is encrypted, a state key is needed"));
+        let err = restored.replay_journal(&path, &plaintext).unwrap_err().to_string();
+        assert!(err.contains("the journal is encrypted"));
+        restored.load_snapshot_with(&snapshot[..], &encrypted)?;
+        assert_eq!(restored.balances().count(), 2);
+        let mut replayed = TxProcessor::new();
+        assert_eq!(replayed.replay_journal(&path, &encrypted)?, 5);
+        assert_eq!(replayed.clients_balance, processor.clients_balance);
+
+        // An entry moved to another position doesn't pass for the entry there.
+        let mut lines: Vec<_> = journal.lines().collect();
+        let moved = lines[1].split_once(' ').unwrap().1.split_once(' ').unwrap().1;
+        let moved = format!("{ENCRYPTED_ENTRY}1 {moved}");
+        lines[0] = &moved;
+        std::fs::write(&path, lines.join("\n") + "\n")?;
+        let err = replayed.replay_journal(&path, &encrypted).unwrap_err().to_string();
+        assert!(err.starts_with("corrupted journal: it can't be decrypted"), "{err}");
+
+        // Unencrypted state is only read when migrating it.
+        let mut plain = vec![];
+        processor.save_snapshot(&mut plain)?;
+        let err = restored.load_snapshot_with(&plain[..], &encrypted).unwrap_err().to_string();
+        assert_eq!(err, "corrupted snapshot: it isn't encrypted, though there is a state key");
+        let entry = r#"{"sequence":1,"tx":{"type":"deposit","client":1,"tx":1,"amount":1.0}}"#;
+        std::fs::write(&path, format!("{entry}\n"))?;
+        let mut replayed = TxProcessor::new();
+        assert!(replayed.replay_journal(&path, &encrypted).unwrap_err().to_string().contains("it isn't encrypted"));
+        encrypted.migrate_plaintext = true;
+        restored.load_snapshot_with(&plain[..], &encrypted)?;
+        assert_eq!(replayed.replay_journal(&path, &encrypted)?, 1);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
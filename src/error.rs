@@ -0,0 +1,158 @@
+use crate::model::{ClientId, TxAmount, TxId};
+use std::io;
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TxProcessorError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[cfg(feature = "parquet")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[cfg(feature = "kafka")]
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    #[cfg(feature = "sled")]
+    #[error("sled error: {0}")]
+    Sled(#[from] sled::Error),
+    #[cfg(feature = "redis")]
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("invalid `{field}` field: {message}")]
+    Parse { field: &'static str, message: String },
+    /// A CSV record that could not be parsed, with its position in the input and its raw content.
+    #[error("line {line} (record {record}): {source}, in `{raw}`")]
+    InvalidRecord {
+        line: u64,
+        record: u64,
+        raw: String,
+        source: Box<TxProcessorError>,
+    },
+    /// The records before an input trailer don't add up to the count and total it has, see
+    /// `input::read_transactions_csv`.
+    #[error("trailer expects {expected_records} records totalling {expected_total}, found {records} totalling {total}")]
+    TrailerMismatch {
+        expected_records: u64,
+        expected_total: TxAmount,
+        records: u64,
+        total: TxAmount,
+    },
+    /// A transaction earlier than one before it in the input files, see `ordering`.
+    #[error("{at} is out of order: its timestamp {timestamp} is earlier than {latest}, of a transaction before it")]
+    OutOfOrder { at: String, timestamp: i64, latest: i64 },
+    /// Transactions earlier than one before them, processed as they are with
+    /// `OrderingPolicy::Warn`, see `ordering`.
+    #[error("{records} out of order: timestamps from {earliest} are earlier than {latest}, of a transaction before them")]
+    OutOfOrderSpan { records: String, earliest: i64, latest: i64 },
+    #[error("amount missing for transaction {0}")]
+    MissingAmount(TxId),
+    /// The transaction could not be applied, see `RejectReason`.
+    #[error(transparent)]
+    Rejected(#[from] RejectReason),
+    #[error("{0} overflow")]
+    Overflow(&'static str),
+    /// A snapshot or journal whose content doesn't match its checksum, see `checksum`.
+    #[error("corrupted {what}: {message}")]
+    Corrupted { what: &'static str, message: String },
+    /// Another server instance updated the client's balance while the transaction was processed,
+    /// see `redis_store`. The transaction wasn't applied, and can be submitted again.
+    #[error("client {0} was updated by another instance")]
+    Conflict(ClientId),
+    /// See `TxProcessor::merge_accounts`.
+    #[error("cannot merge client {from} into client {into}: {reason}")]
+    InvalidMerge {
+        from: ClientId,
+        into: ClientId,
+        reason: String,
+    },
+}
+
+impl TxProcessorError {
+    /// Whether the error is about the content of an input record, rather than a failure to read
+    /// it.
+    pub fn is_malformed_record(&self) -> bool {
+        match self {
+            TxProcessorError::Parse { .. }
+            | TxProcessorError::InvalidRecord { .. }
+            | TxProcessorError::MissingAmount(_) => true,
+            TxProcessorError::Csv(err) => !matches!(err.kind(), csv::ErrorKind::Io(_)),
+            TxProcessorError::Json(err) => !err.is_io(),
+            _ => false,
+        }
+    }
+}
+
+/// Receives the errors that servers and background threads carry on after, with what was being
+/// done, ie for the binary to log them. The library doesn't write to stderr itself.
+pub type ErrorHandler = Arc<dyn Fn(&str, &TxProcessorError) + Send + Sync>;
+
+/// Why a transaction was not applied. Unlike other errors these don't abort processing.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, serde::Serialize, serde::Deserialize)]
+pub enum RejectReason {
+    #[error("not enough funds")]
+    InsufficientFunds,
+    #[error("unknown transaction reference {0}")]
+    UnknownTxReference(TxId),
+    #[error("account {0} is locked")]
+    LockedAccount(ClientId),
+    /// Funds can't leave a frozen account, see `ClientBalance::frozen`.
+    #[error("account {0} is frozen")]
+    FrozenAccount(ClientId),
+    /// The account was merged into another, see `TxProcessor::merge_accounts`.
+    #[error("account {client} was merged into account {into}")]
+    MergedAccount { client: ClientId, into: ClientId },
+    #[error("duplicate transaction {0}")]
+    DuplicateTx(TxId),
+    /// The transaction has an open dispute already, see `TxProcessor::open_disputes`.
+    #[error("transaction {0} is already disputed")]
+    AlreadyDisputed(TxId),
+    /// Only a disputed transaction can be resolved or charged back.
+    #[error("transaction {0} is not disputed")]
+    NotDisputed(TxId),
+    /// The deposit is still pending, see `clearing`.
+    #[error("deposit {0} has not cleared")]
+    PendingDeposit(TxId),
+    /// A pending deposit can't clear after a time without a timestamp, see `ClearingPeriod::Elapsed`.
+    #[error("pending deposit {0} has no timestamp to clear after")]
+    MissingTimestamp(TxId),
+    #[error("refunds exceed withdrawal {0}")]
+    RefundExceedsWithdrawal(TxId),
+    /// Zero, negative or not a finite number.
+    #[error("amount of transaction {0} is not a positive number")]
+    NonPositiveAmount(TxId),
+    /// The balance would overflow the amount type, see `OverflowPolicy::Reject`.
+    #[error("balance of client {0} would overflow")]
+    BalanceOverflow(ClientId),
+    /// Rejected by one of the processor's validators.
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl RejectReason {
+    /// Stable identifier of the reason, for clients to act on without parsing the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::InsufficientFunds => "insufficient_funds",
+            RejectReason::UnknownTxReference(_) => "unknown_tx_reference",
+            RejectReason::LockedAccount(_) => "locked_account",
+            RejectReason::FrozenAccount(_) => "frozen_account",
+            RejectReason::MergedAccount { .. } => "merged_account",
+            RejectReason::DuplicateTx(_) => "duplicate_tx",
+            RejectReason::AlreadyDisputed(_) => "already_disputed",
+            RejectReason::NotDisputed(_) => "not_disputed",
+            RejectReason::PendingDeposit(_) => "pending_deposit",
+            RejectReason::MissingTimestamp(_) => "missing_timestamp",
+            RejectReason::RefundExceedsWithdrawal(_) => "refund_exceeds_withdrawal",
+            RejectReason::NonPositiveAmount(_) => "non_positive_amount",
+            RejectReason::BalanceOverflow(_) => "balance_overflow",
+            RejectReason::Invalid(_) => "invalid",
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Export of client activity, from the recorded history (see `history`), as OFX or QIF files that
+//! accounting packages import: one file per client, `client-<id>.ofx` or `client-<id>.qif`.
+//!
+//! Only transactions that change a client's total are exported; disputes, resolves, holds and
+//! releases only move funds between available and held. Transactions carry no dates, so they are
+//! all dated with the day of the export, in input order. OFX statements also give the total and
+//! available balances; QIF has no balances.
+
+use crate::amount::Amount;
+use crate::history::HistoryEvent;
+use crate::model::{ClientBalance, ClientId, TxAmount, TxType, AMOUNT_DECIMALS};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::io;
+use std::path::Path;
+use strum_macros::EnumString;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum ExportFormat {
+    #[default]
+    Ofx,
+    Qif,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Ofx => "ofx",
+            ExportFormat::Qif => "qif",
+        }
+    }
+}
+
+/// Currency of OFX statements when none is given.
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+/// A calendar day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportDate {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl ExportDate {
+    pub fn today() -> Self {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Self::from_days(secs as i64 / 86_400)
+    }
+
+    /// The date `days` days after 1970-01-01, in the proleptic Gregorian calendar.
+    fn from_days(days: i64) -> Self {
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        Self { year, month, day }
+    }
+
+    fn ofx(&self) -> String {
+        format!("{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+
+    fn qif(&self) -> String {
+        format!("{:02}/{:02}/{:04}", self.month, self.day, self.year)
+    }
+}
+
+/// Change of the client's total by `event`.
+fn total_delta(event: &HistoryEvent) -> TxAmount {
+    (event.available_delta + event.held_delta).round_dp(AMOUNT_DECIMALS)
+}
+
+fn exported(history: &[HistoryEvent]) -> impl Iterator<Item = (&HistoryEvent, TxAmount)> {
+    history
+        .iter()
+        .map(|event| (event, total_delta(event)))
+        .filter(|(_, delta)| *delta != TxAmount::default())
+}
+
+fn ofx_tx_type(tx_type: TxType) -> &'static str {
+    match tx_type {
+        TxType::Deposit => "DEP",
+        TxType::Withdrawal | TxType::Capture => "DEBIT",
+        TxType::Refund => "CREDIT",
+        _ => "OTHER",
+    }
+}
+
+/// Writes the history of `client` as an OFX 1.02 bank statement, ending with `balance`.
+pub fn write_ofx<OUT: io::Write>(
+    mut out: OUT,
+    client: ClientId,
+    history: &[HistoryEvent],
+    balance: &ClientBalance,
+    currency: &str,
+    date: ExportDate,
+) -> GResult<()> {
+    let date = date.ofx();
+    let round = |amount: TxAmount| amount.round_dp(AMOUNT_DECIMALS);
+    write!(
+        out,
+        "OFXHEADER:100\nDATA:OFXSGML\nVERSION:102\nSECURITY:NONE\nENCODING:USASCII\nCHARSET:1252\n\
+         COMPRESSION:NONE\nOLDFILEUID:NONE\nNEWFILEUID:NONE\n\n"
+    )?;
+    writeln!(out, "<OFX>")?;
+    writeln!(out, "<SIGNONMSGSRSV1><SONRS><STATUS><CODE>0<SEVERITY>INFO</STATUS>")?;
+    writeln!(out, "<DTSERVER>{date}<LANGUAGE>ENG</SONRS></SIGNONMSGSRSV1>")?;
+    writeln!(out, "<BANKMSGSRSV1><STMTTRNRS><TRNUID>{client}<STATUS><CODE>0<SEVERITY>INFO</STATUS>")?;
+    writeln!(out, "<STMTRS><CURDEF>{currency}")?;
+    writeln!(out, "<BANKACCTFROM><BANKID>TXPROCESSOR<ACCTID>{client}<ACCTTYPE>CHECKING</BANKACCTFROM>")?;
+    writeln!(out, "<BANKTRANLIST><DTSTART>{date}<DTEND>{date}")?;
+    for (event, amount) in exported(history) {
+        writeln!(out, "<STMTTRN><TRNTYPE>{}<DTPOSTED>{date}<TRNAMT>{amount}", ofx_tx_type(event.tx_type))?;
+        writeln!(out, "<FITID>{}-{}<NAME>{} {}</STMTTRN>", event.sequence, event.tx_id, event.tx_type, event.tx_id)?;
+    }
+    writeln!(out, "</BANKTRANLIST>")?;
+    writeln!(out, "<LEDGERBAL><BALAMT>{}<DTASOF>{date}</LEDGERBAL>", round(balance.total))?;
+    writeln!(out, "<AVAILBAL><BALAMT>{}<DTASOF>{date}</AVAILBAL>", round(balance.available))?;
+    writeln!(out, "</STMTRS></STMTTRNRS></BANKMSGSRSV1>")?;
+    writeln!(out, "</OFX>")?;
+    Ok(())
+}
+
+/// Writes the history of a client as a QIF bank account. Tags are in the memo.
+pub fn write_qif<OUT: io::Write>(mut out: OUT, history: &[HistoryEvent], date: ExportDate) -> GResult<()> {
+    let date = date.qif();
+    writeln!(out, "!Type:Bank")?;
+    for (event, amount) in exported(history) {
+        writeln!(out, "D{date}\nT{amount}\nN{}\nP{}", event.tx_id, event.tx_type)?;
+        if !event.tags.is_empty() {
+            writeln!(out, "M{}", event.tags.join(";"))?;
+        }
+        writeln!(out, "^")?;
+    }
+    Ok(())
+}
+
+/// Exports the activity of every client with a recorded history to a file in `dir`, which must
+/// exist. Returns the number of files written.
+pub fn export_history(
+    dir: &str,
+    format: ExportFormat,
+    currency: &str,
+    date: ExportDate,
+    processor: &TxProcessor,
+) -> GResult<usize> {
+    let mut clients: Vec<_> = processor.history.keys().copied().collect();
+    clients.sort();
+    for &client in &clients {
+        let path = Path::new(dir).join(format!("client-{client}.{}", format.extension()));
+        let out = io::BufWriter::new(std::fs::File::create(path)?);
+        let history = processor.client_history(client);
+        match format {
+            ExportFormat::Ofx => {
+                let balance = processor.balance_of(client).cloned().unwrap_or_else(|| ClientBalance::new_empty(client));
+                write_ofx(out, client, history, &balance, currency, date)?
+            }
+            ExportFormat::Qif => write_qif(out, history, date)?,
+        }
+    }
+    Ok(clients.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_processor::ProcessorConfig;
+
+    #[test]
+    fn test_export() -> GResult<()> {
+        assert_eq!(ExportDate::from_days(0), ExportDate { year: 1970, month: 1, day: 1 });
+        assert_eq!(ExportDate::from_days(20_742), ExportDate { year: 2026, month: 10, day: 16 });
+        assert_eq!(ExportDate::from_days(11_016), ExportDate { year: 2000, month: 2, day: 29 });
+
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            record_history: true,
+            ..Default::default()
+        });
+        let input = "type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,30.5\ndeposit,1,3,20\n\
+            dispute,1,3,\nchargeback,1,3,\n";
+        processor.process_input(crate::read_transactions_csv(input.as_bytes()))?;
+        let date = ExportDate { year: 2026, month: 10, day: 16 };
+
+        let mut out = vec![];
+        write_qif(&mut out, processor.client_history(1), date)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "!Type:Bank\n\
+             D10/16/2026\nT100\nN1\nPdeposit\n^\n\
+             D10/16/2026\nT-30.5\nN2\nPwithdrawal\n^\n\
+             D10/16/2026\nT20\nN3\nPdeposit\n^\n\
+             D10/16/2026\nT-20\nN3\nPchargeback\n^\n"
+        );
+
+        let mut out = vec![];
+        let balance = processor.balance_of(1).unwrap();
+        write_ofx(&mut out, 1, processor.client_history(1), balance, "EUR", date)?;
+        let ofx = String::from_utf8(out).unwrap();
+        assert!(ofx.starts_with("OFXHEADER:100\n"));
+        assert!(ofx.contains("<STMTRS><CURDEF>EUR\n"));
+        assert!(ofx.contains(
+            "<STMTTRN><TRNTYPE>DEBIT<DTPOSTED>20261016<TRNAMT>-30.5\n<FITID>2-2<NAME>withdrawal 2</STMTTRN>\n"
+        ));
+        assert!(ofx.contains("<STMTTRN><TRNTYPE>OTHER<DTPOSTED>20261016<TRNAMT>-20\n<FITID>5-3<NAME>chargeback 3</STMTTRN>\n"));
+        assert_eq!(ofx.matches("<STMTTRN>").count(), 4);
+        assert!(ofx.contains("<LEDGERBAL><BALAMT>69.5<DTASOF>20261016</LEDGERBAL>\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Fixed-width input, ie from mainframe feeds: each line is a record, with the fields of a
+//! transaction at the byte ranges of a `FixedWidthLayout`. Blank lines are skipped, and fields
+//! are trimmed, so that padding and short lines (trailing spaces stripped in transfer) are fine.
+
+use crate::error::TxProcessorError;
+use crate::encoding::InputEncoding;
+use crate::input::{open_input, parse_csv_fields, CsvColumns, TransactionIter};
+use crate::model::Transaction;
+use crate::GResult;
+use csv::StringRecord;
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Byte ranges of the fields of a transaction in a fixed-width record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedWidthLayout {
+    pub tx_type: Range<usize>,
+    pub client: Range<usize>,
+    pub tx: Range<usize>,
+    pub amount: Option<Range<usize>>,
+    pub idempotency_key: Option<Range<usize>>,
+}
+
+impl FromStr for FixedWidthLayout {
+    type Err = TxProcessorError;
+
+    /// Parses a layout like `type=0..10,client=10..15,tx=15..25,amount=25..40`: the byte range,
+    /// end excluded, of each field. `type`, `client` and `tx` are required.
+    fn from_str(layout: &str) -> GResult<Self> {
+        let invalid = |message: String| TxProcessorError::Parse {
+            field: "fixed_width_layout",
+            message,
+        };
+        let mut ranges = std::collections::HashMap::new();
+        for field in layout.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+            let range = field.split_once('=').and_then(|(name, range)| {
+                let (start, end) = range.trim().split_once("..")?;
+                let range = start.trim().parse().ok()?..end.trim().parse().ok()?;
+                Some((name.trim().to_lowercase(), range))
+            });
+            match range {
+                Some((name, range)) if range.start < range.end => ranges.insert(name, range),
+                _ => return Err(invalid(format!("`{field}` is not a `name=start..end` field"))),
+            };
+        }
+        let mut take = |name: &str| ranges.remove(name);
+        let required = |range: Option<Range<usize>>, name: &str| range.ok_or_else(|| invalid(format!("missing `{name}` field")));
+        let layout = Self {
+            tx_type: required(take("type"), "type")?,
+            client: required(take("client"), "client")?,
+            tx: required(take("tx"), "tx")?,
+            amount: take("amount"),
+            idempotency_key: take("idempotency_key"),
+        };
+        match ranges.keys().next() {
+            Some(name) => Err(invalid(format!("unknown field `{name}`"))),
+            None => Ok(layout),
+        }
+    }
+}
+
+impl FixedWidthLayout {
+    /// The fields of `line`, in the default CSV column order.
+    fn fields(&self, line: &str) -> StringRecord {
+        let field = |range: &Range<usize>| {
+            let end = range.end.min(line.len());
+            line.get(range.start.min(end)..end).unwrap_or_default()
+        };
+        let optional = |range: &Option<Range<usize>>| range.as_ref().map_or("", field);
+        StringRecord::from(vec![
+            field(&self.tx_type),
+            field(&self.client),
+            field(&self.tx),
+            optional(&self.amount),
+            optional(&self.idempotency_key),
+        ])
+    }
+}
+
+/// Streams the transactions of a fixed-width input. Errors parsing a record are
+/// `TxProcessorError::InvalidRecord`, with its line and raw content.
+pub fn read_transactions_fixed_width<'a, IN: io::Read + 'a>(
+    input: IN,
+    layout: FixedWidthLayout,
+) -> Box<dyn Iterator<Item = GResult<Transaction>> + 'a> {
+    let columns = CsvColumns::default();
+    let mut record = 0;
+    let lines = io::BufReader::new(input).lines().enumerate();
+    Box::new(lines.filter_map(move |(index, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => return Some(Err(err.into())),
+        };
+        if line.trim().is_empty() {
+            return None;
+        }
+        record += 1;
+        let tx: GResult<Transaction> = parse_csv_fields(&layout.fields(&line), &columns);
+        Some(tx.map_err(|err| TxProcessorError::InvalidRecord {
+            line: index as u64 + 1,
+            record,
+            raw: line,
+            source: Box::new(err),
+        }))
+    }))
+}
+
+/// Chains the transactions of fixed-width files in `encoding`, see `read_transactions_files`.
+pub fn read_fixed_width_files(
+    paths: &[String],
+    layout: &FixedWidthLayout,
+    encoding: InputEncoding,
+) -> impl Iterator<Item = GResult<Transaction>> {
+    let (paths, layout) = (paths.to_vec(), layout.clone());
+    paths.into_iter().flat_map(move |path| {
+        match open_input(&path, encoding) {
+            Ok(file) => read_transactions_fixed_width(file, layout.clone()),
+            Err(err) => Box::new(std::iter::once(Err(err))) as TransactionIter,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TxType;
+    use crate::test_support::amount;
+
+    #[test]
+    fn test_fixed_width() -> GResult<()> {
+        let layout: FixedWidthLayout = "type=0..10, client=10..15, tx=15..25, amount=25..40".parse()?;
+        let input = "deposit   00001000000001       12.5000\n\ndispute   00001000000001\nwithdrawal0000x000000002\n";
+        let transactions: Vec<_> = read_transactions_fixed_width(input.as_bytes(), layout).collect();
+
+        let tx = transactions[0].as_ref().unwrap();
+        assert_eq!((tx.tx_type, tx.client, tx.tx_id, tx.amount), (TxType::Deposit, 1, 1, Some(amount(12.5))));
+        let tx = transactions[1].as_ref().unwrap();
+        assert_eq!((tx.tx_type, tx.client, tx.tx_id, tx.amount), (TxType::Dispute, 1, 1, None));
+        let err = transactions[2].as_ref().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 4 (record 3): invalid `client` field: invalid digit found in string, in `withdrawal0000x000000002`"
+        );
+
+        let err = "type=0..10,client=10..15".parse::<FixedWidthLayout>().unwrap_err();
+        assert_eq!(err.to_string(), "invalid `fixed_width_layout` field: missing `tx` field");
+        assert!("type=0..10,client=10..15,tx=15..25,region=25..27".parse::<FixedWidthLayout>().is_err());
+        assert!("type=0..10,client=15..10,tx=15..25".parse::<FixedWidthLayout>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Handover of a running server to a new process (ie a new version of the binary), without losing
+//! or applying twice any transaction:
+//!
+//! 1. The running server listens for handover requests on a Unix socket, see
+//!    `listen_for_handover`.
+//! 2. The new process connects to it and sends `handover`, see `take_over`.
+//! 3. The running server drains: it stops accepting connections and closes its listener, and lets
+//!    the transactions being applied finish. It then writes its state, as a snapshot, to the new
+//!    process and exits without saving or truncating anything.
+//! 4. The new process loads the state, replays the journal they share (entries already covered by
+//!    the state are skipped), and starts listening in turn.
+//!
+//! Connections are refused between the old listener closing and the new one opening, clients
+//! should retry (with idempotency keys, retrying is always safe).
+
+use crate::error::{ErrorHandler, TxProcessorError};
+use crate::server::{DrainSignal, DRAIN_POLL_INTERVAL};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread::{self, JoinHandle};
+
+const HANDOVER_REQUEST: &str = "handover";
+
+/// Waits for a handover request on the socket at `path`, in a background thread. A request drains
+/// the server, and the thread returns the connection to write the state to once drained. The
+/// thread returns `None`, and removes the socket, if the server drains for another reason. Invalid
+/// requests are reported to `on_error`.
+pub fn listen_for_handover(
+    path: &str,
+    drain: DrainSignal,
+    on_error: ErrorHandler,
+) -> GResult<JoinHandle<Option<UnixStream>>> {
+    // A socket file left behind by a process that crashed would make the bind fail.
+    if UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)?;
+    // Non-blocking, so that the thread notices a drain.
+    listener.set_nonblocking(true)?;
+    let path = path.to_string();
+    let handle = thread::Builder::new().name("tx-handover".to_string()).spawn(move || {
+        while !drain.is_draining() {
+            match listener.accept() {
+                Ok((stream, _)) => match read_request(&stream) {
+                    Ok(()) => {
+                        drain.drain();
+                        return Some(stream);
+                    }
+                    Err(err) => on_error("Invalid handover request", &err),
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(DRAIN_POLL_INTERVAL),
+                Err(err) => on_error("Failed to accept handover connection", &err.into()),
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        None
+    })?;
+    Ok(handle)
+}
+
+fn read_request(stream: &UnixStream) -> GResult<()> {
+    stream.set_nonblocking(false)?;
+    let mut request = String::new();
+    BufReader::new(stream).read_line(&mut request)?;
+    if request.trim() != HANDOVER_REQUEST {
+        return Err(TxProcessorError::Parse {
+            field: "handover",
+            message: format!("unexpected request `{}`", request.trim()),
+        });
+    }
+    Ok(())
+}
+
+/// Writes the state of the drained server to the process taking over, then removes the socket at
+/// `path`, so that the new process can listen on it.
+pub fn hand_over(mut stream: UnixStream, processor: &TxProcessor, path: &str) -> GResult<()> {
+    processor.save_snapshot(&mut stream)?;
+    stream.flush()?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Asks the server listening at `path` to hand over, and loads its state into `processor` once it
+/// has drained.
+pub fn take_over(path: &str, processor: &mut TxProcessor) -> GResult<()> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{HANDOVER_REQUEST}")?;
+    processor.load_snapshot(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+    use crate::test_support::collected_errors;
+
+    #[test]
+    fn test_handover() -> GResult<()> {
+        let path = std::env::temp_dir().join(format!("tx_processor_handover_{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut old = TxProcessor::new();
+        old.process_input(read_transactions_file("tests/example.csv")?)?;
+        let drain = DrainSignal::default();
+        let (on_error, errors) = collected_errors();
+        let listener = listen_for_handover(&path, drain.clone(), on_error)?;
+
+        let new_path = path.clone();
+        let new = thread::spawn(move || {
+            let mut new = TxProcessor::new();
+            take_over(&new_path, &mut new).map(|()| new)
+        });
+        // The old server would now be draining, then hand over once drained.
+        let stream = listener.join().unwrap().expect("a handover was requested");
+        assert!(drain.is_draining());
+        hand_over(stream, &old, &path)?;
+        assert!(errors.lock().unwrap().is_empty());
+
+        let new = new.join().unwrap()?;
+        assert_eq!(new.clients_balance, old.clients_balance);
+        assert_eq!(new.counters.sequence, 5);
+        assert!(!std::path::Path::new(&path).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_handover() -> GResult<()> {
+        let path = std::env::temp_dir().join(format!("tx_processor_no_handover_{}.sock", std::process::id()));
+        let drain = DrainSignal::default();
+        let listener = listen_for_handover(path.to_str().unwrap(), drain.clone(), collected_errors().0)?;
+        drain.drain();
+        assert!(listener.join().unwrap().is_none());
+        assert!(!path.exists());
+        Ok(())
+    }
+}
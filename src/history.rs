@@ -0,0 +1,155 @@
+//! Per-client history of applied transactions, recorded when `ProcessorConfig::record_history`
+//! is set, to explain how a balance came to be.
+
+use crate::amount::Amount;
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::io;
+
+/// A transaction applied to a client, with how it changed the balance.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct HistoryEvent<A = TxAmount> {
+    /// Sequence number of the transaction in the processor's input.
+    pub sequence: u64,
+    pub tx_type: TxType,
+    pub tx_id: TxId,
+    pub amount: Option<A>,
+    pub available_delta: A,
+    pub held_delta: A,
+    /// Balance after the transaction.
+    pub balance: ClientBalance<A>,
+    pub tags: Vec<String>,
+}
+
+impl<A: Amount> HistoryEvent<A> {
+    pub(crate) fn new(sequence: u64, tx: &Transaction<A>, before: &ClientBalance<A>, after: &ClientBalance<A>) -> Self {
+        Self {
+            sequence,
+            tx_type: tx.tx_type,
+            tx_id: tx.tx_id,
+            amount: tx.amount,
+            available_delta: after.available - before.available,
+            held_delta: after.held - before.held,
+            balance: after.clone(),
+            tags: tx.tags.clone(),
+        }
+    }
+}
+
+/// Events written between flushes by `write_history_csv`, so that the output goes out in chunks
+/// however long a client's history is.
+const WRITE_PAGE_SIZE: usize = 10_000;
+
+/// Part of a client's history, see `TxProcessor::client_history_page`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryPage<'a, A = TxAmount> {
+    pub events: &'a [HistoryEvent<A>],
+    /// Offset of the next page, if there are more events.
+    pub next_offset: Option<usize>,
+}
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Applied transactions of `client`, in order. Empty unless history is being recorded.
+    pub fn client_history(&self, client: ClientId) -> &[HistoryEvent<A>] {
+        self.history.get(&client).map_or(&[], Vec::as_slice)
+    }
+
+    /// At most `limit` events of the history of `client`, starting at `offset`. Pages borrow from
+    /// the history, nothing is copied.
+    pub fn client_history_page(&self, client: ClientId, offset: usize, limit: usize) -> HistoryPage<'_, A> {
+        let history = self.client_history(client);
+        let start = offset.min(history.len());
+        let end = start.saturating_add(limit).min(history.len());
+        HistoryPage {
+            events: &history[start..end],
+            next_offset: (end < history.len()).then_some(end),
+        }
+    }
+}
+
+/// Writes the history of all clients as CSV, ordered by client and then by sequence.
+pub fn write_history_csv<OUT: io::Write>(out: OUT, processor: &TxProcessor) -> GResult<()> {
+    let mut clients: Vec<_> = processor.history.keys().copied().collect();
+    clients.sort();
+
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "client", "sequence", "type", "tx", "amount", "available_delta", "held_delta", "available", "held", "total",
+        "locked", "tags",
+    ])?;
+    for client in clients {
+        let mut offset = Some(0);
+        while let Some(page_offset) = offset {
+            let page = processor.client_history_page(client, page_offset, WRITE_PAGE_SIZE);
+            for event in page.events {
+                write_history_row(&mut writer, client, event)?;
+            }
+            writer.flush()?;
+            offset = page.next_offset;
+        }
+    }
+    Ok(())
+}
+
+fn write_history_row<OUT: io::Write>(writer: &mut csv::Writer<OUT>, client: ClientId, event: &HistoryEvent) -> GResult<()> {
+    writer.write_record([
+        client.to_string(),
+        event.sequence.to_string(),
+        event.tx_type.to_string(),
+        event.tx_id.to_string(),
+        event.amount.map(|amount| amount.to_string()).unwrap_or_default(),
+        event.available_delta.to_string(),
+        event.held_delta.to_string(),
+        event.balance.available.to_string(),
+        event.balance.held.to_string(),
+        event.balance.total.to_string(),
+        event.balance.locked.to_string(),
+        event.tags.join(";"),
+    ])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+    use crate::test_support::amount;
+    use crate::tx_processor::ProcessorConfig;
+
+    #[test]
+    fn test_client_history() -> GResult<()> {
+        let config = ProcessorConfig {
+            record_history: true,
+            ..Default::default()
+        };
+        let mut processor = TxProcessor::with_config(config);
+        processor.process_input(read_transactions_file("tests/example.csv")?)?;
+
+        let history = processor.client_history(2);
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[1].tx_type, history[1].sequence), (TxType::Dispute, 5));
+        assert_eq!((history[1].available_delta, history[1].held_delta), (amount(-80.0), amount(80.0)));
+        assert_eq!(history[1].balance.held, amount(80.0));
+        assert!(processor.client_history(3).is_empty());
+
+        let mut output = vec![];
+        write_history_csv(&mut output, &processor)?;
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("client,sequence,type,tx,amount,available_delta,held_delta,available,held,total,locked,tags\n1,1,deposit,1,100,100,0,100,0,100,false,\n"));
+        assert!(output.ends_with("2,5,dispute,4,,-80,80,0,80,80,false,\n"));
+
+        let page = processor.client_history_page(1, 0, 1);
+        assert_eq!((page.events.len(), page.events[0].sequence, page.next_offset), (1, 1, Some(1)));
+        let page = processor.client_history_page(1, 1, 10);
+        assert_eq!((page.events.len(), page.next_offset), (2, None));
+        assert!(processor.client_history_page(1, 5, 10).events.is_empty());
+
+        let mut processor = TxProcessor::new();
+        processor.process_input(read_transactions_file("tests/example.csv")?)?;
+        assert!(processor.client_history(1).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! Authorization holds (pre-auths): a `hold` (or `authorize`) transaction reserves available
+//! funds of a client, moving them to `held` until a `release` transaction referencing the hold's
+//! id gives them back. A `capture` settles the hold instead: its amount, or all of the hold, is
+//! withdrawn, and the rest is given back.
+//!
+//! With `ProcessorConfig::hold_expiry` set, holds that are still open after that many further
+//! transactions are released automatically.
+
+use crate::amount::Amount;
+use crate::error::RejectReason;
+use crate::model::{ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+
+/// An open hold, in `TxProcessor::holds` by the id of the transaction that placed it.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct Hold<A = TxAmount> {
+    pub client: ClientId,
+    pub amount: A,
+    /// Sequence number after which the hold is released, if holds expire.
+    pub expires_at: Option<u64>,
+}
+
+/// Removes the hold referenced by `tx`, which must belong to the same client.
+pub(crate) fn take_hold<A: Amount>(holds: &mut impl StateMap<TxId, Hold<A>>, tx: &Transaction<A>) -> GResult<Hold<A>> {
+    match holds.get(&tx.tx_id) {
+        Some(hold) if hold.client == tx.client => Ok(holds.remove(&tx.tx_id).expect("hold was just found")),
+        _ => Err(RejectReason::UnknownTxReference(tx.tx_id).into()),
+    }
+}
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Releases the holds that expired before the current sequence number.
+    pub(crate) fn release_expired_holds(&mut self) -> GResult<()> {
+        while let Some(&(expires_at, tx_id)) = self.hold_expiries.front() {
+            if expires_at >= self.counters.sequence {
+                break;
+            }
+            self.hold_expiries.pop_front();
+            // Holds released explicitly leave their entry behind, as may a hold whose id was
+            // reused since.
+            if self.holds.get(&tx_id).is_none_or(|hold| hold.expires_at != Some(expires_at)) {
+                continue;
+            }
+            let hold = self.holds.remove(&tx_id).expect("hold was just found");
+            let before = self.ledger_balance(hold.client);
+            if let Some(balance) = self.clients_balance.get_mut(&hold.client) {
+                balance.resolve_funds(hold.amount, self.config.overflow_policy.unrejectable())?;
+            }
+            if let Some(before) = before {
+                self.post_ledger(TxType::Release, tx_id, vec!["expired".to_string()], &before);
+            }
+            self.notify_balance_changed(hold.client, tx_id)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the expiry queue from the open holds, ie after loading them from a snapshot.
+    pub(crate) fn rebuild_hold_expiries(&mut self) {
+        let mut expiries: Vec<_> = self
+            .holds
+            .iter()
+            .filter_map(|(tx_id, hold)| Some((hold.expires_at?, *tx_id)))
+            .collect();
+        expiries.sort();
+        self.hold_expiries = expiries.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::tx_processor::{ProcessorConfig, TxOutcome};
+
+    fn balance(processor: &TxProcessor, client: ClientId) -> (TxAmount, TxAmount, TxAmount) {
+        let balance = &processor.clients_balance[&client];
+        (balance.available, balance.held, balance.total)
+    }
+
+    #[test]
+    fn test_hold_and_release() -> GResult<()> {
+        let mut processor = TxProcessor::new();
+        processor.process_transaction(&mut deposit(1, 1, 100.0))?;
+        assert_eq!(processor.process_transaction(&mut hold(1, 2, 60.0))?, TxOutcome::Applied);
+        assert_eq!(balance(&processor, 1), (amount(40.0), amount(60.0), amount(100.0)));
+
+        // Held funds can't be withdrawn or held again.
+        let outcome = processor.process_transaction(&mut withdrawal(1, 3, 50.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::InsufficientFunds));
+        let outcome = processor.process_transaction(&mut hold(1, 4, 50.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::InsufficientFunds));
+        let outcome = processor.process_transaction(&mut hold(1, 2, 10.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::DuplicateTx(2)));
+
+        // Only the client that placed a hold can release it, and only once.
+        let outcome = processor.process_transaction(&mut release(2, 2))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(2)));
+        assert_eq!(processor.process_transaction(&mut release(1, 2))?, TxOutcome::Applied);
+        let outcome = processor.process_transaction(&mut release(1, 2))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(2)));
+        assert_eq!(balance(&processor, 1), (amount(100.0), amount(0.0), amount(100.0)));
+        assert!(processor.holds.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture() -> GResult<()> {
+        let mut processor = TxProcessor::new();
+        processor.process_transaction(&mut deposit(1, 1, 100.0))?;
+        processor.process_transaction(&mut hold(1, 2, 60.0))?;
+        processor.process_transaction(&mut hold(1, 3, 30.0))?;
+
+        // A capture can't exceed its hold, which stays open.
+        let outcome = processor.process_transaction(&mut capture(1, 2, Some(70.0)))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::InsufficientFunds));
+        assert_eq!(balance(&processor, 1), (amount(10.0), amount(90.0), amount(100.0)));
+
+        // A partial capture gives back the rest of the hold.
+        assert_eq!(processor.process_transaction(&mut capture(1, 2, Some(45.0)))?, TxOutcome::Applied);
+        assert_eq!(balance(&processor, 1), (amount(25.0), amount(30.0), amount(55.0)));
+        assert_eq!(processor.counters.withdrawn_volume, amount(45.0));
+
+        // Without an amount, all of the hold is captured.
+        assert_eq!(processor.process_transaction(&mut capture(1, 3, None))?, TxOutcome::Applied);
+        assert_eq!(balance(&processor, 1), (amount(25.0), amount(0.0), amount(25.0)));
+        let outcome = processor.process_transaction(&mut capture(1, 3, None))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(3)));
+        assert!(processor.holds.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_authorize() -> GResult<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,100\nauthorize,1,2,40\ncapture,1,2,\n";
+        let mut processor = TxProcessor::new();
+        processor.process_input(crate::read_transactions_csv(input.as_bytes()))?;
+        assert_eq!(balance(&processor, 1), (amount(60.0), amount(0.0), amount(60.0)));
+        assert_eq!(TxType::Hold.to_string(), "hold");
+        Ok(())
+    }
+
+    #[test]
+    fn test_hold_expiry() -> GResult<()> {
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            hold_expiry: Some(2),
+            ..Default::default()
+        });
+        processor.process_transaction(&mut deposit(1, 1, 100.0))?;
+        processor.process_transaction(&mut hold(1, 2, 60.0))?;
+        processor.process_transaction(&mut deposit(2, 3, 1.0))?;
+        processor.process_transaction(&mut deposit(2, 4, 1.0))?;
+        assert_eq!(processor.clients_balance[&1].held, amount(60.0));
+
+        processor.process_transaction(&mut deposit(2, 5, 1.0))?;
+        assert_eq!(processor.clients_balance[&1].held, amount(0.0));
+        assert_eq!(processor.clients_balance[&1].available, amount(100.0));
+        assert!(processor.holds.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+//! HTTP API over a shared `TxProcessor`, or with the `async` feature over a `ClientRouter` that
+//! processes each client in a task of its own:
+//!
+//! - `POST /transactions` submits a transaction as a JSON object, and responds with its outcome.
+//!   A rejected transaction is answered with 409 if the account is locked or frozen and 422
+//!   otherwise, with the reason's `reason_code`.
+//! - `GET /clients?after=&limit=&locked=&frozen=` gets a page of the client balances, ordered by
+//!   client id and filtered by status, with the `next_cursor` to get the next page `after`, see
+//!   `accounts`.
+//! - `GET /clients/{client}` gets the balance of one client.
+//! - `GET /clients/{client}/history?offset=&limit=` gets a page of the client's history, if the
+//!   processor records it, with the `next_offset` to get the next page from.
+//! - `POST /admin/drain` drains the server: new submissions are refused with 503, and the
+//!   server exits once in-flight requests are done.
+//!
+//! With a `ClientRouter`, the tasks of clients idle for the given time are ended periodically.
+
+use crate::accounts::{AccountFilter, AccountPage};
+#[cfg(feature = "async")]
+use crate::{accounts::account_page, actors::ClientRouter};
+use crate::error::{RejectReason, TxProcessorError};
+use crate::history::HistoryEvent;
+use crate::model::{ClientBalance, ClientId, Transaction};
+use crate::output::rounded;
+use crate::server::{lock, DrainSignal, DRAIN_POLL_INTERVAL};
+use crate::tx_processor::{TxOutcome, TxProcessor};
+use crate::GResult;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::time::Duration;
+
+type SharedProcessor = Arc<Mutex<TxProcessor>>;
+
+/// What the API processes transactions with.
+#[derive(Clone)]
+enum Backend {
+    Processor(SharedProcessor),
+    #[cfg(feature = "async")]
+    Actors(Arc<ClientRouter>),
+}
+
+/// Page size of `GET /clients/{client}/history` without a `limit`, and the largest allowed.
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1000;
+/// Page size of `GET /clients` without a `limit`, and the largest allowed.
+const DEFAULT_CLIENTS_LIMIT: usize = 100;
+const MAX_CLIENTS_LIMIT: usize = 1000;
+
+#[derive(Clone)]
+struct ApiState {
+    backend: Backend,
+    drain: DrainSignal,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SubmitResponse {
+    outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason_code: Option<&'static str>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ClientsQuery {
+    after: Option<ClientId>,
+    limit: Option<usize>,
+    locked: Option<bool>,
+    frozen: Option<bool>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct HistoryResponse {
+    events: Vec<HistoryEvent>,
+    next_offset: Option<usize>,
+}
+
+impl IntoResponse for TxProcessorError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    }
+}
+
+pub fn router(processor: SharedProcessor, drain: DrainSignal) -> Router {
+    routes(Backend::Processor(processor), drain)
+}
+
+fn routes(backend: Backend, drain: DrainSignal) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transaction))
+        .route("/clients", get(list_clients))
+        .route("/clients/{client}", get(get_client))
+        .route("/clients/{client}/history", get(get_client_history))
+        .route("/admin/drain", post(start_drain))
+        .with_state(ApiState { backend, drain })
+}
+
+/// Serves the HTTP API on `listener` until `drain` is signalled and in-flight requests are done.
+pub async fn serve_http(
+    listener: tokio::net::TcpListener,
+    processor: SharedProcessor,
+    drain: DrainSignal,
+) -> GResult<()> {
+    serve_routes(listener, router(processor, drain.clone()), drain).await
+}
+
+/// Serves the HTTP API with `router`, like `serve_http`, ending the tasks of clients that had no
+/// transactions for `idle_timeout`.
+#[cfg(feature = "async")]
+pub async fn serve_http_actors(
+    listener: tokio::net::TcpListener,
+    router: Arc<ClientRouter>,
+    idle_timeout: Duration,
+    drain: DrainSignal,
+) -> GResult<()> {
+    let reaped = Arc::clone(&router);
+    let reaper = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(idle_timeout).await;
+            reaped.end_idle_tasks(idle_timeout);
+        }
+    });
+    let served = serve_routes(listener, routes(Backend::Actors(router), drain.clone()), drain).await;
+    reaper.abort();
+    served
+}
+
+async fn serve_routes(listener: tokio::net::TcpListener, routes: Router, drain: DrainSignal) -> GResult<()> {
+    axum::serve(listener, routes)
+        .with_graceful_shutdown(async move {
+            while !drain.is_draining() {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+async fn submit_transaction(State(state): State<ApiState>, Json(mut tx): Json<Transaction>) -> Response {
+    if state.drain.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response();
+    }
+    let outcome = match &state.backend {
+        Backend::Processor(processor) => lock(processor).process_transaction(&mut tx),
+        #[cfg(feature = "async")]
+        Backend::Actors(router) => router.submit(tx).await,
+    };
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(err) => return err.into_response(),
+    };
+    let (status, reason_code) = match &outcome {
+        TxOutcome::Rejected(reason) => (rejection_status(reason), Some(reason.code())),
+        _ => (StatusCode::OK, None),
+    };
+    let response = SubmitResponse {
+        outcome: outcome.to_string(),
+        reason_code,
+    };
+    (status, Json(response)).into_response()
+}
+
+fn rejection_status(reason: &RejectReason) -> StatusCode {
+    match reason {
+        RejectReason::LockedAccount(_) | RejectReason::FrozenAccount(_) => StatusCode::CONFLICT,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+async fn list_clients(State(state): State<ApiState>, Query(query): Query<ClientsQuery>) -> Json<AccountPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_CLIENTS_LIMIT).min(MAX_CLIENTS_LIMIT);
+    let filter = AccountFilter {
+        locked: query.locked,
+        frozen: query.frozen,
+    };
+    Json(match &state.backend {
+        Backend::Processor(processor) => lock(processor).account_page(query.after, limit, &filter),
+        #[cfg(feature = "async")]
+        Backend::Actors(router) => account_page(router.balances().iter(), query.after, limit, &filter),
+    })
+}
+
+async fn get_client(State(state): State<ApiState>, Path(client): Path<ClientId>) -> Result<Json<ClientBalance<f64>>, StatusCode> {
+    let balance = match &state.backend {
+        Backend::Processor(processor) => lock(processor).balance_of(client).map(rounded),
+        #[cfg(feature = "async")]
+        Backend::Actors(router) => router.balance_of(client).as_ref().map(rounded),
+    };
+    balance.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_client_history(
+    State(state): State<ApiState>,
+    Path(client): Path<ClientId>,
+    Query(query): Query<HistoryQuery>,
+) -> Json<HistoryResponse> {
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).min(MAX_HISTORY_LIMIT);
+    let history_page = |processor: &TxProcessor| {
+        let page = processor.client_history_page(client, query.offset, limit);
+        HistoryResponse {
+            events: page.events.to_vec(),
+            next_offset: page.next_offset,
+        }
+    };
+    Json(match &state.backend {
+        Backend::Processor(processor) => history_page(&lock(processor)),
+        #[cfg(feature = "async")]
+        Backend::Actors(router) => router.with_client(client, history_page).unwrap_or(HistoryResponse {
+            events: vec![],
+            next_offset: None,
+        }),
+    })
+}
+
+async fn start_drain(State(state): State<ApiState>) -> StatusCode {
+    state.drain.drain();
+    StatusCode::ACCEPTED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_processor::ProcessorConfig;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> GResult<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn test_http_api() -> GResult<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))?;
+        let addr = listener.local_addr()?;
+        let drain = DrainSignal::default();
+        let processor = TxProcessor::with_config(ProcessorConfig {
+            record_history: true,
+            ..Default::default()
+        });
+        let server = runtime.spawn(serve_http(listener, Arc::new(Mutex::new(processor)), drain.clone()));
+
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.5}"#;
+        let response = request(addr, "POST", "/transactions", deposit)?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with(r#"{"outcome":"applied"}"#));
+
+        let withdrawal = r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 20}"#;
+        let response = request(addr, "POST", "/transactions", withdrawal)?;
+        assert!(response.starts_with("HTTP/1.1 422"));
+        assert!(response.ends_with(r#"{"outcome":"rejected: not enough funds","reason_code":"insufficient_funds"}"#));
+
+        let dispute = r#"{"type": "dispute", "client": 1, "tx": 1}"#;
+        request(addr, "POST", "/transactions", dispute)?;
+        let chargeback = r#"{"type": "chargeback", "client": 1, "tx": 1}"#;
+        request(addr, "POST", "/transactions", chargeback)?;
+        let response = request(addr, "POST", "/transactions", deposit.replace("\"tx\": 1", "\"tx\": 3").as_str())?;
+        assert!(response.starts_with("HTTP/1.1 409"));
+        assert!(response.ends_with(r#"{"outcome":"rejected: account 1 is locked","reason_code":"locked_account"}"#));
+
+        let response = request(addr, "GET", "/clients/1", "")?;
+        assert!(response.ends_with(r#"{"client":1,"available":0.0,"held":0.0,"total":0.0,"locked":true}"#));
+
+        let response = request(addr, "GET", "/clients", "")?;
+        let client_1 = r#"{"client":1,"available":0.0,"held":0.0,"total":0.0,"locked":true}"#;
+        assert!(response.ends_with(&format!(r#"{{"clients":[{client_1}],"next_cursor":null}}"#)));
+        request(addr, "POST", "/transactions", r#"{"type": "deposit", "client": 3, "tx": 4, "amount": 1}"#)?;
+        request(addr, "POST", "/transactions", r#"{"type": "freeze", "client": 3, "tx": 0}"#)?;
+        let response = request(addr, "GET", "/clients?limit=1", "")?;
+        assert!(response.ends_with(&format!(r#"{{"clients":[{client_1}],"next_cursor":1}}"#)));
+        let response = request(addr, "GET", "/clients?after=1&limit=1", "")?;
+        assert!(response.contains(r#"{"clients":[{"client":3,"#) && response.ends_with(r#""next_cursor":null}"#));
+        let response = request(addr, "GET", "/clients?frozen=true", "")?;
+        assert!(response.contains(r#"{"clients":[{"client":3,"#));
+        let response = request(addr, "GET", "/clients?locked=false&frozen=false", "")?;
+        assert!(response.ends_with(r#"{"clients":[],"next_cursor":null}"#));
+
+        let response = request(addr, "GET", "/clients/2", "")?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        // Deposit, dispute and chargeback were applied to client 1.
+        let response = request(addr, "GET", "/clients/1/history?offset=1&limit=1", "")?;
+        assert!(response.contains(r#"{"events":[{"sequence":3,"tx_type":"dispute","tx_id":1,"#));
+        assert!(response.ends_with(r#""next_offset":2}"#));
+        let response = request(addr, "GET", "/clients/1/history?offset=2", "")?;
+        assert!(response.contains(r#""tx_type":"chargeback""#));
+        assert!(response.ends_with(r#""next_offset":null}"#));
+        let response = request(addr, "GET", "/clients/2/history", "")?;
+        assert!(response.ends_with(r#"{"events":[],"next_offset":null}"#));
+
+        let response = request(addr, "POST", "/admin/drain", "")?;
+        assert!(response.starts_with("HTTP/1.1 202"));
+        runtime.block_on(server).unwrap()?;
+        assert!(drain.is_draining());
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_http_api_actors() -> GResult<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))?;
+        let addr = listener.local_addr()?;
+        let drain = DrainSignal::default();
+        let config = ProcessorConfig {
+            record_history: true,
+            ..Default::default()
+        };
+        let handle = runtime.handle().clone();
+        let router = Arc::new(ClientRouter::new(config, move |task| {
+            handle.spawn(task);
+        }));
+        let idle_timeout = Duration::from_millis(10);
+        let server = runtime.spawn(serve_http_actors(listener, Arc::clone(&router), idle_timeout, drain.clone()));
+
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.5}"#;
+        let response = request(addr, "POST", "/transactions", deposit)?;
+        assert!(response.ends_with(r#"{"outcome":"applied"}"#));
+        request(addr, "POST", "/transactions", r#"{"type": "deposit", "client": 2, "tx": 2, "amount": 1}"#)?;
+        let response = request(addr, "GET", "/clients?limit=1", "")?;
+        assert!(response.contains(r#"{"clients":[{"client":1,"available":10.5,"#));
+        assert!(response.ends_with(r#""next_cursor":1}"#));
+        let response = request(addr, "GET", "/clients/2", "")?;
+        assert!(response.ends_with(r#"{"client":2,"available":1.0,"held":0.0,"total":1.0,"locked":false}"#));
+        let response = request(addr, "GET", "/clients/1/history", "")?;
+        assert!(response.contains(r#"{"events":[{"sequence":1,"tx_type":"deposit","tx_id":1,"#));
+
+        // Idle client tasks are ended, and spawned again for the client's next transaction.
+        std::thread::sleep(idle_timeout * 5);
+        assert_eq!((router.task_count(), router.client_count()), (0, 2));
+        let response = request(addr, "POST", "/transactions", r#"{"type": "dispute", "client": 1, "tx": 1}"#)?;
+        assert!(response.ends_with(r#"{"outcome":"applied"}"#));
+        let response = request(addr, "GET", "/clients/1", "")?;
+        assert!(response.ends_with(r#"{"client":1,"available":0.0,"held":10.5,"total":10.5,"locked":false}"#));
+
+        request(addr, "POST", "/admin/drain", "")?;
+        runtime.block_on(server).unwrap()?;
+        Ok(())
+    }
+}
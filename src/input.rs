@@ -0,0 +1,458 @@
+//! Transaction input: files (CSV, compressed CSV or Parquet), CSV readers, and CSV records
+//! parsed into `Transaction`s. Part of the I/O layer, see `tx_processor` for the core.
+
+use crate::amount::Amount;
+use crate::digest::InputDigests;
+use crate::encoding::InputEncoding;
+use crate::error::TxProcessorError;
+use crate::model::{Transaction, TxAmount, TxType, AMOUNT_DECIMALS};
+use crate::GResult;
+use csv::StringRecord;
+use std::fmt::Display;
+use std::io;
+use std::str::FromStr;
+use strum_macros::EnumString;
+
+pub type TransactionIter = Box<dyn Iterator<Item = GResult<Transaction>>>;
+
+/// Streams the transactions of the file at `path`. Files are CSV, unless the `parquet` feature
+/// is enabled and the file has a `.parquet` extension. Gzip and zstd compressed CSV files are
+/// decompressed on the fly.
+pub fn read_transactions_file(path: &str) -> GResult<TransactionIter> {
+    read_encoded_file(path, InputEncoding::default())
+}
+
+/// Like `read_transactions_file`, for CSV files in `encoding`.
+pub fn read_encoded_file(path: &str, encoding: InputEncoding) -> GResult<TransactionIter> {
+    #[cfg(feature = "parquet")]
+    if path.ends_with(".parquet") {
+        return Ok(Box::new(crate::parquet_io::ParquetTransactions::open(std::fs::File::open(path)?)?));
+    }
+    Ok(read_transactions_csv(open_input(path, encoding)?))
+}
+
+/// Opens a text input file, read with io_uring where available (see `uring`), decompressed (see
+/// `compression`) and decoded (see `encoding`).
+pub fn open_input(path: &str, encoding: InputEncoding) -> GResult<Box<dyn io::Read>> {
+    open_hashed_input(path, encoding, None)
+}
+
+/// Like `open_input`, hashing the file into `digests` as it is read, if given.
+pub fn open_hashed_input(path: &str, encoding: InputEncoding, digests: Option<&InputDigests>) -> GResult<Box<dyn io::Read>> {
+    let file = crate::uring::open_file(path)?;
+    let file = match digests {
+        Some(digests) => Box::new(digests.hashing(path, file)),
+        None => file,
+    };
+    crate::encoding::decoded(crate::compression::decompressed(file)?, encoding)
+}
+
+/// Format of the transaction input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum InputFormat {
+    /// CSV with a header, or Parquet for `.parquet` files, see `read_transactions_file`.
+    #[default]
+    Csv,
+    /// Fixed-width records, see `fixed_width`.
+    FixedWidth,
+}
+
+/// What to do when an input trailer doesn't match the records before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum TrailerPolicy {
+    /// Processing stops with the `TxProcessorError::TrailerMismatch`.
+    #[default]
+    Fail,
+    /// The mismatch is reported in `ProcessReport::warnings`, and processing goes on.
+    Warn,
+}
+
+/// Streams the transactions of a CSV input (with header).
+///
+/// A `trailer` record, ie `trailer,<record count>,<amount total>` as some partners append, is
+/// checked against the records before it (since the previous trailer, if any): their number, and
+/// the sum of their amounts to `AMOUNT_DECIMALS` places. A mismatch is a
+/// `TxProcessorError::TrailerMismatch` in place of the trailer, a trailer that matches is skipped.
+pub fn read_transactions_csv<IN: io::Read + 'static>(input: IN) -> TransactionIter {
+    csv_transactions(csv_reader(input))
+}
+
+/// A CSV reader for transactions. It is flexible so that trailers can be shorter than the header,
+/// the width of other records is checked by `csv_transactions`.
+pub(crate) fn csv_reader<IN: io::Read>(input: IN) -> csv::Reader<IN> {
+    csv::ReaderBuilder::new().flexible(true).from_reader(input)
+}
+
+/// Streams the transactions of a CSV reader, finding the columns by the names in its header.
+pub(crate) fn csv_transactions<'a, IN: io::Read + 'a>(
+    mut reader: csv::Reader<IN>,
+) -> Box<dyn Iterator<Item = GResult<Transaction>> + 'a> {
+    let columns = match reader.headers() {
+        Ok(header) => CsvColumns::from_header(header).map(|columns| (columns, header.len())),
+        Err(err) => Err(err.into()),
+    };
+    match columns {
+        Ok((columns, width)) => {
+            let (mut records, mut total) = (0, TxAmount::default());
+            Box::new(reader.into_records().filter_map(move |record| {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(err) => return Some(Err(err.into())),
+                };
+                let is_trailer = record
+                    .get(columns.tx_type)
+                    .is_some_and(|tx_type| tx_type.trim().eq_ignore_ascii_case("trailer"));
+                if is_trailer {
+                    let checked = check_trailer(&record, &columns, records, total);
+                    (records, total) = (0, TxAmount::default());
+                    return checked.err().map(Err);
+                }
+                records += 1;
+                if record.len() != width && width > 0 {
+                    let err = TxProcessorError::Parse {
+                        field: "record",
+                        message: format!("found {} fields, but the header has {width}", record.len()),
+                    };
+                    return Some(at_record(&record, Err(err)));
+                }
+                let tx = parse_csv_transaction(&record, &columns);
+                if let Ok(Transaction { amount: Some(amount), .. }) = &tx {
+                    total += *amount;
+                }
+                Some(tx)
+            }))
+        }
+        Err(err) => Box::new(std::iter::once(Err(err))),
+    }
+}
+
+/// Chains the transactions of each file, opening each one only when the previous is exhausted.
+pub fn read_transactions_files(paths: &[String]) -> impl Iterator<Item = GResult<Transaction>> {
+    read_encoded_files(paths, InputEncoding::default())
+}
+
+/// Like `read_transactions_files`, for CSV files in `encoding`.
+pub fn read_encoded_files(paths: &[String], encoding: InputEncoding) -> impl Iterator<Item = GResult<Transaction>> {
+    // Owned, so that the iterator can outlive `paths` (ie be moved to a parsing thread).
+    let paths = paths.to_vec();
+    paths.into_iter().flat_map(move |path| match read_encoded_file(&path, encoding) {
+        Ok(transactions) => transactions,
+        Err(err) => Box::new(std::iter::once(Err(err))),
+    })
+}
+
+/// Expands glob patterns (ie `data/2024-*.csv`) into the sorted list of matching paths. Arguments
+/// that aren't patterns are kept as they are.
+pub fn expand_paths(patterns: &[String]) -> GResult<Vec<String>> {
+    let mut paths = vec![];
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let invalid = |message: String| TxProcessorError::Parse {
+            field: "path",
+            message,
+        };
+        let mut matches = glob::glob(pattern)
+            .map_err(|err| invalid(err.to_string()))?
+            .map(|path| Ok(path.map_err(|err| invalid(err.to_string()))?.display().to_string()))
+            .collect::<GResult<Vec<_>>>()?;
+        if matches.is_empty() {
+            return Err(invalid(format!("no files match `{pattern}`")));
+        }
+        matches.sort();
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Indices of the fields of a transaction in a CSV record. The default is the positional layout
+/// `type,client,tx,amount,idempotency_key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvColumns {
+    pub tx_type: usize,
+    pub client: usize,
+    pub tx: usize,
+    pub amount: Option<usize>,
+    pub idempotency_key: Option<usize>,
+    pub timestamp: Option<usize>,
+    pub pending: Option<usize>,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        Self {
+            tx_type: 0,
+            client: 1,
+            tx: 2,
+            amount: Some(3),
+            idempotency_key: Some(4),
+            timestamp: None,
+            pending: None,
+        }
+    }
+}
+
+impl CsvColumns {
+    /// Finds the columns by name in a header row, in any order and ignoring case and surrounding
+    /// spaces. `type`, `client` and `tx` are required, other unknown columns are ignored.
+    pub fn from_header(header: &StringRecord) -> GResult<Self> {
+        // An empty input has no header, nor records to parse.
+        if header.is_empty() {
+            return Ok(Self::default());
+        }
+        let find = |name: &str| header.iter().position(|column| column.trim().eq_ignore_ascii_case(name));
+        let required = |name: &'static str| {
+            find(name).ok_or_else(|| TxProcessorError::Parse {
+                field: name,
+                message: "missing column in header".to_string(),
+            })
+        };
+        Ok(Self {
+            tx_type: required("type")?,
+            client: required("client")?,
+            tx: required("tx")?,
+            amount: find("amount"),
+            idempotency_key: find("idempotency_key"),
+            timestamp: find("timestamp"),
+            pending: find("pending"),
+        })
+    }
+}
+
+/// Parses a CSV record into a transaction. If the record has a position (ie it was read from a
+/// file), errors are `TxProcessorError::InvalidRecord`, with the position and the raw record.
+pub(crate) fn parse_csv_transaction(record: &StringRecord, columns: &CsvColumns) -> GResult<Transaction> {
+    at_record(record, parse_csv_fields(record, columns))
+}
+
+/// Checks a trailer record against the number of `records` before it and their amount `total`.
+/// The count and total are the first two non-empty fields after the type.
+fn check_trailer(record: &StringRecord, columns: &CsvColumns, records: u64, total: TxAmount) -> GResult<()> {
+    let fields: StringRecord = record
+        .iter()
+        .enumerate()
+        .filter(|(index, field)| *index != columns.tx_type && !field.trim().is_empty())
+        .map(|(_, field)| field)
+        .collect();
+    let expected_records: u64 = at_record(record, parse_field(&fields, 0, "trailer record count"))?;
+    let expected_total: TxAmount = at_record(record, parse_field(&fields, 1, "trailer amount total"))?;
+    let total = total.round_dp(AMOUNT_DECIMALS);
+    if expected_records != records || expected_total.round_dp(AMOUNT_DECIMALS) != total {
+        return Err(TxProcessorError::TrailerMismatch {
+            expected_records,
+            expected_total,
+            records,
+            total,
+        });
+    }
+    Ok(())
+}
+
+/// Adds the position and raw content of `record` to an error parsing it, see
+/// `TxProcessorError::InvalidRecord`.
+fn at_record<T>(record: &StringRecord, result: GResult<T>) -> GResult<T> {
+    result.map_err(|err| match record.position() {
+        Some(position) => TxProcessorError::InvalidRecord {
+            line: position.line(),
+            record: position.record(),
+            raw: raw_record(record),
+            source: Box::new(err),
+        },
+        None => err,
+    })
+}
+
+/// `record` as a CSV line, with the fields that need it quoted.
+fn raw_record(record: &StringRecord) -> String {
+    let fields: Vec<_> = record
+        .iter()
+        .map(|field| match field.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", field.replace('"', "\"\"")),
+            false => field.to_string(),
+        })
+        .collect();
+    fields.join(",")
+}
+
+pub(crate) fn parse_csv_fields(record: &StringRecord, columns: &CsvColumns) -> GResult<Transaction> {
+    // not using serde with CSV reader directly because it seems to
+    // have problems parsing number with leading spaces?
+
+    let tx_type: TxType = parse_field(record, columns.tx_type, "type")?;
+    let client: u16 = parse_field(record, columns.client, "client")?;
+    let tx: u32 = parse_field(record, columns.tx, "tx")?;
+    let amount = columns.amount.and_then(|index| record.get(index)).unwrap_or("").trim();
+    let amount: Option<TxAmount> = match columns.amount {
+        Some(index) if !amount.is_empty() => Some(parse_field(record, index, "amount")?),
+        _ => None,
+    };
+    if amount.is_none() && tx_type.requires_amount() {
+        return Err(TxProcessorError::MissingAmount(tx));
+    }
+
+    // Optional column
+    let idempotency_key = columns
+        .idempotency_key
+        .and_then(|index| record.get(index))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string);
+    let timestamp = match columns.timestamp.and_then(|index| record.get(index)).map(str::trim) {
+        Some(timestamp) if !timestamp.is_empty() => Some(
+            crate::ordering::parse_timestamp(timestamp).map_err(|message| TxProcessorError::Parse {
+                field: "timestamp",
+                message,
+            })?,
+        ),
+        _ => None,
+    };
+    let pending = match columns.pending.and_then(|index| record.get(index)) {
+        Some(pending) => crate::clearing::parse_pending(pending).map_err(|message| TxProcessorError::Parse {
+            field: "pending",
+            message,
+        })?,
+        None => false,
+    };
+
+    Ok(Transaction {
+        idempotency_key,
+        timestamp,
+        pending,
+        ..Transaction::new(tx_type, client, tx, amount)
+    })
+}
+
+fn parse_field<T>(record: &StringRecord, index: usize, field: &'static str) -> GResult<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = record.get(index).ok_or_else(|| TxProcessorError::Parse {
+        field,
+        message: "missing column".to_string(),
+    })?;
+    value.trim().parse().map_err(|err: T::Err| TxProcessorError::Parse {
+        field,
+        message: err.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TxType::{Chargeback, Deposit, Dispute, Resolve, Withdrawal};
+    use crate::test_support::amount;
+
+    // test serialization
+    #[test]
+    fn test_parse_csv_transaction() {
+        let input = r#"type, client,tx, amount
+deposit, 1, 2, 3.0
+withdrawal, 4, 5, 6.0
+dispute, 1, 2,
+resolve, 3, 4,
+chargeback, 5, 6,
+"#
+        .as_bytes();
+
+        let mut reader = csv::Reader::from_reader(input);
+        let iter = reader
+            .records()
+            .map::<Transaction, _>(|record| parse_csv_transaction(&record.unwrap(), &CsvColumns::default()).unwrap());
+        let txs = iter.collect::<Vec<Transaction>>();
+
+        assert!(txs.len() == 5);
+
+        assert_eq!(txs[0], Transaction::new(Deposit, 1, 2, Some(amount(3.0))));
+        assert_eq!(txs[1], Transaction::new(Withdrawal, 4, 5, Some(amount(6.0))));
+        assert_eq!(txs[2], Transaction::new(Dispute, 1, 2, None));
+        assert_eq!(txs[3], Transaction::new(Resolve, 3, 4, None));
+        assert_eq!(txs[4], Transaction::new(Chargeback, 5, 6, None));
+    }
+
+    #[test]
+    fn test_parse_csv_transaction_errors() {
+        let record = StringRecord::from(vec!["deposit", "1", "x", "1.0"]);
+        let err = parse_csv_transaction(&record, &CsvColumns::default()).unwrap_err();
+        assert!(matches!(err, TxProcessorError::Parse { field: "tx", .. }));
+
+        let record = StringRecord::from(vec!["transfer", "1", "2", "1.0"]);
+        let err = parse_csv_transaction(&record, &CsvColumns::default()).unwrap_err();
+        assert!(matches!(err, TxProcessorError::Parse { field: "type", .. }));
+
+        let record = StringRecord::from(vec!["deposit", "1"]);
+        let err = parse_csv_transaction(&record, &CsvColumns::default()).unwrap_err();
+        assert!(matches!(err, TxProcessorError::Parse { field: "tx", .. }));
+    }
+
+    #[test]
+    fn test_parse_csv_error_position() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,\"2.0\"\ndeposit, 1, 3, 1.x\n";
+        let err = read_transactions_csv(input.as_bytes()).nth(2).unwrap().unwrap_err();
+        let TxProcessorError::InvalidRecord { line, record, raw, source } = &err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!((*line, *record), (4, 3));
+        assert_eq!(raw, "deposit, 1, 3, 1.x");
+        assert!(matches!(**source, TxProcessorError::Parse { field: "amount", .. }));
+        let message = if cfg!(feature = "minor-units") { "invalid decimal amount `1.x`" } else { "invalid float literal" };
+        assert_eq!(
+            err.to_string(),
+            format!("line 4 (record 3): invalid `amount` field: {message}, in `deposit, 1, 3, 1.x`")
+        );
+    }
+
+    #[test]
+    fn test_parse_csv_header_columns() -> GResult<()> {
+        let input = "Amount, region, TX,client,type,idempotency_key\n1.5,eu,7,3,deposit,k-1\n,us,7,3,dispute,\n";
+        let transactions: Vec<_> = read_transactions_csv(input.as_bytes()).collect::<GResult<_>>()?;
+        assert_eq!(
+            (transactions[0].tx_type, transactions[0].client, transactions[0].tx_id, transactions[0].amount),
+            (Deposit, 3, 7, Some(amount(1.5)))
+        );
+        assert_eq!(transactions[0].idempotency_key.as_deref(), Some("k-1"));
+        assert_eq!((transactions[1].tx_type, transactions[1].amount), (Dispute, None));
+
+        let input = "type,tx,amount\ndeposit,1,1.0\n";
+        let err = read_transactions_csv(input.as_bytes()).next().unwrap().unwrap_err();
+        assert!(matches!(err, TxProcessorError::Parse { field: "client", .. }));
+        assert_eq!(read_transactions_csv("".as_bytes()).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_csv_idempotency_key() -> GResult<()> {
+        let record = StringRecord::from(vec!["deposit", "1", "2", "1.0", " 5f0c-11 "]);
+        let tx = parse_csv_transaction(&record, &CsvColumns::default())?;
+        assert_eq!(tx.idempotency_key.as_deref(), Some("5f0c-11"));
+
+        let record = StringRecord::from(vec!["dispute", "1", "2", "", ""]);
+        let tx = parse_csv_transaction(&record, &CsvColumns::default())?;
+        assert_eq!(tx.idempotency_key, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailer() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.5\ndispute,1,1,\ntrailer,2,1.5\n\
+            deposit,1,2,2\nwithdrawal,1,3,1\nTRAILER,,2,3\n";
+        let transactions: Vec<_> = read_transactions_csv(input.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(transactions.iter().map(|tx| tx.tx_id).collect::<Vec<_>>(), vec![1, 1, 2, 3]);
+
+        let input = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,1,2,2\ntrailer,2,1.5\n";
+        let err = read_transactions_csv(input.as_bytes()).nth(2).unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "trailer expects 2 records totalling 1.5, found 2 totalling 3.5");
+
+        // A short record that isn't a trailer is still an error.
+        let input = "type,client,tx,amount\ntrailer,x\ndeposit,1,1\n";
+        let errors: Vec<_> = read_transactions_csv(input.as_bytes()).map(|tx| tx.unwrap_err().to_string()).collect();
+        assert_eq!(errors, vec![
+            "line 2 (record 1): invalid `trailer record count` field: invalid digit found in string, in `trailer,x`",
+            "line 3 (record 2): invalid `record` field: found 3 fields, but the header has 4, in `deposit,1,1`",
+        ]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Write-ahead journal: every transaction given to a `TxProcessor` is appended to the journal
+//! before it is applied, so that after a crash the state can be rebuilt by replaying it (on top of
+//! the last snapshot, if there is one).
+//!
+//! Entries are JSON lines tagged with the processor's sequence number, and prefixed with their
+//! checksum (see `checksum`). Replay skips entries that a loaded snapshot already covers, so a
+//! crash between saving a snapshot and truncating the journal doesn't apply anything twice.
+//! With a state key, entries are encrypted instead, with their sequence number in the clear, see
+//! `encryption`.
+
+use crate::checksum;
+use crate::encryption::{self, StateEncryption, StateKey, ENCRYPTED_ENTRY};
+use crate::error::TxProcessorError;
+use crate::model::Transaction;
+use crate::store::Stores;
+use crate::tx_processor::{JournalSink, TxProcessor};
+use crate::GResult;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+
+#[derive(serde::Serialize)]
+struct EntryRef<'a> {
+    sequence: u64,
+    tx: &'a Transaction,
+}
+
+#[derive(serde::Deserialize)]
+struct Entry {
+    sequence: u64,
+    tx: Transaction,
+}
+
+/// Appends to a journal file. Each entry is flushed to the OS before the transaction is applied,
+/// so it survives the process crashing, though not necessarily the machine.
+pub struct Journal {
+    file: File,
+    key: Option<StateKey>,
+}
+
+impl Journal {
+    pub fn open(path: &str) -> GResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file, key: None })
+    }
+
+    /// Encrypts the entries appended with `key`.
+    pub fn encrypted(mut self, key: StateKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+}
+
+impl JournalSink for Journal {
+    fn append(&mut self, sequence: u64, tx: &Transaction) -> GResult<()> {
+        let json = serde_json::to_vec(&EntryRef { sequence, tx })?;
+        let mut line = match &self.key {
+            Some(key) => {
+                let sealed = encryption::encode_hex(&key.seal("journal", &sequence.to_string(), &json)?);
+                format!("{ENCRYPTED_ENTRY}{sequence} {sealed}").into_bytes()
+            }
+            None => {
+                let mut line = format!("{:08x} ", checksum::crc32(&json)).into_bytes();
+                line.extend_from_slice(&json);
+                line
+            }
+        };
+        line.push(b'\n');
+        // A single write per entry, so a crash can only leave the last entry partially written.
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> GResult<()> {
+        self.file.set_len(0)?;
+        Ok(())
+    }
+}
+
+impl<S: Stores> TxProcessor<S> {
+    /// Applies the entries of the journal at `path` that are newer than the processor state, and
+    /// returns how many were applied. A missing journal is empty. Encrypted entries are decrypted
+    /// with the key of `encryption`.
+    pub fn replay_journal(&mut self, path: &str, encryption: &StateEncryption) -> GResult<u64> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut input = BufReader::new(file);
+        let mut replayed = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            // A crash can only leave the last entry partially written, before it was applied.
+            let Some(line) = line.strip_suffix('\n') else {
+                break;
+            };
+            let entry = match line.strip_prefix(ENCRYPTED_ENTRY) {
+                Some(sealed) => open_entry(sealed, encryption)?,
+                None => {
+                    encryption.check_plaintext("journal")?;
+                    match line.split_once(' ') {
+                        Some((checksum, json)) if !line.starts_with('{') => {
+                            checksum::verify("journal", checksum, json.as_bytes())?;
+                            serde_json::from_str(json)?
+                        }
+                        // Entries journaled before checksums were added.
+                        _ => serde_json::from_str(line)?,
+                    }
+                }
+            };
+            if entry.sequence <= self.counters.sequence {
+                continue;
+            }
+            if entry.sequence != self.counters.sequence + 1 {
+                return Err(TxProcessorError::Parse {
+                    field: "journal",
+                    message: format!(
+                        "entry {} doesn't follow the processor state at {}",
+                        entry.sequence, self.counters.sequence
+                    ),
+                });
+            }
+            let mut tx = entry.tx;
+            self.process_transaction(&mut tx)?;
+            replayed += 1;
+        }
+        Ok(replayed)
+    }
+}
+
+/// Decrypts the `<sequence> <sealed hex>` of an encrypted entry.
+fn open_entry(sealed: &str, encryption: &StateEncryption) -> GResult<Entry> {
+    let key = encryption.key_for("journal")?;
+    let invalid = || TxProcessorError::Corrupted {
+        what: "journal",
+        message: "invalid encrypted entry".to_string(),
+    };
+    let (sequence, sealed) = sealed.split_once(' ').ok_or_else(invalid)?;
+    let sealed = encryption::decode_hex(sealed).ok_or_else(invalid)?;
+    let entry: Entry = serde_json::from_slice(&key.open("journal", sequence, &sealed)?)?;
+    if entry.sequence.to_string() != sequence {
+        return Err(invalid());
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+
+    #[test]
+    fn test_journal_replay() -> GResult<()> {
+        let path = std::env::temp_dir().join(format!("tx_journal_test_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let plaintext = StateEncryption::default();
+
+        let mut processor = TxProcessor::builder().journal(Journal::open(&path)?).build();
+        processor.process_input(read_transactions_file("tests/rejections.csv")?)?;
+        let mut snapshot = vec![];
+        processor.save_snapshot(&mut snapshot)?;
+        processor.process_input(read_transactions_file("tests/example.csv")?)?;
+        drop(processor.journal.take());
+        // A crash while writing an entry leaves a partial last line.
+        std::fs::OpenOptions::new().append(true).open(&path)?.write_all(b"{\"sequence\":12,\"tx\":{")?;
+
+        let mut expected = TxProcessor::new();
+        expected.process_input(read_transactions_file("tests/rejections.csv")?)?;
+        expected.process_input(read_transactions_file("tests/example.csv")?)?;
+
+        let mut recovered = TxProcessor::new();
+        assert_eq!(recovered.replay_journal(&path, &plaintext)?, 11);
+        assert_eq!(recovered.clients_balance, expected.clients_balance);
+
+        // Entries covered by the snapshot are skipped.
+        let mut recovered = TxProcessor::new();
+        recovered.load_snapshot(snapshot.as_slice())?;
+        assert_eq!(recovered.replay_journal(&path, &plaintext)?, 5);
+        assert_eq!(recovered.clients_balance, expected.clients_balance);
+        assert_eq!(recovered.counters, expected.counters);
+
+        // A complete entry that doesn't match its checksum is corrupted, not partially written.
+        let journal = std::fs::read_to_string(&path)?.replacen("\"amount\":\"100\"", "\"amount\":\"900\"", 1);
+        std::fs::write(&path, journal)?;
+        let err = TxProcessor::new().replay_journal(&path, &plaintext).unwrap_err();
+        assert!(err.to_string().starts_with("corrupted journal: checksum is "));
+
+        std::fs::remove_file(&path)?;
+        assert_eq!(TxProcessor::new().replay_journal(&path, &plaintext)?, 0);
+        Ok(())
+    }
+}
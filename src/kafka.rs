@@ -0,0 +1,243 @@
+//! Kafka topics as a transaction source. Each record value is one transaction, as a CSV record
+//! or JSON object (see `server::parse_transaction_line`).
+//!
+//! Offsets are committed only after a record has been processed and reported, so a record is
+//! never skipped. A record processed just before a crash is delivered again, so producers
+//! should set idempotency keys to avoid applying it twice.
+//!
+//! With a reply topic, each rejected transaction is answered with a JSON record on that topic,
+//! keyed by the key of the record that submitted it, so that submitters can correlate replies.
+//!
+//! In the other direction, `KafkaNotifier` is a `NotificationSink` that publishes every balance
+//! change, chargeback and account lock to a topic as it happens, as the JSON `Notification` keyed
+//! by client id, so that the events of a client stay in order on one partition. Publishing is
+//! asynchronous: events that fail to be delivered are only reported, and dropping the notifier
+//! waits for the pending ones.
+
+use crate::error::{ErrorHandler, RejectReason, TxProcessorError};
+use crate::model::{ClientId, Transaction, TxId};
+use crate::server::parse_transaction_line;
+use crate::tx_processor::{Notification, NotificationSink, TxOutcome, TxProcessor};
+use crate::GResult;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::DeliveryResult;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer, ProducerContext};
+use rdkafka::{ClientConfig, ClientContext, Message};
+use std::time::Duration;
+
+/// How long to wait for a reply to be delivered before failing.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for the pending events once a `KafkaNotifier` is dropped.
+const NOTIFY_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct KafkaSourceConfig {
+    /// Comma-separated `host:port` list.
+    pub brokers: String,
+    pub group_id: String,
+    pub topics: Vec<String>,
+    /// Topic to send rejections to, see the module docs.
+    pub reply_topic: Option<String>,
+}
+
+/// Reply record for a rejected transaction.
+#[derive(Debug, serde::Serialize)]
+struct RejectionReply<'a> {
+    tx: TxId,
+    client: ClientId,
+    reason_code: &'static str,
+    reason: String,
+    idempotency_key: Option<&'a str>,
+}
+
+/// Consumes transactions from the configured topics into `processor`, reporting each outcome
+/// to `on_outcome`. Runs until an error, which stops consumption before the failed record's
+/// offset is committed.
+pub fn consume_kafka<F>(config: &KafkaSourceConfig, processor: &mut TxProcessor, mut on_outcome: F) -> GResult<()>
+where
+    F: FnMut(&Transaction, &TxOutcome) -> GResult<()>,
+{
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &config.brokers)
+        .set("group.id", &config.group_id)
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    let topics: Vec<&str> = config.topics.iter().map(String::as_str).collect();
+    consumer.subscribe(&topics)?;
+    let producer: Option<BaseProducer> = match config.reply_topic {
+        Some(_) => Some(ClientConfig::new().set("bootstrap.servers", &config.brokers).create()?),
+        None => None,
+    };
+
+    for message in consumer.iter() {
+        let message = message?;
+        let mut tx = parse_record(message.payload())?;
+        let outcome = processor.process_transaction(&mut tx)?;
+        on_outcome(&tx, &outcome)?;
+        if let (Some(producer), Some(topic), TxOutcome::Rejected(reason)) = (&producer, &config.reply_topic, &outcome) {
+            let reply = serde_json::to_vec(&rejection_reply(&tx, reason))?;
+            let mut record = BaseRecord::<[u8], _>::to(topic).payload(&reply);
+            if let Some(key) = message.key() {
+                record = record.key(key);
+            }
+            producer.send(record).map_err(|(err, _)| err)?;
+            // Delivered before the offset is committed, so a reply is never lost.
+            producer.flush(REPLY_TIMEOUT)?;
+        }
+        consumer.commit_message(&message, CommitMode::Sync)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct KafkaSinkConfig {
+    /// Comma-separated `host:port` list.
+    pub brokers: String,
+    /// Topic the events are published to.
+    pub topic: String,
+}
+
+/// Reports the events that couldn't be delivered.
+struct DeliveryLog(ErrorHandler);
+
+impl ClientContext for DeliveryLog {}
+
+impl ProducerContext for DeliveryLog {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if let Err((err, message)) = result {
+            let payload = message.payload().map(String::from_utf8_lossy).unwrap_or_default();
+            (self.0)(&format!("Failed to publish event {payload}"), &err.clone().into());
+        }
+    }
+}
+
+/// Publishes notifications to a Kafka topic, balance changes included, see the module docs.
+pub struct KafkaNotifier {
+    producer: BaseProducer<DeliveryLog>,
+    topic: String,
+}
+
+impl KafkaNotifier {
+    /// Connects to the brokers. Events that fail to be published are reported to `on_error`.
+    pub fn connect(config: &KafkaSinkConfig, on_error: ErrorHandler) -> GResult<Self> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &config.brokers)
+            // Retries must not reorder the events of a client.
+            .set("enable.idempotence", "true")
+            .create_with_context(DeliveryLog(on_error))?;
+        Ok(Self {
+            producer,
+            topic: config.topic.clone(),
+        })
+    }
+}
+
+impl NotificationSink for KafkaNotifier {
+    fn notify(&mut self, notification: &Notification) -> GResult<()> {
+        let (key, payload) = notification_record(notification)?;
+        let mut record = BaseRecord::to(&self.topic).key(&key).payload(&payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => break,
+                // Waits for deliveries to make room in the producer queue.
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    record = returned;
+                    self.producer.poll(Duration::from_millis(100));
+                }
+                Err((err, _)) => return Err(err.into()),
+            }
+        }
+        // Serves the delivery reports, without waiting.
+        self.producer.poll(Duration::ZERO);
+        Ok(())
+    }
+
+    fn balance_changes(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for KafkaNotifier {
+    fn drop(&mut self) {
+        if let Err(err) = self.producer.flush(NOTIFY_FLUSH_TIMEOUT) {
+            (self.producer.context().0)("Failed to publish the pending events", &err.into());
+        }
+    }
+}
+
+/// Key and payload of the record a notification is published as.
+fn notification_record(notification: &Notification) -> GResult<(String, Vec<u8>)> {
+    Ok((notification.client.to_string(), serde_json::to_vec(notification)?))
+}
+
+fn rejection_reply<'a>(tx: &'a Transaction, reason: &RejectReason) -> RejectionReply<'a> {
+    RejectionReply {
+        tx: tx.tx_id,
+        client: tx.client,
+        reason_code: reason.code(),
+        reason: reason.to_string(),
+        idempotency_key: tx.idempotency_key.as_deref(),
+    }
+}
+
+fn parse_record(payload: Option<&[u8]>) -> GResult<Transaction> {
+    let payload = payload.unwrap_or_default();
+    let line = std::str::from_utf8(payload).map_err(|err| TxProcessorError::Parse {
+        field: "record",
+        message: err.to_string(),
+    })?;
+    parse_transaction_line(line.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ClientBalance, TxType};
+    use crate::test_support::amount;
+    use crate::tx_processor::NotificationEvent;
+
+    #[test]
+    fn test_parse_record() -> GResult<()> {
+        let tx = parse_record(Some(b"deposit,1,2,3.5\n"))?;
+        assert_eq!((tx.tx_type, tx.client, tx.tx_id, tx.amount), (TxType::Deposit, 1, 2, Some(amount(3.5))));
+
+        let tx = parse_record(Some(br#"{"type": "chargeback", "client": 1, "tx": 2}"#))?;
+        assert_eq!(tx.tx_type, TxType::Chargeback);
+
+        assert!(parse_record(None).is_err());
+        assert!(parse_record(Some(b"\xff")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_notification_record() -> GResult<()> {
+        let notification = Notification {
+            event: NotificationEvent::BalanceChanged,
+            sequence: 3,
+            client: 7,
+            tx_id: 2,
+            balance: ClientBalance::new_empty(7),
+            run_id: None,
+        };
+        let (key, payload) = notification_record(&notification)?;
+        assert_eq!(key, "7");
+        let expected = r#"{"event":"balance_changed","sequence":3,"client":7,"tx":2,"balance":{"client":7,"#;
+        assert!(std::str::from_utf8(&payload).unwrap().starts_with(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejection_reply() -> GResult<()> {
+        let tx = parse_record(Some(b"withdrawal,1,2,3.5,key-2"))?;
+        let reply = serde_json::to_string(&rejection_reply(&tx, &RejectReason::InsufficientFunds))?;
+        assert_eq!(
+            reply,
+            r#"{"tx":2,"client":1,"reason_code":"insufficient_funds","reason":"not enough funds","idempotency_key":"key-2"}"#
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Double-entry ledger, recorded when `ProcessorConfig::record_ledger` is set: every change to a
+//! client balance is an entry of balanced postings, debits and credits that sum to zero, against
+//! the client's `available` and `held` accounts and a house account. Client accounts are
+//! liabilities, so funds owed to a client are credits.
+//!
+//! Disputes, resolves and chargebacks post against `house:disputes`, other funds coming in or
+//! going out against `house:cash`. Holds released by expiry or by a settlement are entries too,
+//! tagged `expired` and `settlement`, as are disputes closed by a settlement.
+
+use crate::amount::Amount;
+use crate::model::{ClientBalance, ClientId, TxAmount, TxId, TxType, AMOUNT_DECIMALS};
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Account {
+    Available(ClientId),
+    Held(ClientId),
+    HouseCash,
+    HouseDisputes,
+}
+
+impl Display for Account {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Account::Available(client) => write!(f, "client:{client}:available"),
+            Account::Held(client) => write!(f, "client:{client}:held"),
+            Account::HouseCash => write!(f, "house:cash"),
+            Account::HouseDisputes => write!(f, "house:disputes"),
+        }
+    }
+}
+
+/// An amount posted to an account: positive amounts are debits, negative ones credits.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct Posting<A = TxAmount> {
+    pub account: Account,
+    pub amount: A,
+}
+
+/// The postings of one balance change, that sum to zero.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct LedgerEntry<A = TxAmount> {
+    /// Sequence number of the transaction in the processor's input.
+    pub sequence: u64,
+    pub tx_type: TxType,
+    pub client: ClientId,
+    pub tx_id: TxId,
+    pub postings: Vec<Posting<A>>,
+    pub tags: Vec<String>,
+}
+
+impl<A: Amount> LedgerEntry<A> {
+    /// The entry for the change of a client's balance from `before` to `after`.
+    pub(crate) fn new(
+        sequence: u64,
+        tx_type: TxType,
+        tx_id: TxId,
+        tags: Vec<String>,
+        before: &ClientBalance<A>,
+        after: &ClientBalance<A>,
+    ) -> Self {
+        let client = after.client;
+        let available = -(after.available - before.available).round_dp(AMOUNT_DECIMALS);
+        let held = -(after.held - before.held).round_dp(AMOUNT_DECIMALS);
+        let house = match tx_type {
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => Account::HouseDisputes,
+            _ => Account::HouseCash,
+        };
+        let postings = [
+            (Account::Available(client), available),
+            (Account::Held(client), held),
+            (house, -(available + held)),
+        ]
+        .into_iter()
+        .filter(|(_, amount)| *amount != A::default())
+        .map(|(account, amount)| Posting { account, amount })
+        .collect();
+        Self {
+            sequence,
+            tx_type,
+            client,
+            tx_id,
+            postings,
+            tags,
+        }
+    }
+}
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// The balance of `client`, to record the ledger entry of a change to it, if the ledger is
+    /// recorded.
+    pub(crate) fn ledger_balance(&self, client: ClientId) -> Option<ClientBalance<A>> {
+        if !self.config.record_ledger {
+            return None;
+        }
+        let balance = self.clients_balance.get(&client).cloned();
+        Some(balance.unwrap_or_else(|| ClientBalance::new_empty(client)))
+    }
+
+    /// Records the entry for the change of a client's balance since `before`.
+    pub(crate) fn post_ledger(&mut self, tx_type: TxType, tx_id: TxId, tags: Vec<String>, before: &ClientBalance<A>) {
+        let Some(after) = self.clients_balance.get(&before.client) else {
+            return;
+        };
+        let entry = LedgerEntry::new(self.counters.sequence, tx_type, tx_id, tags, before, after);
+        if !entry.postings.is_empty() {
+            self.ledger.push(entry);
+        }
+    }
+}
+
+/// Writes the ledger as a CSV journal, one posting per row, in order.
+pub fn write_ledger_csv<OUT: io::Write>(out: OUT, processor: &TxProcessor) -> GResult<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["sequence", "type", "client", "tx", "account", "debit", "credit", "tags"])?;
+    for entry in &processor.ledger {
+        for posting in &entry.postings {
+            let (debit, credit) = if posting.amount > TxAmount::default() {
+                (posting.amount.to_string(), String::new())
+            } else {
+                (String::new(), (-posting.amount).to_string())
+            };
+            writer.write_record([
+                entry.sequence.to_string(),
+                entry.tx_type.to_string(),
+                entry.client.to_string(),
+                entry.tx_id.to_string(),
+                posting.account.to_string(),
+                debit,
+                credit,
+                entry.tags.join(";"),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use crate::tx_processor::ProcessorConfig;
+
+    #[test]
+    fn test_ledger() -> GResult<()> {
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            record_ledger: true,
+            hold_expiry: Some(1),
+            ..Default::default()
+        });
+        let input = "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,4\nwithdrawal,1,3,40\n\
+            dispute,1,1,\nchargeback,1,1,\ndeposit,2,4,5\nhold,2,5,2\ndeposit,2,6,1\ndeposit,2,7,1\n";
+        processor.process_input(crate::read_transactions_csv(input.as_bytes()))?;
+
+        // Every entry balances, and rejected transactions have none.
+        for entry in &processor.ledger {
+            assert_eq!(entry.postings.iter().map(|posting| posting.amount).sum::<TxAmount>(), amount(0.0));
+        }
+        let types: Vec<_> = processor.ledger.iter().map(|entry| (entry.tx_type, entry.tags.clone())).collect();
+        let expired = vec!["expired".to_string()];
+        assert_eq!(types, vec![
+            (TxType::Deposit, vec![]),
+            (TxType::Withdrawal, vec![]),
+            (TxType::Dispute, vec![]),
+            (TxType::Chargeback, vec![]),
+            (TxType::Deposit, vec![]),
+            (TxType::Hold, vec![]),
+            (TxType::Deposit, vec![]),
+            (TxType::Release, expired),
+            (TxType::Deposit, vec![]),
+        ]);
+
+        let mut out = vec![];
+        write_ledger_csv(&mut out, &processor)?;
+        let journal = String::from_utf8(out).unwrap();
+        assert!(journal.starts_with(
+            "sequence,type,client,tx,account,debit,credit,tags\n\
+             1,deposit,1,1,client:1:available,,10,\n\
+             1,deposit,1,1,house:cash,10,,\n\
+             2,withdrawal,1,2,client:1:available,4,,\n\
+             2,withdrawal,1,2,house:cash,,4,\n\
+             4,dispute,1,1,client:1:available,10,,\n\
+             4,dispute,1,1,client:1:held,,10,\n\
+             5,chargeback,1,1,client:1:held,10,,\n\
+             5,chargeback,1,1,house:disputes,,10,\n"
+        ));
+        assert!(journal.contains("\n9,release,2,5,client:2:available,,2,expired\n9,release,2,5,client:2:held,2,,expired\n"));
+        Ok(())
+    }
+}
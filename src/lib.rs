@@ -1,129 +1,847 @@
-use crate::tx_processor::TxProcessor;
-use csv::StringRecord;
-use model::{Transaction, TxType};
-use std::error::Error;
+use crate::tx_processor::{ProcessorConfig, TxOutcome, TxProcessor};
+use error::TxProcessorError;
+use output::{AmountFormat, OutputFormat};
+use sink::{BalanceSink, BufferedBalanceSink, CsvBalanceSink, CsvReportSink, EventSink, JsonBalanceSink, JsonLinesBalanceSink, ReportKind};
+use validation::RoundAmount;
+use model::{ClientId, Transaction, TxType};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
+use std::time::{Duration, Instant, SystemTime};
 
+pub mod accounts;
+#[cfg(feature = "async")]
+pub mod actors;
+pub mod amount;
+pub mod audit;
+pub mod backfill;
+pub mod checksum;
+pub mod clearing;
+pub mod collisions;
+pub mod compression;
+pub mod digest;
+pub mod dispute_window;
+pub mod encoding;
+pub mod encryption;
+pub mod error;
+pub mod export;
+pub mod fixed_width;
+#[cfg(unix)]
+pub mod handover;
+pub mod history;
+pub mod holds;
+#[cfg(feature = "http")]
+pub mod http_api;
+pub mod input;
+pub mod journal;
+pub mod ledger;
+pub mod lint;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+pub mod merge;
+#[cfg(feature = "kafka")]
+pub mod kafka;
 pub mod model;
+pub mod negative;
+pub mod ordering;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet_io;
+pub mod pipeline;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+pub mod reconcile;
+#[cfg(feature = "redis")]
+pub mod redis_store;
+pub mod replay;
+pub mod report;
+pub mod scheduler;
+pub mod server;
+pub mod settlement;
+pub mod shared;
+pub mod sharding;
+pub mod simulation;
+pub mod sink;
+pub mod snapshot;
+pub mod soak;
+pub mod store;
+pub mod summary;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_support;
 pub mod tx_processor;
+pub mod uring;
+pub mod validation;
+pub mod verify;
+pub mod webhooks;
 
 // Result alias to be less verbose
-pub type GResult<T> = Result<T, Box<dyn Error>>;
-
-pub fn process_file_and_output<OUT: io::Write>(path: &str, stdout: &mut OUT) -> GResult<()> {
-    let file = std::fs::File::open(path)?;
-    let mut reader = csv::Reader::from_reader(file);
-    let mut iter = reader.records().map::<GResult<Transaction>, _>(|record| {
-        let transaction = parse_csv_transaction(&record?)?;
-        Ok(transaction)
+pub type GResult<T> = Result<T, TxProcessorError>;
+
+pub use input::{
+    expand_paths, read_transactions_csv, read_transactions_file, read_transactions_files, CsvColumns, TransactionIter,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// If set, every rejected transaction is written to a CSV file at this path, with the reason.
+    pub rejected_report_path: Option<String>,
+    /// If set, every validation warning on an applied transaction is written to a CSV file at
+    /// this path.
+    pub warnings_report_path: Option<String>,
+    pub input_format: input::InputFormat,
+    /// Character encoding of the input files, and of the reader given to
+    /// `process_reader_and_output`, see `encoding`.
+    pub input_encoding: encoding::InputEncoding,
+    /// What to do with transaction ids that repeat across input files, see `collisions`.
+    pub tx_id_collisions: collisions::TxIdCollisionPolicy,
+    /// What to do with transactions of the input files that are out of time order, see
+    /// `ordering`.
+    pub ordering: ordering::OrderingPolicy,
+    /// Input records to skip before processing, ie to bisect a large input. Skipped records are
+    /// still read, but neither applied nor reported, even if they are malformed. Sequence numbers
+    /// keep counting them, so that they stay positions in the whole input.
+    pub skip: u64,
+    /// Input records to process, after the skipped ones. The rest of the input isn't read.
+    pub limit: Option<u64>,
+    /// Byte ranges of the fields, for `InputFormat::FixedWidth`.
+    pub fixed_width_layout: Option<fixed_width::FixedWidthLayout>,
+    pub output_format: OutputFormat,
+    pub amount_format: AmountFormat,
+    /// Round amounts to this many decimal places, with a validation warning for each amount
+    /// that was rounded. `model::AMOUNT_DECIMALS` if not set.
+    pub round_amount_decimals: Option<u32>,
+    /// Output balances sorted by client id, so that runs over the same input can be diffed.
+    pub sort_by_client: bool,
+    /// Bytes of output buffered before it is written out, `output::DEFAULT_OUTPUT_BUFFER` if not
+    /// set, see `output::OutputBuffer`.
+    pub output_buffer: Option<usize>,
+    /// Also write the buffered output out this often, so that it can be followed as it's written.
+    pub output_flush_interval: Option<Duration>,
+    /// Process on this many worker threads, with transactions partitioned by client, see
+    /// `sharding`. The output is the same for any number of shards, and sorted by client. Not
+    /// supported together with the per-transaction reports.
+    pub shards: Option<usize>,
+    /// Read and parse files on a separate thread from processing.
+    pub parse_in_background: bool,
+    /// Keep deposit amounts in a disk-backed store in this scratch directory, instead of in
+    /// memory. Requires the `sled` feature.
+    pub tx_store_path: Option<String>,
+    /// Transactions of the disk-backed store to cache in memory, `DEFAULT_TX_STORE_CACHE` if not
+    /// set, see `store::CachedTxStore`.
+    pub tx_store_cache: Option<usize>,
+    /// Save a state snapshot to this path every `checkpoint_every` input records, and once
+    /// processing is done.
+    pub checkpoint_path: Option<String>,
+    /// Records between checkpoints, `DEFAULT_CHECKPOINT_EVERY` if not set.
+    pub checkpoint_every: Option<u64>,
+    /// Restore the state from the checkpoint, if there is one, and skip the input records it
+    /// already covers. Reports are appended to instead of being recreated.
+    pub resume: bool,
+    /// How the checkpoint and settlement snapshots are encrypted, and the checkpoint resumed from
+    /// decrypted, see `encryption`.
+    pub state_encryption: encryption::StateEncryption,
+    /// Write a manifest of the run, signed with the key in the file at `manifest_key_path`, to
+    /// this path. Requires the `manifest` feature, see `manifest`.
+    pub manifest_path: Option<String>,
+    pub manifest_key_path: Option<String>,
+    /// Write a JSON summary of the run to this path, see `summary`. Only for input files.
+    pub summary_path: Option<String>,
+    /// Columns of the CSV balances output, in order, instead of the default ones.
+    pub columns: Option<Vec<output::ColumnSpec>>,
+    /// Number formatting of the `table` output format.
+    pub locale: output::NumberLocale,
+    /// If set, the history of every client is written to a CSV file at this path, see
+    /// `history::write_history_csv`. On resume, it only covers the records processed by this run.
+    pub history_path: Option<String>,
+    /// If set, every transaction, applied or not, is written to an audit trail at this path, see
+    /// `audit`. On resume, the trail is appended to.
+    pub audit_path: Option<String>,
+    /// If set, the activity of every client is exported to a file in this directory, see
+    /// `export`. On resume, it only covers the records processed by this run.
+    pub export_dir: Option<String>,
+    pub export_format: export::ExportFormat,
+    /// Currency of OFX exports, `export::DEFAULT_CURRENCY` if not set.
+    pub export_currency: Option<String>,
+    pub audit_format: audit::AuditFormat,
+    /// If set, a double-entry ledger of every balance change is written as a CSV journal at this
+    /// path, see `ledger::write_ledger_csv`. On resume, it only covers the records processed by
+    /// this run.
+    pub ledger_path: Option<String>,
+    /// If set, the clients left with negative `available` or `total` funds are written as CSV at
+    /// this path, see `negative`. The transaction that drove each balance negative is only given
+    /// when history is recorded.
+    pub negative_balances_path: Option<String>,
+    /// Release holds automatically after this many further transactions, see `holds`.
+    pub hold_expiry: Option<u64>,
+    /// Clear pending deposits automatically after this period, see `clearing`.
+    pub clearing_period: Option<clearing::ClearingPeriod>,
+    /// Allow disputes of withdrawals, see `ProcessorConfig::dispute_withdrawals`.
+    pub dispute_withdrawals: bool,
+    /// See `ProcessorConfig::dispute_funds_policy`.
+    pub dispute_funds_policy: tx_processor::DisputeFundsPolicy,
+    /// See `ProcessorConfig::overflow_policy`.
+    pub overflow_policy: model::OverflowPolicy,
+    /// Stop at the first malformed input record, or skip malformed records and report them in
+    /// `ProcessReport::malformed_records`.
+    pub parse_mode: tx_processor::ParseMode,
+    /// If set, with lenient parsing, every malformed record is written to a CSV file at this
+    /// path, see `sink::DeadLetterCsv`. On resume, the file is appended to.
+    pub dead_letter_path: Option<String>,
+    /// Stamped into the audit trail, checkpoints and log lines of the run. When resuming, the
+    /// checkpoint's run id is kept, otherwise one is generated with `new_run_id`.
+    pub run_id: Option<String>,
+    /// Whether an input trailer that doesn't match the records before it stops processing, see
+    /// `read_transactions_csv`.
+    pub trailer_mismatch: input::TrailerPolicy,
+    /// See `ProcessorConfig::dispute_timeout`.
+    pub dispute_timeout: Option<u64>,
+    /// See `ProcessorConfig::dispute_window`.
+    pub dispute_window: Option<u64>,
+    pub dispute_timeout_action: settlement::DisputeTimeoutAction,
+    /// Settle once the input is processed, as if it ended with a `settle` marker record.
+    pub settle_at_end: bool,
+    /// If set, every settlement is written to a CSV file at this path, see
+    /// `settlement::SettlementCsv`. On resume, the report is appended to.
+    pub settlement_report_path: Option<String>,
+    /// If set, a state snapshot is saved in this directory after every settlement, as
+    /// `settlement-<sequence>.json`.
+    pub settlement_snapshot_dir: Option<String>,
+}
+
+impl ProcessOptions {
+    /// Positions of the input records to process, from 0, see `skip` and `limit`.
+    pub fn record_range(&self) -> std::ops::Range<u64> {
+        self.skip..self.limit.map_or(u64::MAX, |limit| self.skip.saturating_add(limit))
+    }
+}
+
+/// What a run of the `process_*` functions did, so that callers don't have to parse the output.
+/// With sharded processing, outcomes are not counted: only `transactions`, `clients_touched`, the
+/// accounts and `elapsed` are filled in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessReport {
+    /// Transactions processed, by type. Malformed records are not included.
+    pub transactions: BTreeMap<TxType, u64>,
+    pub applied: u64,
+    pub queued: u64,
+    /// Rejected transactions, by reason code (see `RejectReason::code`).
+    pub rejections: BTreeMap<&'static str, u64>,
+    /// Retries of transactions with an idempotency key, see `tx_processor::REPLAYED_TAG`. They
+    /// aren't counted in the transactions and outcomes above.
+    pub replayed: u64,
+    /// Input records skipped because they could not be parsed, see `ParseMode::Lenient`.
+    pub malformed: u64,
+    /// The first of them, see `TxProcessor::malformed_records`.
+    pub malformed_records: Vec<tx_processor::MalformedRecord>,
+    /// Problems with the input that processing went on after, ie trailer mismatches with
+    /// `TrailerPolicy::Warn` and transactions out of order with `OrderingPolicy::Warn`.
+    pub warnings: Vec<String>,
+    /// Clients that had at least one transaction processed.
+    pub clients_touched: BTreeSet<ClientId>,
+    /// Accounts opened by this run, ie not in the checkpoint it resumed from.
+    pub accounts_created: u64,
+    /// Accounts locked by this run.
+    pub accounts_locked: u64,
+    /// See `TxProcessor::run_id`.
+    pub run_id: Option<String>,
+    pub elapsed: Duration,
+    /// Time and allocations of each phase. Not filled in with sharded processing.
+    #[cfg(feature = "profiling")]
+    pub profile: profiling::RunProfile,
+}
+
+impl ProcessReport {
+    pub fn record(&mut self, tx: &Transaction, outcome: &TxOutcome) {
+        if tx.has_tag(tx_processor::REPLAYED_TAG) {
+            self.replayed += 1;
+            return;
+        }
+        *self.transactions.entry(tx.tx_type).or_default() += 1;
+        match outcome {
+            TxOutcome::Applied => self.applied += 1,
+            TxOutcome::Queued => self.queued += 1,
+            TxOutcome::Rejected(reason) => *self.rejections.entry(reason.code()).or_default() += 1,
+        }
+        if tx.tx_type != TxType::Settle {
+            self.clients_touched.insert(tx.client);
+        }
+    }
+
+    /// Number of transactions processed, of any type.
+    pub fn total(&self) -> u64 {
+        self.transactions.values().sum()
+    }
+}
+
+pub const DEFAULT_CHECKPOINT_EVERY: u64 = 1_000_000;
+pub const DEFAULT_TX_STORE_CACHE: usize = 100_000;
+
+/// A run id unique to this process and moment, ie `18f3a2c4d5e6f708-3039`.
+pub fn new_run_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    format!("{nanos:x}-{:x}", std::process::id())
+}
+
+pub fn process_file_and_output<OUT: io::Write>(
+    path: &str,
+    stdout: &mut OUT,
+    options: &ProcessOptions,
+) -> GResult<ProcessReport> {
+    process_files_and_output(&[path.to_string()], stdout, options)
+}
+
+/// Processes several files in sequence, as if they were a single input, into one balance output.
+pub fn process_files_and_output<OUT: io::Write>(
+    paths: &[String],
+    stdout: &mut OUT,
+    options: &ProcessOptions,
+) -> GResult<ProcessReport> {
+    if options.manifest_path.is_none() && options.summary_path.is_none() {
+        return process_files(paths, stdout, options, None);
+    }
+    // Read first, so that a run isn't wasted on a missing key.
+    let manifest_key = match &options.manifest_path {
+        Some(_) => Some(manifest_key(options)?),
+        None => None,
+    };
+    let started_at = SystemTime::now();
+    let mut output = digest::HashingWriter::new(&mut *stdout);
+    let digests = digest::InputDigests::default();
+    let report = process_files(paths, &mut output, options, Some(digests.clone()))?;
+    let output = output.finish();
+    let inputs = digests.finish(paths)?;
+    if let (Some(path), Some(key)) = (&options.manifest_path, manifest_key) {
+        write_signed_manifest(path, &key, options, inputs.clone(), output.clone(), &report)?;
+    }
+    if let Some(path) = &options.summary_path {
+        summary::write_summary(path, &summary::RunSummary::new(started_at, inputs, output, &report))?;
+    }
+    Ok(report)
+}
+
+/// Like `process_files_and_output`, hashing the input files into `digests` as they are read.
+fn process_files<OUT: io::Write>(
+    paths: &[String],
+    stdout: &mut OUT,
+    options: &ProcessOptions,
+    digests: Option<digest::InputDigests>,
+) -> GResult<ProcessReport> {
+    let layout = fixed_width_layout(options)?.cloned();
+    let (paths, encoding) = (paths.to_vec(), options.input_encoding);
+    let (collisions, ordering) = (options.tx_id_collisions, options.ordering);
+    let read = move || -> TransactionIter {
+        let read_file = move |path: &str| -> TransactionIter {
+            #[cfg(feature = "parquet")]
+            if layout.is_none() && path.ends_with(".parquet") {
+                return read_transactions_file(path).unwrap_or_else(|err| Box::new(std::iter::once(Err(err))));
+            }
+            let file = match input::open_hashed_input(path, encoding, digests.as_ref()) {
+                Ok(file) => file,
+                Err(err) => return Box::new(std::iter::once(Err(err))),
+            };
+            match &layout {
+                Some(layout) => fixed_width::read_transactions_fixed_width(file, layout.clone()),
+                None => input::read_transactions_csv(file),
+            }
+        };
+        Box::new(collisions::read_files_checked(paths, collisions, read_file).ordered(ordering))
+    };
+    if options.parse_in_background {
+        let transactions = pipeline::parse_in_background(move || Ok(read()));
+        return process_transactions_and_output(transactions, stdout, options);
+    }
+    process_transactions_and_output(read(), stdout, options)
+}
+
+/// The key to sign the manifest with, see `ProcessOptions::manifest_key_path`.
+#[cfg(feature = "manifest")]
+fn manifest_key(options: &ProcessOptions) -> GResult<Vec<u8>> {
+    let key_path = options.manifest_key_path.as_deref().ok_or_else(|| TxProcessorError::Parse {
+        field: "manifest_key",
+        message: "a key is required to sign the manifest".to_string(),
+    })?;
+    manifest::read_key_file(key_path)
+}
+
+#[cfg(not(feature = "manifest"))]
+fn manifest_key(_options: &ProcessOptions) -> GResult<Vec<u8>> {
+    Err(TxProcessorError::Parse {
+        field: "manifest",
+        message: "a signed manifest requires the `manifest` feature".to_string(),
+    })
+}
+
+#[cfg(feature = "manifest")]
+fn write_signed_manifest(
+    path: &str,
+    key: &[u8],
+    options: &ProcessOptions,
+    inputs: Vec<digest::FileDigest>,
+    output: digest::FileDigest,
+    report: &ProcessReport,
+) -> GResult<()> {
+    let manifest = manifest::Manifest::new(options.run_id.clone(), inputs, output, report);
+    manifest::write_manifest(path, &manifest.sign(key)?)
+}
+
+#[cfg(not(feature = "manifest"))]
+fn write_signed_manifest(
+    _path: &str,
+    _key: &[u8],
+    _options: &ProcessOptions,
+    _inputs: Vec<digest::FileDigest>,
+    _output: digest::FileDigest,
+    _report: &ProcessReport,
+) -> GResult<()> {
+    unreachable!("keys can't be read without the `manifest` feature")
+}
+
+/// The layout of fixed-width input, if that is the input format.
+fn fixed_width_layout(options: &ProcessOptions) -> GResult<Option<&fixed_width::FixedWidthLayout>> {
+    match (options.input_format, &options.fixed_width_layout) {
+        (input::InputFormat::Csv, _) => Ok(None),
+        (input::InputFormat::FixedWidth, Some(layout)) => Ok(Some(layout)),
+        (input::InputFormat::FixedWidth, None) => Err(TxProcessorError::Parse {
+            field: "fixed_width_layout",
+            message: "a layout is required for fixed-width input".to_string(),
+        }),
+    }
+}
+
+/// Like `process_file_and_output`, but reads the transactions CSV from any reader (ie stdin).
+pub fn process_reader_and_output<IN: io::Read, OUT: io::Write>(
+    input: IN,
+    stdout: &mut OUT,
+    options: &ProcessOptions,
+) -> GResult<ProcessReport> {
+    let input = encoding::decoded(input, options.input_encoding)?;
+    if let Some(layout) = fixed_width_layout(options)? {
+        let transactions = fixed_width::read_transactions_fixed_width(input, layout.clone());
+        return process_transactions_and_output(transactions, stdout, options);
+    }
+    let transactions = input::csv_transactions(input::csv_reader(input));
+    process_transactions_and_output(transactions, stdout, options)
+}
+
+/// Processes already parsed transactions, writing the output as `process_file_and_output` does.
+pub fn process_transactions_and_output<ITER, OUT>(
+    transactions: ITER,
+    stdout: &mut OUT,
+    options: &ProcessOptions,
+) -> GResult<ProcessReport>
+where
+    ITER: Iterator<Item = GResult<Transaction>>,
+    OUT: io::Write,
+{
+    let resumed = resume_checkpoint(options).is_some();
+    let mut events: Vec<Box<dyn EventSink>> = vec![];
+    if let Some(path) = &options.rejected_report_path {
+        events.push(Box::new(CsvReportSink::create(path, ReportKind::Rejected, resumed)?));
+    }
+    if let Some(path) = &options.warnings_report_path {
+        events.push(Box::new(CsvReportSink::create(path, ReportKind::Warnings, resumed)?));
+    }
+    let capacity = options.output_buffer.unwrap_or(output::DEFAULT_OUTPUT_BUFFER);
+    let mut out = output::OutputBuffer::new(stdout, capacity, options.output_flush_interval);
+    let mut balances = balance_sink(&mut out, options)?;
+    let report = process_transactions_into(transactions, balances.as_mut(), &mut events, options)?;
+    drop(balances);
+    io::Write::flush(&mut out)?;
+    Ok(report)
+}
+
+/// Processes transactions, giving the outcome of each to `events` and the final balances to
+/// `balances`. Events are not reported with sharded processing.
+pub fn process_transactions_into<ITER>(
+    transactions: ITER,
+    balances: &mut dyn BalanceSink,
+    events: &mut dyn EventSink,
+    options: &ProcessOptions,
+) -> GResult<ProcessReport>
+where
+    ITER: Iterator<Item = GResult<Transaction>>,
+{
+    let started = Instant::now();
+    let mut report = ProcessReport::default();
+    let mut warnings = vec![];
+    let transactions = transactions.filter(|tx| {
+        let warning = match tx {
+            Err(TxProcessorError::TrailerMismatch { .. }) => options.trailer_mismatch == input::TrailerPolicy::Warn,
+            Err(TxProcessorError::OutOfOrderSpan { .. }) => true,
+            _ => false,
+        };
+        if let (true, Err(err)) = (warning, tx) {
+            warnings.push(err.to_string());
+        }
+        !warning
     });
-    let mut tx_processor = TxProcessor::new();
-    tx_processor.process_input(&mut iter)?;
+    if options.shards.is_some() && options.checkpoint_path.is_some() {
+        return Err(TxProcessorError::Parse {
+            field: "checkpoint",
+            message: "checkpoints are not supported with sharded processing".to_string(),
+        });
+    }
+
+    if options.shards.is_some() && options.parse_mode == tx_processor::ParseMode::Lenient {
+        return Err(TxProcessorError::Parse {
+            field: "parse_mode",
+            message: "lenient parsing is not supported with sharded processing".to_string(),
+        });
+    }
+
+    if options.dead_letter_path.is_some() && options.parse_mode != tx_processor::ParseMode::Lenient {
+        return Err(TxProcessorError::Parse {
+            field: "dead_letter",
+            message: "a dead-letter file requires lenient parsing".to_string(),
+        });
+    }
 
-    // Write output
-    write!(stdout, "client, available, held, total, locked\n")?;
-    let values = tx_processor.clients_balance.values();
+    if let Some(shards) = options.shards {
+        if options.rejected_report_path.is_some()
+            || options.warnings_report_path.is_some()
+            || options.history_path.is_some()
+            || options.audit_path.is_some()
+            || options.ledger_path.is_some()
+            || options.export_dir.is_some()
+            || options.settlement_report_path.is_some()
+            || options.settlement_snapshot_dir.is_some()
+            || options.settle_at_end
+        {
+            return Err(TxProcessorError::Parse {
+                field: "shards",
+                message: "per-transaction reports, history, exports, ledger, audit and settlement reports are not supported with sharded processing".to_string(),
+            });
+        }
+        // These count transactions (or time) across clients, which a shard only sees a part of, so
+        // their results would depend on the number of shards.
+        if options.hold_expiry.is_some()
+            || options.clearing_period.is_some()
+            || options.dispute_timeout.is_some()
+            || options.dispute_window.is_some()
+        {
+            return Err(TxProcessorError::Parse {
+                field: "shards",
+                message: "hold expiry, clearing periods, dispute timeouts and dispute windows are not supported with sharded processing".to_string(),
+            });
+        }
+        let tx_store = open_tx_store(options)?;
+        let range = options.record_range();
+        let transactions = transactions.take(range.end as usize).skip(range.start as usize);
+        // Outcomes stay in the shards, only the input is counted.
+        let transactions = transactions.inspect(|tx| {
+            if let Ok(tx) = tx {
+                *report.transactions.entry(tx.tx_type).or_default() += 1;
+                report.clients_touched.insert(tx.client);
+            }
+        });
+        let shard_balances =
+            sharding::process_sharded(transactions, shards, || build_processor(options, tx_store.clone()))?;
+        report.warnings = warnings;
+        if let Some(path) = &options.negative_balances_path {
+            let negative = negative::negative_balances(shard_balances.values(), |_| &[]);
+            negative::write_negative_balances_csv(std::fs::File::create(path)?, &negative)?;
+        }
+        // Sorted whatever the options, so that the output doesn't depend on the number of shards.
+        output_balances(balances, shard_balances.values(), true)?;
+        report.accounts_created = shard_balances.len() as u64;
+        report.accounts_locked = locked_accounts(shard_balances.values());
+        report.run_id = options.run_id.clone();
+        report.elapsed = started.elapsed();
+        return Ok(report);
+    }
+
+    let mut tx_processor = build_processor(options, open_tx_store(options)?);
+    let resumed = match resume_checkpoint(options) {
+        Some(path) => {
+            tx_processor.load_snapshot_with(std::fs::File::open(path)?, &options.state_encryption)?;
+            true
+        }
+        None => false,
+    };
+    let range = options.record_range();
+    if !resumed {
+        tx_processor.counters.sequence = range.start;
+    }
+    if tx_processor.run_id.is_none() {
+        tx_processor.run_id = Some(new_run_id());
+    }
+    if let Some(path) = &options.audit_path {
+        tx_processor.audit = Some(Box::new(audit::AuditLog::create(path, options.audit_format, resumed)?));
+    }
+    let mut settlement_report = match &options.settlement_report_path {
+        Some(path) => Some(settlement::SettlementCsv::create(path, resumed)?),
+        None => None,
+    };
+    let mut dead_letter = match &options.dead_letter_path {
+        Some(path) => Some(sink::DeadLetterCsv::create(path, resumed)?),
+        None => None,
+    };
+    let malformed_before = tx_processor.counters.malformed;
+    let (accounts_before, locked_before) = (tx_processor.client_count(), locked_accounts(tx_processor.balances()));
+    #[cfg(feature = "profiling")]
+    let (mut parse_profile, apply_started) = (profiling::PhaseProfile::default(), profiling::Mark::now());
+    #[cfg(feature = "profiling")]
+    let transactions = profiling::Profiled::new(transactions, &mut parse_profile);
+    // The sequence counter is the number of input records skipped, or that the checkpoint covers.
+    let transactions = transactions.take(range.end as usize);
+    let mut transactions = transactions.skip(tx_processor.counters.sequence as usize).map(|tx| match (tx, &mut dead_letter) {
+        // A failure to write it stops processing, it isn't a malformed record.
+        (Err(err), Some(dead_letter)) if err.is_malformed_record() => dead_letter.write(&err).and(Err(err)),
+        (tx, _) => tx,
+    });
+
+    let chunk_size = match options.checkpoint_path {
+        Some(_) => options.checkpoint_every.unwrap_or(DEFAULT_CHECKPOINT_EVERY).max(1) as usize,
+        None => usize::MAX,
+    };
+    loop {
+        let sequence = tx_processor.counters.sequence;
+        // A chunk ends with a settlement marker, so that the settlement is reported with the state
+        // it left.
+        let mut settled = false;
+        let chunk = std::iter::from_fn(|| {
+            if settled {
+                return None;
+            }
+            let tx = transactions.next()?;
+            settled = matches!(&tx, Ok(tx) if tx.tx_type == TxType::Settle);
+            Some(tx)
+        });
+        tx_processor.process_input_with(chunk.take(chunk_size), |tx, outcome| {
+            report.record(tx, outcome);
+            events.event(tx, outcome)
+        })?;
+        for settlement in std::mem::take(&mut tx_processor.settlements) {
+            record_settlement(&tx_processor, &settlement, settlement_report.as_mut(), options)?;
+        }
+        // Events are flushed first, so that they cover at least what the checkpoint does.
+        events.flush()?;
+        if let Some(audit) = &mut tx_processor.audit {
+            audit.flush()?;
+        }
+        if let Some(path) = &options.checkpoint_path {
+            tx_processor.save_snapshot_file(path, &options.state_encryption)?;
+        }
+        if tx_processor.counters.sequence == sequence {
+            break;
+        }
+    }
+    drop(transactions);
 
-    for cb in values {
-        let client = cb.client;
-        let (available, held, total, locked) = (cb.available, cb.held, cb.total, cb.locked);
-        write!(stdout, "{client}, {available}, {held}, {total}, {locked}\n")?;
+    tx_processor.flush_tx_store()?;
+    if options.settle_at_end {
+        let settlement = tx_processor.settle()?;
+        record_settlement(&tx_processor, &settlement, settlement_report.as_mut(), options)?;
+    }
+    #[cfg(feature = "profiling")]
+    let output_started = {
+        let applied = apply_started.elapsed();
+        report.profile.parse = parse_profile;
+        report.profile.apply = applied - parse_profile;
+        profiling::Mark::now()
+    };
+    if let Some(path) = &options.history_path {
+        history::write_history_csv(std::fs::File::create(path)?, &tx_processor)?;
+    }
+    if let Some(path) = &options.ledger_path {
+        ledger::write_ledger_csv(std::fs::File::create(path)?, &tx_processor)?;
+    }
+    if let Some(dir) = &options.export_dir {
+        let currency = options.export_currency.as_deref().unwrap_or(export::DEFAULT_CURRENCY);
+        export::export_history(dir, options.export_format, currency, export::ExportDate::today(), &tx_processor)?;
+    }
+    if let Some(path) = &options.negative_balances_path {
+        let negative = negative::negative_balances(tx_processor.balances(), |client| tx_processor.client_history(client));
+        negative::write_negative_balances_csv(std::fs::File::create(path)?, &negative)?;
+    }
+    output_balances(balances, tx_processor.balances(), options.sort_by_client)?;
+    #[cfg(feature = "profiling")]
+    {
+        report.profile.output = output_started.elapsed();
+    }
+    report.malformed = tx_processor.counters.malformed - malformed_before;
+    report.malformed_records = std::mem::take(&mut tx_processor.malformed_records);
+    report.warnings = warnings;
+    report.accounts_created = tx_processor.client_count().saturating_sub(accounts_before) as u64;
+    report.accounts_locked = locked_accounts(tx_processor.balances()).saturating_sub(locked_before);
+    report.run_id = tx_processor.run_id.clone();
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+/// Writes `settlement` to the settlement report, and snapshots the state it left, as `options` say.
+fn record_settlement(
+    tx_processor: &TxProcessor,
+    settlement: &settlement::Settlement,
+    report: Option<&mut settlement::SettlementCsv<std::fs::File>>,
+    options: &ProcessOptions,
+) -> GResult<()> {
+    if let Some(report) = report {
+        report.write(settlement)?;
+        report.flush()?;
+    }
+    if let Some(dir) = &options.settlement_snapshot_dir {
+        let path = std::path::Path::new(dir).join(format!("settlement-{}.json", settlement.sequence));
+        tx_processor.save_snapshot_file(&path.to_string_lossy(), &options.state_encryption)?;
     }
     Ok(())
 }
 
-fn parse_csv_transaction(record: &StringRecord) -> GResult<Transaction> {
-    // not using serde with CSV reader directly because it seems to
-    // have problems parsing number with leading spaces?
+/// The checkpoint to resume from, if resuming and there is one.
+fn resume_checkpoint(options: &ProcessOptions) -> Option<&str> {
+    let path = options.checkpoint_path.as_deref()?;
+    (options.resume && std::path::Path::new(path).exists()).then_some(path)
+}
 
-    let tx_type: TxType = record[0].parse()?;
-    let client: u16 = record[1].trim().parse()?;
-    let tx: u32 = record[2].trim().parse()?;
-    let amount = record[3].trim();
-    let amount: Option<f64> = if amount.is_empty() {
-        None
-    } else {
-        Some(amount.parse()?)
-    };
+fn locked_accounts<'a>(balances: impl Iterator<Item = &'a model::ClientBalance>) -> u64 {
+    balances.filter(|balance| balance.locked).count() as u64
+}
 
-    Ok(Transaction {
-        tx_type,
-        client,
-        tx_id: tx,
-        amount,
+fn output_balances<'a>(
+    sink: &mut dyn BalanceSink,
+    balances: impl Iterator<Item = &'a model::ClientBalance>,
+    sort_by_client: bool,
+) -> GResult<()> {
+    let mut balances: Vec<_> = balances.collect();
+    if sort_by_client {
+        balances.sort_by_key(|balance| balance.client);
+    }
+    sink::sink_balances(sink, balances)
+}
+
+/// The sink writing balances to `out` in the output format of `options`.
+pub fn balance_sink<'a, OUT: io::Write>(out: &'a mut OUT, options: &ProcessOptions) -> GResult<Box<dyn BalanceSink + 'a>> {
+    let amount_format = options.amount_format;
+    Ok(match (&options.columns, options.output_format) {
+        (Some(columns), OutputFormat::Csv) => {
+            Box::new(CsvBalanceSink::with_columns(out, amount_format, Some(columns.clone()))?)
+        }
+        (Some(_), _) => {
+            return Err(TxProcessorError::Parse {
+                field: "columns",
+                message: "column selection only applies to CSV output".to_string(),
+            })
+        }
+        (None, OutputFormat::Csv) => Box::new(CsvBalanceSink::new(out, amount_format)?),
+        (None, OutputFormat::Json) => Box::new(JsonBalanceSink::new(out)?),
+        (None, OutputFormat::JsonLines) => Box::new(JsonLinesBalanceSink(out)),
+        #[cfg(feature = "parquet")]
+        (None, OutputFormat::Parquet) => Box::new(BufferedBalanceSink::new(move |balances| {
+            parquet_io::write_balances_parquet(&mut *out, balances)
+        })),
+        (None, OutputFormat::Table) => {
+            let locale = options.locale;
+            Box::new(BufferedBalanceSink::new(move |balances| {
+                output::write_balances_table(&mut *out, balances, amount_format, locale)
+            }))
+        }
     })
 }
 
+#[cfg(feature = "sled")]
+pub(crate) fn open_tx_store(options: &ProcessOptions) -> GResult<Option<store::CachedTxStore<store::SledTxStore>>> {
+    let capacity = options.tx_store_cache.unwrap_or(DEFAULT_TX_STORE_CACHE);
+    let tx_store = options.tx_store_path.as_deref().map(store::SledTxStore::open).transpose()?;
+    Ok(tx_store.map(|tx_store| store::CachedTxStore::new(tx_store, capacity)))
+}
+
+#[cfg(not(feature = "sled"))]
+pub(crate) fn open_tx_store(options: &ProcessOptions) -> GResult<Option<std::collections::HashMap<model::TxId, store::StoredTx>>> {
+    match options.tx_store_path {
+        Some(_) => Err(TxProcessorError::Parse {
+            field: "tx_store",
+            message: "a disk-backed store requires the `sled` feature".to_string(),
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Builds a processor for `options`. With several shards, each gets a clone of `tx_store`.
+pub(crate) fn build_processor<S: store::TxStore + Clone + 'static>(options: &ProcessOptions, tx_store: Option<S>) -> TxProcessor {
+    let mut builder = TxProcessor::builder().config(ProcessorConfig {
+        record_history: options.history_path.is_some() || options.export_dir.is_some(),
+        record_ledger: options.ledger_path.is_some(),
+        hold_expiry: options.hold_expiry,
+        clearing_period: options.clearing_period,
+        dispute_withdrawals: options.dispute_withdrawals,
+        dispute_funds_policy: options.dispute_funds_policy,
+        overflow_policy: options.overflow_policy,
+        parse_mode: options.parse_mode,
+        dispute_timeout: options.dispute_timeout,
+        dispute_window: options.dispute_window,
+        dispute_timeout_action: options.dispute_timeout_action,
+        ..Default::default()
+    });
+    if let Some(tx_store) = tx_store {
+        builder = builder.tx_store(tx_store);
+    }
+    if let Some(run_id) = &options.run_id {
+        builder = builder.run_id(run_id);
+    }
+    let decimals = options.round_amount_decimals.unwrap_or(model::AMOUNT_DECIMALS);
+    builder.validator(RoundAmount { decimals }).build()
+}
+
+/// Processes the transactions in the file at `path`, reporting each outcome to `on_outcome`.
+pub fn process_file_with<F>(path: &str, on_outcome: F) -> GResult<TxProcessor>
+where
+    F: FnMut(&Transaction, &TxOutcome) -> GResult<()>,
+{
+    let mut tx_processor = TxProcessor::new();
+    tx_processor.process_input_with(read_transactions_file(path)?, on_outcome)?;
+    Ok(tx_processor)
+}
+
+pub fn process_reader_with<IN, F>(input: IN, on_outcome: F) -> GResult<TxProcessor>
+where
+    IN: io::Read,
+    F: FnMut(&Transaction, &TxOutcome) -> GResult<()>,
+{
+    let transactions = input::csv_transactions(input::csv_reader(input));
+    let mut tx_processor = TxProcessor::new();
+    tx_processor.process_input_with(transactions, on_outcome)?;
+    Ok(tx_processor)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::model::TxType::{Chargeback, Deposit, Dispute, Resolve, Withdrawal};
-
-    // test serialization
+    use crate::test_support::amount;
     #[test]
-    fn test_parse_csv_transaction() {
-        let input = r#"type, client,tx, amount
-deposit, 1, 2, 3.0
-withdrawal, 4, 5, 6.0
-dispute, 1, 2,
-resolve, 3, 4,
-chargeback, 5, 6,
-"#
-        .as_bytes();
-
-        let mut reader = csv::Reader::from_reader(input);
-        let iter = reader.records().map::<Transaction, _>(|record| {
-            let transaction = parse_csv_transaction(&record.unwrap()).unwrap();
-            transaction
-        });
-        let txs = iter.collect::<Vec<Transaction>>();
+    fn test_process_transactions_into() -> GResult<()> {
+        let options = ProcessOptions {
+            sort_by_client: true,
+            ..Default::default()
+        };
+        let mut balances = sink::MemoryBalanceSink::default();
+        let mut events = sink::MemoryEventSink::default();
+        let report =
+            process_transactions_into(read_transactions_file("tests/example.csv")?, &mut balances, &mut events, &options)?;
 
-        assert!(txs.len() == 5);
+        let clients: Vec<_> = balances.balances.iter().map(|balance| balance.client).collect();
+        assert_eq!(clients, vec![1, 2]);
+        assert_eq!(balances.balances[1].held, amount(80.0));
+        assert_eq!(events.events.len(), 5);
+        assert!(events.events.iter().all(|(_, outcome)| *outcome == TxOutcome::Applied));
 
-        assert_eq!(
-            txs[0],
-            Transaction {
-                tx_type: Deposit,
-                client: 1,
-                tx_id: 2,
-                amount: Some(3.0),
-            }
-        );
-        assert_eq!(
-            txs[1],
-            Transaction {
-                tx_type: Withdrawal,
-                client: 4,
-                tx_id: 5,
-                amount: Some(6.0),
-            }
-        );
-        assert_eq!(
-            txs[2],
-            Transaction {
-                tx_type: Dispute,
-                client: 1,
-                tx_id: 2,
-                amount: None,
-            }
-        );
-        assert_eq!(
-            txs[3],
-            Transaction {
-                tx_type: Resolve,
-                client: 3,
-                tx_id: 4,
-                amount: None,
-            }
-        );
-        assert_eq!(
-            txs[4],
-            Transaction {
-                tx_type: Chargeback,
-                client: 5,
-                tx_id: 6,
-                amount: None,
-            }
-        );
+        assert_eq!(report.total(), 5);
+        assert_eq!(report.transactions[&TxType::Deposit], 3);
+        assert_eq!((report.applied, report.queued, report.malformed), (5, 0, 0));
+        assert!(report.rejections.is_empty());
+        assert_eq!(report.clients_touched, BTreeSet::from([1, 2]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_replays() -> GResult<()> {
+        let input = "type,client,tx,amount,idempotency_key\ndeposit,1,1,10,a\ndeposit,1,1,10,a\nwithdrawal,1,2,50,b\n";
+        let (mut balances, mut events) = (sink::MemoryBalanceSink::default(), sink::MemoryEventSink::default());
+        let transactions = read_transactions_csv(input.as_bytes());
+        let report = process_transactions_into(transactions, &mut balances, &mut events, &ProcessOptions::default())?;
+
+        assert_eq!((report.total(), report.applied, report.replayed), (2, 1, 1));
+        assert_eq!(report.rejections, BTreeMap::from([("insufficient_funds", 1)]));
+        Ok(())
     }
 }
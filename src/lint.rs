@@ -0,0 +1,218 @@
+//! Schema check of a transactions CSV, without processing it: the header names, the number of
+//! columns of each record, and the type, client, tx and amount values. Every problem is a
+//! `LintFinding`, errors for records that would not be parsed or applied, warnings for ones
+//! that would be with a change (ie an amount rounded) or that look like a mistake.
+//!
+//! Trailer records (see `read_transactions_csv`) are not checked. Records are not checked either
+//! when the header is missing a required column, as their fields can't be found.
+
+use crate::amount::Amount;
+use crate::input::CsvColumns;
+use crate::model::{ClientId, TxAmount, TxId, TxType, AMOUNT_DECIMALS};
+use crate::validation::Severity;
+use crate::GResult;
+use csv::StringRecord;
+use std::io;
+
+/// A problem found by `lint_csv`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LintFinding {
+    /// Line of the record, or of the header, if the finding is about one.
+    pub line: Option<u64>,
+    /// Name of the column, if the finding is about one.
+    pub column: Option<String>,
+    pub severity: Severity,
+    /// Stable snake_case identifier of the kind of problem, ie `invalid_amount`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+const KNOWN_COLUMNS: [&str; 7] = ["type", "client", "tx", "amount", "idempotency_key", "timestamp", "pending"];
+
+struct Linter {
+    findings: Vec<LintFinding>,
+}
+
+impl Linter {
+    fn find(&mut self, line: Option<u64>, column: Option<&str>, severity: Severity, code: &'static str, message: String) {
+        self.findings.push(LintFinding {
+            line,
+            column: column.map(str::to_string),
+            severity,
+            code,
+            message,
+        });
+    }
+
+    fn header(&mut self, header: &StringRecord, line: Option<u64>) {
+        let names: Vec<_> = header.iter().map(|name| name.trim().to_ascii_lowercase()).collect();
+        for required in ["type", "client", "tx"] {
+            if !names.iter().any(|name| name == required) {
+                self.find(line, Some(required), Severity::Error, "missing_column", "missing column in header".to_string());
+            }
+        }
+        for (index, name) in names.iter().enumerate() {
+            if names[..index].contains(name) {
+                let message = "duplicate column, only the first one is read".to_string();
+                self.find(line, Some(name), Severity::Error, "duplicate_column", message);
+            } else if !KNOWN_COLUMNS.contains(&name.as_str()) {
+                self.find(line, Some(name), Severity::Warning, "unknown_column", "unknown column, ignored".to_string());
+            }
+        }
+    }
+
+    fn record(&mut self, record: &StringRecord, columns: &CsvColumns, width: usize) {
+        let line = record.position().map(|position| position.line());
+        let field = |index: usize| record.get(index).map(str::trim).unwrap_or_default();
+        let tx_type = field(columns.tx_type);
+        if tx_type.eq_ignore_ascii_case("trailer") {
+            return;
+        }
+        if record.len() != width {
+            let message = format!("found {} fields, but the header has {width}", record.len());
+            self.find(line, None, Severity::Error, "column_count", message);
+        }
+        let tx_type = match tx_type.parse::<TxType>() {
+            Ok(tx_type) => Some(tx_type),
+            Err(_) => {
+                let message = format!("unknown transaction type `{tx_type}`");
+                self.find(line, Some("type"), Severity::Error, "invalid_type", message);
+                None
+            }
+        };
+        if let Err(err) = field(columns.client).parse::<ClientId>() {
+            self.find(line, Some("client"), Severity::Error, "invalid_client", err.to_string());
+        }
+        if let Err(err) = field(columns.tx).parse::<TxId>() {
+            self.find(line, Some("tx"), Severity::Error, "invalid_tx", err.to_string());
+        }
+
+        let amount = columns.amount.map(field).unwrap_or_default();
+        let needs_amount = matches!(
+            tx_type,
+            Some(TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Refund)
+        );
+        let takes_amount = needs_amount || tx_type == Some(TxType::Capture);
+        if amount.is_empty() {
+            if needs_amount {
+                self.find(line, Some("amount"), Severity::Error, "missing_amount", "missing amount".to_string());
+            }
+            return;
+        }
+        if tx_type.is_some() && !takes_amount {
+            let message = "amount is ignored for this transaction type".to_string();
+            self.find(line, Some("amount"), Severity::Warning, "unexpected_amount", message);
+        }
+        match amount.parse::<TxAmount>() {
+            Err(err) => self.find(line, Some("amount"), Severity::Error, "invalid_amount", err.to_string()),
+            Ok(value) if !Amount::is_finite(value) || value <= TxAmount::default() => {
+                let message = format!("amount {amount} is not positive");
+                self.find(line, Some("amount"), Severity::Error, "non_positive_amount", message);
+            }
+            Ok(_) => {
+                let decimals = amount.split_once('.').map_or(0, |(_, decimals)| decimals.len());
+                if decimals > AMOUNT_DECIMALS as usize {
+                    let message = format!("amount has {decimals} decimal places, it will be rounded to {AMOUNT_DECIMALS}");
+                    self.find(line, Some("amount"), Severity::Warning, "amount_precision", message);
+                }
+            }
+        }
+    }
+}
+
+/// Checks a transactions CSV against the expected schema, see the module docs. Only I/O errors
+/// are returned as errors, unreadable records are findings.
+pub fn lint_csv<R: io::Read>(input: R) -> GResult<Vec<LintFinding>> {
+    let mut reader = crate::input::csv_reader(input);
+    let mut linter = Linter { findings: vec![] };
+    let header = reader.headers()?.clone();
+    if header.is_empty() {
+        return Ok(linter.findings);
+    }
+    linter.header(&header, header.position().map(|position| position.line()));
+    let Ok(columns) = CsvColumns::from_header(&header) else {
+        return Ok(linter.findings);
+    };
+    let mut record = StringRecord::new();
+    loop {
+        match reader.read_record(&mut record) {
+            Ok(true) => linter.record(&record, &columns, header.len()),
+            Ok(false) => break,
+            Err(err) if matches!(err.kind(), csv::ErrorKind::Io(_)) => return Err(err.into()),
+            Err(err) => {
+                let line = err.position().map(|position| position.line());
+                linter.find(line, None, Severity::Error, "unreadable_record", err.to_string());
+            }
+        }
+    }
+    Ok(linter.findings)
+}
+
+/// Checks the transactions CSV file at `path`, which may be compressed.
+pub fn lint_file(path: &str) -> GResult<Vec<LintFinding>> {
+    lint_csv(crate::compression::decompressed(std::fs::File::open(path)?)?)
+}
+
+#[derive(serde::Serialize)]
+struct FileFinding<'a> {
+    file: &'a str,
+    #[serde(flatten)]
+    finding: &'a LintFinding,
+}
+
+/// Writes the findings of the file at `path`, one JSON object per line.
+pub fn write_findings_json_lines<OUT: io::Write>(mut out: OUT, path: &str, findings: &[LintFinding]) -> GResult<()> {
+    for finding in findings {
+        serde_json::to_writer(&mut out, &FileFinding { file: path, finding })?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_csv() -> GResult<()> {
+        let input = "type, client, tx, amount, region, client\n\
+            deposit, 1, 1, 10.5, eu, 1\n\
+            deposit, 1, 2, 1.23456, eu, 1\n\
+            transfer, 70000, x, -1, eu, 1\n\
+            withdrawal, 1, 3, , eu, 1\n\
+            dispute, 1, 1, 2, eu\n\
+            deposit, 1, 4, 1.2.3, eu, 1\n\
+            trailer, 3, 11.73456\n";
+        let findings = lint_csv(input.as_bytes())?;
+        let found: Vec<_> = findings
+            .iter()
+            .map(|finding| (finding.line, finding.column.as_deref(), finding.severity, finding.code))
+            .collect();
+        assert_eq!(found, vec![
+            (Some(1), Some("region"), Severity::Warning, "unknown_column"),
+            (Some(1), Some("client"), Severity::Error, "duplicate_column"),
+            (Some(3), Some("amount"), Severity::Warning, "amount_precision"),
+            (Some(4), Some("type"), Severity::Error, "invalid_type"),
+            (Some(4), Some("client"), Severity::Error, "invalid_client"),
+            (Some(4), Some("tx"), Severity::Error, "invalid_tx"),
+            (Some(4), Some("amount"), Severity::Error, "non_positive_amount"),
+            (Some(5), Some("amount"), Severity::Error, "missing_amount"),
+            (Some(6), None, Severity::Error, "column_count"),
+            (Some(6), Some("amount"), Severity::Warning, "unexpected_amount"),
+            (Some(7), Some("amount"), Severity::Error, "invalid_amount"),
+        ]);
+
+        let mut out = vec![];
+        write_findings_json_lines(&mut out, "in.csv", &findings[2..3])?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"file\":\"in.csv\",\"line\":3,\"column\":\"amount\",\"severity\":\"warning\",\
+             \"code\":\"amount_precision\",\"message\":\"amount has 5 decimal places, it will be rounded to 4\"}\n"
+        );
+
+        let findings = lint_csv("client, tx, amount\n1, 1, 1\n".as_bytes())?;
+        assert_eq!(findings[0].code, "missing_column");
+        assert_eq!(findings[0].column.as_deref(), Some("type"));
+        Ok(())
+    }
+}
@@ -1,13 +1,951 @@
 use std::{error::Error};
-use std::io::stdout;
-use tx_processor::process_file_and_output;
+use std::fs::File;
+use std::io::{stdin, stdout};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tx_processor::backfill::{backfill_files, write_compensations_csv};
+use tx_processor::compression::decompressed;
+use tx_processor::encoding::decoded;
+use tx_processor::encryption::{StateEncryption, StateKey};
+use tx_processor::error::ErrorHandler;
+#[cfg(unix)]
+use tx_processor::handover::{hand_over, listen_for_handover, take_over};
+use tx_processor::journal::Journal;
+use tx_processor::lint::{lint_file, write_findings_json_lines};
+use tx_processor::ordering::OrderingPolicy;
+use tx_processor::output::{parse_columns, write_balances_csv, AmountFormat};
+use tx_processor::pipeline::parse_in_background;
+use tx_processor::reconcile::{reconcile_files, write_reconciliation_csv, ReconcileStatus};
+use tx_processor::report::report_by_group;
+use tx_processor::scheduler::{parse_schedule, start_scheduler};
+use tx_processor::server::{serve, DrainSignal};
+use tx_processor::shared::SharedTxProcessor;
+use tx_processor::simulation::{open_disputes_in_file, simulate_disputes, DisputeSimConfig};
+use tx_processor::soak::{parse_duration, parse_rate, run_soak, SoakConfig};
+use tx_processor::tx_processor::{NotificationSink, ProcessorConfig, TxProcessor};
+use tx_processor::uring::ReadBenchConfig;
+use tx_processor::validation::Severity;
+use tx_processor::verify::{verify_files, write_trial_balance};
+use tx_processor::webhooks::{WebhookConfig, Webhooks};
+use tx_processor::{
+    expand_paths, new_run_id, process_files_and_output, process_reader_and_output, process_transactions_and_output,
+    read_transactions_csv, ProcessOptions, ProcessReport,
+};
+
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static ALLOCATOR: tx_processor::profiling::CountingAllocator = tx_processor::profiling::CountingAllocator;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("soak") => {
+            args.next();
+            soak_command(args)
+        }
+        Some("bench") => {
+            args.next();
+            bench_command(args)
+        }
+        Some("backfill") => {
+            args.next();
+            backfill_command(args)
+        }
+        Some("verify") => {
+            args.next();
+            verify_command(args)
+        }
+        Some("reconcile") => {
+            args.next();
+            reconcile_command(args)
+        }
+        Some("lint") => {
+            args.next();
+            lint_command(args)
+        }
+        Some("report") => {
+            args.next();
+            report_command(args)
+        }
+        Some("serve") => {
+            args.next();
+            serve_command(args)
+        }
+        Some("simulate-disputes") => {
+            args.next();
+            simulate_disputes_command(args)
+        }
+        Some("kafka") => {
+            args.next();
+            kafka_command(args)
+        }
+        Some("merge-accounts") => {
+            args.next();
+            merge_accounts_command(args)
+        }
+        Some("verify-manifest") => {
+            args.next();
+            verify_manifest_command(args)
+        }
+        _ => process_command(args),
+    }
+}
+
+fn process_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    let mut options = ProcessOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rejected-report" => {
+                let report_path = args.next().ok_or("Missing path for --rejected-report")?;
+                options.rejected_report_path = Some(report_path);
+            }
+            "--warnings-report" => {
+                let report_path = args.next().ok_or("Missing path for --warnings-report")?;
+                options.warnings_report_path = Some(report_path);
+            }
+            "--history" => {
+                let history_path = args.next().ok_or("Missing path for --history")?;
+                options.history_path = Some(history_path);
+            }
+            "--ledger" => {
+                let ledger_path = args.next().ok_or("Missing path for --ledger")?;
+                options.ledger_path = Some(ledger_path);
+            }
+            "--negative-balances" => {
+                let report_path = args.next().ok_or("Missing path for --negative-balances")?;
+                options.negative_balances_path = Some(report_path);
+            }
+            "--export" => {
+                let export_dir = args.next().ok_or("Missing path for --export")?;
+                options.export_dir = Some(export_dir);
+            }
+            "--export-format" => {
+                let format = args.next().ok_or("Missing value for --export-format")?;
+                options.export_format = format.parse()?;
+            }
+            "--export-currency" => {
+                let currency = args.next().ok_or("Missing value for --export-currency")?;
+                options.export_currency = Some(currency);
+            }
+            "--audit" => {
+                let audit_path = args.next().ok_or("Missing path for --audit")?;
+                options.audit_path = Some(audit_path);
+            }
+            "--audit-format" => {
+                let format = args.next().ok_or("Missing value for --audit-format")?;
+                options.audit_format = format.parse()?;
+            }
+            "--sorted" => options.sort_by_client = true,
+            "--output-buffer" => {
+                let bytes = args.next().ok_or("Missing value for --output-buffer")?;
+                options.output_buffer = Some(bytes.parse()?);
+            }
+            "--flush-interval" => {
+                let interval = args.next().ok_or("Missing value for --flush-interval")?;
+                options.output_flush_interval = Some(parse_duration(&interval)?);
+            }
+            "--pipeline" => options.parse_in_background = true,
+            "--checkpoint" => {
+                let checkpoint_path = args.next().ok_or("Missing path for --checkpoint")?;
+                options.checkpoint_path = Some(checkpoint_path);
+            }
+            "--checkpoint-every" => {
+                let records = args.next().ok_or("Missing value for --checkpoint-every")?;
+                options.checkpoint_every = Some(records.parse()?);
+            }
+            "--resume" => options.resume = true,
+            "--manifest" => {
+                let manifest_path = args.next().ok_or("Missing path for --manifest")?;
+                options.manifest_path = Some(manifest_path);
+            }
+            "--summary" => {
+                let summary_path = args.next().ok_or("Missing path for --summary")?;
+                options.summary_path = Some(summary_path);
+            }
+            "--manifest-key-file" => {
+                let key_path = args.next().ok_or("Missing path for --manifest-key-file")?;
+                options.manifest_key_path = Some(key_path);
+            }
+            "--state-key-file" => {
+                let key_path = args.next().ok_or("Missing path for --state-key-file")?;
+                options.state_encryption.key = Some(StateKey::from_file(&key_path)?);
+            }
+            "--state-key-env" => {
+                let variable = args.next().ok_or("Missing variable for --state-key-env")?;
+                options.state_encryption.key = Some(StateKey::from_env(&variable)?);
+            }
+            "--migrate-plaintext-state" => options.state_encryption.migrate_plaintext = true,
+            "--settle" => options.settle_at_end = true,
+            "--settlement-report" => {
+                let report_path = args.next().ok_or("Missing path for --settlement-report")?;
+                options.settlement_report_path = Some(report_path);
+            }
+            "--settlement-snapshots" => {
+                let snapshot_dir = args.next().ok_or("Missing path for --settlement-snapshots")?;
+                options.settlement_snapshot_dir = Some(snapshot_dir);
+            }
+            "--input-encoding" => {
+                let encoding = args.next().ok_or("Missing value for --input-encoding")?;
+                options.input_encoding = encoding.parse()?;
+            }
+            "--tx-id-collisions" => {
+                let policy = args.next().ok_or("Missing value for --tx-id-collisions")?;
+                options.tx_id_collisions = policy.parse()?;
+            }
+            "--input-format" => {
+                let format = args.next().ok_or("Missing value for --input-format")?;
+                options.input_format = format.parse()?;
+            }
+            "--fixed-width-layout" => {
+                let layout = args.next().ok_or("Missing value for --fixed-width-layout")?;
+                options.fixed_width_layout = Some(layout.parse()?);
+            }
+            "--trailer-mismatch" => {
+                let policy = args.next().ok_or("Missing value for --trailer-mismatch")?;
+                options.trailer_mismatch = policy.parse()?;
+            }
+            "--run-id" => options.run_id = Some(args.next().ok_or("Missing value for --run-id")?),
+            "--dead-letter" => {
+                let dead_letter_path = args.next().ok_or("Missing path for --dead-letter")?;
+                options.dead_letter_path = Some(dead_letter_path);
+            }
+            "--ordering" => {
+                let policy = args.next().ok_or("Missing value for --ordering")?;
+                options.ordering = policy.parse()?;
+            }
+            "--tx-store" => {
+                let store_path = args.next().ok_or("Missing path for --tx-store")?;
+                options.tx_store_path = Some(store_path);
+            }
+            "--tx-store-cache" => {
+                let entries = args.next().ok_or("Missing value for --tx-store-cache")?;
+                options.tx_store_cache = Some(entries.parse()?);
+            }
+            "--shards" => {
+                let shards = args.next().ok_or("Missing value for --shards")?;
+                options.shards = Some(shards.parse()?);
+            }
+            "--format" => {
+                let format = args.next().ok_or("Missing value for --format")?;
+                options.output_format = format.parse()?;
+            }
+            "--columns" => {
+                let columns = args.next().ok_or("Missing value for --columns")?;
+                options.columns = Some(parse_columns(&columns)?);
+            }
+            "--locale" => {
+                let locale = args.next().ok_or("Missing value for --locale")?;
+                options.locale = locale.parse()?;
+            }
+            "--decimals" => {
+                let decimals = args.next().ok_or("Missing value for --decimals")?;
+                options.amount_format = AmountFormat::Fixed(decimals.parse()?);
+            }
+            _ if parse_process_option(&mut options, &arg, &mut args)? => {}
+            _ => paths.push(arg),
+        }
+    }
+
+    // Read from stdin when no path, or `-`, is given.
+    let read_stdin = match paths.as_slice() {
+        [] => true,
+        [path] => path == "-",
+        _ => false,
+    };
+    if read_stdin && options.manifest_path.is_some() {
+        Err("--manifest requires input files")?;
+    }
+    if read_stdin && options.summary_path.is_some() {
+        Err("--summary requires input files")?;
+    }
+    if read_stdin && options.ordering != OrderingPolicy::Ignore {
+        Err("--ordering requires input files")?;
+    }
+    let report = if !read_stdin {
+        process_files_and_output(&expand_paths(&paths)?, &mut stdout(), &options)?
+    } else if options.parse_in_background {
+        let encoding = options.input_encoding;
+        let transactions = parse_in_background(move || Ok(read_transactions_csv(decoded(decompressed(stdin())?, encoding)?)));
+        process_transactions_and_output(transactions, &mut stdout(), &options)?
+    } else {
+        process_reader_and_output(decompressed(stdin())?, &mut stdout(), &options)?
+    };
+    report_warnings(&report);
+    #[cfg(feature = "profiling")]
+    eprintln!("Profile: {}", report.profile);
+    Ok(())
+}
+
+/// Reports the problems with the input that processing went on after.
+fn report_warnings(report: &ProcessReport) {
+    for warning in &report.warnings {
+        eprintln!("Warning: {warning}");
+    }
+    if report.malformed == 0 {
+        return;
+    }
+    let run_id = report.run_id.as_deref().unwrap_or_default();
+    for record in &report.malformed_records {
+        eprintln!("[run {run_id}] Skipped malformed record {}: {}", record.sequence, record.error);
+    }
+    eprintln!("[run {run_id}] Skipped {} malformed records in total", report.malformed);
+}
+
+/// Logs the errors that servers and background threads carry on after.
+fn log_errors() -> ErrorHandler {
+    Arc::new(|context, err| eprintln!("{context}: {err}"))
+}
+
+fn bench_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut config = ReadBenchConfig::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--rows" => config.rows = value()?.parse()?,
+            "--clients" => config.clients = value()?.parse()?,
+            "--input" => config.path = Some(value()?),
+            _ => Err(format!("Unknown bench option: {arg}"))?,
+        }
+    }
+
+    read_bench_command(&config)
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn read_bench_command(config: &ReadBenchConfig) -> Result<(), Box<dyn Error>> {
+    println!("{}", tx_processor::uring::run_read_benchmark(config)?);
+    Ok(())
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn read_bench_command(_config: &ReadBenchConfig) -> Result<(), Box<dyn Error>> {
+    Err("bench requires the `io-uring` feature, on Linux")?
+}
+
+fn soak_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut config = SoakConfig::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--rate" => config.rate = parse_rate(&value()?)?,
+            "--duration" => config.duration = parse_duration(&value()?)?,
+            "--clients" => config.clients = value()?.parse()?,
+            "--report-interval" => config.report_interval = parse_duration(&value()?)?,
+            _ => Err(format!("Unknown soak option: {arg}"))?,
+        }
+    }
+
+    let report = run_soak(&config, |progress| eprintln!("{progress}"))?;
+    println!("{report}");
+    Ok(())
+}
+
+fn report_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut groups_path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--groups" => groups_path = Some(args.next().ok_or("Missing path for --groups")?),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or("Not enough args")?;
+    let groups_path = groups_path.ok_or("Missing --groups mapping file")?;
+    report_by_group(&path, &groups_path, stdout())?;
+    Ok(())
+}
+
+fn lint_command(args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let paths: Vec<String> = args.collect();
+    if paths.is_empty() {
         Err("Not enough args")?;
     }
+    let mut errors = 0;
+    for path in expand_paths(&paths)? {
+        let findings = lint_file(&path)?;
+        write_findings_json_lines(stdout(), &path, &findings)?;
+        errors += findings.iter().filter(|finding| finding.severity == Severity::Error).count();
+    }
+    if errors > 0 {
+        Err(format!("Lint failed with {errors} errors"))?;
+    }
+    Ok(())
+}
+
+fn verify_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    let mut options = ProcessOptions::default();
+
+    // Options that change balances, so that the input is processed as the run being verified.
+    while let Some(arg) = args.next() {
+        if !parse_process_option(&mut options, &arg, &mut args)? {
+            paths.push(arg);
+        }
+    }
+
+    if paths.is_empty() {
+        Err("Not enough args")?;
+    }
+    let trial = verify_files(&expand_paths(&paths)?, &options)?;
+    write_trial_balance(stdout(), &trial)?;
+    if !trial.is_balanced() {
+        Err(format!("Verification failed with {} discrepancies", trial.discrepancies.len()))?;
+    }
+    Ok(())
+}
+
+/// Parses an option that changes how the input is processed into balances, shared by the
+/// commands that process input. Returns whether `flag` is one of them.
+fn parse_process_option(
+    options: &mut ProcessOptions,
+    flag: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<bool, Box<dyn Error>> {
+    match flag {
+        "--hold-expiry" => {
+            let transactions = args.next().ok_or("Missing value for --hold-expiry")?;
+            options.hold_expiry = Some(transactions.parse()?);
+        }
+        "--clearing-period" => {
+            let period = args.next().ok_or("Missing value for --clearing-period")?;
+            options.clearing_period = Some(period.parse()?);
+        }
+        "--dispute-withdrawals" => options.dispute_withdrawals = true,
+        "--dispute-funds-policy" => {
+            let policy = args.next().ok_or("Missing value for --dispute-funds-policy")?;
+            options.dispute_funds_policy = policy.parse()?;
+        }
+        "--overflow-policy" => {
+            let policy = args.next().ok_or("Missing value for --overflow-policy")?;
+            options.overflow_policy = policy.parse()?;
+        }
+        "--dispute-timeout" => {
+            let transactions = args.next().ok_or("Missing value for --dispute-timeout")?;
+            options.dispute_timeout = Some(transactions.parse()?);
+        }
+        "--dispute-timeout-action" => {
+            let action = args.next().ok_or("Missing value for --dispute-timeout-action")?;
+            options.dispute_timeout_action = action.parse()?;
+        }
+        "--dispute-window" => {
+            let transactions = args.next().ok_or("Missing value for --dispute-window")?;
+            options.dispute_window = Some(transactions.parse()?);
+        }
+        "--round" => {
+            let decimals = args.next().ok_or("Missing value for --round")?;
+            options.round_amount_decimals = Some(decimals.parse()?);
+        }
+        "--skip" => {
+            let records = args.next().ok_or("Missing value for --skip")?;
+            options.skip = records.parse()?;
+        }
+        "--limit" => {
+            let records = args.next().ok_or("Missing value for --limit")?;
+            options.limit = Some(records.parse()?);
+        }
+        "--parse-mode" => {
+            let mode = args.next().ok_or("Missing value for --parse-mode")?;
+            options.parse_mode = mode.parse()?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn reconcile_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut balances_path = None;
+    let mut statement_path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--statement" => statement_path = Some(args.next().ok_or("Missing path for --statement")?),
+            _ => balances_path = Some(arg),
+        }
+    }
+
+    let balances_path = balances_path.ok_or("Not enough args")?;
+    let statement_path = statement_path.ok_or("Missing --statement file")?;
+    let lines = reconcile_files(&balances_path, &statement_path)?;
+    write_reconciliation_csv(stdout(), &lines)?;
+    let unmatched = lines.iter().filter(|line| line.status != ReconcileStatus::Match).count();
+    if unmatched > 0 {
+        Err(format!("{unmatched} of {} clients don't reconcile", lines.len()))?;
+    }
+    Ok(())
+}
+
+fn backfill_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut original_path = None;
+    let mut corrected_path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--original" => original_path = Some(args.next().ok_or("Missing path for --original")?),
+            "--corrected" => corrected_path = Some(args.next().ok_or("Missing path for --corrected")?),
+            _ => Err(format!("Unknown backfill option: {arg}"))?,
+        }
+    }
+
+    let original_path = original_path.ok_or("Missing --original feed")?;
+    let corrected_path = corrected_path.ok_or("Missing --corrected feed")?;
+    let plan = backfill_files(&original_path, &corrected_path)?;
+    // The compensating batch goes to stdout, to be reviewed and applied after the original feed.
+    write_compensations_csv(stdout(), &plan.compensations)?;
+    for (client, reason) in &plan.unresolved {
+        eprintln!("client {client} needs manual review: {reason}");
+    }
+    Ok(())
+}
+
+fn serve_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut listen = "127.0.0.1:7878".to_string();
+    let mut http = None;
+    let mut schedule_path = None;
+    let mut snapshot_path = None;
+    let mut journal_path = None;
+    let mut record_history = false;
+    let mut run_id = None;
+    let mut handover_path = None;
+    let mut take_over_path = None;
+    let mut webhooks = WebhookConfig::default();
+    let mut events_brokers = "localhost:9092".to_string();
+    let mut events_topic = None;
+    let mut redis_url = None;
+    let mut redis_prefix = "tx_processor".to_string();
+    let mut shards = None;
+    let mut actors = false;
+    let mut state_encryption = StateEncryption::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => listen = args.next().ok_or("Missing address for --listen")?,
+            "--http" => http = Some(args.next().ok_or("Missing address for --http")?),
+            "--schedule" => schedule_path = Some(args.next().ok_or("Missing path for --schedule")?),
+            "--snapshot" => snapshot_path = Some(args.next().ok_or("Missing path for --snapshot")?),
+            "--journal" => journal_path = Some(args.next().ok_or("Missing path for --journal")?),
+            "--state-key-file" => {
+                let key_path = args.next().ok_or("Missing path for --state-key-file")?;
+                state_encryption.key = Some(StateKey::from_file(&key_path)?);
+            }
+            "--state-key-env" => {
+                let variable = args.next().ok_or("Missing variable for --state-key-env")?;
+                state_encryption.key = Some(StateKey::from_env(&variable)?);
+            }
+            "--migrate-plaintext-state" => state_encryption.migrate_plaintext = true,
+            "--history" => record_history = true,
+            "--run-id" => run_id = Some(args.next().ok_or("Missing value for --run-id")?),
+            "--handover-socket" => handover_path = Some(args.next().ok_or("Missing path for --handover-socket")?),
+            "--take-over" => take_over_path = Some(args.next().ok_or("Missing path for --take-over")?),
+            "--shards" => shards = Some(args.next().ok_or("Missing value for --shards")?.parse()?),
+            "--actors" => actors = true,
+            "--webhook" => webhooks.urls.push(args.next().ok_or("Missing URL for --webhook")?),
+            "--webhook-attempts" => {
+                webhooks.max_attempts = args.next().ok_or("Missing value for --webhook-attempts")?.parse()?;
+            }
+            "--webhook-dead-letter" => {
+                webhooks.dead_letter_path = Some(args.next().ok_or("Missing path for --webhook-dead-letter")?);
+            }
+            "--events-brokers" => events_brokers = args.next().ok_or("Missing value for --events-brokers")?,
+            "--events-topic" => events_topic = Some(args.next().ok_or("Missing value for --events-topic")?),
+            "--redis" => redis_url = Some(args.next().ok_or("Missing URL for --redis")?),
+            "--redis-prefix" => redis_prefix = args.next().ok_or("Missing value for --redis-prefix")?,
+            _ => Err(format!("Unknown serve option: {arg}"))?,
+        }
+    }
+
+    // Handovers go through a Unix domain socket.
+    #[cfg(not(unix))]
+    if handover_path.is_some() || take_over_path.is_some() {
+        Err("--handover-socket and --take-over are only supported on Unix platforms")?;
+    }
+    // Each start of the server is a run of its own, even when restoring from a snapshot.
+    let run_id = run_id.unwrap_or_else(new_run_id);
+    eprintln!("Starting run {run_id}");
+    // The state is in Redis, shared with the other instances, rather than in snapshots and journals.
+    if redis_url.is_some() {
+        let unsupported = shards.is_some()
+            || http.is_some()
+            || schedule_path.is_some()
+            || snapshot_path.is_some()
+            || journal_path.is_some()
+            || handover_path.is_some()
+            || take_over_path.is_some();
+        if unsupported {
+            Err("--redis doesn't support --shards, --http, --schedule, --snapshot, --journal nor handovers")?;
+        }
+    }
+    if let Some(shards) = shards {
+        let unsupported = http.is_some()
+            || schedule_path.is_some()
+            || snapshot_path.is_some()
+            || journal_path.is_some()
+            || handover_path.is_some()
+            || take_over_path.is_some()
+            || !webhooks.urls.is_empty()
+            || events_topic.is_some();
+        if unsupported {
+            Err("--shards doesn't support --http, --schedule, --snapshot, --journal, handovers, webhooks nor events")?;
+        }
+        return serve_shared_command(&listen, shards, record_history, &run_id);
+    }
+    if actors {
+        let unsupported = http.is_none()
+            || redis_url.is_some()
+            || schedule_path.is_some()
+            || snapshot_path.is_some()
+            || journal_path.is_some()
+            || handover_path.is_some()
+            || take_over_path.is_some();
+        if unsupported {
+            Err("--actors requires --http, and doesn't support --redis, --schedule, --snapshot, --journal nor handovers")?;
+        }
+    }
+    // State is restored from the snapshot, if there is one, and saved to it after draining.
+    let mut processor = TxProcessor::with_config(ProcessorConfig {
+        record_history,
+        ..Default::default()
+    });
+    processor.run_id = Some(run_id.clone());
+    // Taking over from a running server gets its live state instead of the last snapshot.
+    #[cfg(unix)]
+    if let Some(path) = &take_over_path {
+        take_over(path, &mut processor)?;
+        eprintln!("Took over from {path} at sequence {}", processor.counters.sequence);
+    }
+    let snapshot = snapshot_path.as_ref().filter(|path| take_over_path.is_none() && Path::new(path).exists());
+    if let Some(path) = snapshot {
+        processor.load_snapshot_with(File::open(path)?, &state_encryption)?;
+        eprintln!("Restored state from {path}");
+    }
+    // Transactions accepted after the snapshot are recovered from the journal, which then records
+    // the new ones.
+    if let Some(path) = &journal_path {
+        let replayed = processor.replay_journal(path, &state_encryption)?;
+        eprintln!("Replayed {replayed} transactions from {path}");
+        let journal = Journal::open(path)?;
+        processor.journal = Some(match state_encryption.key.clone() {
+            Some(key) => Box::new(journal.encrypted(key)),
+            None => Box::new(journal),
+        });
+    }
+    // Set after the replay, replayed transactions were notified by the previous run.
+    let mut notifiers: Vec<Box<dyn NotificationSink>> = vec![];
+    if !webhooks.urls.is_empty() {
+        notifiers.push(Box::new(Webhooks::start(webhooks, log_errors())?));
+    }
+    if let Some(topic) = events_topic {
+        notifiers.push(kafka_notifier(&events_brokers, topic)?);
+    }
+    if let Some(http) = http.as_ref().filter(|_| actors) {
+        return serve_actors_command(http, processor.config, &run_id, notifiers);
+    }
+    if !notifiers.is_empty() {
+        processor.notifier = Some(Box::new(notifiers));
+    }
+    if let Some(url) = redis_url {
+        return serve_redis_command(&listen, processor, &url, &redis_prefix);
+    }
+    let processor = Arc::new(Mutex::new(processor));
+    // SIGTERM drains the server, which then exits with the final balances.
+    let drain = DrainSignal::default();
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, drain.flag())?;
+    #[cfg(unix)]
+    let handover = match &handover_path {
+        Some(path) => Some(listen_for_handover(path, drain.clone(), log_errors())?),
+        None => None,
+    };
+    let scheduler = match schedule_path {
+        Some(path) => {
+            let jobs = parse_schedule(&std::fs::read_to_string(path)?)?;
+            Some(start_scheduler(jobs, Arc::clone(&processor), drain.clone(), log_errors())?)
+        }
+        None => None,
+    };
+    if let Some(http) = http {
+        serve_http_command(&http, Arc::clone(&processor), drain)?;
+    } else {
+        let listener = TcpListener::bind(&listen)?;
+        eprintln!("Listening on {}", listener.local_addr()?);
+        serve(listener, Arc::clone(&processor), drain, log_errors())?;
+    }
+
+    if let Some(scheduler) = scheduler {
+        scheduler.join().map_err(|_| "scheduler thread panicked")?;
+    }
+    let mut processor = processor.lock().map_err(|_| "transaction processor poisoned")?;
+    // After a handover the new process owns the state, the snapshot and the journal.
+    #[cfg(unix)]
+    {
+        let handover = handover.map(|handover| handover.join().map_err(|_| "handover thread panicked")).transpose()?;
+        if let (Some(stream), Some(path)) = (handover.flatten(), &handover_path) {
+            hand_over(stream, &processor, path)?;
+            eprintln!("Handed over at sequence {}", processor.counters.sequence);
+            return Ok(());
+        }
+    }
+    eprintln!("Drained, writing final balances");
+    if let Some(path) = snapshot_path {
+        processor.save_snapshot_file(&path, &state_encryption)?;
+        if let Some(journal) = &mut processor.journal {
+            journal.truncate()?;
+        }
+        eprintln!("Saved state to {path}");
+    }
+    let mut balances: Vec<_> = processor.balances().collect();
+    balances.sort_by_key(|balance| balance.client);
+    write_balances_csv(stdout(), balances, AmountFormat::default())?;
+    Ok(())
+}
+
+/// Serves with a `SharedTxProcessor`, so that connections of clients of different shards are
+/// processed in parallel.
+fn serve_shared_command(listen: &str, shards: usize, record_history: bool, run_id: &str) -> Result<(), Box<dyn Error>> {
+    let config = ProcessorConfig {
+        record_history,
+        ..Default::default()
+    };
+    let processor = Arc::new(SharedTxProcessor::new(shards, || {
+        TxProcessor::builder().config(config.clone()).run_id(run_id).build()
+    }));
+    let drain = DrainSignal::default();
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, drain.flag())?;
+    let listener = TcpListener::bind(listen)?;
+    eprintln!("Listening on {} with {shards} shards", listener.local_addr()?);
+    serve(listener, Arc::clone(&processor), drain, log_errors())?;
+    eprintln!("Drained, writing final balances");
+    write_balances_csv(stdout(), &processor.balances(), AmountFormat::default())?;
+    Ok(())
+}
+
+/// Serves with a `RedisSubmitter`, so that several instances share the balances and transaction
+/// records in Redis.
+#[cfg(feature = "redis")]
+fn serve_redis_command(listen: &str, processor: TxProcessor, url: &str, prefix: &str) -> Result<(), Box<dyn Error>> {
+    use tx_processor::redis_store::{RedisStore, RedisSubmitter};
+    use tx_processor::server::TxSubmitter;
+
+    let processor = Arc::new(RedisSubmitter::new(processor, RedisStore::open(url, prefix)?)?);
+    let drain = DrainSignal::default();
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, drain.flag())?;
+    let listener = TcpListener::bind(listen)?;
+    eprintln!("Listening on {} with state in {url}", listener.local_addr()?);
+    serve(listener, Arc::clone(&processor), drain, log_errors())?;
+    eprintln!("Drained, writing the balances of the clients processed here");
+    write_balances_csv(stdout(), &processor.sorted_balances(), AmountFormat::default())?;
+    Ok(())
+}
+
+#[cfg(not(feature = "redis"))]
+fn serve_redis_command(_listen: &str, _processor: TxProcessor, _url: &str, _prefix: &str) -> Result<(), Box<dyn Error>> {
+    Err("--redis requires the `redis` feature".into())
+}
+
+/// Serves the HTTP API with a `ClientRouter`, so that each client is processed in a task of its
+/// own.
+#[cfg(all(feature = "async", feature = "http"))]
+fn serve_actors_command(
+    addr: &str,
+    config: ProcessorConfig,
+    run_id: &str,
+    notifiers: Vec<Box<dyn NotificationSink>>,
+) -> Result<(), Box<dyn Error>> {
+    use tx_processor::actors::{ClientRouter, DEFAULT_IDLE_TIMEOUT};
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = runtime.handle().clone();
+    let mut router = ClientRouter::new(config, move |task| {
+        handle.spawn(task);
+    })
+    .run_id(run_id);
+    if !notifiers.is_empty() {
+        router = router.notifier(notifiers);
+    }
+    let router = Arc::new(router);
+    let drain = DrainSignal::default();
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, drain.flag())?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!("HTTP API listening on {} with a task per client", listener.local_addr()?);
+        let served = tx_processor::http_api::serve_http_actors(listener, Arc::clone(&router), DEFAULT_IDLE_TIMEOUT, drain);
+        served.await
+    })?;
+    eprintln!("Drained, writing final balances");
+    write_balances_csv(stdout(), &router.balances(), AmountFormat::default())?;
+    Ok(())
+}
+
+#[cfg(not(all(feature = "async", feature = "http")))]
+fn serve_actors_command(
+    _addr: &str,
+    _config: ProcessorConfig,
+    _run_id: &str,
+    _notifiers: Vec<Box<dyn NotificationSink>>,
+) -> Result<(), Box<dyn Error>> {
+    Err("--actors requires the `async` and `http` features".into())
+}
+
+#[cfg(feature = "http")]
+fn serve_http_command(addr: &str, processor: Arc<Mutex<TxProcessor>>, drain: DrainSignal) -> Result<(), Box<dyn Error>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!("HTTP API listening on {}", listener.local_addr()?);
+        tx_processor::http_api::serve_http(listener, processor, drain).await?;
+        Ok(())
+    })
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http_command(_addr: &str, _processor: Arc<Mutex<TxProcessor>>, _drain: DrainSignal) -> Result<(), Box<dyn Error>> {
+    Err("--http requires the `http` feature".into())
+}
+
+fn simulate_disputes_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut config = DisputeSimConfig::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--resolve-rate" => config.resolve_rate = value()?.parse()?,
+            "--chargeback-rate" => config.chargeback_rate = value()?.parse()?,
+            "--scenarios" => config.scenarios = value()?.parse()?,
+            "--seed" => config.seed = value()?.parse()?,
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or("Not enough args")?;
+    let (processor, disputes) = open_disputes_in_file(&path)?;
+    println!("{}", simulate_disputes(&processor, &disputes, &config)?);
+    Ok(())
+}
+
+/// Merges an account into another in a checkpoint snapshot, which processing then resumes from.
+fn merge_accounts_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut snapshot_path = None;
+    let (mut from, mut into) = (None, None);
+    let mut state_encryption = StateEncryption::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--from" => from = Some(value()?.parse()?),
+            "--into" => into = Some(value()?.parse()?),
+            "--state-key-file" => state_encryption.key = Some(StateKey::from_file(&value()?)?),
+            "--state-key-env" => state_encryption.key = Some(StateKey::from_env(&value()?)?),
+            "--migrate-plaintext-state" => state_encryption.migrate_plaintext = true,
+            _ => snapshot_path = Some(arg),
+        }
+    }
+
+    let snapshot_path = snapshot_path.ok_or("Not enough args")?;
+    let from = from.ok_or("Missing --from client")?;
+    let into = into.ok_or("Missing --into client")?;
+    let mut processor = TxProcessor::new();
+    processor.load_snapshot_with(File::open(&snapshot_path)?, &state_encryption)?;
+    processor.merge_accounts(from, into)?;
+    processor.save_snapshot_file(&snapshot_path, &state_encryption)?;
+    Ok(())
+}
+
+/// Checks a run manifest's signature, and that the given output files are the output it describes.
+#[cfg(feature = "manifest")]
+fn verify_manifest_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    use tx_processor::manifest::{read_key_file, read_manifest};
+
+    let mut manifest_path = None;
+    let mut key_path = None;
+    let mut outputs = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key-file" => key_path = Some(args.next().ok_or("Missing path for --key-file")?),
+            "--output" => outputs.push(args.next().ok_or("Missing path for --output")?),
+            _ => manifest_path = Some(arg),
+        }
+    }
+
+    let manifest = read_manifest(&manifest_path.ok_or("Not enough args")?)?;
+    manifest.verify(&read_key_file(&key_path.ok_or("Missing --key-file")?)?)?;
+    for output in outputs {
+        manifest.verify_output(&output)?;
+    }
+    println!("{}", serde_json::to_string_pretty(&manifest.manifest)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "manifest"))]
+fn verify_manifest_command(_args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    Err("verify-manifest requires the `manifest` feature".into())
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_command(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    use tx_processor::kafka::{consume_kafka, KafkaSourceConfig};
+    use tx_processor::tx_processor::TxOutcome;
+
+    let mut config = KafkaSourceConfig {
+        brokers: "localhost:9092".to_string(),
+        group_id: "tx-processor".to_string(),
+        topics: vec![],
+        reply_topic: None,
+    };
+    let mut events_topic = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--brokers" => config.brokers = value()?,
+            "--group" => config.group_id = value()?,
+            "--topic" => config.topics.push(value()?),
+            "--reply-topic" => config.reply_topic = Some(value()?),
+            "--events-topic" => events_topic = Some(value()?),
+            _ => Err(format!("Unknown kafka option: {arg}"))?,
+        }
+    }
+    if config.topics.is_empty() {
+        Err("Missing --topic")?;
+    }
+
+    let mut processor = TxProcessor::new();
+    if let Some(topic) = events_topic {
+        processor.notifier = Some(kafka_notifier(&config.brokers, topic)?);
+    }
+    consume_kafka(&config, &mut processor, |tx, outcome| {
+        if let TxOutcome::Rejected(reason) = outcome {
+            eprintln!("Transaction {} {}: {reason}", tx.tx_id, tx.tx_type);
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_command(_args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    Err("kafka requires the `kafka` feature".into())
+}
+
+/// Publishes balance changes, chargebacks and account locks to `topic`.
+#[cfg(feature = "kafka")]
+fn kafka_notifier(brokers: &str, topic: String) -> Result<Box<dyn NotificationSink>, Box<dyn Error>> {
+    use tx_processor::kafka::{KafkaNotifier, KafkaSinkConfig};
+
+    let config = KafkaSinkConfig {
+        brokers: brokers.to_string(),
+        topic,
+    };
+    Ok(Box::new(KafkaNotifier::connect(&config, log_errors())?))
+}
 
-    let path = &args[1];
-    process_file_and_output(path, &mut stdout())
+#[cfg(not(feature = "kafka"))]
+fn kafka_notifier(_brokers: &str, _topic: String) -> Result<Box<dyn NotificationSink>, Box<dyn Error>> {
+    Err("--events-topic requires the `kafka` feature".into())
 }
@@ -0,0 +1,178 @@
+//! Signed manifest of a run, for downstream consumers to check that the balances they ingest are
+//! the complete, untampered output of the inputs they expect. The manifest gives the SHA-256 and
+//! size of the output and of each input file, the record counts and the engine version, and is
+//! signed with HMAC-SHA256 using a key shared with the consumers.
+//!
+//! The signature covers the compact JSON of the manifest without its `signature` field.
+
+pub use crate::digest::{digest_file, FileDigest, HashingWriter};
+use crate::encryption::{decode_hex, encode_hex};
+use crate::error::TxProcessorError;
+use crate::{GResult, ProcessReport};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{self, Write};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Name of the engine in manifests.
+pub const ENGINE: &str = env!("CARGO_PKG_NAME");
+
+/// Records of a run, see `ProcessReport`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RecordCounts {
+    pub transactions: u64,
+    pub applied: u64,
+    pub queued: u64,
+    pub rejected: u64,
+    pub malformed: u64,
+}
+
+impl From<&ProcessReport> for RecordCounts {
+    fn from(report: &ProcessReport) -> Self {
+        Self {
+            transactions: report.total(),
+            applied: report.applied,
+            queued: report.queued,
+            rejected: report.rejections.values().sum(),
+            malformed: report.malformed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub engine: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    pub inputs: Vec<FileDigest>,
+    pub output: FileDigest,
+    pub records: RecordCounts,
+    /// Clients that had at least one transaction processed.
+    pub clients: u64,
+}
+
+impl Manifest {
+    /// The manifest of a run of this engine over `inputs`, that wrote `output`.
+    pub fn new(run_id: Option<String>, inputs: Vec<FileDigest>, output: FileDigest, report: &ProcessReport) -> Self {
+        Self {
+            engine: ENGINE.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            run_id,
+            inputs,
+            output,
+            records: report.into(),
+            clients: report.clients_touched.len() as u64,
+        }
+    }
+
+    fn mac(&self, key: &[u8]) -> GResult<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&serde_json::to_vec(self)?);
+        Ok(mac)
+    }
+
+    pub fn sign(self, key: &[u8]) -> GResult<SignedManifest> {
+        let signature = format!("hmac-sha256:{}", encode_hex(&self.mac(key)?.finalize().into_bytes()));
+        Ok(SignedManifest { manifest: self, signature })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SignedManifest {
+    #[serde(flatten)]
+    pub manifest: Manifest,
+    pub signature: String,
+}
+
+impl SignedManifest {
+    /// Fails unless the manifest was signed with `key`.
+    pub fn verify(&self, key: &[u8]) -> GResult<()> {
+        let signature = self.signature.strip_prefix("hmac-sha256:").and_then(decode_hex);
+        let verified = match signature {
+            Some(signature) => self.manifest.mac(key)?.verify_slice(&signature).is_ok(),
+            None => false,
+        };
+        match verified {
+            true => Ok(()),
+            false => Err(TxProcessorError::Corrupted {
+                what: "manifest",
+                message: "its signature doesn't match the key".to_string(),
+            }),
+        }
+    }
+
+    /// Fails unless the file at `path` is the output the manifest describes.
+    pub fn verify_output(&self, path: &str) -> GResult<()> {
+        let digest = digest_file(path)?;
+        let expected = &self.manifest.output;
+        if digest.sha256 != expected.sha256 || digest.bytes != expected.bytes {
+            return Err(TxProcessorError::Corrupted {
+                what: "output",
+                message: format!("`{path}` is not the output of the run, or it is incomplete"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Reads a signing key from the file at `path`. Surrounding whitespace, ie a trailing newline, is
+/// not part of the key.
+pub fn read_key_file(path: &str) -> GResult<Vec<u8>> {
+    let key = std::fs::read(path)?;
+    let key = key.trim_ascii();
+    if key.is_empty() {
+        return Err(TxProcessorError::Parse {
+            field: "manifest_key",
+            message: format!("the key file `{path}` is empty"),
+        });
+    }
+    Ok(key.to_vec())
+}
+
+pub fn write_manifest(path: &str, manifest: &SignedManifest) -> GResult<()> {
+    let mut out = io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(&mut out, manifest)?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn read_manifest(path: &str) -> GResult<SignedManifest> {
+    Ok(serde_json::from_reader(io::BufReader::new(std::fs::File::open(path)?))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    #[test]
+    fn test_signed_manifest() -> GResult<()> {
+        let mut output = HashingWriter::new(vec![]);
+        output.write_all(b"client,available\n")?;
+        let output = output.finish();
+        assert_eq!(output.bytes, 17);
+        assert_eq!(output.sha256, encode_hex(&Sha256::digest(b"client,available\n")));
+        let input = digest_file("tests/example.csv")?;
+        assert_eq!(input.bytes, std::fs::metadata("tests/example.csv")?.len());
+
+        let report = ProcessReport {
+            applied: 3,
+            malformed: 1,
+            ..Default::default()
+        };
+        let manifest = Manifest::new(Some("run-1".to_string()), vec![input], output, &report).sign(b"secret")?;
+        manifest.verify(b"secret")?;
+        assert!(manifest.verify(b"other").is_err());
+
+        let json = serde_json::to_string(&manifest)?;
+        let read: SignedManifest = serde_json::from_str(&json)?;
+        read.verify(b"secret")?;
+        let tampered: SignedManifest = serde_json::from_str(&json.replace("\"applied\":3", "\"applied\":4"))?;
+        let err = tampered.verify(b"secret").unwrap_err().to_string();
+        assert_eq!(err, "corrupted manifest: its signature doesn't match the key");
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! Merging the account of a client into another, for customers that ended up with duplicate
+//! client ids. The funds of the old account are added to the new one, and its deposits,
+//! withdrawals, open disputes, holds and pending deposits go along, so that they can be resolved,
+//! charged back, released, refunded or cleared with the new id. The old id is tombstoned: its
+//! further transactions are rejected with `RejectReason::MergedAccount`, which gives the new id.
+//!
+//! The history and queued transactions of the old account stay under its id, and merges aren't
+//! posted to the ledger.
+
+use crate::amount::Amount;
+use crate::error::TxProcessorError;
+use crate::model::{ClientBalance, ClientId, OverflowPolicy};
+use crate::store::{StateMap, Stores, StoredTx};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Merges the account of `from` into the account of `into`, see the module docs. `from` must
+    /// be a client the processor has seen, and neither may have been merged already. A merge that
+    /// fails, ie because the combined balance would overflow, changes no balance.
+    pub fn merge_accounts(&mut self, from: ClientId, into: ClientId) -> GResult<()> {
+        let invalid = |reason: String| TxProcessorError::InvalidMerge { from, into, reason };
+        if from == into {
+            return Err(invalid("it is the same client".to_string()));
+        }
+        for client in [from, into] {
+            if let Some(merged_into) = self.merged_accounts.get(&client) {
+                return Err(invalid(format!("client {client} was already merged into client {merged_into}")));
+            }
+        }
+        let old = self
+            .clients_balance
+            .get(&from)
+            .cloned()
+            .ok_or_else(|| invalid(format!("client {from} has no account")))?;
+        let mut merged = self.clients_balance.get(&into).cloned().unwrap_or_else(|| ClientBalance::new_empty(into));
+        merged.merge(&old, OverflowPolicy::Abort)?;
+
+        // Collected first, the stores can't be updated while they are iterated.
+        let mut moved = vec![];
+        for entry in self.account_transactions.entries() {
+            let (tx_id, stored) = entry?;
+            if stored.client == Some(from) {
+                moved.push((tx_id, stored));
+            }
+        }
+        for (tx_id, stored) in moved {
+            self.account_transactions.insert(tx_id, StoredTx { client: Some(into), ..stored })?;
+        }
+        let holds: Vec<_> = self.holds.iter().filter(|(_, hold)| hold.client == from).map(|(tx_id, _)| *tx_id).collect();
+        for tx_id in holds {
+            self.holds.get_mut(&tx_id).expect("hold was just found").client = into;
+        }
+        let pending: Vec<_> =
+            self.pending_deposits.iter().filter(|(_, deposit)| deposit.client == from).map(|(tx_id, _)| *tx_id).collect();
+        for tx_id in pending {
+            self.pending_deposits.get_mut(&tx_id).expect("deposit was just found").client = into;
+        }
+        let disputes: Vec<_> =
+            self.open_disputes.iter().filter(|(_, dispute)| dispute.client == from).map(|(tx_id, _)| *tx_id).collect();
+        for tx_id in disputes {
+            self.open_disputes.get_mut(&tx_id).expect("dispute was just found").client = into;
+        }
+
+        self.clients_balance.remove(&from);
+        self.clients_balance.insert(into, merged);
+        // Accounts merged into the old one before now point to the new one.
+        let redirected: Vec<_> =
+            self.merged_accounts.iter().filter(|(_, merged_into)| **merged_into == from).map(|(client, _)| *client).collect();
+        for client in redirected {
+            self.merged_accounts.insert(client, into);
+        }
+        self.merged_accounts.insert(from, into);
+        Ok(())
+    }
+
+    /// The client that the account of `client` was merged into, if it was.
+    pub fn merged_into(&self, client: ClientId) -> Option<ClientId> {
+        self.merged_accounts.get(&client).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RejectReason;
+    use crate::test_support::*;
+    use crate::tx_processor::TxOutcome;
+
+    #[test]
+    fn test_merge_accounts() -> GResult<()> {
+        let mut processor = Scenario::new()
+            .tx(deposit(1, 1, 100.0))
+            .tx(withdrawal(1, 2, 30.0))
+            .tx(hold(1, 3, 20.0))
+            .tx(deposit(2, 4, 10.0))
+            .tx(dispute(2, 4))
+            .tx(deposit(3, 5, 5.0))
+            .into_processor();
+        processor.merge_accounts(2, 1)?;
+        assert_eq!(processor.merged_into(2), Some(1));
+        assert_eq!(processor.open_disputes.get(&4).map(|dispute| dispute.client), Some(1));
+
+        // The old id is rejected, its disputes are resolved with the new one.
+        Scenario::from_processor(processor)
+            .balance(1, 50.0, 30.0, false)
+            .outcome(deposit(2, 6, 1.0), TxOutcome::Rejected(RejectReason::MergedAccount { client: 2, into: 1 }))
+            .rejected(resolve(2, 4), "merged_account")
+            .tx(resolve(1, 4))
+            .tx(release(1, 3))
+            .balance(1, 80.0, 0.0, false)
+            .tx(dispute(1, 4))
+            .tx(chargeback(1, 4))
+            .balance(1, 70.0, 0.0, true);
+
+        let mut processor = Scenario::new().tx(deposit(1, 1, 1.0)).tx(deposit(2, 2, 2.0)).into_processor();
+        let err = processor.merge_accounts(1, 1).unwrap_err().to_string();
+        assert_eq!(err, "cannot merge client 1 into client 1: it is the same client");
+        assert!(processor.merge_accounts(3, 1).unwrap_err().to_string().contains("client 3 has no account"));
+        processor.merge_accounts(1, 2)?;
+        assert!(processor.merge_accounts(1, 2).unwrap_err().to_string().contains("already merged into client 2"));
+        // Merging into a client without an account yet moves the account over.
+        processor.merge_accounts(2, 3)?;
+        assert_eq!(processor.merged_into(1), Some(3));
+        assert_balance(&processor, 3, 3.0, 0.0, false);
+        assert_eq!(processor.balances().count(), 1);
+        Ok(())
+    }
+}
@@ -1,109 +1,363 @@
-use strum_macros::EnumString;
+use strum_macros::{Display, EnumString};
+use crate::amount::Amount;
+use crate::error::{RejectReason, TxProcessorError};
+use crate::validation::Finding;
 use crate::GResult;
 
-#[derive(Debug, Eq, PartialEq, serde::Deserialize, EnumString)]
-#[strum(ascii_case_insensitive)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, serde::Serialize, serde::Deserialize, EnumString, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(ascii_case_insensitive, serialize_all = "lowercase")]
 pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// Reserves an amount of available funds, see `holds`. Card feeds call it `authorize`.
+    #[serde(alias = "authorize")]
+    #[strum(to_string = "hold", serialize = "authorize")]
+    Hold,
+    /// Gives back the funds of the hold with the same transaction id.
+    Release,
+    /// Withdraws the amount, all of it if not set, of the hold with the same transaction id, and
+    /// gives back the rest of the hold.
+    Capture,
+    /// Credits back all or part of the withdrawal with the same transaction id.
+    Refund,
+    /// End-of-day marker, see `settlement`. Its client and transaction id are ignored.
+    Settle,
+    /// Admin record freezing the client's account, see `ClientBalance::frozen`. Its transaction id
+    /// is ignored.
+    Freeze,
+    /// Admin record lifting a freeze. Its transaction id is ignored.
+    Unfreeze,
+    /// Clears the pending deposit with the same transaction id, see `clearing`.
+    Clear,
+}
+
+impl TxType {
+    /// Whether records of this type are malformed without an amount.
+    pub fn requires_amount(self) -> bool {
+        matches!(self, TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Refund)
+    }
 }
 
 pub type ClientId = u16;
 pub type TxId = u32;
+#[cfg(not(feature = "minor-units"))]
 pub type TxAmount = f64;
+/// Exact amounts, see `amount`.
+#[cfg(feature = "minor-units")]
+pub type TxAmount = crate::amount::MinorUnits;
+
+/// Decimal places of amounts in inputs and outputs.
+pub const AMOUNT_DECIMALS: u32 = 4;
 
-#[derive(Debug, PartialEq,  serde::Deserialize)]
-pub struct Transaction {
+#[derive(Debug, Clone, PartialEq,  serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct Transaction<A = TxAmount> {
     #[serde(rename = "type")]
     pub tx_type: TxType,
     pub client: ClientId,
+    #[serde(alias = "tx")]
     pub tx_id: TxId,
-    pub amount: Option<TxAmount>,
+    #[serde(default, with = "crate::amount::units::option")]
+    pub amount: Option<A>,
+    /// Optional client-supplied key; a repeated key gets the outcome of the first transaction.
+    pub idempotency_key: Option<String>,
+    /// When the transaction happened, from an optional `timestamp` column, see `ordering`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// Set on deposits whose funds are held until they clear, from an optional `pending` column,
+    /// see `clearing`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+    /// Notes and warnings added by validators while processing.
+    #[serde(skip)]
+    pub findings: Vec<Finding>,
+    /// Labels for downstream filtering (ie "high-risk", "rounded"), added while processing and
+    /// carried into the per-transaction outputs.
+    #[serde(skip)]
+    pub tags: Vec<String>,
+}
+
+impl<A: Amount> Transaction<A> {
+    /// A transaction with only the columns every input has; the optional ones can be set with
+    /// struct update syntax, ie `Transaction { pending: true, ..Transaction::new(...) }`.
+    pub fn new(tx_type: TxType, client: ClientId, tx_id: TxId, amount: Option<A>) -> Self {
+        Transaction {
+            tx_type,
+            client,
+            tx_id,
+            amount,
+            idempotency_key: None,
+            timestamp: None,
+            pending: false,
+            findings: vec![],
+            tags: vec![],
+        }
+    }
+
+    /// Adds a tag, unless the transaction already has it.
+    pub fn tag(&mut self, tag: &str) {
+        if !self.has_tag(tag) {
+            self.tags.push(tag.to_string());
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|existing| existing == tag)
+    }
 }
 
-#[derive(Debug, PartialEq,  serde::Serialize)]
-pub struct ClientBalance {
+#[derive(Debug, Clone, PartialEq,  serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct ClientBalance<A = TxAmount> {
     pub client: ClientId,
-    pub total: TxAmount,
-    pub held: TxAmount,
-    pub available: TxAmount,
+    pub available: A,
+    pub held: A,
+    pub total: A,
     pub locked: bool,
+    /// Set and cleared by `freeze` and `unfreeze` admin records: funds can't leave a frozen
+    /// account (withdrawals, holds and captures are rejected), but deposits and disputes still
+    /// apply. Unlike `locked`, which a chargeback sets for good, it is reversible. Only serialized
+    /// when set, so that JSON outputs are unchanged for other accounts.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+    /// Part of `held` that is deposits which haven't cleared yet, see `clearing`. Only serialized
+    /// when there are some.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub pending: A,
+}
+
+fn is_zero<A: Amount>(amount: &A) -> bool {
+    *amount == A::default()
+}
+
+/// What to do when a balance update overflows the amount type, ie on pathological inputs with
+/// integer or decimal amounts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// The transaction is rejected with `RejectReason::BalanceOverflow`, the balance is unchanged.
+    #[default]
+    Reject,
+    /// Amounts that overflow are clamped to the largest (or smallest) finite amount, and the
+    /// transaction is applied with a warning finding.
+    Saturate,
+    /// Processing stops with `TxProcessorError::Overflow`, the balance is unchanged.
+    Abort,
+}
+
+impl OverflowPolicy {
+    /// The policy for updates that can't be rejected, ie releases of expired holds: they saturate,
+    /// unless processing aborts on overflows.
+    pub(crate) fn unrejectable(self) -> Self {
+        match self {
+            OverflowPolicy::Abort => OverflowPolicy::Abort,
+            OverflowPolicy::Reject | OverflowPolicy::Saturate => OverflowPolicy::Saturate,
+        }
+    }
+}
+
+/// How a balance update went, see `OverflowPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceUpdate {
+    Exact,
+    /// Some amounts overflowed, and were saturated.
+    Saturated,
+}
+
+impl BalanceUpdate {
+    /// Saturated if either update was.
+    pub fn and(self, other: BalanceUpdate) -> BalanceUpdate {
+        match (self, other) {
+            (BalanceUpdate::Exact, BalanceUpdate::Exact) => BalanceUpdate::Exact,
+            _ => BalanceUpdate::Saturated,
+        }
+    }
 }
 
-impl ClientBalance {
-    pub fn new_empty(client: ClientId) -> ClientBalance {
+/// Balance updates are checked: an update that overflows is handled according to the
+/// `OverflowPolicy` given, and either applies to available, held and total funds or to none.
+impl<A: Amount> ClientBalance<A> {
+    pub fn new_empty(client: ClientId) -> ClientBalance<A> {
         ClientBalance {
             client,
-            total: 0.0,
-            available: 0.0,
-            held: 0.0,
+            total: A::default(),
+            available: A::default(),
+            held: A::default(),
             locked: false,
+            frozen: false,
+            pending: A::default(),
         }
     }
 
-    pub fn add_funds(&mut self, amount: TxAmount) {
-        self.available += amount;
-        self.total += amount;
+    /// Adds the `available`, `held` and `total` deltas.
+    fn update(&mut self, policy: OverflowPolicy, available: A, held: A, total: A) -> GResult<BalanceUpdate> {
+        let (client, mut update) = (self.client, BalanceUpdate::Exact);
+        let mut add = |value: A, delta: A| match value.checked_add(delta) {
+            Some(sum) => Ok(sum),
+            None => match policy {
+                OverflowPolicy::Reject => Err(TxProcessorError::from(RejectReason::BalanceOverflow(client))),
+                OverflowPolicy::Abort => Err(TxProcessorError::Overflow("client balance")),
+                OverflowPolicy::Saturate => {
+                    update = BalanceUpdate::Saturated;
+                    Ok(if delta > A::default() { A::max_finite() } else { -A::max_finite() })
+                }
+            },
+        };
+        let available = add(self.available, available)?;
+        let held = add(self.held, held)?;
+        let total = add(self.total, total)?;
+        (self.available, self.held, self.total) = (available, held, total);
+        Ok(update)
     }
 
-    pub fn remove_funds(&mut self, amount: TxAmount) -> GResult<()> {
+    pub fn add_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        self.update(policy, amount, A::default(), amount)
+    }
+
+    /// Adds a deposit that hasn't cleared yet: it is held, and counted in `pending`, until
+    /// `clear_funds`.
+    pub fn add_pending_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        let update = self.update(policy, A::default(), amount, amount)?;
+        // Can only overflow if `held` was saturated.
+        self.pending = self.pending.checked_add(amount).unwrap_or_else(A::max_finite);
+        Ok(update)
+    }
+
+    /// Makes a pending deposit available.
+    pub fn clear_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        let update = self.update(policy, amount, -amount, A::default())?;
+        self.pending -= amount;
+        Ok(update)
+    }
+
+    pub fn remove_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
         if self.available >= amount {
-            self.available -= amount;
-            self.total -= amount;
-            Ok(())
+            self.update(policy, -amount, A::default(), -amount)
         } else {
-            Err("Not enough founds to withdraw".into())
+            Err(RejectReason::InsufficientFunds.into())
         }
     }
 
-    pub fn hold_funds(&mut self, amount: TxAmount) {
-        self.held += amount;
-        self.available -= amount;
+    pub fn hold_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        self.update(policy, -amount, amount, A::default())
+    }
+
+    pub fn resolve_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        self.update(policy, amount, -amount, A::default())
+    }
+
+    /// Holds the amount of a disputed withdrawal, pending its return to the client.
+    pub fn dispute_withdrawal(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        self.update(policy, A::default(), amount, amount)
+    }
+
+    pub fn resolve_withdrawal(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        self.update(policy, A::default(), -amount, -amount)
+    }
+
+    /// Returns the amount of a disputed withdrawal to the client, and locks the account.
+    pub fn chargeback_withdrawal(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        let update = self.update(policy, amount, -amount, A::default())?;
+        self.locked = true;
+        Ok(update)
+    }
+
+    /// Adds the funds of `other`, the account of another client being merged into this one. The
+    /// account is locked or frozen if either was.
+    pub fn merge(&mut self, other: &ClientBalance<A>, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        let update = self.update(policy, other.available, other.held, other.total)?;
+        self.locked |= other.locked;
+        self.frozen |= other.frozen;
+        self.pending = self.pending.checked_add(other.pending).unwrap_or_else(A::max_finite);
+        Ok(update)
     }
 
-    pub fn resolve_funds(&mut self, amount: TxAmount) {
-        self.held -= amount;
-        self.available += amount;
+    /// Withdraws held funds, ie to settle an authorization hold.
+    pub fn capture_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        self.update(policy, A::default(), -amount, -amount)
     }
 
-    pub fn chargeback_funds(&mut self, amount: TxAmount) {
-        // TODO: validate held >= amount
-        self.held -= amount;
-        self.total -=amount;
+    /// Withdraws held funds and locks the account. The processor only charges back the funds held
+    /// by an open dispute, so `held` covers `amount`.
+    pub fn chargeback_funds(&mut self, amount: A, policy: OverflowPolicy) -> GResult<BalanceUpdate> {
+        let update = self.update(policy, A::default(), -amount, -amount)?;
         self.locked = true;
+        Ok(update)
     }
 }
 
 #[test]
 fn test_client_balance() {
     let mut balance = ClientBalance::new_empty(123);
-    assert!(balance.locked == false);
+    assert!(!balance.locked);
 
-    balance.add_funds(100.0);
+    balance.add_funds(100.0, OverflowPolicy::Reject).unwrap();
     assert!(balance.available == 100.0);
     assert!(balance.total == 100.0);
 
-    balance.hold_funds(60.0);
+    balance.hold_funds(60.0, OverflowPolicy::Reject).unwrap();
     // fails:
-    balance.remove_funds(60.0).unwrap_err();
+    balance.remove_funds(60.0, OverflowPolicy::Reject).unwrap_err();
     // succeeds:
-    balance.remove_funds(40.0).unwrap();
+    balance.remove_funds(40.0, OverflowPolicy::Reject).unwrap();
 
     assert!(balance.available == 0.0);
     assert!(balance.total == 60.0);
     assert!(balance.held == 60.0);
-    balance.resolve_funds(60.0);
+    balance.resolve_funds(60.0, OverflowPolicy::Reject).unwrap();
     assert!(balance.available == 60.0);
     assert!(balance.total == 60.0);
     assert!(balance.held == 00.0);
 
-    balance.hold_funds(20.0);
-    balance.chargeback_funds(20.0);
+    balance.hold_funds(20.0, OverflowPolicy::Reject).unwrap();
+    balance.chargeback_funds(20.0, OverflowPolicy::Reject).unwrap();
     assert!(balance.available == 40.0);
     assert!(balance.total == 40.0);
     assert!(balance.held == 00.0);
-    assert!(balance.locked == true);
-}
\ No newline at end of file
+    assert!(balance.locked);
+}
+#[test]
+fn test_client_balance_backends() {
+    use crate::amount::MinorUnits;
+
+    // The same balance arithmetic, exact with integer amounts.
+    let mut balance: ClientBalance<MinorUnits> = ClientBalance::new_empty(1);
+    for _ in 0..10 {
+        balance.add_funds("0.1".parse().unwrap(), OverflowPolicy::Reject).unwrap();
+    }
+    balance.remove_funds("1".parse().unwrap(), OverflowPolicy::Reject).unwrap();
+    assert_eq!(balance.available, MinorUnits::ZERO);
+    balance.remove_funds(MinorUnits(1), OverflowPolicy::Reject).unwrap_err();
+
+    let mut balance: ClientBalance<f64> = ClientBalance::new_empty(1);
+    balance.add_funds(0.1, OverflowPolicy::Reject).unwrap();
+    balance.add_funds(0.2, OverflowPolicy::Reject).unwrap();
+    assert_eq!(balance.total.round_dp(AMOUNT_DECIMALS), 0.3);
+}
+
+#[test]
+fn test_client_balance_overflow() {
+    use crate::amount::MinorUnits;
+
+    let mut balance: ClientBalance<MinorUnits> = ClientBalance::new_empty(1);
+    let max = MinorUnits::max_finite();
+    balance.add_funds(max, OverflowPolicy::Reject).unwrap();
+    let err = balance.add_funds(MinorUnits(1), OverflowPolicy::Reject).unwrap_err();
+    assert_eq!(err.to_string(), "balance of client 1 would overflow");
+    let err = balance.add_funds(MinorUnits(1), OverflowPolicy::Abort).unwrap_err();
+    assert_eq!(err.to_string(), "client balance overflow");
+    assert_eq!((balance.available, balance.total), (max, max));
+
+    // Disputes hold on top, so held funds overflow on their own.
+    balance.hold_funds(max, OverflowPolicy::Reject).unwrap();
+    assert_eq!((balance.available, balance.held), (MinorUnits::ZERO, max));
+    let update = balance.dispute_withdrawal(MinorUnits(5), OverflowPolicy::Saturate).unwrap();
+    assert_eq!(update, BalanceUpdate::Saturated);
+    assert_eq!((balance.held, balance.total), (max, max));
+    assert!(balance.held.is_finite());
+    assert_eq!(balance.resolve_funds(MinorUnits(5), OverflowPolicy::Reject).unwrap(), BalanceUpdate::Exact);
+}
@@ -0,0 +1,142 @@
+//! End-of-run report of the clients left with negative funds, ie by disputes of deposits that
+//! were already spent (see `DisputeFundsPolicy::AllowNegative`). When history is recorded, the
+//! report gives the transaction that drove each balance negative.
+
+use crate::amount::Amount;
+use crate::history::HistoryEvent;
+use crate::model::{ClientBalance, ClientId, TxAmount, TxId, TxType, AMOUNT_DECIMALS};
+use crate::GResult;
+use std::io;
+
+/// A client whose `available` or `total` funds are negative.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegativeBalance {
+    pub balance: ClientBalance,
+    /// The last transaction that took the balance from non-negative to negative, if there is
+    /// history to tell.
+    pub driven_by: Option<DrivingTx>,
+}
+
+/// A transaction that drove a balance negative.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DrivingTx {
+    /// Sequence number of the transaction in the processor's input.
+    pub sequence: u64,
+    pub tx_type: TxType,
+    pub tx_id: TxId,
+}
+
+fn is_negative(amount: TxAmount) -> bool {
+    amount.round_dp(AMOUNT_DECIMALS) < TxAmount::default()
+}
+
+fn has_negative_funds(balance: &ClientBalance) -> bool {
+    is_negative(balance.available) || is_negative(balance.total)
+}
+
+/// The last event of `history` after which the balance was negative while it wasn't before.
+fn driving_tx(history: &[HistoryEvent]) -> Option<DrivingTx> {
+    let mut was_negative = false;
+    let mut driven_by = None;
+    for event in history {
+        let negative = has_negative_funds(&event.balance);
+        if negative && !was_negative {
+            driven_by = Some(DrivingTx {
+                sequence: event.sequence,
+                tx_type: event.tx_type,
+                tx_id: event.tx_id,
+            });
+        }
+        was_negative = negative;
+    }
+    driven_by
+}
+
+/// The clients of `balances` with negative funds, ordered by client. `history` gives the history
+/// of a client, empty if it isn't recorded.
+pub fn negative_balances<'a, 'h>(
+    balances: impl Iterator<Item = &'a ClientBalance>,
+    history: impl Fn(ClientId) -> &'h [HistoryEvent],
+) -> Vec<NegativeBalance> {
+    let mut negative: Vec<_> = balances
+        .filter(|balance| has_negative_funds(balance))
+        .map(|balance| NegativeBalance {
+            balance: balance.clone(),
+            driven_by: driving_tx(history(balance.client)),
+        })
+        .collect();
+    negative.sort_by_key(|negative| negative.balance.client);
+    negative
+}
+
+/// Writes `negative` as CSV, with empty driving transaction columns when it isn't known.
+pub fn write_negative_balances_csv<OUT: io::Write>(out: OUT, negative: &[NegativeBalance]) -> GResult<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["client", "available", "held", "total", "locked", "sequence", "type", "tx"])?;
+    for NegativeBalance { balance, driven_by } in negative {
+        writer.write_record([
+            balance.client.to_string(),
+            balance.available.to_string(),
+            balance.held.to_string(),
+            balance.total.to_string(),
+            balance.locked.to_string(),
+            driven_by.map(|tx| tx.sequence.to_string()).unwrap_or_default(),
+            driven_by.map(|tx| tx.tx_type.to_string()).unwrap_or_default(),
+            driven_by.map(|tx| tx.tx_id.to_string()).unwrap_or_default(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::tx_processor::ProcessorConfig;
+
+    #[test]
+    fn test_negative_balances() -> GResult<()> {
+        let processor = Scenario::with_config(ProcessorConfig {
+            record_history: true,
+            ..Default::default()
+        })
+        .tx(deposit(1, 1, 10.0))
+        .tx(withdrawal(1, 2, 4.0))
+        .tx(dispute(1, 1))
+        .tx(deposit(2, 3, 5.0))
+        .tx(deposit(3, 4, 2.0))
+        .tx(deposit(3, 5, 3.0))
+        .tx(withdrawal(3, 6, 5.0))
+        .tx(dispute(3, 4))
+        .tx(resolve(3, 4))
+        .tx(dispute(3, 5))
+        .into_processor();
+
+        let negative = negative_balances(processor.balances(), |client| processor.client_history(client));
+        let clients: Vec<_> = negative.iter().map(|negative| negative.balance.client).collect();
+        assert_eq!(clients, [1, 3]);
+        assert_eq!(
+            negative[0].driven_by,
+            Some(DrivingTx {
+                sequence: 3,
+                tx_type: TxType::Dispute,
+                tx_id: 1,
+            })
+        );
+        // Client 3 was out of negative funds in between.
+        assert_eq!(negative[1].driven_by.map(|tx| (tx.tx_type, tx.tx_id)), Some((TxType::Dispute, 5)));
+
+        let without_history = negative_balances(processor.balances(), |_| &[]);
+        assert_eq!(without_history[0].driven_by, None);
+
+        let mut out = vec![];
+        write_negative_balances_csv(&mut out, &negative)?;
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("client,available,held,total,locked,sequence,type,tx"));
+        assert_eq!(lines.next(), Some("1,-4,10,6,false,3,dispute,1"));
+        assert_eq!(lines.count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+//! Time ordering of the transactions of several input files, merged in the order the files are
+//! given, so that files concatenated in the wrong order are caught instead of disputes resolving
+//! against the wrong state. Transactions get their time from an optional `timestamp` column, see
+//! `parse_timestamp`.
+//!
+//! With an `OrderingPolicy` other than `Ignore`, every transaction must have a timestamp, except
+//! settlement markers, which keep their place in the input.
+
+use crate::error::TxProcessorError;
+use crate::model::{Transaction, TxType};
+use crate::GResult;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+/// Parses a timestamp: an integer, taken as it is, or an RFC 3339 date and time (ie
+/// `2024-05-01T09:30:00Z` or `2024-05-01 11:30:00.250+02:00`), in milliseconds since the Unix
+/// epoch. Timestamps are only compared with each other, so the inputs of a run should use the
+/// same kind.
+pub fn parse_timestamp(value: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = value.parse() {
+        return Ok(timestamp);
+    }
+    parse_rfc3339(value).ok_or_else(|| format!("`{value}` is neither an integer nor an RFC 3339 date and time"))
+}
+
+/// The number in `value[range]`, if it is all ASCII digits.
+fn digits(value: &str, range: std::ops::Range<usize>) -> Option<i64> {
+    value.get(range).filter(|digits| digits.bytes().all(|byte| byte.is_ascii_digit()))?.parse().ok()
+}
+
+fn parse_rfc3339(value: &str) -> Option<i64> {
+    let bytes = value.as_bytes();
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if bytes.len() < 20
+        || separators.iter().any(|&(at, separator)| bytes[at] != separator)
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+    {
+        return None;
+    }
+    let (year, month, day) = (digits(value, 0..4)?, digits(value, 5..7)?, digits(value, 8..10)?);
+    let (hour, minute, second) = (digits(value, 11..13)?, digits(value, 14..16)?, digits(value, 17..19)?);
+    // A leap second is taken as the second before it.
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &value[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        millis = format!("{:0<3}", &fraction[..len.min(3)]).parse().ok()?;
+        rest = &fraction[len..];
+    }
+    let offset_minutes = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let offset = digits(rest, 1..3)? * 60 + digits(rest, 4..6)?;
+            match rest.as_bytes()[0] {
+                b'+' => offset,
+                b'-' => -offset,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second.min(59);
+    Some((seconds - offset_minutes * 60) * 1000 + millis)
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years start in March, so that the leap day is the last day of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// What to do with transactions that are out of time order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderingPolicy {
+    /// Timestamps are not checked.
+    #[default]
+    Ignore,
+    /// The first transaction earlier than one before it stops processing with a
+    /// `TxProcessorError::OutOfOrder`.
+    Check,
+    /// Each span of transactions earlier than one before them is reported with a
+    /// `TxProcessorError::OutOfOrderSpan` after it, and processing goes on.
+    Warn,
+    /// Transactions are sorted by timestamp within a window of this many transactions, ties
+    /// keeping their input order. A transaction out of order by more than the window stops
+    /// processing, as with `Check`.
+    Resort(usize),
+}
+
+impl FromStr for OrderingPolicy {
+    type Err = TxProcessorError;
+
+    /// Parses `ignore`, `check`, `warn` or `resort:<window>`.
+    fn from_str(value: &str) -> GResult<Self> {
+        let invalid = || TxProcessorError::Parse {
+            field: "ordering",
+            message: format!("expected `ignore`, `check`, `warn` or `resort:<window>`, found `{value}`"),
+        };
+        Ok(match value.to_ascii_lowercase().as_str() {
+            "ignore" => Self::Ignore,
+            "check" => Self::Check,
+            "warn" => Self::Warn,
+            other => match other.strip_prefix("resort:").map(str::parse) {
+                Some(Ok(window)) if window > 0 => Self::Resort(window),
+                _ => return Err(invalid()),
+            },
+        })
+    }
+}
+
+impl fmt::Display for OrderingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderingPolicy::Ignore => write!(f, "ignore"),
+            OrderingPolicy::Check => write!(f, "check"),
+            OrderingPolicy::Warn => write!(f, "warn"),
+            OrderingPolicy::Resort(window) => write!(f, "resort:{window}"),
+        }
+    }
+}
+
+/// A record of one of the input files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    file: usize,
+    record: u64,
+}
+
+/// Transactions earlier than `latest`, the latest timestamp before them.
+#[derive(Debug)]
+struct Span {
+    first: Position,
+    last: Position,
+    earliest: i64,
+    latest: i64,
+}
+
+/// A transaction waiting to be re-sorted.
+#[derive(Debug)]
+struct Pending {
+    timestamp: i64,
+    arrival: u64,
+    position: Position,
+    tx: Transaction,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.arrival).cmp(&(other.timestamp, other.arrival))
+    }
+}
+
+/// Checks the transactions of input files, in order, for their time order. Transactions are
+/// pushed as they are read, and popped once they are ready to be processed.
+#[derive(Debug)]
+pub struct OrderCheck {
+    policy: OrderingPolicy,
+    paths: Vec<String>,
+    /// Records of the current file.
+    records: u64,
+    /// Latest timestamp taken, or given out when re-sorting.
+    latest: Option<i64>,
+    span: Option<Span>,
+    pending: BinaryHeap<Reverse<Pending>>,
+    arrivals: u64,
+    ready: VecDeque<GResult<Transaction>>,
+}
+
+impl OrderCheck {
+    pub fn new(policy: OrderingPolicy) -> Self {
+        Self {
+            policy,
+            paths: vec![],
+            records: 0,
+            latest: None,
+            span: None,
+            pending: BinaryHeap::new(),
+            arrivals: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Starts checking the transactions of the file at `path`.
+    pub fn start_file(&mut self, path: &str) {
+        self.paths.push(path.to_string());
+        self.records = 0;
+    }
+
+    pub fn push(&mut self, tx: Transaction) {
+        self.records += 1;
+        if self.policy == OrderingPolicy::Ignore {
+            self.ready.push_back(Ok(tx));
+            return;
+        }
+        let position = Position {
+            file: self.paths.len().saturating_sub(1),
+            record: self.records,
+        };
+        let Some(timestamp) = tx.timestamp else {
+            if tx.tx_type == TxType::Settle {
+                self.flush_pending();
+                self.ready.push_back(Ok(tx));
+            } else {
+                self.ready.push_back(Err(TxProcessorError::Parse {
+                    field: "timestamp",
+                    message: format!("missing in {}, it is needed to check the ordering", self.describe(position)),
+                }));
+            }
+            return;
+        };
+        let latest = self.latest.filter(|latest| timestamp < *latest);
+        match (self.policy, latest) {
+            (OrderingPolicy::Ignore, _) => unreachable!("transactions are not checked"),
+            (OrderingPolicy::Check, Some(latest)) => self.ready.push_back(Err(self.out_of_order(position, timestamp, latest))),
+            (OrderingPolicy::Warn, Some(latest)) => {
+                let span = self.span.get_or_insert(Span {
+                    first: position,
+                    last: position,
+                    earliest: timestamp,
+                    latest,
+                });
+                span.last = position;
+                span.earliest = span.earliest.min(timestamp);
+                self.ready.push_back(Ok(tx));
+            }
+            (OrderingPolicy::Check | OrderingPolicy::Warn, None) => {
+                self.report_span();
+                self.latest = Some(timestamp);
+                self.ready.push_back(Ok(tx));
+            }
+            (OrderingPolicy::Resort(window), _) => {
+                self.arrivals += 1;
+                let pending = Pending {
+                    timestamp,
+                    arrival: self.arrivals,
+                    position,
+                    tx,
+                };
+                self.pending.push(Reverse(pending));
+                while self.pending.len() > window {
+                    self.give_out_earliest();
+                }
+            }
+        }
+    }
+
+    /// Counts a record of the current file that isn't pushed, ie a malformed one, so that the
+    /// records after it are reported at their position.
+    pub fn skip(&mut self) {
+        self.records += 1;
+    }
+
+    /// The next transaction ready to be processed, if any.
+    pub fn pop(&mut self) -> Option<GResult<Transaction>> {
+        self.ready.pop_front()
+    }
+
+    /// Readies the transactions still being re-sorted, once the input is over.
+    pub fn finish(&mut self) {
+        self.flush_pending();
+        self.report_span();
+    }
+
+    fn flush_pending(&mut self) {
+        while !self.pending.is_empty() {
+            self.give_out_earliest();
+        }
+    }
+
+    fn give_out_earliest(&mut self) {
+        let Some(Reverse(pending)) = self.pending.pop() else {
+            return;
+        };
+        match self.latest {
+            Some(latest) if pending.timestamp < latest => {
+                let err = self.out_of_order(pending.position, pending.timestamp, latest);
+                self.ready.push_back(Err(err));
+            }
+            _ => {
+                self.latest = Some(pending.timestamp);
+                self.ready.push_back(Ok(pending.tx));
+            }
+        }
+    }
+
+    fn report_span(&mut self) {
+        if let Some(span) = self.span.take() {
+            let records = match span.first == span.last {
+                true => self.describe(span.first),
+                false => format!("{} to {}", self.describe(span.first), self.describe(span.last)),
+            };
+            self.ready.push_back(Err(TxProcessorError::OutOfOrderSpan {
+                records,
+                earliest: span.earliest,
+                latest: span.latest,
+            }));
+        }
+    }
+
+    fn out_of_order(&self, position: Position, timestamp: i64, latest: i64) -> TxProcessorError {
+        TxProcessorError::OutOfOrder {
+            at: self.describe(position),
+            timestamp,
+            latest,
+        }
+    }
+
+    fn describe(&self, position: Position) -> String {
+        match self.paths.get(position.file) {
+            Some(path) => format!("record {} of `{path}`", position.record),
+            None => format!("record {}", position.record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1714555800"), Ok(1_714_555_800));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Ok(0));
+        assert_eq!(parse_timestamp("2000-03-01T00:00:00Z"), Ok(951_868_800_000));
+        assert_eq!(parse_timestamp("2024-02-29 12:00:00.5+01:00"), Ok(1_709_204_400_500));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59.999-00:00"), Ok(-1));
+        for invalid in ["", "2024-02-29", "2024-13-01T00:00:00Z", "2024-02-29T12:00:00", "2024-02-29T12:00:00+1:00"] {
+            assert!(parse_timestamp(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    fn at(timestamp: i64, tx: Transaction) -> Transaction {
+        Transaction {
+            timestamp: Some(timestamp),
+            ..tx
+        }
+    }
+
+    /// The transactions of `files`, checked with `policy`, as tx ids or error messages.
+    fn check(policy: OrderingPolicy, files: Vec<Vec<Transaction>>) -> Vec<Result<u32, String>> {
+        let mut check = OrderCheck::new(policy);
+        let mut checked = vec![];
+        for (file, transactions) in files.into_iter().enumerate() {
+            check.start_file(&format!("day-{}.csv", file + 1));
+            for tx in transactions {
+                check.push(tx);
+                checked.extend(std::iter::from_fn(|| check.pop()));
+            }
+        }
+        check.finish();
+        checked.extend(std::iter::from_fn(|| check.pop()));
+        checked.into_iter().map(|tx| tx.map(|tx| tx.tx_id).map_err(|err| err.to_string())).collect()
+    }
+
+    #[test]
+    fn test_order_check() {
+        let files = || {
+            vec![
+                vec![at(10, deposit(1, 1, 1.0)), at(20, deposit(1, 2, 1.0))],
+                vec![at(15, deposit(1, 3, 1.0)), at(30, deposit(1, 4, 1.0)), at(25, deposit(1, 5, 1.0))],
+            ]
+        };
+        assert_eq!(check(OrderingPolicy::Ignore, files()), [Ok(1), Ok(2), Ok(3), Ok(4), Ok(5)]);
+        let span = "record 1 of `day-2.csv` out of order: timestamps from 15 are earlier than 20, of a transaction before them";
+        let last = "record 3 of `day-2.csv` out of order: timestamps from 25 are earlier than 30, of a transaction before them";
+        assert_eq!(check(OrderingPolicy::Warn, files()), [
+            Ok(1),
+            Ok(2),
+            Ok(3),
+            Err(span.to_string()),
+            Ok(4),
+            Ok(5),
+            Err(last.to_string())
+        ]);
+        let checked = check(OrderingPolicy::Check, files());
+        let err = "record 1 of `day-2.csv` is out of order: its timestamp 15 is earlier than 20, of a transaction before it";
+        assert_eq!(checked[..3], [Ok(1), Ok(2), Err(err.to_string())]);
+
+        assert_eq!(check(OrderingPolicy::Resort(2), files()), [Ok(1), Ok(3), Ok(2), Ok(5), Ok(4)]);
+        // Out of order by more than the window.
+        let files = vec![
+            vec![at(10, deposit(1, 1, 1.0)), at(20, deposit(1, 2, 1.0)), at(30, deposit(1, 3, 1.0))],
+            vec![at(15, deposit(1, 4, 1.0))],
+        ];
+        let checked = check(OrderingPolicy::Resort(1), files);
+        assert_eq!(checked[..2], [Ok(1), Ok(2)]);
+        assert!(checked[2].as_ref().unwrap_err().starts_with("record 1 of `day-2.csv` is out of order"));
+
+        // A settlement marker keeps its place, re-sorting starts over after it.
+        let files = vec![vec![at(20, deposit(1, 1, 1.0)), settle(), at(10, deposit(1, 2, 1.0))]];
+        let checked = check(OrderingPolicy::Resort(5), files);
+        assert_eq!(checked[..2], [Ok(1), Ok(0)]);
+        assert!(checked[2].is_err());
+        let checked = check(OrderingPolicy::Check, vec![vec![deposit(1, 1, 1.0)]]);
+        assert_eq!(checked, [Err("invalid `timestamp` field: missing in record 1 of `day-1.csv`, it is needed to check the ordering".to_string())]);
+    }
+
+    #[test]
+    fn test_ordering_policy() {
+        for policy in ["ignore", "check", "warn", "resort:100"] {
+            assert_eq!(policy.parse::<OrderingPolicy>().unwrap().to_string(), policy);
+        }
+        assert!("resort:0".parse::<OrderingPolicy>().is_err());
+        assert!("sort".parse::<OrderingPolicy>().is_err());
+    }
+}
@@ -0,0 +1,522 @@
+use crate::amount::Amount;
+use crate::error::TxProcessorError;
+use crate::model::{ClientBalance, TxAmount, AMOUNT_DECIMALS};
+use crate::sink::{sink_balances, CsvBalanceSink, JsonBalanceSink, JsonLinesBalanceSink};
+use crate::GResult;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::io;
+use std::time::{Duration, Instant};
+use strum_macros::{Display, EnumString};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum OutputFormat {
+    #[default]
+    #[strum(serialize = "csv")]
+    Csv,
+    /// A single JSON array of balances.
+    #[strum(serialize = "json")]
+    Json,
+    /// One JSON object per line.
+    #[strum(serialize = "jsonl")]
+    JsonLines,
+    #[cfg(feature = "parquet")]
+    #[strum(serialize = "parquet")]
+    Parquet,
+    /// Aligned table for people to read, with locale-formatted amounts (see `NumberLocale`).
+    #[strum(serialize = "table")]
+    Table,
+}
+
+/// Number formatting conventions for human-facing output. Machine-readable outputs always use
+/// plain numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "lowercase")]
+pub enum NumberLocale {
+    /// `1,234.5`
+    #[default]
+    En,
+    /// `1.234,5`
+    De,
+    /// `1 234,5`, grouped with a narrow no-break space.
+    Fr,
+    /// `1'234.5`
+    Ch,
+}
+
+impl NumberLocale {
+    fn separators(&self) -> (char, char) {
+        match self {
+            NumberLocale::En => ('.', ','),
+            NumberLocale::De => (',', '.'),
+            NumberLocale::Fr => (',', '\u{202f}'),
+            NumberLocale::Ch => ('.', '\''),
+        }
+    }
+
+    /// Formats an amount with `format`, then applies the locale's decimal and grouping separators.
+    pub fn format(&self, amount: TxAmount, format: AmountFormat) -> String {
+        let (decimal_separator, group_separator) = self.separators();
+        let plain = format.format(amount);
+        let (sign, unsigned) = plain.strip_prefix('-').map_or(("", plain.as_str()), |rest| ("-", rest));
+        let (integer, fraction) = unsigned.split_once('.').map_or((unsigned, None), |(i, f)| (i, Some(f)));
+
+        let mut formatted = sign.to_string();
+        for (index, digit) in integer.chars().enumerate() {
+            if index > 0 && (integer.len() - index) % 3 == 0 {
+                formatted.push(group_separator);
+            }
+            formatted.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            formatted.push(decimal_separator);
+            formatted.push_str(fraction);
+        }
+        formatted
+    }
+}
+
+/// How amounts are formatted in the balances output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AmountFormat {
+    /// Shortest representation of the amount rounded to `AMOUNT_DECIMALS`, ie `127.9` or `0`.
+    #[default]
+    Shortest,
+    /// Fixed number of decimal places, ie `127.9000` for `Fixed(4)`.
+    Fixed(usize),
+}
+
+impl AmountFormat {
+    pub fn format(&self, amount: TxAmount) -> String {
+        match self {
+            AmountFormat::Shortest => amount.round_dp(AMOUNT_DECIMALS).to_string(),
+            AmountFormat::Fixed(decimals) => format!("{amount:.decimals$}"),
+        }
+    }
+}
+
+/// A column of the CSV balances output, see `parse_columns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(ascii_case_insensitive, serialize_all = "lowercase")]
+pub enum BalanceColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    /// Not in the default columns, see `ClientBalance::frozen`.
+    Frozen,
+    /// Not in the default columns, see `ClientBalance::pending`.
+    Pending,
+    /// `locked`, `frozen` or `active`, for loaders that expect a status rather than a boolean.
+    Status,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnSpec {
+    pub column: BalanceColumn,
+    pub header: String,
+}
+
+/// Parses a comma-separated column list, in output order, where each column may be renamed with
+/// `column=header`. For example `client,total=balance,status`.
+pub fn parse_columns(spec: &str) -> GResult<Vec<ColumnSpec>> {
+    spec.split(',')
+        .map(|column| {
+            let (name, header) = column.split_once('=').unwrap_or((column, column));
+            let column = name.trim().parse().map_err(|_| TxProcessorError::Parse {
+                field: "columns",
+                message: format!("unknown column `{}`", name.trim()),
+            })?;
+            Ok(ColumnSpec {
+                column,
+                header: header.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Like `write_balances_csv`, with the given columns instead of the default ones.
+pub fn write_balances_csv_columns<'a, OUT, ITER>(
+    out: OUT,
+    balances: ITER,
+    format: AmountFormat,
+    columns: &[ColumnSpec],
+) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    sink_balances(&mut CsvBalanceSink::with_columns(out, format, Some(columns.to_vec()))?, balances)
+}
+
+/// A `ClientBalance` serialized with amounts formatted according to `AmountFormat`.
+pub(crate) struct BalanceRow<'a> {
+    pub balance: &'a ClientBalance,
+    pub format: AmountFormat,
+}
+
+impl Serialize for BalanceRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let balance = self.balance;
+        let mut row = serializer.serialize_struct("ClientBalance", 5)?;
+        row.serialize_field("client", &balance.client)?;
+        row.serialize_field("available", &self.format.format(balance.available))?;
+        row.serialize_field("held", &self.format.format(balance.held))?;
+        row.serialize_field("total", &self.format.format(balance.total))?;
+        row.serialize_field("locked", &balance.locked)?;
+        row.end()
+    }
+}
+
+/// Writes balances in the given format. `amount_format` only applies to CSV, JSON and Parquet
+/// amounts are always written as numbers.
+pub fn write_balances<'a, OUT, ITER>(
+    out: OUT,
+    balances: ITER,
+    format: OutputFormat,
+    amount_format: AmountFormat,
+) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    match format {
+        OutputFormat::Csv => write_balances_csv(out, balances, amount_format),
+        OutputFormat::Json => write_balances_json(out, balances),
+        OutputFormat::JsonLines => write_balances_json_lines(out, balances),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => crate::parquet_io::write_balances_parquet(out, balances),
+        OutputFormat::Table => write_balances_table(out, balances, amount_format, NumberLocale::default()),
+    }
+}
+
+/// Writes balances as a table with right-aligned columns, for people rather than programs.
+pub fn write_balances_table<'a, OUT, ITER>(
+    mut out: OUT,
+    balances: ITER,
+    format: AmountFormat,
+    locale: NumberLocale,
+) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    let mut rows = vec![["client", "available", "held", "total", "locked"].map(String::from)];
+    for balance in balances {
+        rows.push([
+            balance.client.to_string(),
+            locale.format(balance.available, format),
+            locale.format(balance.held, format),
+            locale.format(balance.total, format),
+            if balance.locked { "yes" } else { "no" }.to_string(),
+        ]);
+    }
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:>1$}", cell, width))
+            .collect();
+        writeln!(out, "{}", cells.join("  "))?;
+    }
+    Ok(())
+}
+
+pub fn write_balances_json<'a, OUT, ITER>(out: OUT, balances: ITER) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    sink_balances(&mut JsonBalanceSink::new(out)?, balances)
+}
+
+pub fn write_balances_json_lines<'a, OUT, ITER>(out: OUT, balances: ITER) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    sink_balances(&mut JsonLinesBalanceSink(out), balances)
+}
+
+/// The balance with amounts rounded to `AMOUNT_DECIMALS`, in currency units whatever the amount
+/// type, for outputs that write numbers as is.
+pub(crate) fn rounded(balance: &ClientBalance) -> ClientBalance<f64> {
+    let rounded = |amount: TxAmount| amount.round_dp(AMOUNT_DECIMALS).to_f64();
+    ClientBalance {
+        client: balance.client,
+        available: rounded(balance.available),
+        held: rounded(balance.held),
+        total: rounded(balance.total),
+        locked: balance.locked,
+        frozen: balance.frozen,
+        pending: rounded(balance.pending),
+    }
+}
+
+/// Serializes balances as `rounded` ones.
+pub(crate) fn serialize_rounded<S: Serializer>(balances: &[ClientBalance], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(balances.iter().map(rounded))
+}
+
+pub fn write_balances_csv<'a, OUT, ITER>(out: OUT, balances: ITER, format: AmountFormat) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    sink_balances(&mut CsvBalanceSink::new(out, format)?, balances)
+}
+
+/// Bytes of output buffered by default, see `OutputBuffer`.
+pub const DEFAULT_OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// Buffers the output to `inner`, so that it is written in chunks of up to `capacity` bytes
+/// instead of once per formatted value: stdout is line-buffered, and each write to it or to a
+/// socket is a syscall. With a `flush_interval`, the buffer is also written out and `inner`
+/// flushed once that long has passed since the last flush, so that whoever follows the output
+/// sees it progress. Flush once done: errors are lost if the buffer is only written out on drop.
+pub struct OutputBuffer<W: io::Write> {
+    inner: io::BufWriter<W>,
+    flush_interval: Option<Duration>,
+    flushed_at: Instant,
+}
+
+impl<W: io::Write> OutputBuffer<W> {
+    pub fn new(inner: W, capacity: usize, flush_interval: Option<Duration>) -> Self {
+        Self {
+            inner: io::BufWriter::with_capacity(capacity.max(1), inner),
+            flush_interval,
+            flushed_at: Instant::now(),
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for OutputBuffer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if self.flush_interval.is_some_and(|interval| self.flushed_at.elapsed() >= interval) {
+            self.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+
+    #[test]
+    fn test_write_balances_csv() -> GResult<()> {
+        let balances = vec![
+            ClientBalance {
+                client: 1,
+                available: amount(1.5),
+                held: amount(0.25),
+                total: amount(1.75),
+                locked: false,
+                frozen: false,
+                pending: amount(0.0),
+            },
+            ClientBalance {
+                client: 2,
+                available: amount(0.0),
+                held: amount(0.0),
+                total: amount(0.0),
+                locked: true,
+                frozen: false,
+                pending: amount(0.0),
+            },
+        ];
+
+        let mut output = vec![];
+        write_balances_csv(&mut output, &balances, AmountFormat::Shortest)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5,0.25,1.75,false\n2,0,0,0,true\n"
+        );
+
+        let mut output = vec![];
+        write_balances_csv(&mut output, &balances, AmountFormat::Fixed(4))?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.2500,1.7500,false\n2,0.0000,0.0000,0.0000,true\n"
+        );
+
+        let mut output = vec![];
+        write_balances_csv(&mut output, &[], AmountFormat::Shortest)?;
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_balances_csv_columns() -> GResult<()> {
+        let balances = vec![ClientBalance {
+            client: 7,
+            available: amount(1.5),
+            held: amount(0.25),
+            total: amount(1.75),
+            locked: true,
+            frozen: false,
+            pending: amount(0.0),
+        }];
+        let columns = parse_columns("total=balance, client,status")?;
+
+        let mut output = vec![];
+        write_balances_csv_columns(&mut output, &balances, AmountFormat::Fixed(2), &columns)?;
+        assert_eq!(String::from_utf8(output).unwrap(), "balance,client,status\n1.75,7,locked\n");
+
+        assert!(parse_columns("client,owner").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_balances_table() -> GResult<()> {
+        let balances = vec![ClientBalance {
+            client: 12,
+            available: amount(1234567.5),
+            held: amount(0.0),
+            total: amount(-1234.5),
+            locked: true,
+            frozen: false,
+            pending: amount(0.0),
+        }];
+
+        let mut output = vec![];
+        write_balances_table(&mut output, &balances, AmountFormat::Fixed(2), NumberLocale::De)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client     available  held      total  locked\n    12  1.234.567,50  0,00  -1.234,50     yes\n"
+        );
+
+        assert_eq!(NumberLocale::En.format(amount(1234.5), AmountFormat::Shortest), "1,234.5");
+        assert_eq!(NumberLocale::Fr.format(amount(999.0), AmountFormat::Shortest), "999");
+        assert_eq!(NumberLocale::Fr.format(amount(1000.25), AmountFormat::Shortest), "1\u{202f}000,25");
+        assert_eq!(NumberLocale::Ch.format(amount(-100000.0), AmountFormat::Shortest), "-100'000");
+        assert_eq!("de".parse::<NumberLocale>().unwrap(), NumberLocale::De);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_balances_json() -> GResult<()> {
+        let balances = vec![
+            ClientBalance {
+                client: 1,
+                available: amount(1.5),
+                held: amount(0.25),
+                total: amount(1.75),
+                locked: false,
+                frozen: false,
+                pending: amount(0.0),
+            },
+            ClientBalance {
+                client: 2,
+                available: amount(0.0),
+                held: amount(0.0),
+                total: amount(0.0),
+                locked: true,
+                frozen: false,
+                pending: amount(0.0),
+            },
+        ];
+        let expected_1 = r#"{"client":1,"available":1.5,"held":0.25,"total":1.75,"locked":false}"#;
+        let expected_2 = r#"{"client":2,"available":0.0,"held":0.0,"total":0.0,"locked":true}"#;
+
+        let mut output = vec![];
+        write_balances(&mut output, &balances, OutputFormat::Json, AmountFormat::Shortest)?;
+        assert_eq!(String::from_utf8(output).unwrap(), format!("[{expected_1},{expected_2}]\n"));
+
+        let mut output = vec![];
+        write_balances(&mut output, &balances, OutputFormat::JsonLines, AmountFormat::Shortest)?;
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{expected_1}\n{expected_2}\n"));
+
+        assert_eq!("jsonl".parse::<OutputFormat>().unwrap(), OutputFormat::JsonLines);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "minor-units"))]
+    fn test_amount_rounding() -> GResult<()> {
+        let balances = vec![ClientBalance {
+            client: 1,
+            available: amount(0.1 + 0.2),
+            held: amount(-0.00001),
+            total: amount(0.30000999),
+            locked: false,
+            frozen: false,
+            pending: amount(0.0),
+        }];
+
+        assert_eq!(AmountFormat::Shortest.format(0.1 + 0.2), "0.3");
+        let mut output = vec![];
+        write_balances_csv(&mut output, &balances, AmountFormat::Shortest)?;
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,0.3,0,0.3,false\n");
+
+        let mut output = vec![];
+        write_balances(&mut output, &balances, OutputFormat::JsonLines, AmountFormat::Shortest)?;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"client\":1,\"available\":0.3,\"held\":0.0,\"total\":0.3,\"locked\":false}\n"
+        );
+        Ok(())
+    }
+
+    /// Counts the writes that reach it.
+    #[derive(Default)]
+    struct CountingWriter {
+        written: Vec<u8>,
+        writes: usize,
+    }
+
+    impl io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_buffer() -> GResult<()> {
+        use std::io::Write;
+
+        let balances: Vec<_> = (1..=1000).map(ClientBalance::new_empty).collect();
+        let mut expected = vec![];
+        write_balances_json_lines(&mut expected, &balances)?;
+
+        let mut inner = CountingWriter::default();
+        let mut out = OutputBuffer::new(&mut inner, 4096, None);
+        write_balances_json_lines(&mut out, &balances)?;
+        out.flush()?;
+        drop(out);
+        assert!(inner.written == expected);
+        // Rows are written when the next one doesn't fit, so each write is most of the buffer.
+        assert!(inner.writes <= expected.len() / 2048 + 1, "{} writes", inner.writes);
+
+        // Flushed on every write once the interval has passed.
+        let mut inner = CountingWriter::default();
+        let mut out = OutputBuffer::new(&mut inner, 4096, Some(Duration::ZERO));
+        out.write_all(b"1\n")?;
+        out.write_all(b"2\n")?;
+        drop(out);
+        assert_eq!((inner.written.as_slice(), inner.writes), (&b"1\n2\n"[..], 2));
+        Ok(())
+    }
+}
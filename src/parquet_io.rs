@@ -0,0 +1,192 @@
+//! Parquet input and output, so large transaction batches don't need converting to CSV first.
+//! Transactions are read from `type` (string), `client`, `tx` (integers) and `amount` (float,
+//! nullable) columns; integer and float columns are cast as needed.
+
+use crate::amount::Amount;
+use crate::error::TxProcessorError;
+use crate::model::{ClientBalance, Transaction, TxAmount, TxType};
+use crate::output::rounded;
+use crate::GResult;
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float64Type, UInt16Type, UInt32Type};
+use arrow_array::{Array, ArrayRef, BooleanArray, Float64Array, RecordBatch, UInt16Array};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+const BATCH_SIZE: usize = 8192;
+
+/// Streams the transactions of a Parquet file, one record batch at a time.
+pub struct ParquetTransactions {
+    reader: ParquetRecordBatchReader,
+    batch: Vec<Transaction>,
+}
+
+impl ParquetTransactions {
+    pub fn open(file: File) -> GResult<Self> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)?
+            .with_batch_size(BATCH_SIZE)
+            .build()?;
+        Ok(Self { reader, batch: vec![] })
+    }
+}
+
+impl Iterator for ParquetTransactions {
+    type Item = GResult<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.batch.is_empty() {
+            let batch = match self.reader.next()? {
+                Ok(batch) => batch,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match batch_transactions(&batch) {
+                Ok(mut transactions) => {
+                    transactions.reverse();
+                    self.batch = transactions;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.batch.pop().map(Ok)
+    }
+}
+
+fn column(batch: &RecordBatch, name: &'static str, data_type: &DataType) -> GResult<ArrayRef> {
+    let column = batch.column_by_name(name).ok_or_else(|| TxProcessorError::Parse {
+        field: name,
+        message: "missing column".to_string(),
+    })?;
+    Ok(arrow_cast::cast(column, data_type)?)
+}
+
+fn batch_transactions(batch: &RecordBatch) -> GResult<Vec<Transaction>> {
+    let types = column(batch, "type", &DataType::Utf8)?;
+    let types = types.as_string::<i32>();
+    let clients = column(batch, "client", &DataType::UInt16)?;
+    let clients = clients.as_primitive::<UInt16Type>();
+    let tx_ids = column(batch, "tx", &DataType::UInt32)?;
+    let tx_ids = tx_ids.as_primitive::<UInt32Type>();
+    let amounts = column(batch, "amount", &DataType::Float64)?;
+    let amounts = amounts.as_primitive::<Float64Type>();
+
+    let missing = |field| TxProcessorError::Parse {
+        field,
+        message: "missing value".to_string(),
+    };
+    (0..batch.num_rows())
+        .map(|row| {
+            let tx_type: TxType = types
+                .is_valid(row)
+                .then(|| types.value(row))
+                .ok_or_else(|| missing("type"))?
+                .trim()
+                .parse()
+                .map_err(|err: strum::ParseError| TxProcessorError::Parse {
+                    field: "type",
+                    message: err.to_string(),
+                })?;
+            Ok(Transaction::new(
+                tx_type,
+                clients.is_valid(row).then(|| clients.value(row)).ok_or_else(|| missing("client"))?,
+                tx_ids.is_valid(row).then(|| tx_ids.value(row)).ok_or_else(|| missing("tx"))?,
+                amounts.is_valid(row).then(|| TxAmount::from_f64(amounts.value(row))),
+            ))
+        })
+        .collect()
+}
+
+/// Writes the balances as a single Parquet file. Balances are bounded by the number of client ids,
+/// so the file is built in memory and then copied to `out`.
+pub fn write_balances_parquet<'a, OUT, ITER>(mut out: OUT, balances: ITER) -> GResult<()>
+where
+    OUT: io::Write,
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    let balances: Vec<_> = balances.into_iter().map(rounded).collect();
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", DataType::Float64, false),
+        Field::new("held", DataType::Float64, false),
+        Field::new("total", DataType::Float64, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]));
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt16Array::from_iter_values(balances.iter().map(|b| b.client))),
+        Arc::new(Float64Array::from_iter_values(balances.iter().map(|b| b.available.to_f64()))),
+        Arc::new(Float64Array::from_iter_values(balances.iter().map(|b| b.held.to_f64()))),
+        Arc::new(Float64Array::from_iter_values(balances.iter().map(|b| b.total.to_f64()))),
+        Arc::new(BooleanArray::from_iter(balances.iter().map(|b| Some(b.locked)))),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut buffer = vec![];
+    let mut writer = ArrowWriter::try_new(&mut buffer, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    out.write_all(&buffer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use arrow_array::{Int64Array, StringArray};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_read_transactions() -> GResult<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::Int64, false),
+            Field::new("tx", DataType::Int64, false),
+            Field::new("amount", DataType::Float64, true),
+        ]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![
+            Arc::new(StringArray::from(vec!["deposit", "dispute"])),
+            Arc::new(Int64Array::from(vec![1, 1])),
+            Arc::new(Int64Array::from(vec![10, 10])),
+            Arc::new(Float64Array::from(vec![Some(2.5), None])),
+        ])?;
+        let path = std::env::temp_dir().join("tx_processor_parquet_test.parquet");
+        let mut writer = ArrowWriter::try_new(File::create(&path)?, schema, None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+
+        let transactions = ParquetTransactions::open(File::open(&path)?)?.collect::<GResult<Vec<_>>>()?;
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].tx_type, TxType::Deposit);
+        assert_eq!((transactions[0].client, transactions[0].tx_id), (1, 10));
+        assert_eq!(transactions[0].amount, Some(amount(2.5)));
+        assert_eq!(transactions[1].tx_type, TxType::Dispute);
+        assert_eq!(transactions[1].amount, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_balances() -> GResult<()> {
+        let balances = vec![ClientBalance {
+            client: 7,
+            available: amount(1.5),
+            held: amount(0.5),
+            total: amount(2.0),
+            locked: true,
+            frozen: false,
+            pending: amount(0.0),
+        }];
+        let mut output = vec![];
+        write_balances_parquet(&mut output, &balances)?;
+
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(output))?.build()?;
+        let batch = reader.next().unwrap()?;
+        assert_eq!(batch.num_rows(), 1);
+        assert_eq!(batch.column_by_name("client").unwrap().as_primitive::<UInt16Type>().value(0), 7);
+        assert_eq!(batch.column_by_name("total").unwrap().as_primitive::<Float64Type>().value(0), 2.0);
+        assert!(batch.column_by_name("locked").unwrap().as_boolean().value(0));
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+//! Two-stage pipeline: transactions are read and parsed on a background thread, and handed over
+//! to the processing thread through a bounded channel, so that I/O and parsing overlap with
+//! processing.
+
+use crate::model::Transaction;
+use crate::GResult;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::{self, JoinHandle};
+
+/// Transactions are handed over in batches, to keep channel overhead low.
+const BATCH_SIZE: usize = 1024;
+/// Parsed batches waiting to be processed. Bounds memory when parsing is faster than processing.
+const QUEUED_BATCHES: usize = 16;
+
+type Batch = Vec<GResult<Transaction>>;
+
+/// Runs `open`, and the iterator it returns, on a background thread. Parsing stops at the first
+/// error, which is yielded like any other item.
+pub fn parse_in_background<F, ITER>(open: F) -> BackgroundTransactions
+where
+    F: FnOnce() -> GResult<ITER> + Send + 'static,
+    ITER: Iterator<Item = GResult<Transaction>>,
+{
+    let (sender, receiver) = sync_channel::<Batch>(QUEUED_BATCHES);
+    let parser = thread::Builder::new()
+        .name("tx-parser".to_string())
+        .spawn(move || {
+            let transactions = match open() {
+                Ok(transactions) => transactions,
+                Err(err) => {
+                    let _ = sender.send(vec![Err(err)]);
+                    return;
+                }
+            };
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            for tx in transactions {
+                let failed = tx.is_err();
+                batch.push(tx);
+                if failed || batch.len() == BATCH_SIZE {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                    // The receiver is gone if processing stopped, no point parsing the rest.
+                    if sender.send(full).is_err() || failed {
+                        return;
+                    }
+                }
+            }
+            let _ = sender.send(batch);
+        });
+
+    match parser {
+        Ok(parser) => BackgroundTransactions {
+            receiver,
+            batch: vec![].into_iter(),
+            parser: Some(parser),
+        },
+        // The sender was dropped with the closure, so this only yields the error.
+        Err(err) => BackgroundTransactions {
+            receiver,
+            batch: vec![Err(err.into())].into_iter(),
+            parser: None,
+        },
+    }
+}
+
+/// Transactions parsed on a background thread, see `parse_in_background`.
+pub struct BackgroundTransactions {
+    receiver: Receiver<Batch>,
+    batch: std::vec::IntoIter<GResult<Transaction>>,
+    parser: Option<JoinHandle<()>>,
+}
+
+impl Iterator for BackgroundTransactions {
+    type Item = GResult<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(tx) = self.batch.next() {
+                return Some(tx);
+            }
+            match self.receiver.recv() {
+                Ok(batch) => self.batch = batch.into_iter(),
+                Err(_) => {
+                    // The parser is done. Check it didn't stop because of a panic, which would
+                    // otherwise look like the end of the input.
+                    let parser = self.parser.take()?;
+                    return parser
+                        .join()
+                        .err()
+                        .map(|_| Err(std::io::Error::other("transaction parser thread panicked").into()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TxProcessorError;
+    use crate::read_transactions_file;
+
+    #[test]
+    fn test_parse_in_background() -> GResult<()> {
+        let expected: Vec<_> = read_transactions_file("tests/example.csv")?.collect::<GResult<_>>()?;
+        let parsed: Vec<_> =
+            parse_in_background(|| read_transactions_file("tests/example.csv")).collect::<GResult<_>>()?;
+        assert_eq!(parsed, expected);
+
+        let mut failed = parse_in_background(|| read_transactions_file("tests/missing.csv"));
+        assert!(matches!(failed.next(), Some(Err(TxProcessorError::Io(_)))));
+        assert!(failed.next().is_none());
+
+        let mut panicked = parse_in_background(|| -> GResult<std::vec::IntoIter<_>> { panic!("parser bug") });
+        assert!(matches!(panicked.next(), Some(Err(TxProcessorError::Io(_)))));
+        assert!(panicked.next().is_none());
+        Ok(())
+    }
+}
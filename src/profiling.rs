@@ -0,0 +1,181 @@
+//! Instrumentation of a run (`profiling` feature): the time spent in each phase of
+//! `process_transactions_into`, and the allocations made meanwhile, reported in
+//! `ProcessReport::profile`. It locates hotspots where an external profiler can't be used.
+//!
+//! Phases are parsing (taking transactions from the input iterator), applying them (with the
+//! per-transaction reports and checkpoints) and output (balances, history, ledger and exports).
+//! Allocations are only counted when the program uses `CountingAllocator` as its global
+//! allocator, as the `tx_processor` binary does with this feature. They are counted across all
+//! threads, so with background parsing (see `pipeline`) the parser's allocations fall into
+//! whichever phase is running, and the parse time is the time spent waiting for it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::{self, Display, Formatter};
+use std::ops::{AddAssign, Sub};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting allocations and the bytes allocated. To be installed with
+/// `#[global_allocator]`.
+pub struct CountingAllocator;
+
+fn count(bytes: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+// SAFETY: all calls go to the system allocator, counting doesn't allocate.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Time and allocations of a phase, over a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PhaseProfile {
+    pub elapsed: Duration,
+    /// Allocations and reallocations.
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+}
+
+impl AddAssign for PhaseProfile {
+    fn add_assign(&mut self, other: Self) {
+        self.elapsed += other.elapsed;
+        self.allocations += other.allocations;
+        self.allocated_bytes += other.allocated_bytes;
+    }
+}
+
+impl Sub for PhaseProfile {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            elapsed: self.elapsed.saturating_sub(other.elapsed),
+            allocations: self.allocations.saturating_sub(other.allocations),
+            allocated_bytes: self.allocated_bytes.saturating_sub(other.allocated_bytes),
+        }
+    }
+}
+
+impl Display for PhaseProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3}s, {} allocations ({} bytes)",
+            self.elapsed.as_secs_f64(),
+            self.allocations,
+            self.allocated_bytes
+        )
+    }
+}
+
+/// Time and allocations of each phase of a run, see the module docs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RunProfile {
+    pub parse: PhaseProfile,
+    pub apply: PhaseProfile,
+    pub output: PhaseProfile,
+}
+
+impl Display for RunProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "parse: {}; apply: {}; output: {}", self.parse, self.apply, self.output)
+    }
+}
+
+/// The start of a measurement.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Mark {
+    at: Instant,
+    allocations: u64,
+    allocated_bytes: u64,
+}
+
+impl Mark {
+    pub(crate) fn now() -> Self {
+        Self {
+            at: Instant::now(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    /// What was spent since the mark.
+    pub(crate) fn elapsed(&self) -> PhaseProfile {
+        PhaseProfile {
+            elapsed: self.at.elapsed(),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed) - self.allocations,
+            allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed) - self.allocated_bytes,
+        }
+    }
+}
+
+/// Adds what taking each item of `inner` costs to `phase`.
+pub(crate) struct Profiled<'a, ITER> {
+    inner: ITER,
+    phase: &'a mut PhaseProfile,
+}
+
+impl<'a, ITER> Profiled<'a, ITER> {
+    pub(crate) fn new(inner: ITER, phase: &'a mut PhaseProfile) -> Self {
+        Self { inner, phase }
+    }
+}
+
+impl<ITER: Iterator> Iterator for Profiled<'_, ITER> {
+    type Item = ITER::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mark = Mark::now();
+        let item = self.inner.next();
+        *self.phase += mark.elapsed();
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_profiled() {
+        let mut phase = PhaseProfile::default();
+        let items: Vec<_> = Profiled::new((0..3).map(|n| vec![n; 100]), &mut phase).collect();
+        assert_eq!(items.len(), 3);
+        assert!(phase.elapsed > Duration::ZERO);
+        // Other tests may allocate meanwhile.
+        assert!(phase.allocations >= 3 && phase.allocated_bytes >= 3 * 400, "{phase:?}");
+
+        let spent = PhaseProfile {
+            elapsed: Duration::from_millis(1500),
+            allocations: 3,
+            allocated_bytes: 120,
+        };
+        assert_eq!(spent - PhaseProfile { allocations: 5, ..spent }, PhaseProfile::default());
+        assert_eq!(spent.to_string(), "1.500s, 3 allocations (120 bytes)");
+    }
+}
@@ -0,0 +1,174 @@
+//! Reconciliation of the balances output of the processor against an external statement: a CSV of
+//! `client,expected balance` rows (with a header, of any names). The balances are read from the
+//! CSV output, with the default or custom columns, as long as it has the `client` and `total`
+//! columns.
+
+use crate::amount::Amount;
+use crate::error::TxProcessorError;
+use crate::model::{ClientId, TxAmount, AMOUNT_DECIMALS};
+use crate::GResult;
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconcileStatus {
+    Match,
+    /// The balance total differs from the statement, by `delta` (balance minus statement).
+    Mismatch { delta: TxAmount },
+    MissingFromStatement,
+    MissingFromBalances,
+}
+
+impl ReconcileStatus {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReconcileStatus::Match => "match",
+            ReconcileStatus::Mismatch { .. } => "mismatch",
+            ReconcileStatus::MissingFromStatement => "missing_from_statement",
+            ReconcileStatus::MissingFromBalances => "missing_from_balances",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconcileLine {
+    pub client: ClientId,
+    pub total: Option<TxAmount>,
+    pub expected: Option<TxAmount>,
+    pub status: ReconcileStatus,
+}
+
+/// Amounts are read as text and parsed with `FromStr`, the CSV reader would otherwise read the
+/// ones that look like numbers through `f64`.
+#[derive(serde::Deserialize)]
+struct BalanceRow {
+    client: ClientId,
+    total: String,
+}
+
+fn parse_amount(amount: &str, field: &'static str) -> GResult<TxAmount> {
+    amount.parse().map_err(|err| TxProcessorError::Parse {
+        field,
+        message: format!("`{amount}`: {err}"),
+    })
+}
+
+/// Reads the client totals of a CSV balances output.
+pub fn read_balance_totals<R: io::Read>(reader: R) -> GResult<BTreeMap<ClientId, TxAmount>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let mut totals = BTreeMap::new();
+    for row in reader.deserialize() {
+        let row: BalanceRow = row?;
+        totals.insert(row.client, parse_amount(&row.total, "total")?);
+    }
+    Ok(totals)
+}
+
+/// Reads a statement of `client,expected balance` rows.
+pub fn read_statement<R: io::Read>(reader: R) -> GResult<BTreeMap<ClientId, TxAmount>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let mut expected = BTreeMap::new();
+    for record in reader.deserialize() {
+        let (client, balance): (ClientId, String) = record?;
+        expected.insert(client, parse_amount(&balance, "expected balance")?);
+    }
+    Ok(expected)
+}
+
+/// Compares the totals with the statement, client by client. Amounts are compared to
+/// `AMOUNT_DECIMALS` places.
+pub fn reconcile(
+    totals: &BTreeMap<ClientId, TxAmount>,
+    statement: &BTreeMap<ClientId, TxAmount>,
+) -> Vec<ReconcileLine> {
+    let mut clients: Vec<_> = totals.keys().chain(statement.keys()).copied().collect();
+    clients.sort();
+    clients.dedup();
+    clients
+        .into_iter()
+        .map(|client| {
+            let total = totals.get(&client).copied();
+            let expected = statement.get(&client).copied();
+            let status = match (total, expected) {
+                (Some(total), Some(expected)) => {
+                    let delta = (total - expected).round_dp(AMOUNT_DECIMALS);
+                    if delta == TxAmount::default() {
+                        ReconcileStatus::Match
+                    } else {
+                        ReconcileStatus::Mismatch { delta }
+                    }
+                }
+                (Some(_), None) => ReconcileStatus::MissingFromStatement,
+                (None, _) => ReconcileStatus::MissingFromBalances,
+            };
+            ReconcileLine {
+                client,
+                total,
+                expected,
+                status,
+            }
+        })
+        .collect()
+}
+
+/// Reconciles the balances output at `balances_path` against the statement at `statement_path`.
+pub fn reconcile_files(balances_path: &str, statement_path: &str) -> GResult<Vec<ReconcileLine>> {
+    let totals = read_balance_totals(std::fs::File::open(balances_path)?)?;
+    let statement = read_statement(std::fs::File::open(statement_path)?)?;
+    Ok(reconcile(&totals, &statement))
+}
+
+/// Writes the reconciliation as CSV: `client,status,total,expected,delta`.
+pub fn write_reconciliation_csv<OUT: io::Write>(out: OUT, lines: &[ReconcileLine]) -> GResult<()> {
+    let amount = |amount: Option<TxAmount>| amount.map(|amount| amount.to_string()).unwrap_or_default();
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["client", "status", "total", "expected", "delta"])?;
+    for line in lines {
+        let delta = match line.status {
+            ReconcileStatus::Mismatch { delta } => Some(delta),
+            _ => None,
+        };
+        writer.write_record([
+            line.client.to_string(),
+            line.status.code().to_string(),
+            amount(line.total),
+            amount(line.expected),
+            amount(delta),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconcile() -> GResult<()> {
+        let balances = "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,0,80,80,false\n3,5,0,5,true\n";
+        let statement = "client, expected balance\n1, 1.50\n2, 75.25\n4, 10\n";
+        let lines = reconcile(&read_balance_totals(balances.as_bytes())?, &read_statement(statement.as_bytes())?);
+
+        let mut out = vec![];
+        write_reconciliation_csv(&mut out, &lines)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,status,total,expected,delta\n\
+             1,match,1.5,1.5,\n\
+             2,mismatch,80,75.25,4.75\n\
+             3,missing_from_statement,5,,\n\
+             4,missing_from_balances,,10,\n"
+        );
+
+        // Amounts beyond the precision of `f64` are compared exactly.
+        let balances = "client,total\n1,100000000000000.0001\n";
+        let statement = "client,expected\n1,100000000000000.0002\n";
+        let lines = reconcile(&read_balance_totals(balances.as_bytes())?, &read_statement(statement.as_bytes())?);
+        let delta = if cfg!(feature = "minor-units") { Some(-0.0001) } else { None };
+        let delta = delta.map(|delta| ReconcileStatus::Mismatch { delta: Amount::from_f64(delta) });
+        assert_eq!(lines[0].status, delta.unwrap_or(ReconcileStatus::Match));
+        assert!(read_statement("client,expected\n1,1.x\n".as_bytes()).is_err());
+        Ok(())
+    }
+}
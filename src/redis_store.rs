@@ -0,0 +1,631 @@
+//! Redis as the shared state of server instances, for horizontally scaled deployments where
+//! several instances ingest transactions. Requires the `redis` feature.
+//!
+//! Transaction records are kept in the `<prefix>:txs` hash, by transaction id (see `RedisStore`),
+//! and the balance of each client in a `<prefix>:balance:<client>` hash, with a `version` field
+//! counting its updates. `RedisSubmitter` loads the client's balance before each transaction, and
+//! once it is applied writes the new balance and the transaction records together, in a Redis
+//! transaction that only commits if no other instance updated the balance since it was loaded
+//! (optimistic locking, with `WATCH`). Otherwise the transaction fails with
+//! `TxProcessorError::Conflict`, the local state it changed is rolled back, and it can be
+//! submitted again. Notifications and audit records are held back until the commit, and dropped
+//! with a transaction that fails this way.
+//!
+//! Only balances and transaction records are shared: open disputes, holds, idempotency keys and
+//! the rest of the state stay with the instance that processed them. The transactions of a client
+//! should still go to one instance at a time (ie with clients partitioned over the instances), the
+//! locking catches those that don't.
+
+use crate::accounts::{AccountFilter, AccountPage};
+use crate::clearing::PendingDeposit;
+use crate::error::{RejectReason, TxProcessorError};
+use crate::holds::Hold;
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::server::{lock, TxSubmitter};
+use crate::settlement::OpenDispute;
+use crate::store::{decode_stored, encode_stored, StateMap, StoredTx, TxStore};
+use crate::tx_processor::{
+    AuditRecord, AuditSink, Notification, NotificationSink, ProcessorCounters, TxOutcome, TxProcessor,
+};
+use crate::GResult;
+use redis::{ConnectionLike, Pipeline};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Transaction records in Redis, shared by the instances using the same key prefix. Writes are
+/// buffered, until `RedisStore::commit_balance` writes them with the balance of the transaction
+/// that made them, or the store is flushed. Clones share the connection and the buffer.
+pub struct RedisStore<C = redis::Connection> {
+    shared: Arc<Mutex<Shared<C>>>,
+}
+
+struct Shared<C> {
+    connection: C,
+    prefix: String,
+    /// Records not written yet, `None` for removed ones.
+    writes: BTreeMap<TxId, Option<StoredTx>>,
+}
+
+impl<C> Clone for RedisStore<C> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `url`, ie `redis://host:6379/0`.
+    pub fn open(url: &str, prefix: &str) -> GResult<Self> {
+        let connection = redis::Client::open(url)?.get_connection()?;
+        Ok(Self::with_connection(connection, prefix))
+    }
+}
+
+impl<C: ConnectionLike> RedisStore<C> {
+    pub fn with_connection(connection: C, prefix: &str) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                connection,
+                prefix: prefix.to_string(),
+                writes: BTreeMap::new(),
+            })),
+        }
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared<C>> {
+        self.shared.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Loads the balance of `client`, if it has one, with its version (0 if it hasn't), and watches
+    /// it for `commit_balance`.
+    pub fn watch_balance(&self, client: ClientId) -> GResult<(Option<ClientBalance>, u64)> {
+        let shared = &mut *self.shared();
+        let key = balance_key(&shared.prefix, client);
+        redis::cmd("WATCH").arg(&key).query::<()>(&mut shared.connection)?;
+        let fields: HashMap<String, String> = redis::cmd("HGETALL").arg(&key).query(&mut shared.connection)?;
+        parse_balance(client, &fields)
+    }
+
+    /// Writes `balance` and the buffered records, if the balance of the client is still at
+    /// `version`. Returns whether it was, otherwise nothing is written and the records are dropped.
+    pub fn commit_balance(&self, balance: &ClientBalance, version: u64) -> GResult<bool> {
+        let shared = &mut *self.shared();
+        let writes = std::mem::take(&mut shared.writes);
+        let mut pipe = records_pipeline(&shared.prefix, writes);
+        pipe.atomic();
+        pipe.cmd("HSET")
+            .arg(balance_key(&shared.prefix, balance.client))
+            .arg(balance_fields(balance, version + 1))
+            .ignore();
+        // EXEC answers nil when a watched key changed.
+        let committed: Option<()> = pipe.query(&mut shared.connection)?;
+        Ok(committed.is_some())
+    }
+
+    /// Drops the buffered records and stops watching the balance, after a transaction that wasn't
+    /// applied.
+    pub fn discard(&self) -> GResult<()> {
+        let shared = &mut *self.shared();
+        shared.writes.clear();
+        redis::cmd("UNWATCH").query::<()>(&mut shared.connection)?;
+        Ok(())
+    }
+
+    fn stored_records(&self) -> GResult<Vec<(TxId, StoredTx)>> {
+        let shared = &mut *self.shared();
+        let records: HashMap<TxId, Vec<u8>> =
+            redis::cmd("HGETALL").arg(txs_key(&shared.prefix)).query(&mut shared.connection)?;
+        let mut records = records
+            .into_iter()
+            .map(|(tx_id, bytes)| Ok((tx_id, decode_record(tx_id, &bytes)?)))
+            .collect::<GResult<BTreeMap<_, _>>>()?;
+        for (tx_id, write) in &shared.writes {
+            match write {
+                Some(stored) => records.insert(*tx_id, *stored),
+                None => records.remove(tx_id),
+            };
+        }
+        Ok(records.into_iter().collect())
+    }
+}
+
+impl<C: ConnectionLike + Send> TxStore for RedisStore<C> {
+    fn get(&self, tx_id: TxId) -> GResult<Option<StoredTx>> {
+        let shared = &mut *self.shared();
+        if let Some(write) = shared.writes.get(&tx_id) {
+            return Ok(*write);
+        }
+        let bytes: Option<Vec<u8>> =
+            redis::cmd("HGET").arg(txs_key(&shared.prefix)).arg(tx_id).query(&mut shared.connection)?;
+        bytes.map(|bytes| decode_record(tx_id, &bytes)).transpose()
+    }
+
+    fn insert(&mut self, tx_id: TxId, stored: StoredTx) -> GResult<()> {
+        self.shared().writes.insert(tx_id, Some(stored));
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: TxId) -> GResult<()> {
+        self.shared().writes.insert(tx_id, None);
+        Ok(())
+    }
+
+    /// Reads the whole hash at once, it is meant for snapshots of moderate stores.
+    fn entries(&self) -> Box<dyn Iterator<Item = GResult<(TxId, StoredTx)>> + '_> {
+        match self.stored_records() {
+            Ok(records) => Box::new(records.into_iter().map(Ok)),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        let shared = &mut *self.shared();
+        if shared.writes.is_empty() {
+            return Ok(());
+        }
+        let writes = std::mem::take(&mut shared.writes);
+        records_pipeline(&shared.prefix, writes).query::<()>(&mut shared.connection)?;
+        Ok(())
+    }
+}
+
+fn txs_key(prefix: &str) -> String {
+    format!("{prefix}:txs")
+}
+
+fn balance_key(prefix: &str, client: ClientId) -> String {
+    format!("{prefix}:balance:{client}")
+}
+
+/// Writes and removes `writes` in the transaction records hash.
+fn records_pipeline(prefix: &str, writes: BTreeMap<TxId, Option<StoredTx>>) -> Pipeline {
+    let mut pipe = redis::pipe();
+    for (tx_id, write) in writes {
+        match write {
+            Some(stored) => pipe.cmd("HSET").arg(txs_key(prefix)).arg(tx_id).arg(encode_stored(stored)).ignore(),
+            None => pipe.cmd("HDEL").arg(txs_key(prefix)).arg(tx_id).ignore(),
+        };
+    }
+    pipe
+}
+
+fn decode_record(tx_id: TxId, bytes: &[u8]) -> GResult<StoredTx> {
+    // An amount and a direction, see `encode_stored`.
+    if bytes.len() < 9 {
+        return Err(TxProcessorError::Corrupted {
+            what: "Redis transaction record",
+            message: format!("transaction {tx_id} has {} bytes", bytes.len()),
+        });
+    }
+    Ok(decode_stored(bytes))
+}
+
+/// The fields of a balance hash. Amounts are written as decimal strings, which read back the
+/// same.
+fn balance_fields(balance: &ClientBalance, version: u64) -> Vec<(&'static str, String)> {
+    vec![
+        ("available", balance.available.to_string()),
+        ("held", balance.held.to_string()),
+        ("total", balance.total.to_string()),
+        ("pending", balance.pending.to_string()),
+        ("locked", u8::from(balance.locked).to_string()),
+        ("frozen", u8::from(balance.frozen).to_string()),
+        ("version", version.to_string()),
+    ]
+}
+
+fn parse_balance(client: ClientId, fields: &HashMap<String, String>) -> GResult<(Option<ClientBalance>, u64)> {
+    if fields.is_empty() {
+        return Ok((None, 0));
+    }
+    let amount = |name| parse_field::<TxAmount>(client, fields, name);
+    let flag = |name| parse_field::<u8>(client, fields, name).map(|value| value != 0);
+    let balance = ClientBalance {
+        client,
+        available: amount("available")?,
+        held: amount("held")?,
+        total: amount("total")?,
+        locked: flag("locked")?,
+        frozen: flag("frozen")?,
+        pending: amount("pending")?,
+    };
+    Ok((Some(balance), parse_field(client, fields, "version")?))
+}
+
+fn parse_field<T: FromStr>(client: ClientId, fields: &HashMap<String, String>, name: &str) -> GResult<T> {
+    let value = fields.get(name).map(String::as_str).unwrap_or_default();
+    value.parse().map_err(|_| TxProcessorError::Corrupted {
+        what: "Redis balance",
+        message: format!("client {client} has `{value}` as `{name}`"),
+    })
+}
+
+/// Processes transactions with a local processor, against the balances and transaction records
+/// in Redis, see the module docs.
+pub struct RedisSubmitter<C = redis::Connection> {
+    processor: Mutex<TxProcessor>,
+    store: RedisStore<C>,
+    outbox: Arc<Mutex<Outbox>>,
+}
+
+impl<C: ConnectionLike + Send + 'static> RedisSubmitter<C> {
+    /// Keeps the transaction records of `processor` in `store`. Holds that expire, deposits that
+    /// clear after a period and dispute windows are not supported: they change the state of other
+    /// transactions than the one processed, which a conflict can't roll back.
+    pub fn new(mut processor: TxProcessor, store: RedisStore<C>) -> GResult<Self> {
+        let config = &processor.config;
+        if config.hold_expiry.is_some() || config.clearing_period.is_some() || config.dispute_window.is_some() {
+            return Err(TxProcessorError::Parse {
+                field: "redis",
+                message: "hold expiry, clearing periods and dispute windows are not supported with Redis".to_string(),
+            });
+        }
+        processor.account_transactions = Box::new(store.clone());
+        let (notifier, audit) = (processor.notifier.take(), processor.audit.take());
+        let balance_changes = notifier.as_ref().is_some_and(|notifier| notifier.balance_changes());
+        let (notified, audited) = (notifier.is_some(), audit.is_some());
+        let outbox = Arc::new(Mutex::new(Outbox {
+            notifier,
+            audit,
+            notifications: Vec::new(),
+            records: Vec::new(),
+        }));
+        let deferred = || Deferred {
+            outbox: Arc::clone(&outbox),
+            balance_changes,
+        };
+        if notified {
+            processor.notifier = Some(Box::new(deferred()));
+        }
+        if audited {
+            processor.audit = Some(Box::new(deferred()));
+        }
+        Ok(Self {
+            processor: Mutex::new(processor),
+            store,
+            outbox,
+        })
+    }
+
+    pub fn into_processor(self) -> TxProcessor {
+        let mut processor = self.processor.into_inner().unwrap_or_else(PoisonError::into_inner);
+        let mut outbox = lock_outbox(&self.outbox);
+        processor.notifier = outbox.notifier.take();
+        processor.audit = outbox.audit.take();
+        processor
+    }
+}
+
+/// Balances are listed as this instance last saw them, for the clients it processed. Settlement
+/// markers are rejected, a settlement of the balances of one instance would mean little.
+impl<C: ConnectionLike + Send + 'static> TxSubmitter for RedisSubmitter<C> {
+    fn submit(&self, tx: &mut Transaction) -> GResult<TxOutcome> {
+        if tx.tx_type == TxType::Settle {
+            let reason = "settlement markers are not supported with Redis".to_string();
+            return Ok(TxOutcome::Rejected(RejectReason::Invalid(reason)));
+        }
+        let mut processor = lock(&self.processor);
+        let (balance, version) = self.store.watch_balance(tx.client)?;
+        match balance {
+            Some(balance) => processor.clients_balance.insert(tx.client, balance),
+            None => processor.clients_balance.remove(&tx.client),
+        };
+        let checkpoint = Checkpoint::new(&processor, tx);
+        let outcome = processor.process_transaction(tx);
+        let committed = match (&outcome, processor.clients_balance.get(&tx.client)) {
+            (Ok(TxOutcome::Applied), Some(balance)) => self.store.commit_balance(balance, version),
+            // Nothing to write, though what wasn't applied is still notified and audited.
+            (Ok(_), _) => self.store.discard().map(|()| true),
+            (Err(_), _) => self.store.discard().map(|()| false),
+        };
+        if !matches!(committed, Ok(true)) {
+            checkpoint.restore(&mut processor, tx);
+            lock_outbox(&self.outbox).discard();
+        }
+        match committed? {
+            true => {
+                lock_outbox(&self.outbox).deliver()?;
+                outcome
+            }
+            false => outcome.and(Err(TxProcessorError::Conflict(tx.client))),
+        }
+    }
+
+    fn sorted_balances(&self) -> Vec<ClientBalance> {
+        let mut balances: Vec<_> = lock(&self.processor).balances().cloned().collect();
+        balances.sort_by_key(|balance| balance.client);
+        balances
+    }
+
+    fn account_page(&self, after: Option<ClientId>, limit: usize, filter: &AccountFilter) -> AccountPage {
+        lock(&self.processor).account_page(after, limit, filter)
+    }
+}
+
+/// What processing a transaction may change in the local processor, other than in Redis, to
+/// restore it when the transaction isn't committed. The entries of the transaction id are enough,
+/// since nothing that changes other transactions is supported, see `RedisSubmitter::new`.
+struct Checkpoint {
+    counters: ProcessorCounters,
+    balance: Option<ClientBalance>,
+    idempotency_outcome: Option<TxOutcome>,
+    queued: usize,
+    history: usize,
+    ledger: usize,
+    hold: Option<Hold>,
+    pending_deposit: Option<PendingDeposit>,
+    capped_dispute: Option<TxAmount>,
+    refunded: Option<TxAmount>,
+    open_dispute: Option<OpenDispute>,
+}
+
+impl Checkpoint {
+    fn new(processor: &TxProcessor, tx: &Transaction) -> Self {
+        let key = tx.idempotency_key.as_ref();
+        Self {
+            counters: processor.counters.clone(),
+            balance: processor.clients_balance.get(&tx.client).cloned(),
+            idempotency_outcome: key.and_then(|key| processor.idempotency_outcomes.get(key)).cloned(),
+            queued: processor.locked_queue.get(&tx.client).map_or(0, Vec::len),
+            history: processor.history.get(&tx.client).map_or(0, Vec::len),
+            ledger: processor.ledger.len(),
+            hold: processor.holds.get(&tx.tx_id).cloned(),
+            pending_deposit: processor.pending_deposits.get(&tx.tx_id).cloned(),
+            capped_dispute: processor.capped_disputes.get(&tx.tx_id).copied(),
+            refunded: processor.refunded.get(&tx.tx_id).copied(),
+            open_dispute: processor.open_disputes.get(&tx.tx_id).cloned(),
+        }
+    }
+
+    fn restore(self, processor: &mut TxProcessor, tx: &Transaction) {
+        processor.counters = self.counters;
+        restore_entry(&mut processor.clients_balance, tx.client, self.balance);
+        if let Some(key) = &tx.idempotency_key {
+            restore_entry(&mut processor.idempotency_outcomes, key.clone(), self.idempotency_outcome);
+        }
+        truncate_entry(&mut processor.locked_queue, tx.client, self.queued);
+        truncate_entry(&mut processor.history, tx.client, self.history);
+        processor.ledger.truncate(self.ledger);
+        restore_entry(&mut processor.holds, tx.tx_id, self.hold);
+        restore_entry(&mut processor.pending_deposits, tx.tx_id, self.pending_deposit);
+        restore_entry(&mut processor.capped_disputes, tx.tx_id, self.capped_dispute);
+        restore_entry(&mut processor.refunded, tx.tx_id, self.refunded);
+        restore_entry(&mut processor.open_disputes, tx.tx_id, self.open_dispute);
+    }
+}
+
+fn restore_entry<K, V>(map: &mut impl StateMap<K, V>, key: K, value: Option<V>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+fn truncate_entry<K, V>(map: &mut impl StateMap<K, Vec<V>>, key: K, len: usize) {
+    match len {
+        0 => drop(map.remove(&key)),
+        _ => map.get_mut(&key).into_iter().for_each(|entries| entries.truncate(len)),
+    }
+}
+
+/// The notifier and audit trail of a `RedisSubmitter`'s processor, with what the transaction
+/// being submitted sent to them, until it is committed.
+struct Outbox {
+    notifier: Option<Box<dyn NotificationSink>>,
+    audit: Option<Box<dyn AuditSink>>,
+    notifications: Vec<Notification>,
+    records: Vec<AuditRecord>,
+}
+
+impl Outbox {
+    fn deliver(&mut self) -> GResult<()> {
+        if let Some(notifier) = &mut self.notifier {
+            self.notifications.drain(..).try_for_each(|notification| notifier.notify(&notification))?;
+        }
+        if let Some(audit) = &mut self.audit {
+            self.records.drain(..).try_for_each(|record| audit.append(&record))?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self) {
+        self.notifications.clear();
+        self.records.clear();
+    }
+}
+
+fn lock_outbox(outbox: &Mutex<Outbox>) -> MutexGuard<'_, Outbox> {
+    outbox.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Stands in for the processor's notifier and audit trail, keeping what is sent in the `Outbox`.
+struct Deferred {
+    outbox: Arc<Mutex<Outbox>>,
+    balance_changes: bool,
+}
+
+impl NotificationSink for Deferred {
+    fn notify(&mut self, notification: &Notification) -> GResult<()> {
+        lock_outbox(&self.outbox).notifications.push(notification.clone());
+        Ok(())
+    }
+
+    fn balance_changes(&self) -> bool {
+        self.balance_changes
+    }
+}
+
+impl AuditSink for Deferred {
+    fn append(&mut self, record: &AuditRecord) -> GResult<()> {
+        lock_outbox(&self.outbox).records.push(record.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        lock_outbox(&self.outbox).audit.as_mut().map_or(Ok(()), |audit| audit.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Direction;
+    use crate::test_support::{amount, deposit, dispute, with_key, withdrawal};
+    use redis::Value;
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    fn fields(balance: &ClientBalance, version: u64) -> Value {
+        let fields = balance_fields(balance, version).into_iter();
+        Value::Array(fields.flat_map(|(name, value)| [name.into(), value.into_bytes()]).map(Value::BulkString).collect())
+    }
+
+    fn watch(client: ClientId, balance: Value) -> [MockCmd; 2] {
+        let key = balance_key("tx", client);
+        [MockCmd::new(redis::cmd("WATCH").arg(&key), Ok("OK")), MockCmd::new(redis::cmd("HGETALL").arg(&key), Ok(balance))]
+    }
+
+    /// The commit of `balance` and `writes`, which fails if `committed` is false.
+    fn commit(writes: &[(TxId, StoredTx)], balance: &ClientBalance, version: u64, committed: bool) -> MockCmd {
+        let mut pipe = records_pipeline("tx", writes.iter().map(|(tx_id, stored)| (*tx_id, Some(*stored))).collect());
+        pipe.atomic();
+        pipe.cmd("HSET").arg(balance_key("tx", balance.client)).arg(balance_fields(balance, version)).ignore();
+        let exec = match committed {
+            true => Value::Array(vec![Value::Int(1); writes.len() + 1]),
+            false => Value::Nil,
+        };
+        MockCmd::with_values(pipe, Ok(vec![exec]))
+    }
+
+    fn balance(available: f64, held: f64) -> ClientBalance {
+        ClientBalance {
+            available: amount(available),
+            held: amount(held),
+            total: amount(available + held),
+            ..ClientBalance::new_empty(1)
+        }
+    }
+
+    #[test]
+    fn test_submitter() -> GResult<()> {
+        let deposited = StoredTx {
+            amount: amount(10.0),
+            direction: Direction::Deposit,
+            client: Some(1),
+        };
+        // A new client.
+        let mut commands = Vec::from(watch(1, Value::Array(vec![])));
+        commands.push(commit(&[(1, deposited)], &balance(10.0, 0.0), 1, true));
+        // Another instance credited 5 since, and this one is rejected.
+        commands.extend(watch(1, fields(&balance(15.0, 0.0), 2)));
+        commands.push(MockCmd::new(redis::cmd("UNWATCH"), Ok("OK")));
+        // The deposit is read back from Redis, and the balance changes before the commit.
+        commands.extend(watch(1, fields(&balance(15.0, 0.0), 2)));
+        commands.push(MockCmd::new(redis::cmd("HGET").arg("tx:txs").arg(7), Ok(encode_stored(deposited))));
+        commands.push(commit(&[], &balance(5.0, 10.0), 3, false));
+        let connection = MockRedisConnection::new(commands);
+        let submitter = RedisSubmitter::new(TxProcessor::new(), RedisStore::with_connection(connection, "tx"))?;
+
+        assert_eq!(submitter.submit(&mut deposit(1, 1, 10.0))?, TxOutcome::Applied);
+        let outcome = submitter.submit(&mut withdrawal(1, 2, 20.0))?;
+        assert!(matches!(outcome, TxOutcome::Rejected(_)), "{outcome}");
+        assert_eq!(submitter.sorted_balances(), vec![balance(15.0, 0.0)]);
+        let err = submitter.submit(&mut dispute(1, 7)).unwrap_err();
+        assert!(matches!(err, TxProcessorError::Conflict(1)), "{err}");
+        Ok(())
+    }
+
+    /// Ids of the transactions whose balance changes were notified.
+    #[derive(Default, Clone)]
+    struct Notified(Arc<Mutex<Vec<TxId>>>);
+
+    impl NotificationSink for Notified {
+        fn notify(&mut self, notification: &Notification) -> GResult<()> {
+            self.0.lock().unwrap().push(notification.tx_id);
+            Ok(())
+        }
+
+        fn balance_changes(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_resubmit_after_conflict() -> GResult<()> {
+        let deposited = StoredTx {
+            amount: amount(10.0),
+            direction: Direction::Deposit,
+            client: Some(1),
+        };
+        let read_deposit = || MockCmd::new(redis::cmd("HGET").arg("tx:txs").arg(7), Ok(encode_stored(deposited)));
+        let mut commands = Vec::from(watch(1, fields(&balance(15.0, 0.0), 2)));
+        commands.push(read_deposit());
+        commands.push(commit(&[], &balance(5.0, 10.0), 3, false));
+        // Another instance credited 5 more, and the dispute goes through this time.
+        commands.extend(watch(1, fields(&balance(20.0, 0.0), 3)));
+        commands.push(read_deposit());
+        commands.push(commit(&[], &balance(10.0, 10.0), 4, true));
+        let connection = MockRedisConnection::new(commands);
+        let notified = Notified::default();
+        let processor = TxProcessor::builder().notifier(notified.clone()).build();
+        let submitter = RedisSubmitter::new(processor, RedisStore::with_connection(connection, "tx"))?;
+
+        let err = submitter.submit(&mut with_key(dispute(1, 7), "d7")).unwrap_err();
+        assert!(matches!(err, TxProcessorError::Conflict(1)), "{err}");
+        {
+            let processor = lock(&submitter.processor);
+            assert!(processor.open_disputes.is_empty());
+            assert!(processor.idempotency_outcomes.is_empty());
+            assert_eq!(processor.counters.applied, 0);
+        }
+        assert_eq!(submitter.sorted_balances(), vec![balance(15.0, 0.0)]);
+        assert!(notified.0.lock().unwrap().is_empty());
+
+        // Not the outcome of the failed attempt, which would be returned for the key otherwise.
+        assert_eq!(submitter.submit(&mut with_key(dispute(1, 7), "d7"))?, TxOutcome::Applied);
+        assert_eq!(submitter.sorted_balances(), vec![balance(10.0, 10.0)]);
+        assert_eq!(*notified.0.lock().unwrap(), vec![7]);
+        let processor = submitter.into_processor();
+        assert!(processor.open_disputes.contains_key(&7));
+        assert_eq!(processor.counters.applied, 1);
+        assert!(processor.notifier.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_store() -> GResult<()> {
+        let stored = StoredTx {
+            amount: amount(0.25),
+            direction: Direction::Withdrawal,
+            client: None,
+        };
+        let records = Value::Array(vec![
+            Value::BulkString(b"3".to_vec()),
+            Value::BulkString(encode_stored(stored)),
+            Value::BulkString(b"4".to_vec()),
+            Value::BulkString(encode_stored(stored)),
+        ]);
+        let flushed = records_pipeline("tx", BTreeMap::from([(4, None), (5, Some(stored))]));
+        let connection = MockRedisConnection::new([
+            MockCmd::new(redis::cmd("HGETALL").arg("tx:txs"), Ok(records)),
+            MockCmd::with_values(flushed, Ok(vec![Value::Int(1), Value::Int(1)])),
+            MockCmd::new(redis::cmd("HGET").arg("tx:txs").arg(6), Ok(b"\x01".to_vec())),
+        ]);
+        let mut store = RedisStore::with_connection(connection, "tx");
+        // Writes are buffered until flushed.
+        store.insert(5, stored)?;
+        store.remove(4)?;
+        assert_eq!(store.get(5)?, Some(stored));
+        let entries = store.entries().collect::<GResult<Vec<_>>>()?;
+        assert_eq!(entries, vec![(3, stored), (5, stored)]);
+        store.flush()?;
+        assert!(store.shared().writes.is_empty());
+
+        let err = store.get(6).unwrap_err();
+        assert_eq!(err.to_string(), "corrupted Redis transaction record: transaction 6 has 1 bytes");
+        let fields = HashMap::from([("available".to_string(), "x".to_string())]);
+        assert!(parse_balance(1, &fields).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! Replays transactions against a running HTTP server (see `http_api`), at a target rate, and
+//! measures the latency of each submission. Used by the `tx-replayer` binary to load-test a
+//! deployment with production-shaped data.
+//!
+//! Only depends on the standard library, so it builds without the `http` feature: requests are
+//! plain HTTP/1.1 over a kept-alive connection.
+
+use crate::error::TxProcessorError;
+use crate::model::Transaction;
+use crate::simulation::Distribution;
+use crate::GResult;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// `host:port` of the HTTP server.
+    pub addr: String,
+    /// Target transactions per second, as fast as the server answers if not set.
+    pub rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    pub sent: u64,
+    /// Submissions answered with a 2xx status.
+    pub accepted: u64,
+    /// Submissions answered with 409 or 422, see `http_api`.
+    pub rejected: u64,
+    /// Submissions answered with any other status.
+    pub failed: u64,
+    pub elapsed: Duration,
+    /// Latency of each submission, in milliseconds.
+    pub latency_ms: Distribution,
+}
+
+impl Display for ReplayReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let rate = self.sent as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+        writeln!(
+            f,
+            "sent: {} in {:.1}s ({rate:.0}/s), accepted: {}, rejected: {}, failed: {}",
+            self.sent,
+            self.elapsed.as_secs_f64(),
+            self.accepted,
+            self.rejected,
+            self.failed
+        )?;
+        write!(f, "latency (ms): {}", self.latency_ms)
+    }
+}
+
+/// Submits each transaction to `POST /transactions`, one at a time, paced to `config.rate`.
+pub fn replay_http<ITER>(transactions: ITER, config: &ReplayConfig) -> GResult<ReplayReport>
+where
+    ITER: Iterator<Item = GResult<Transaction>>,
+{
+    let mut connection = HttpConnection::open(&config.addr)?;
+    let interval = config.rate.map(|rate| Duration::from_secs_f64(1.0 / rate.max(1) as f64));
+    let (mut accepted, mut rejected, mut failed) = (0, 0, 0);
+    let mut latencies = vec![];
+    let start = Instant::now();
+
+    for (index, tx) in transactions.enumerate() {
+        if let Some(interval) = interval {
+            let next_send = start + interval.mul_f64(index as f64);
+            std::thread::sleep(next_send.saturating_duration_since(Instant::now()));
+        }
+        let body = serde_json::to_vec(&tx?)?;
+        let sent_at = Instant::now();
+        let status = connection.post("/transactions", &body)?;
+        latencies.push(sent_at.elapsed().as_secs_f64() * 1000.0);
+        match status {
+            200..=299 => accepted += 1,
+            409 | 422 => rejected += 1,
+            _ => failed += 1,
+        }
+    }
+
+    Ok(ReplayReport {
+        sent: latencies.len() as u64,
+        accepted,
+        rejected,
+        failed,
+        elapsed: start.elapsed(),
+        latency_ms: Distribution::from_samples(latencies),
+    })
+}
+
+/// A kept-alive HTTP/1.1 connection, reopened if the server closes it.
+struct HttpConnection {
+    addr: String,
+    reader: BufReader<TcpStream>,
+}
+
+impl HttpConnection {
+    fn open(addr: &str) -> GResult<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            addr: addr.to_string(),
+            reader: BufReader::new(stream),
+        })
+    }
+
+    /// Sends a JSON body, and returns the response status once the whole response is read.
+    fn post(&mut self, path: &str, body: &[u8]) -> GResult<u16> {
+        match self.try_post(path, body) {
+            Ok(status) => Ok(status),
+            // The server may have closed the idle connection, retry once on a new one.
+            Err(TxProcessorError::Io(_)) => {
+                *self = Self::open(&self.addr)?;
+                self.try_post(path, body)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn try_post(&mut self, path: &str, body: &[u8]) -> GResult<u16> {
+        let stream = self.reader.get_mut();
+        write!(
+            stream,
+            "POST {path} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            self.addr,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+
+        let invalid = |message: String| TxProcessorError::Parse { field: "response", message };
+        let mut status_line = String::new();
+        if self.reader.read_line(&mut status_line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid(format!("invalid status line `{}`", status_line.trim())))?;
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            self.reader.read_line(&mut header)?;
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().map_err(|_| invalid(format!("invalid header `{header}`")))?;
+                }
+            }
+        }
+        io::copy(&mut self.reader.by_ref().take(content_length), &mut io::sink())?;
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+    use std::net::TcpListener;
+
+    /// Answers withdrawals with 422 and everything else with 200, on a single connection.
+    fn fake_server(listener: TcpListener) -> GResult<u64> {
+        let (stream, _) = listener.accept()?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut requests = 0;
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 {
+                    return Ok(requests);
+                }
+                if let Some(length) = line.strip_prefix("Content-Length: ") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            requests += 1;
+            let status = match String::from_utf8(body).unwrap().contains("withdrawal") {
+                true => "422 Unprocessable Entity",
+                false => "200 OK",
+            };
+            write!(writer, "HTTP/1.1 {status}\r\nContent-Length: 2\r\n\r\n{{}}")?;
+        }
+    }
+
+    #[test]
+    fn test_replay_http() -> GResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let config = ReplayConfig {
+            addr: listener.local_addr()?.to_string(),
+            rate: Some(1000),
+        };
+        let server = std::thread::spawn(move || fake_server(listener));
+
+        let report = replay_http(read_transactions_file("tests/example.csv")?, &config)?;
+        assert_eq!((report.sent, report.accepted, report.rejected, report.failed), (5, 4, 1, 0));
+        // Paced at 1000/s, the last of 5 is sent at least 4ms after the first.
+        assert!(report.elapsed >= Duration::from_millis(4));
+        assert!(report.latency_ms.max >= report.latency_ms.p50);
+        assert!(report.to_string().starts_with("sent: 5 in "));
+        // All went over the one kept-alive connection.
+        assert_eq!(server.join().unwrap()?, 5);
+        Ok(())
+    }
+}
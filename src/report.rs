@@ -0,0 +1,171 @@
+//! Balances and dispute statistics rolled up per client group (parent entity, program...), from a
+//! mapping file of `client,group` rows.
+
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxType};
+use crate::tx_processor::TxOutcome;
+use crate::GResult;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+/// Group used for clients missing from the mapping file.
+pub const UNGROUPED: &str = "ungrouped";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisputeStats {
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+}
+
+impl DisputeStats {
+    /// Counts the transaction if it is an applied dispute, resolve or chargeback.
+    pub fn record(&mut self, tx: &Transaction, outcome: &TxOutcome) {
+        if !matches!(outcome, TxOutcome::Applied) {
+            return;
+        }
+        match tx.tx_type {
+            TxType::Dispute => self.disputes += 1,
+            TxType::Resolve => self.resolves += 1,
+            TxType::Chargeback => self.chargebacks += 1,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct GroupSummary {
+    pub group: String,
+    pub clients: u64,
+    pub locked_clients: u64,
+    pub available: TxAmount,
+    pub held: TxAmount,
+    pub total: TxAmount,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+}
+
+/// Reads a `client,group` CSV mapping (with header).
+pub fn read_group_mapping<R: io::Read>(reader: R) -> GResult<HashMap<ClientId, String>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let mut mapping = HashMap::new();
+    for record in reader.deserialize() {
+        let (client, group): (ClientId, String) = record?;
+        mapping.insert(client, group);
+    }
+    Ok(mapping)
+}
+
+/// Rolls up balances and dispute statistics per group, ordered by group name.
+pub fn group_summaries<'a, ITER>(
+    balances: ITER,
+    dispute_stats: &HashMap<ClientId, DisputeStats>,
+    mapping: &HashMap<ClientId, String>,
+) -> Vec<GroupSummary>
+where
+    ITER: IntoIterator<Item = &'a ClientBalance>,
+{
+    let mut summaries = BTreeMap::<&str, GroupSummary>::new();
+    for balance in balances {
+        let group = mapping.get(&balance.client).map_or(UNGROUPED, String::as_str);
+        let summary = summaries.entry(group).or_insert_with(|| GroupSummary {
+            group: group.to_string(),
+            ..Default::default()
+        });
+        summary.clients += 1;
+        summary.locked_clients += balance.locked as u64;
+        summary.available += balance.available;
+        summary.held += balance.held;
+        summary.total += balance.total;
+        if let Some(stats) = dispute_stats.get(&balance.client) {
+            summary.disputes += stats.disputes;
+            summary.resolves += stats.resolves;
+            summary.chargebacks += stats.chargebacks;
+        }
+    }
+    summaries.into_values().collect()
+}
+
+pub fn write_group_summaries_csv<OUT: io::Write>(out: OUT, summaries: &[GroupSummary]) -> GResult<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for summary in summaries {
+        writer.serialize(summary)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Processes the transactions file and writes the per-group report.
+pub fn report_by_group<OUT: io::Write>(path: &str, groups_path: &str, out: OUT) -> GResult<()> {
+    let mapping = read_group_mapping(std::fs::File::open(groups_path)?)?;
+    let mut dispute_stats = HashMap::<ClientId, DisputeStats>::new();
+    let tx_processor = crate::process_file_with(path, |tx, outcome| {
+        dispute_stats.entry(tx.client).or_default().record(tx, outcome);
+        Ok(())
+    })?;
+    let summaries = group_summaries(tx_processor.balances(), &dispute_stats, &mapping);
+    write_group_summaries_csv(out, &summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+
+    fn balance(client: ClientId, total: TxAmount, held: TxAmount, locked: bool) -> ClientBalance {
+        ClientBalance {
+            client,
+            available: total - held,
+            held,
+            total,
+            locked,
+            frozen: false,
+            pending: TxAmount::default(),
+        }
+    }
+
+    #[test]
+    fn test_group_summaries() -> GResult<()> {
+        let mapping = read_group_mapping("client, group\n1, acme\n2, acme\n3, globex\n".as_bytes())?;
+        let balances = vec![
+            balance(1, amount(100.0), amount(20.0), false),
+            balance(2, amount(50.0), amount(0.0), true),
+            balance(3, amount(10.0), amount(0.0), false),
+            balance(4, amount(5.0), amount(0.0), false),
+        ];
+        let dispute_stats = HashMap::from([
+            (1, DisputeStats { disputes: 2, resolves: 1, chargebacks: 0 }),
+            (2, DisputeStats { disputes: 1, resolves: 0, chargebacks: 1 }),
+        ]);
+
+        let summaries = group_summaries(&balances, &dispute_stats, &mapping);
+        assert_eq!(summaries, vec![
+            GroupSummary {
+                group: "acme".to_string(),
+                clients: 2,
+                locked_clients: 1,
+                available: amount(130.0),
+                held: amount(20.0),
+                total: amount(150.0),
+                disputes: 3,
+                resolves: 1,
+                chargebacks: 1,
+            },
+            GroupSummary {
+                group: "globex".to_string(),
+                clients: 1,
+                available: amount(10.0),
+                total: amount(10.0),
+                ..Default::default()
+            },
+            GroupSummary {
+                group: UNGROUPED.to_string(),
+                clients: 1,
+                available: amount(5.0),
+                total: amount(5.0),
+                ..Default::default()
+            },
+        ]);
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Periodic jobs run in server mode, defined in a schedule file with one job per line:
+//!
+//! ```text
+//! # every  job              arguments
+//! 1h       balances-report  /var/reports/balances.csv
+//! ```
+//!
+//! Empty lines and lines starting with `#` are ignored. Intervals use `soak::parse_duration`
+//! units (`30s`, `15m`, `1h`, `1d`).
+
+use crate::error::{ErrorHandler, TxProcessorError};
+use crate::output::{write_balances_csv, AmountFormat};
+use crate::server::{lock, DrainSignal, DRAIN_POLL_INTERVAL};
+use crate::soak::parse_duration;
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Job {
+    /// Writes all balances, ordered by client, as CSV to the path. The file is replaced
+    /// atomically, so readers never see a partial report.
+    BalancesReport { path: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledJob {
+    pub every: Duration,
+    pub job: Job,
+}
+
+pub fn parse_schedule(schedule: &str) -> GResult<Vec<ScheduledJob>> {
+    let mut jobs = vec![];
+    for line in schedule.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: String| TxProcessorError::Parse { field: "schedule", message };
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let job = match fields[1..] {
+            ["balances-report", path] => Job::BalancesReport { path: path.to_string() },
+            _ => return Err(invalid(format!("unknown job `{line}`"))),
+        };
+        let every = parse_duration(fields[0])?;
+        if every.is_zero() {
+            return Err(invalid(format!("job interval can't be zero in `{line}`")));
+        }
+        jobs.push(ScheduledJob { every, job });
+    }
+    Ok(jobs)
+}
+
+/// Runs each job every `every` on a background thread, until `drain` is signalled. A failing job
+/// is reported to `on_error`, and tried again on its next run.
+pub fn start_scheduler(
+    jobs: Vec<ScheduledJob>,
+    processor: Arc<Mutex<TxProcessor>>,
+    drain: DrainSignal,
+    on_error: ErrorHandler,
+) -> GResult<JoinHandle<()>> {
+    let scheduler = thread::Builder::new().name("tx-scheduler".to_string()).spawn(move || {
+        let start = Instant::now();
+        let mut next_runs: Vec<_> = jobs.iter().map(|job| start + job.every).collect();
+        while !drain.is_draining() {
+            let now = Instant::now();
+            for (job, next_run) in jobs.iter().zip(&mut next_runs) {
+                if now < *next_run {
+                    continue;
+                }
+                if let Err(err) = run_job(&job.job, &processor) {
+                    on_error(&format!("Scheduled job {:?} failed", job.job), &err);
+                }
+                // Runs missed while a job was slow are skipped, not caught up on.
+                while *next_run <= now {
+                    *next_run += job.every;
+                }
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+    })?;
+    Ok(scheduler)
+}
+
+pub fn run_job(job: &Job, processor: &Mutex<TxProcessor>) -> GResult<()> {
+    match job {
+        Job::BalancesReport { path } => {
+            let mut report = vec![];
+            {
+                let processor = lock(processor);
+                let mut balances: Vec<_> = processor.balances().collect();
+                balances.sort_by_key(|balance| balance.client);
+                write_balances_csv(&mut report, balances, AmountFormat::default())?;
+            }
+            let partial_path = format!("{path}.partial");
+            std::fs::write(&partial_path, report)?;
+            std::fs::rename(partial_path, path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Transaction, TxType};
+    use crate::test_support::{amount, collected_errors};
+
+    #[test]
+    fn test_parse_schedule() -> GResult<()> {
+        let jobs = parse_schedule("# comment\n\n 30m balances-report /tmp/balances.csv\n")?;
+        assert_eq!(
+            jobs,
+            vec![ScheduledJob {
+                every: Duration::from_secs(1800),
+                job: Job::BalancesReport {
+                    path: "/tmp/balances.csv".to_string()
+                },
+            }]
+        );
+        assert!(parse_schedule("1h compact").is_err());
+        assert!(parse_schedule("0s balances-report /tmp/balances.csv").is_err());
+        assert!(parse_schedule("soon balances-report /tmp/balances.csv").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduler() -> GResult<()> {
+        let path = std::env::temp_dir().join(format!("scheduled_balances_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let processor = Arc::new(Mutex::new(TxProcessor::new()));
+        lock(&processor).process_transaction(&mut Transaction::new(TxType::Deposit, 3, 1, Some(amount(2.5))))?;
+
+        let jobs = vec![ScheduledJob {
+            every: Duration::from_millis(50),
+            job: Job::BalancesReport { path: path.clone() },
+        }];
+        let drain = DrainSignal::default();
+        let (on_error, errors) = collected_errors();
+        let scheduler = start_scheduler(jobs, processor, drain.clone(), on_error)?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !std::path::Path::new(&path).exists() {
+            assert!(Instant::now() < deadline, "balances report was not written");
+            thread::sleep(Duration::from_millis(10));
+        }
+        drain.drain();
+        scheduler.join().unwrap();
+
+        let report = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(report, "client,available,held,total,locked\n3,2.5,0,2.5,false\n");
+        assert!(errors.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
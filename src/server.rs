@@ -0,0 +1,377 @@
+//! Long-lived TCP service: clients submit transactions one record per line, as CSV
+//! (`type,client,tx,amount[,idempotency_key]`, no header) or as a JSON object, and all
+//! connections update the same shared `TxProcessor`. A rejected transaction is answered on the
+//! connection with a `rejected,<tx>,<reason_code>` line, applied ones get no reply.
+//!
+//! Control lines:
+//! - `balances` writes the current balances back on the connection as CSV, followed by an empty
+//!   line. `balances limit=<n> [after=<client>] [locked=<bool>] [frozen=<bool>]` writes a page of
+//!   them instead (see `accounts`), followed by a `next,<cursor>` line if there are more, and the
+//!   empty line.
+//! - `drain` starts draining the server, see `DrainSignal`.
+//!
+//! Chargebacks and account locks can be notified to webhooks, see `webhooks`, and balance changes
+//! published to Kafka too, see `kafka::KafkaNotifier`.
+//!
+//! Transactions go to a `TxSubmitter`: a processor behind a single lock, a
+//! `shared::SharedTxProcessor` for connections of different clients to be processed in parallel,
+//! or a `redis_store::RedisSubmitter` for several instances to share their state in Redis. A
+//! transaction that another instance got in the way of is answered with a `conflict,<tx>` line,
+//! and can be submitted again.
+
+use crate::accounts::{AccountFilter, AccountPage};
+use crate::error::{ErrorHandler, TxProcessorError};
+use crate::model::{ClientBalance, ClientId, Transaction};
+use crate::shared::SharedTxProcessor;
+use crate::output::{write_balances_csv, AmountFormat, OutputBuffer, DEFAULT_OUTPUT_BUFFER};
+use crate::tx_processor::{TxOutcome, TxProcessor};
+use crate::input::{parse_csv_transaction, CsvColumns};
+use crate::GResult;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Connection threads only buffer a line at a time, so a small stack lets a server hold
+/// thousands of them.
+const CONNECTION_STACK_SIZE: usize = 256 * 1024;
+/// How often the accept loop and idle connections check whether the server is draining.
+pub(crate) const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const BALANCES_QUERY: &str = "balances";
+const DRAIN_COMMAND: &str = "drain";
+
+/// Once set, the server stops accepting connections and submissions, lets the transactions being
+/// applied finish, and `serve` returns. Clones share the same signal.
+#[derive(Debug, Clone, Default)]
+pub struct DrainSignal(Arc<AtomicBool>);
+
+impl DrainSignal {
+    pub fn drain(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// The underlying flag, ie to have it set by a signal handler.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.0)
+    }
+}
+
+/// What the server submits transactions to.
+pub trait TxSubmitter: Send + Sync {
+    fn submit(&self, tx: &mut Transaction) -> GResult<TxOutcome>;
+
+    /// Balances of all clients, sorted by client.
+    fn sorted_balances(&self) -> Vec<ClientBalance>;
+
+    /// A page of the balances, see `accounts::account_page`.
+    fn account_page(&self, after: Option<ClientId>, limit: usize, filter: &AccountFilter) -> AccountPage;
+}
+
+impl TxSubmitter for Mutex<TxProcessor> {
+    fn submit(&self, tx: &mut Transaction) -> GResult<TxOutcome> {
+        lock(self).process_transaction(tx)
+    }
+
+    fn sorted_balances(&self) -> Vec<ClientBalance> {
+        let mut balances: Vec<_> = lock(self).balances().cloned().collect();
+        balances.sort_by_key(|balance| balance.client);
+        balances
+    }
+
+    fn account_page(&self, after: Option<ClientId>, limit: usize, filter: &AccountFilter) -> AccountPage {
+        lock(self).account_page(after, limit, filter)
+    }
+}
+
+impl TxSubmitter for SharedTxProcessor {
+    fn submit(&self, tx: &mut Transaction) -> GResult<TxOutcome> {
+        self.process_transaction(tx)
+    }
+
+    fn sorted_balances(&self) -> Vec<ClientBalance> {
+        self.balances()
+    }
+
+    fn account_page(&self, after: Option<ClientId>, limit: usize, filter: &AccountFilter) -> AccountPage {
+        SharedTxProcessor::account_page(self, after, limit, filter)
+    }
+}
+
+/// Accepts connections on `listener`, handling each one on its own thread, until `drain` is
+/// signalled and all connections are done. Invalid lines, rejected transactions and failed
+/// connections are reported to `on_error`.
+pub fn serve<P: TxSubmitter + 'static>(
+    listener: TcpListener,
+    processor: Arc<P>,
+    drain: DrainSignal,
+    on_error: ErrorHandler,
+) -> GResult<()> {
+    // Non-blocking, so that the accept loop notices a drain.
+    listener.set_nonblocking(true)?;
+    let mut connections: Vec<JoinHandle<()>> = vec![];
+    while !drain.is_draining() {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                connections.retain(|connection| !connection.is_finished());
+                thread::sleep(DRAIN_POLL_INTERVAL);
+                continue;
+            }
+            Err(err) => {
+                on_error("Failed to accept connection", &err.into());
+                continue;
+            }
+        };
+        let processor = Arc::clone(&processor);
+        let drain = drain.clone();
+        let on_error = Arc::clone(&on_error);
+        let connection = thread::Builder::new()
+            .name("tx-connection".to_string())
+            .stack_size(CONNECTION_STACK_SIZE)
+            .spawn(move || {
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                if let Err(err) = handle_connection(stream, processor.as_ref(), &drain, on_error.as_ref()) {
+                    on_error(&format!("Connection {peer} closed"), &err);
+                }
+            })?;
+        connections.push(connection);
+    }
+
+    drop(listener);
+    for connection in connections {
+        let _ = connection.join();
+    }
+    Ok(())
+}
+
+fn handle_connection<P: TxSubmitter>(
+    stream: TcpStream,
+    processor: &P,
+    drain: &DrainSignal,
+    on_error: &(dyn Fn(&str, &TxProcessorError) + Send + Sync),
+) -> GResult<()> {
+    stream.set_nonblocking(false)?;
+    // Idle connections wake up regularly to notice a drain.
+    stream.set_read_timeout(Some(DRAIN_POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = vec![];
+    while !drain.is_draining() {
+        // On a timeout, what was read of the line so far stays in `line` for the next read.
+        let read = match reader.read_until(b'\n', &mut line) {
+            Ok(read) => read,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if read == 0 && line.is_empty() {
+            break;
+        }
+        match std::str::from_utf8(&line) {
+            Ok(text) => handle_line(text.trim(), processor, drain, &mut writer, on_error)?,
+            Err(err) => on_error(
+                "Invalid transaction line",
+                &TxProcessorError::Parse {
+                    field: "line",
+                    message: err.to_string(),
+                },
+            ),
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+fn handle_line<P: TxSubmitter>(
+    line: &str,
+    processor: &P,
+    drain: &DrainSignal,
+    writer: &mut TcpStream,
+    on_error: &(dyn Fn(&str, &TxProcessorError) + Send + Sync),
+) -> GResult<()> {
+    match line {
+        "" => {}
+        BALANCES_QUERY => write_balances(writer, &processor.sorted_balances(), None)?,
+        DRAIN_COMMAND => drain.drain(),
+        _ if line.starts_with("balances ") => match parse_balances_query(&line[BALANCES_QUERY.len()..]) {
+            Ok((after, limit, filter)) => {
+                let page = processor.account_page(after, limit, &filter);
+                write_balances(writer, &page.clients, page.next_cursor)?;
+            }
+            Err(err) => on_error(&format!("Invalid query `{line}`"), &err),
+        },
+        _ => {
+            // A bad line is reported and skipped, it shouldn't drop the rest of the connection.
+            let mut tx = match parse_transaction_line(line) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    on_error(&format!("Invalid transaction `{line}`"), &err);
+                    return Ok(());
+                }
+            };
+            let context = |tx: &Transaction| format!("Transaction {} {}", tx.tx_id, tx.tx_type);
+            let outcome = match processor.submit(&mut tx) {
+                Err(err @ TxProcessorError::Conflict(_)) => {
+                    on_error(&context(&tx), &err);
+                    writeln!(writer, "conflict,{}", tx.tx_id)?;
+                    return Ok(());
+                }
+                outcome => outcome?,
+            };
+            if let TxOutcome::Rejected(reason) = outcome {
+                writeln!(writer, "rejected,{},{}", tx.tx_id, reason.code())?;
+                on_error(&context(&tx), &reason.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a transaction submitted as a single CSV record or JSON object.
+pub fn parse_transaction_line(line: &str) -> GResult<Transaction> {
+    if line.starts_with('{') {
+        return Ok(serde_json::from_str(line)?);
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    reader.read_record(&mut record)?;
+    // A position is meaningless for a single line, errors should only name the field.
+    record.set_position(None);
+    parse_csv_transaction(&record, &CsvColumns::default())
+}
+
+/// Writes `balances` as CSV followed by an empty line, with the `cursor` of the next page if there
+/// is one. The balances were copied out of the processor, so that no lock is held while writing
+/// to a slow client, and they are written a chunk at a time as they are formatted.
+fn write_balances(writer: &mut TcpStream, balances: &[ClientBalance], cursor: Option<ClientId>) -> GResult<()> {
+    let mut out = OutputBuffer::new(writer, DEFAULT_OUTPUT_BUFFER, None);
+    write_balances_csv(&mut out, balances, AmountFormat::default())?;
+    if let Some(cursor) = cursor {
+        writeln!(out, "next,{cursor}")?;
+    }
+    writeln!(out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Parses the `key=value` arguments of a `balances` query for a page.
+fn parse_balances_query(args: &str) -> GResult<(Option<ClientId>, usize, AccountFilter)> {
+    let invalid = |message: String| TxProcessorError::Parse { field: "balances", message };
+    let (mut after, mut limit, mut filter) = (None, None, AccountFilter::default());
+    for arg in args.split_whitespace() {
+        let (key, value) = arg.split_once('=').ok_or_else(|| invalid(format!("expected `key=value`, found `{arg}`")))?;
+        match key {
+            "after" => after = Some(value.parse().map_err(|err| invalid(format!("invalid `after`: {err}")))?),
+            "limit" => limit = Some(value.parse().map_err(|err| invalid(format!("invalid `limit`: {err}")))?),
+            "locked" => filter.locked = Some(value.parse().map_err(|err| invalid(format!("invalid `locked`: {err}")))?),
+            "frozen" => filter.frozen = Some(value.parse().map_err(|err| invalid(format!("invalid `frozen`: {err}")))?),
+            _ => return Err(invalid(format!("unknown argument `{key}`"))),
+        }
+    }
+    let limit = limit.ok_or_else(|| invalid("a page needs a `limit`".to_string()))?;
+    Ok((after, limit, filter))
+}
+
+pub(crate) fn lock(processor: &Mutex<TxProcessor>) -> std::sync::MutexGuard<'_, TxProcessor> {
+    // A panicking connection thread can't leave the processor half-updated in a way the other
+    // connections can't continue from, so a poisoned lock is still used.
+    processor.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::TxType;
+    use crate::test_support::{amount, collected_errors};
+    use std::io::Read;
+
+    #[test]
+    fn test_parse_transaction_line() -> GResult<()> {
+        let tx = parse_transaction_line("deposit, 1, 2, 1.5")?;
+        assert_eq!((tx.tx_type, tx.client, tx.tx_id, tx.amount), (TxType::Deposit, 1, 2, Some(amount(1.5))));
+
+        let tx = parse_transaction_line(r#"{"type": "dispute", "client": 1, "tx": 2}"#)?;
+        assert_eq!((tx.tx_type, tx.client, tx.tx_id, tx.amount), (TxType::Dispute, 1, 2, None));
+
+        assert!(parse_transaction_line("deposit, x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() -> GResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let processor = Arc::new(Mutex::new(TxProcessor::new()));
+        let server_processor = Arc::clone(&processor);
+        let drain = DrainSignal::default();
+        let server_drain = drain.clone();
+        let (on_error, errors) = collected_errors();
+        let server = thread::spawn(move || serve(listener, server_processor, server_drain, on_error));
+
+        let clients: Vec<_> = (1..=4u16)
+            .map(|client| {
+                thread::spawn(move || -> GResult<()> {
+                    let mut stream = TcpStream::connect(addr)?;
+                    writeln!(stream, "deposit,{client},{client},10")?;
+                    writeln!(stream, "not a transaction")?;
+                    let tx = client + 100;
+                    writeln!(stream, r#"{{"type": "withdrawal", "client": {client}, "tx": {tx}, "amount": 4}}"#)?;
+                    Ok(())
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap()?;
+        }
+
+        // Connections are handled concurrently, wait until all of them have been processed.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while lock(&processor).counters.applied < 8 {
+            assert!(std::time::Instant::now() < deadline, "transactions were not all applied");
+            thread::yield_now();
+        }
+
+        let mut stream = TcpStream::connect(addr)?;
+        writeln!(stream, "withdrawal,4,900,100")?;
+        writeln!(stream, "balances")?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("rejected,900,insufficient_funds\nclient,available,held,total,locked\n1,6,0,6,false\n"));
+        assert!(response.ends_with("4,6,0,6,false\n\n"));
+
+        let mut stream = TcpStream::connect(addr)?;
+        writeln!(stream, "balances limit=2 after=1")?;
+        writeln!(stream, "balances limit=2 after=3 locked=false")?;
+        writeln!(stream, "balances after=3")?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let header = "client,available,held,total,locked";
+        assert_eq!(response, format!("{header}\n2,6,0,6,false\n3,6,0,6,false\nnext,3\n\n{header}\n4,6,0,6,false\n\n"));
+
+        // An idle connection doesn't hold up the drain.
+        let _idle = TcpStream::connect(addr)?;
+        let mut stream = TcpStream::connect(addr)?;
+        writeln!(stream, "drain")?;
+        server.join().unwrap()?;
+        assert!(drain.is_draining());
+        assert!(TcpStream::connect(addr).is_err());
+        // Bad lines and rejections are reported, without dropping their connection.
+        let errors = errors.lock().unwrap();
+        let invalid = errors.iter().filter(|error| error.starts_with("Invalid transaction `not a transaction`: "));
+        assert_eq!(invalid.count(), 4, "{errors:?}");
+        assert!(errors.contains(&"Transaction 900 withdrawal: not enough funds".to_string()), "{errors:?}");
+        assert!(errors.iter().any(|error| error.starts_with("Invalid query `balances after=3`: ")), "{errors:?}");
+        Ok(())
+    }
+}
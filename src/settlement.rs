@@ -0,0 +1,232 @@
+//! End-of-day settlement: a `settle` marker record in the input (its client and tx id are
+//! ignored), or a call to `TxProcessor::settle`, finalises the day:
+//!
+//! - authorization holds still open are released, authorizations don't carry over to the next day;
+//! - with `ProcessorConfig::dispute_timeout` set, disputes still open after that many further
+//!   transactions are closed, as `ProcessorConfig::dispute_timeout_action` says;
+//! - a `Settlement` reports what was done, along with the balance totals it left.
+//!
+//! Transactions closing disputes are applied directly: they are not journaled, audited nor
+//! recorded in the history, so replaying a journal settles the same way again. They are in the
+//! ledger, see `ledger`, and chargebacks are notified, see `NotificationSink`.
+
+use crate::amount::Amount;
+use crate::holds::Hold;
+use crate::model::{ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::store::{StateMap, Stores};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::io;
+use strum_macros::EnumString;
+
+/// How `settle` closes a timed-out dispute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum DisputeTimeoutAction {
+    /// The disputed funds go back to the client, as with a `resolve`.
+    #[default]
+    Resolve,
+    /// The disputed funds are taken and the account locked, as with a `chargeback`.
+    Chargeback,
+}
+
+/// A dispute not resolved or charged back yet, in `TxProcessor::open_disputes` by disputed
+/// transaction id.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OpenDispute {
+    pub client: ClientId,
+    /// Sequence number of the dispute.
+    pub opened_at: u64,
+}
+
+/// What a settlement did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settlement<A = TxAmount> {
+    /// Sequence number of the settlement, that of its marker record if it had one.
+    pub sequence: u64,
+    /// Holds that were released, by transaction id.
+    pub released_holds: Vec<(TxId, Hold<A>)>,
+    /// Disputes that timed out, by disputed transaction id, with how they were closed.
+    pub closed_disputes: Vec<(TxId, ClientId, DisputeTimeoutAction)>,
+    /// Totals of the balances of all clients once settled.
+    pub available: A,
+    pub held: A,
+    pub total: A,
+}
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Settles the day, see the module docs.
+    pub fn settle(&mut self) -> GResult<Settlement<A>> {
+        let mut released_holds: Vec<_> = self.holds.keys().copied().collect();
+        released_holds.sort();
+        let released_holds: Vec<_> = released_holds
+            .into_iter()
+            .map(|tx_id| (tx_id, self.holds.remove(&tx_id).expect("hold was just listed")))
+            .collect();
+        for (tx_id, hold) in &released_holds {
+            let before = self.ledger_balance(hold.client);
+            if let Some(balance) = self.clients_balance.get_mut(&hold.client) {
+                balance.resolve_funds(hold.amount, self.config.overflow_policy.unrejectable())?;
+            }
+            if let Some(before) = before {
+                self.post_ledger(TxType::Release, *tx_id, vec!["settlement".to_string()], &before);
+            }
+        }
+        // Expiries of released holds are skipped once due.
+
+        let mut closed_disputes = vec![];
+        if let Some(timeout) = self.config.dispute_timeout {
+            let action = self.config.dispute_timeout_action;
+            let mut timed_out: Vec<_> = self
+                .open_disputes
+                .iter()
+                .filter(|(_, dispute)| dispute.opened_at.saturating_add(timeout) < self.counters.sequence)
+                .map(|(tx_id, dispute)| (*tx_id, dispute.client))
+                .collect();
+            timed_out.sort();
+            for (tx_id, client) in timed_out {
+                let tx_type = match action {
+                    DisputeTimeoutAction::Resolve => TxType::Resolve,
+                    DisputeTimeoutAction::Chargeback => TxType::Chargeback,
+                };
+                let tx = Transaction::new(tx_type, client, tx_id, None);
+                let before = self.ledger_balance(client);
+                let locked_before = self.locked_before(client);
+                self.apply_transaction(&tx)?;
+                if let Some(before) = before {
+                    self.post_ledger(tx_type, tx_id, vec!["settlement".to_string()], &before);
+                }
+                self.notify_applied(&tx, locked_before)?;
+                closed_disputes.push((tx_id, client, action));
+            }
+        }
+
+        let mut settlement = Settlement {
+            sequence: self.counters.sequence,
+            released_holds,
+            closed_disputes,
+            available: A::default(),
+            held: A::default(),
+            total: A::default(),
+        };
+        for balance in self.clients_balance.values() {
+            settlement.available += balance.available;
+            settlement.held += balance.held;
+            settlement.total += balance.total;
+        }
+        Ok(settlement)
+    }
+}
+
+/// A CSV report of settlements: `sequence,event,client,tx,amount`. Each released hold is a
+/// `released_hold` row, each closed dispute a `resolved_dispute` or `charged_back_dispute` row,
+/// and each settlement ends with a `settled` row with the total of all balances.
+pub struct SettlementCsv<OUT: io::Write> {
+    writer: csv::Writer<OUT>,
+}
+
+impl<OUT: io::Write> SettlementCsv<OUT> {
+    pub fn new(out: OUT) -> GResult<Self> {
+        Self::with_header(out, true)
+    }
+
+    fn with_header(out: OUT, header: bool) -> GResult<Self> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+        if header {
+            writer.write_record(["sequence", "event", "client", "tx", "amount"])?;
+        }
+        Ok(Self { writer })
+    }
+
+    pub fn write<A: Amount>(&mut self, settlement: &Settlement<A>) -> GResult<()> {
+        let sequence = settlement.sequence.to_string();
+        for (tx_id, hold) in &settlement.released_holds {
+            let row = [&sequence, "released_hold", &hold.client.to_string(), &tx_id.to_string(), &hold.amount.to_string()];
+            self.writer.write_record(row)?;
+        }
+        for (tx_id, client, action) in &settlement.closed_disputes {
+            let event = match action {
+                DisputeTimeoutAction::Resolve => "resolved_dispute",
+                DisputeTimeoutAction::Chargeback => "charged_back_dispute",
+            };
+            self.writer.write_record([&sequence, event, &client.to_string(), &tx_id.to_string(), ""])?;
+        }
+        self.writer.write_record([&sequence, "settled", "", "", &settlement.total.to_string()])?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> GResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl SettlementCsv<std::fs::File> {
+    /// Creates the report file at `path`. With `append`, rows are added to an existing report.
+    pub fn create(path: &str, append: bool) -> GResult<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(append)
+            .write(true)
+            .truncate(!append)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        Self::with_header(file, is_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use crate::tx_processor::{ProcessorConfig, TxOutcome};
+
+    #[test]
+    fn test_settle() -> GResult<()> {
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            dispute_timeout: Some(2),
+            dispute_timeout_action: DisputeTimeoutAction::Chargeback,
+            ..Default::default()
+        });
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\ndeposit,2,2,50\ndispute,2,2,\nauthorize,1,3,30\ndeposit,1,4,10\ndispute,1,4,\n\
+            settle,0,0,\ndeposit,1,5,1\n";
+        let mut outcomes = vec![];
+        processor.process_input_with(crate::read_transactions_csv(input.as_bytes()), |tx, outcome| {
+            outcomes.push((tx.tx_type, outcome.clone()));
+            Ok(())
+        })?;
+        assert_eq!(outcomes[6], (TxType::Settle, TxOutcome::Applied));
+
+        // The hold is released, and only the older dispute timed out.
+        let settlement = &processor.settlements[0];
+        assert_eq!(settlement.sequence, 7);
+        let released: Vec<_> = settlement.released_holds.iter().map(|(tx_id, hold)| (*tx_id, hold.amount)).collect();
+        assert_eq!(released, vec![(3, amount(30.0))]);
+        assert_eq!(settlement.closed_disputes, vec![(2, 2, DisputeTimeoutAction::Chargeback)]);
+        assert_eq!((settlement.available, settlement.held, settlement.total), (amount(100.0), amount(10.0), amount(110.0)));
+        assert!(processor.balance_of(2).unwrap().locked);
+        assert_eq!(processor.open_disputes.keys().collect::<Vec<_>>(), vec![&4]);
+        // The marker doesn't make a client.
+        assert_eq!(processor.client_count(), 2);
+
+        // On demand, once the last dispute has timed out too.
+        processor.config.dispute_timeout_action = DisputeTimeoutAction::Resolve;
+        processor.process_transaction(&mut Transaction::new(TxType::Deposit, 1, 6, Some(amount(1.0))))?;
+        let settlement = processor.settle()?;
+        assert_eq!(settlement.closed_disputes, vec![(4, 1, DisputeTimeoutAction::Resolve)]);
+        assert_eq!(processor.balance_of(1).unwrap().available, amount(112.0));
+        assert!(processor.open_disputes.is_empty());
+
+        let mut out = vec![];
+        let mut report = SettlementCsv::new(&mut out)?;
+        report.write(&processor.settlements[0])?;
+        report.flush()?;
+        drop(report);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "sequence,event,client,tx,amount\n7,released_hold,1,3,30\n7,charged_back_dispute,2,2,\n7,settled,,,110\n"
+        );
+        Ok(())
+    }
+}
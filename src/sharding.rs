@@ -0,0 +1,191 @@
+//! Parallel processing, with transactions partitioned by `client % shards` over worker threads
+//! that each own a `TxProcessor`.
+//!
+//! The balances don't depend on the number of shards. Each shard gets the transactions of its
+//! clients in input order, and what a shard couldn't see consistently is settled by the dispatcher,
+//! see `ClientScope`: a transaction reusing the id of another client's transaction, or referencing
+//! it, is rejected, and idempotency keys are scoped to the client. Otherwise this gives the same
+//! balances as a single processor. Settlement markers go to every shard.
+
+use crate::collisions::{introduces_id, references_id};
+use crate::error::TxProcessorError;
+use crate::model::{ClientBalance, ClientId, Transaction, TxId, TxType};
+use crate::tx_processor::TxProcessor;
+use crate::validation::{Finding, Severity};
+use crate::GResult;
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+
+/// Transactions are sent to the workers in batches, to keep channel overhead low.
+const BATCH_SIZE: usize = 1024;
+/// Batches in flight per shard, so a slow shard applies back-pressure to the reader.
+const QUEUED_BATCHES: usize = 4;
+
+/// Processes `transactions` over `shards` worker threads, with processors created by
+/// `make_processor`, and returns the merged balances.
+pub fn process_sharded<ITER, F>(
+    transactions: ITER,
+    shards: usize,
+    make_processor: F,
+) -> GResult<HashMap<ClientId, ClientBalance>>
+where
+    ITER: Iterator<Item = GResult<Transaction>>,
+    F: Fn() -> TxProcessor,
+{
+    let shards = shards.max(1);
+    let mut senders = Vec::with_capacity(shards);
+    let mut workers = Vec::with_capacity(shards);
+    for shard in 0..shards {
+        let (sender, receiver) = sync_channel::<Vec<Transaction>>(QUEUED_BATCHES);
+        let mut processor = make_processor();
+        let worker = thread::Builder::new()
+            .name(format!("tx-shard-{shard}"))
+            .spawn(move || -> GResult<TxProcessor> {
+                for batch in receiver {
+                    processor.process_input(batch.into_iter().map(Ok))?;
+                }
+                Ok(processor)
+            })?;
+        senders.push(sender);
+        workers.push(worker);
+    }
+
+    let dispatched = dispatch(transactions, &senders);
+    // Dropping the senders ends the workers' input.
+    drop(senders);
+
+    let mut balances = HashMap::new();
+    let mut worker_error = None;
+    for worker in workers {
+        match worker.join().expect("shard worker panicked") {
+            Ok(processor) => balances.extend(processor.clients_balance),
+            Err(err) => worker_error = worker_error.or(Some(err)),
+        }
+    }
+    // A worker error is the cause of a failed dispatch, so it is reported first.
+    match (worker_error, dispatched) {
+        (Some(err), _) | (None, Err(err)) => Err(err),
+        (None, Ok(())) => Ok(balances),
+    }
+}
+
+/// Rejects the transactions whose outcome would depend on how clients are partitioned over the
+/// shards, since a shard doesn't see the transactions of the other shards: deposits, withdrawals
+/// and holds reusing the id of another client's transaction, and references to another client's
+/// transaction. They get an `Error` finding, so that their shard rejects them. Idempotency keys
+/// are prefixed with the client, so that a key is only deduplicated within a client.
+///
+/// The client of every transaction id is kept, for as long as the run.
+#[derive(Debug, Default)]
+struct ClientScope {
+    owners: HashMap<TxId, ClientId>,
+}
+
+impl ClientScope {
+    fn check(&mut self, tx: &mut Transaction) {
+        if let Some(key) = &mut tx.idempotency_key {
+            *key = format!("{}/{key}", tx.client);
+        }
+        let message = if introduces_id(tx.tx_type) {
+            match *self.owners.entry(tx.tx_id).or_insert(tx.client) {
+                owner if owner == tx.client => return,
+                owner => format!("transaction id {} is already used by client {owner}", tx.tx_id),
+            }
+        } else if references_id(tx.tx_type) {
+            match self.owners.get(&tx.tx_id) {
+                Some(&owner) if owner != tx.client => format!("transaction {} is of client {owner}", tx.tx_id),
+                _ => return,
+            }
+        } else {
+            return;
+        };
+        tx.findings.push(Finding::new(Severity::Error, message));
+    }
+}
+
+fn dispatch<ITER>(transactions: ITER, senders: &[SyncSender<Vec<Transaction>>]) -> GResult<()>
+where
+    ITER: Iterator<Item = GResult<Transaction>>,
+{
+    let shard_stopped = || TxProcessorError::Io(std::io::Error::other("shard worker stopped"));
+    let mut batches: Vec<Vec<Transaction>> = vec![Vec::with_capacity(BATCH_SIZE); senders.len()];
+    let mut scope = ClientScope::default();
+    let mut push = |shard: usize, tx: Transaction| -> GResult<()> {
+        batches[shard].push(tx);
+        if batches[shard].len() == BATCH_SIZE {
+            let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+            senders[shard].send(batch).map_err(|_| shard_stopped())?;
+        }
+        Ok(())
+    };
+    for tx in transactions {
+        let mut tx = tx?;
+        if tx.tx_type == TxType::Settle {
+            for shard in 1..senders.len() {
+                push(shard, tx.clone())?;
+            }
+            push(0, tx)?;
+        } else {
+            scope.check(&mut tx);
+            push(tx.client as usize % senders.len(), tx)?;
+        }
+    }
+    for (sender, batch) in senders.iter().zip(batches) {
+        sender.send(batch).map_err(|_| shard_stopped())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+
+    #[test]
+    fn test_process_sharded() -> GResult<()> {
+        let mut single = TxProcessor::new();
+        let expected = single.process_input(read_transactions_file("tests/example.csv")?)?.clone();
+
+        for shards in [1, 2, 7] {
+            let balances = process_sharded(read_transactions_file("tests/example.csv")?, shards, TxProcessor::new)?;
+            assert_eq!(balances, expected);
+        }
+
+        let transactions = vec![
+            Ok(Transaction::new(crate::model::TxType::Deposit, 1, 1, None)),
+        ];
+        let result = process_sharded(transactions.into_iter(), 2, TxProcessor::new);
+        assert!(matches!(result, Err(TxProcessorError::MissingAmount(1))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_scope() -> GResult<()> {
+        use crate::test_support::*;
+
+        let transactions = || {
+            vec![
+                deposit(1, 1, 10.0),
+                // Reuses the id of client 1's deposit.
+                deposit(2, 1, 5.0),
+                // References client 1's deposit.
+                dispute(2, 1),
+                dispute(1, 1),
+                with_key(deposit(1, 2, 3.0), "key"),
+                // The same key of another client isn't a retry.
+                with_key(deposit(2, 3, 4.0), "key"),
+                with_key(deposit(2, 3, 4.0), "key"),
+            ]
+            .into_iter()
+            .map(Ok)
+        };
+        let expected = process_sharded(transactions(), 1, TxProcessor::new)?;
+        assert_eq!((expected[&1].available, expected[&1].held), (amount(3.0), amount(10.0)));
+        assert_eq!((expected[&2].available, expected[&2].held), (amount(4.0), amount(0.0)));
+        for shards in [2, 3] {
+            assert_eq!(process_sharded(transactions(), shards, TxProcessor::new)?, expected);
+        }
+        Ok(())
+    }
+}
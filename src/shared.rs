@@ -0,0 +1,142 @@
+//! A processor that threads submit transactions to concurrently: clients are partitioned by
+//! `client % shards` over processors that each have their own lock. Transactions of a client are
+//! applied one at a time, in the order their submissions take the lock, while clients of other
+//! shards proceed in parallel.
+//!
+//! As with `sharding`, this gives the same balances as a single processor as long as disputes,
+//! resolves and chargebacks are sent by the client of the transaction they reference. Transaction
+//! ids and idempotency keys are only deduplicated within a shard. A settlement marker settles
+//! every shard, one after the other.
+
+use crate::accounts::{account_page, AccountFilter, AccountPage};
+use crate::model::{ClientBalance, ClientId, Transaction, TxType};
+use crate::server::lock;
+use crate::tx_processor::{TxOutcome, TxProcessor};
+use crate::GResult;
+use std::sync::Mutex;
+
+pub struct SharedTxProcessor {
+    shards: Vec<Mutex<TxProcessor>>,
+}
+
+impl SharedTxProcessor {
+    /// A processor over `shards` processors created by `make_processor`.
+    pub fn new<F: Fn() -> TxProcessor>(shards: usize, make_processor: F) -> Self {
+        let shards = (0..shards.max(1)).map(|_| Mutex::new(make_processor())).collect();
+        Self { shards }
+    }
+
+    fn shard(&self, client: ClientId) -> &Mutex<TxProcessor> {
+        &self.shards[client as usize % self.shards.len()]
+    }
+
+    /// Processes a transaction, see `TxProcessor::process_transaction`. Only the shard of the
+    /// client is locked.
+    pub fn process_transaction(&self, tx: &mut Transaction) -> GResult<TxOutcome> {
+        if tx.tx_type == TxType::Settle {
+            for shard in &self.shards {
+                lock(shard).process_transaction(&mut tx.clone())?;
+            }
+            return Ok(TxOutcome::Applied);
+        }
+        lock(self.shard(tx.client)).process_transaction(tx)
+    }
+
+    pub fn balance_of(&self, client: ClientId) -> Option<ClientBalance> {
+        lock(self.shard(client)).balance_of(client).cloned()
+    }
+
+    /// Balances of all clients, sorted by client. Shards are locked one at a time, so this is not
+    /// a consistent snapshot of the balances while transactions are being submitted.
+    pub fn balances(&self) -> Vec<ClientBalance> {
+        let mut balances: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| lock(shard).balances().cloned().collect::<Vec<_>>())
+            .collect();
+        balances.sort_by_key(|balance| balance.client);
+        balances
+    }
+
+    /// A page of the accounts of all shards, see `accounts::account_page`. Shards are locked one
+    /// at a time, as for `balances`.
+    pub fn account_page(&self, after: Option<ClientId>, limit: usize, filter: &AccountFilter) -> AccountPage {
+        let (mut clients, mut more) = (vec![], false);
+        for shard in &self.shards {
+            let page = lock(shard).account_page(after, limit, filter);
+            more |= page.next_cursor.is_some();
+            clients.extend(page.clients);
+        }
+        // The first accounts of each shard include the first accounts of all.
+        let mut page = account_page(clients.iter(), after, limit, filter);
+        if more && page.next_cursor.is_none() {
+            page.next_cursor = page.clients.last().map(|balance| balance.client);
+        }
+        page
+    }
+
+    /// The processor of each shard, ie to take their settlements or history.
+    pub fn into_processors(self) -> Vec<TxProcessor> {
+        self.shards
+            .into_iter()
+            .map(|shard| shard.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_shared_processor() -> GResult<()> {
+        let shared = Arc::new(SharedTxProcessor::new(3, TxProcessor::new));
+        // Each thread submits the transactions of its own clients, all threads at once.
+        let submitters: Vec<_> = (0..8u16)
+            .map(|thread| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || -> GResult<()> {
+                    for round in 0..100u32 {
+                        for client in [thread * 2, thread * 2 + 1] {
+                            let tx_id = (u32::from(client) << 16) + round * 2;
+                            shared.process_transaction(&mut deposit(client, tx_id, 2.0))?;
+                            shared.process_transaction(&mut withdrawal(client, tx_id + 1, 1.0))?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for submitter in submitters {
+            submitter.join().unwrap()?;
+        }
+
+        let balances = shared.balances();
+        assert_eq!(balances.len(), 16);
+        assert!(balances.iter().enumerate().all(|(index, balance)| balance.client == index as u16));
+        assert!(balances.iter().all(|balance| balance.available == amount(100.0)));
+        let filter = AccountFilter::default();
+        let page = shared.account_page(None, 5, &filter);
+        assert_eq!(page.clients, balances[..5]);
+        assert_eq!(page.next_cursor, Some(4));
+        let page = shared.account_page(Some(12), 5, &filter);
+        assert_eq!((page.clients.len(), page.next_cursor), (3, None));
+
+        // Disputes go to the shard of the client, and a settlement to all of them.
+        let outcome = shared.process_transaction(&mut dispute(5, 5 << 16))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        assert_eq!(shared.balance_of(5).unwrap().held, amount(2.0));
+        shared.process_transaction(&mut settle())?;
+        let settlements: usize = Arc::into_inner(shared)
+            .unwrap()
+            .into_processors()
+            .iter()
+            .map(|processor| processor.settlements.len())
+            .sum();
+        assert_eq!(settlements, 3);
+        Ok(())
+    }
+}
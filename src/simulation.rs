@@ -0,0 +1,249 @@
+//! Monte Carlo simulation of how currently open disputes may end, to estimate the potential
+//! chargeback losses and account locks.
+
+use crate::amount::Amount;
+use crate::error::TxProcessorError;
+use crate::model::{ClientId, TxAmount, TxId, TxType};
+use crate::store::{Direction, StoredTx};
+use crate::tx_processor::{TxOutcome, TxProcessor};
+use crate::{process_file_with, GResult};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenDispute {
+    pub client: ClientId,
+    pub tx_id: TxId,
+    pub amount: TxAmount,
+}
+
+#[derive(Debug, Clone)]
+pub struct DisputeSimConfig {
+    /// Probability that an open dispute ends in a resolve.
+    pub resolve_rate: f64,
+    /// Probability that an open dispute ends in a chargeback. Disputes that neither resolve nor
+    /// charge back stay open.
+    pub chargeback_rate: f64,
+    pub scenarios: u32,
+    pub seed: u64,
+}
+
+impl Default for DisputeSimConfig {
+    fn default() -> Self {
+        Self {
+            resolve_rate: 0.8,
+            chargeback_rate: 0.2,
+            scenarios: 10_000,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl DisputeSimConfig {
+    fn validate(&self) -> GResult<()> {
+        let valid_rate = |rate: f64| (0.0..=1.0).contains(&rate);
+        if !valid_rate(self.resolve_rate)
+            || !valid_rate(self.chargeback_rate)
+            || self.resolve_rate + self.chargeback_rate > 1.0
+        {
+            return Err(TxProcessorError::Parse {
+                field: "rate",
+                message: "rates must be between 0 and 1, and add up to at most 1".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Summary statistics of a value over all scenarios.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Distribution {
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    pub fn from_samples(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_by(f64::total_cmp);
+        let percentile = |percentile: f64| {
+            let index = ((samples.len() as f64 * percentile).ceil() as usize).max(1) - 1;
+            samples[index]
+        };
+        Self {
+            mean: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.2}, p50 {:.2}, p95 {:.2}, p99 {:.2}, max {:.2}",
+            self.mean, self.p50, self.p95, self.p99, self.max
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationReport {
+    pub scenarios: u32,
+    pub open_disputes: usize,
+    pub held: TxAmount,
+    /// Total amount charged back per scenario.
+    pub charged_back: Distribution,
+    /// Number of currently unlocked clients that end up locked, per scenario.
+    pub newly_locked_clients: Distribution,
+}
+
+impl Display for SimulationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "open disputes: {}, held: {}, scenarios: {}",
+            self.open_disputes, self.held, self.scenarios
+        )?;
+        writeln!(f, "charged back: {}", self.charged_back)?;
+        write!(f, "newly locked clients: {}", self.newly_locked_clients)
+    }
+}
+
+/// Processes the file at `path` and returns the processor along with the disputes still open at
+/// the end of it, ordered by transaction id.
+pub fn open_disputes_in_file(path: &str) -> GResult<(TxProcessor, Vec<OpenDispute>)> {
+    let mut open = HashMap::new();
+    let processor = process_file_with(path, |tx, outcome| {
+        if *outcome == TxOutcome::Applied {
+            match tx.tx_type {
+                TxType::Dispute => {
+                    open.insert(tx.tx_id, tx.client);
+                }
+                TxType::Resolve | TxType::Chargeback => {
+                    open.remove(&tx.tx_id);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut disputes = vec![];
+    for (tx_id, client) in open {
+        // Disputed withdrawals aren't simulated.
+        let stored = processor.stored_transaction(tx_id)?;
+        if let Some(StoredTx { amount, direction: Direction::Deposit, .. }) = stored {
+            disputes.push(OpenDispute { client, tx_id, amount });
+        }
+    }
+    disputes.sort_by_key(|dispute| dispute.tx_id);
+    Ok((processor, disputes))
+}
+
+/// Runs `config.scenarios` random outcomes of `disputes`. Clients already locked in `processor`
+/// are not counted as newly locked.
+pub fn simulate_disputes(
+    processor: &TxProcessor,
+    disputes: &[OpenDispute],
+    config: &DisputeSimConfig,
+) -> GResult<SimulationReport> {
+    config.validate()?;
+    let is_locked = |client| processor.balance_of(client).is_some_and(|balance| balance.locked);
+
+    let mut random = XorShift(config.seed.max(1));
+    let mut charged_back = Vec::with_capacity(config.scenarios as usize);
+    let mut newly_locked_clients = Vec::with_capacity(config.scenarios as usize);
+    for _ in 0..config.scenarios {
+        let mut amount = TxAmount::default();
+        let mut locked = HashSet::new();
+        for dispute in disputes {
+            // A resolve only releases held funds, only chargebacks have an impact.
+            let draw = random.next_unit();
+            if draw >= config.resolve_rate && draw < config.resolve_rate + config.chargeback_rate {
+                amount += dispute.amount;
+                if !is_locked(dispute.client) {
+                    locked.insert(dispute.client);
+                }
+            }
+        }
+        charged_back.push(Amount::to_f64(amount));
+        newly_locked_clients.push(locked.len() as f64);
+    }
+
+    Ok(SimulationReport {
+        scenarios: config.scenarios,
+        open_disputes: disputes.len(),
+        held: disputes.iter().map(|dispute| dispute.amount).sum(),
+        charged_back: Distribution::from_samples(charged_back),
+        newly_locked_clients: Distribution::from_samples(newly_locked_clients),
+    })
+}
+
+/// Deterministic xorshift generator, so a simulation can be reproduced from its seed.
+struct XorShift(u64);
+
+impl XorShift {
+    /// Uniform value in `[0, 1)`.
+    fn next_unit(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+
+    #[test]
+    fn test_simulate_disputes() -> GResult<()> {
+        let (processor, disputes) = open_disputes_in_file("tests/disputes.csv")?;
+        assert_eq!(
+            disputes,
+            vec![
+                OpenDispute { client: 1, tx_id: 1, amount: amount(10.0) },
+                OpenDispute { client: 2, tx_id: 3, amount: amount(5.0) },
+            ]
+        );
+
+        let config = DisputeSimConfig {
+            resolve_rate: 0.0,
+            chargeback_rate: 1.0,
+            scenarios: 100,
+            ..DisputeSimConfig::default()
+        };
+        let report = simulate_disputes(&processor, &disputes, &config)?;
+        assert_eq!(report.held, amount(15.0));
+        assert_eq!(report.charged_back.p50, 15.0);
+        // Client 2 is already locked by an earlier chargeback.
+        assert_eq!(report.newly_locked_clients.max, 1.0);
+
+        let config = DisputeSimConfig {
+            resolve_rate: 0.5,
+            chargeback_rate: 0.5,
+            ..DisputeSimConfig::default()
+        };
+        let report = simulate_disputes(&processor, &disputes, &config)?;
+        assert_eq!(report.charged_back.max, 15.0);
+        assert!(report.charged_back.mean > 5.0 && report.charged_back.mean < 10.0);
+
+        let config = DisputeSimConfig {
+            resolve_rate: 0.8,
+            chargeback_rate: 0.5,
+            ..DisputeSimConfig::default()
+        };
+        assert!(simulate_disputes(&processor, &disputes, &config).is_err());
+        Ok(())
+    }
+}
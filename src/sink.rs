@@ -0,0 +1,419 @@
+//! Destinations of processing results. The processing core (`process_transactions_into`) gives
+//! the final balances to a `BalanceSink` and the outcome of each transaction to an `EventSink`,
+//! and never writes output itself: formats and destinations are sink implementations.
+
+use crate::error::TxProcessorError;
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::output::{rounded, AmountFormat, BalanceColumn, BalanceRow, ColumnSpec};
+use crate::tx_processor::TxOutcome;
+use crate::validation::Severity;
+use crate::GResult;
+use std::io;
+
+pub trait BalanceSink {
+    fn balance(&mut self, balance: &ClientBalance) -> GResult<()>;
+
+    /// Called once all balances were given, ie to close the output.
+    fn finish(&mut self) -> GResult<()>;
+}
+
+pub trait EventSink {
+    fn event(&mut self, tx: &Transaction, outcome: &TxOutcome) -> GResult<()>;
+
+    /// Writes out the events given so far, ie before a checkpoint is saved.
+    fn flush(&mut self) -> GResult<()> {
+        Ok(())
+    }
+}
+
+/// Gives all of `balances` to `sink`, then finishes it.
+pub fn sink_balances<'a>(
+    sink: &mut dyn BalanceSink,
+    balances: impl IntoIterator<Item = &'a ClientBalance>,
+) -> GResult<()> {
+    for balance in balances {
+        sink.balance(balance)?;
+    }
+    sink.finish()
+}
+
+/// Balances as CSV, with the default columns or the given ones.
+pub struct CsvBalanceSink<OUT: io::Write> {
+    writer: csv::Writer<OUT>,
+    format: AmountFormat,
+    columns: Option<Vec<ColumnSpec>>,
+}
+
+impl<OUT: io::Write> CsvBalanceSink<OUT> {
+    pub fn new(out: OUT, format: AmountFormat) -> GResult<Self> {
+        Self::with_columns(out, format, None)
+    }
+
+    pub fn with_columns(out: OUT, format: AmountFormat, columns: Option<Vec<ColumnSpec>>) -> GResult<Self> {
+        // Header is written explicitly so that it's present even when there are no balances.
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+        match &columns {
+            Some(columns) => writer.write_record(columns.iter().map(|spec| &spec.header))?,
+            None => writer.write_record(["client", "available", "held", "total", "locked"])?,
+        }
+        Ok(Self { writer, format, columns })
+    }
+}
+
+impl<OUT: io::Write> BalanceSink for CsvBalanceSink<OUT> {
+    fn balance(&mut self, balance: &ClientBalance) -> GResult<()> {
+        let format = self.format;
+        let Some(columns) = &self.columns else {
+            self.writer.serialize(BalanceRow { balance, format })?;
+            return Ok(());
+        };
+        self.writer.write_record(columns.iter().map(|spec| match spec.column {
+            BalanceColumn::Client => balance.client.to_string(),
+            BalanceColumn::Available => format.format(balance.available),
+            BalanceColumn::Held => format.format(balance.held),
+            BalanceColumn::Total => format.format(balance.total),
+            BalanceColumn::Locked => balance.locked.to_string(),
+            BalanceColumn::Frozen => balance.frozen.to_string(),
+            BalanceColumn::Pending => format.format(balance.pending),
+            BalanceColumn::Status => match (balance.locked, balance.frozen) {
+                (true, _) => "locked",
+                (false, true) => "frozen",
+                (false, false) => "active",
+            }
+            .to_string(),
+        }))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> GResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Balances as a single JSON array, written as the balances come.
+pub struct JsonBalanceSink<OUT: io::Write> {
+    out: OUT,
+    empty: bool,
+}
+
+impl<OUT: io::Write> JsonBalanceSink<OUT> {
+    pub fn new(mut out: OUT) -> GResult<Self> {
+        write!(out, "[")?;
+        Ok(Self { out, empty: true })
+    }
+}
+
+impl<OUT: io::Write> BalanceSink for JsonBalanceSink<OUT> {
+    fn balance(&mut self, balance: &ClientBalance) -> GResult<()> {
+        if !self.empty {
+            write!(self.out, ",")?;
+        }
+        self.empty = false;
+        serde_json::to_writer(&mut self.out, &rounded(balance))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> GResult<()> {
+        writeln!(self.out, "]")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Balances as one JSON object per line.
+pub struct JsonLinesBalanceSink<OUT: io::Write>(pub OUT);
+
+impl<OUT: io::Write> BalanceSink for JsonLinesBalanceSink<OUT> {
+    fn balance(&mut self, balance: &ClientBalance) -> GResult<()> {
+        serde_json::to_writer(&mut self.0, &rounded(balance))?;
+        writeln!(self.0)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> GResult<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps the balances, ie for embedding the processor or for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBalanceSink {
+    pub balances: Vec<ClientBalance>,
+}
+
+impl BalanceSink for MemoryBalanceSink {
+    fn balance(&mut self, balance: &ClientBalance) -> GResult<()> {
+        self.balances.push(balance.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> GResult<()> {
+        Ok(())
+    }
+}
+
+/// Collects the balances and hands them all to `write` when finished, for outputs that need all
+/// of them at once (ie a table aligned to its widest cell).
+pub struct BufferedBalanceSink<F: FnMut(&[ClientBalance]) -> GResult<()>> {
+    balances: Vec<ClientBalance>,
+    write: F,
+}
+
+impl<F: FnMut(&[ClientBalance]) -> GResult<()>> BufferedBalanceSink<F> {
+    pub fn new(write: F) -> Self {
+        Self { balances: vec![], write }
+    }
+}
+
+impl<F: FnMut(&[ClientBalance]) -> GResult<()>> BalanceSink for BufferedBalanceSink<F> {
+    fn balance(&mut self, balance: &ClientBalance) -> GResult<()> {
+        self.balances.push(balance.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> GResult<()> {
+        (self.write)(&self.balances)
+    }
+}
+
+/// Which events a `CsvReportSink` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    /// Rejected transactions, with the reason.
+    Rejected,
+    /// Validation warnings of applied transactions, one row per warning.
+    Warnings,
+}
+
+/// A per-transaction CSV report: `type,client,tx,amount,<reason or warning>,tags`.
+pub struct CsvReportSink<OUT: io::Write> {
+    writer: csv::Writer<OUT>,
+    kind: ReportKind,
+}
+
+impl<OUT: io::Write> CsvReportSink<OUT> {
+    pub fn new(out: OUT, kind: ReportKind) -> GResult<Self> {
+        Self::with_header(out, kind, true)
+    }
+
+    fn with_header(out: OUT, kind: ReportKind, header: bool) -> GResult<Self> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+        if header {
+            let reason_column = match kind {
+                ReportKind::Rejected => "reason",
+                ReportKind::Warnings => "warning",
+            };
+            writer.write_record(["type", "client", "tx", "amount", reason_column, "tags"])?;
+        }
+        Ok(Self { writer, kind })
+    }
+
+    fn write_row(&mut self, tx: &Transaction, reason: &str) -> GResult<()> {
+        let amount = tx.amount.map(|amount| amount.to_string()).unwrap_or_default();
+        self.writer.write_record([
+            tx.tx_type.to_string(),
+            tx.client.to_string(),
+            tx.tx_id.to_string(),
+            amount,
+            reason.to_string(),
+            tx.tags.join(";"),
+        ])?;
+        Ok(())
+    }
+}
+
+impl CsvReportSink<std::fs::File> {
+    /// Creates the report file at `path`. With `append`, rows are added to an existing report.
+    pub fn create(path: &str, kind: ReportKind, append: bool) -> GResult<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(append)
+            .write(true)
+            .truncate(!append)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        Self::with_header(file, kind, is_empty)
+    }
+}
+
+impl<OUT: io::Write> EventSink for CsvReportSink<OUT> {
+    fn event(&mut self, tx: &Transaction, outcome: &TxOutcome) -> GResult<()> {
+        match (self.kind, outcome) {
+            (ReportKind::Rejected, TxOutcome::Rejected(reason)) => self.write_row(tx, &reason.to_string()),
+            (ReportKind::Warnings, TxOutcome::Applied) => {
+                for finding in tx.findings.iter().filter(|finding| finding.severity == Severity::Warning) {
+                    self.write_row(tx, &finding.message)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Input records that could not be parsed, skipped in `ParseMode::Lenient`, as CSV:
+/// `line,error,raw`. The raw record is what was read of it, so that it can be fixed and
+/// resubmitted; errors that don't come with it (ie invalid UTF-8) have an empty one. Rows are
+/// flushed as they are written, malformed records being rare.
+pub struct DeadLetterCsv<OUT: io::Write> {
+    writer: csv::Writer<OUT>,
+}
+
+impl<OUT: io::Write> DeadLetterCsv<OUT> {
+    pub fn new(out: OUT) -> GResult<Self> {
+        Self::with_header(out, true)
+    }
+
+    fn with_header(out: OUT, header: bool) -> GResult<Self> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+        if header {
+            writer.write_record(["line", "error", "raw"])?;
+        }
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, err: &TxProcessorError) -> GResult<()> {
+        let (line, error, raw) = match err {
+            TxProcessorError::InvalidRecord { line, raw, source, .. } => (Some(*line), source.to_string(), raw.as_str()),
+            TxProcessorError::Csv(csv_err) => (csv_err.position().map(|position| position.line()), err.to_string(), ""),
+            _ => (None, err.to_string(), ""),
+        };
+        let line = line.map(|line| line.to_string()).unwrap_or_default();
+        self.writer.write_record([line.as_str(), &error, raw])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl DeadLetterCsv<std::fs::File> {
+    /// Creates the file at `path`. With `append`, rows are added to an existing file.
+    pub fn create(path: &str, append: bool) -> GResult<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(append)
+            .write(true)
+            .truncate(!append)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        Self::with_header(file, is_empty)
+    }
+}
+
+/// A transaction and its outcome, as written by `JsonLinesEventSink`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EventRecord {
+    #[serde(rename = "type")]
+    pub tx_type: TxType,
+    pub client: ClientId,
+    pub tx: TxId,
+    pub amount: Option<TxAmount>,
+    /// `applied`, `queued` or `rejected`.
+    pub outcome: String,
+    pub reason_code: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Every event as one JSON object per line, see `EventRecord`.
+pub struct JsonLinesEventSink<OUT: io::Write>(pub OUT);
+
+impl<OUT: io::Write> EventSink for JsonLinesEventSink<OUT> {
+    fn event(&mut self, tx: &Transaction, outcome: &TxOutcome) -> GResult<()> {
+        let (outcome, reason_code) = match outcome {
+            TxOutcome::Applied => ("applied", None),
+            TxOutcome::Queued => ("queued", None),
+            TxOutcome::Rejected(reason) => ("rejected", Some(reason.code().to_string())),
+        };
+        let record = EventRecord {
+            tx_type: tx.tx_type,
+            client: tx.client,
+            tx: tx.tx_id,
+            amount: tx.amount,
+            outcome: outcome.to_string(),
+            reason_code,
+            tags: tx.tags.clone(),
+        };
+        serde_json::to_writer(&mut self.0, &record)?;
+        writeln!(self.0)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps every transaction and its outcome.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEventSink {
+    pub events: Vec<(Transaction, TxOutcome)>,
+}
+
+impl EventSink for MemoryEventSink {
+    fn event(&mut self, tx: &Transaction, outcome: &TxOutcome) -> GResult<()> {
+        self.events.push((tx.clone(), outcome.clone()));
+        Ok(())
+    }
+}
+
+/// Gives each event to all of the sinks, in order. Empty to ignore events.
+impl EventSink for Vec<Box<dyn EventSink>> {
+    fn event(&mut self, tx: &Transaction, outcome: &TxOutcome) -> GResult<()> {
+        self.iter_mut().try_for_each(|sink| sink.event(tx, outcome))
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        self.iter_mut().try_for_each(|sink| sink.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::error::RejectReason;
+    use crate::validation::Finding;
+
+    #[test]
+    fn test_event_sinks() -> GResult<()> {
+        let mut warned = deposit(1, 1, 1.5);
+        warned.findings.push(Finding::new(Severity::Warning, "rounded"));
+        warned.tag("rounded");
+        let rejected = withdrawal(1, 2, 10.0);
+        let events = [
+            (warned, TxOutcome::Applied),
+            (rejected, TxOutcome::Rejected(RejectReason::InsufficientFunds)),
+        ];
+
+        let mut rejected_report = CsvReportSink::new(vec![], ReportKind::Rejected)?;
+        let mut warnings_report = CsvReportSink::new(vec![], ReportKind::Warnings)?;
+        let mut json_lines = JsonLinesEventSink(vec![]);
+        for (tx, outcome) in &events {
+            rejected_report.event(tx, outcome)?;
+            warnings_report.event(tx, outcome)?;
+            json_lines.event(tx, outcome)?;
+        }
+
+        let report = |sink: CsvReportSink<Vec<u8>>| String::from_utf8(sink.writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            report(rejected_report),
+            "type,client,tx,amount,reason,tags\nwithdrawal,1,2,10,not enough funds,\n"
+        );
+        assert_eq!(report(warnings_report), "type,client,tx,amount,warning,tags\ndeposit,1,1,1.5,rounded,rounded\n");
+        let records: Vec<EventRecord> = String::from_utf8(json_lines.0)
+            .unwrap()
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(records[0].tags, vec!["rounded".to_string()]);
+        assert_eq!(records[1].reason_code.as_deref(), Some("insufficient_funds"));
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! Saving and restoring the state of a `TxProcessor`, as JSON, so that a restarted process can
+//! carry on where the previous one stopped.
+//!
+//! Snapshots start with a checksum line, see `checksum`, so that a truncated or corrupted one is
+//! reported instead of being restored. With a state key, they are encrypted, see `encryption`.
+//!
+//! Only state is saved: balances, deposit and withdrawal amounts, counters, the locked account queue, the
+//! idempotency outcomes, open holds, pending deposits, open and capped disputes, refunded amounts,
+//! merged accounts and the eviction schedule of stored transactions.
+//! Configuration, validators and the transaction store come from the processor the snapshot is
+//! loaded into.
+
+use crate::checksum;
+use crate::clearing::PendingDeposit;
+use crate::encryption::StateEncryption;
+use crate::error::TxProcessorError;
+use crate::holds::Hold;
+use crate::settlement::OpenDispute;
+use crate::store::{Direction, StateMap, Stores, StoredTx};
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId};
+use crate::tx_processor::{ProcessorCounters, TxOutcome, TxProcessor};
+use crate::GResult;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Snapshot {
+    version: u32,
+    counters: ProcessorCounters,
+    clients_balance: Vec<ClientBalance>,
+    /// Deposit amounts.
+    account_transactions: Vec<(TxId, TxAmount)>,
+    /// Missing from snapshots saved before withdrawals were stored.
+    #[serde(default)]
+    withdrawals: Vec<(TxId, TxAmount)>,
+    locked_queue: Vec<(ClientId, Vec<Transaction>)>,
+    idempotency_outcomes: Vec<(String, TxOutcome)>,
+    /// Missing from snapshots saved before holds were supported.
+    #[serde(default)]
+    holds: Vec<(TxId, Hold)>,
+    /// Missing from snapshots saved before pending deposits were supported.
+    #[serde(default)]
+    pending_deposits: Vec<(TxId, PendingDeposit)>,
+    #[serde(default)]
+    capped_disputes: Vec<(TxId, TxAmount)>,
+    #[serde(default)]
+    refunded: Vec<(TxId, TxAmount)>,
+    /// Run that saved the snapshot, see `TxProcessor::run_id`.
+    #[serde(default)]
+    run_id: Option<String>,
+    /// Clients of the deposits and withdrawals, by transaction id.
+    #[serde(default)]
+    transaction_clients: Vec<(TxId, ClientId)>,
+    /// Missing from snapshots saved before open disputes were tracked.
+    #[serde(default)]
+    open_disputes: Vec<(TxId, OpenDispute)>,
+    /// Clients merged into another, see `merge`.
+    #[serde(default)]
+    merged_accounts: Vec<(ClientId, ClientId)>,
+    /// Stored transactions to evict, see `dispute_window`.
+    #[serde(default)]
+    tx_expiries: Vec<(u64, TxId)>,
+}
+
+impl<S: Stores> TxProcessor<S> {
+    /// Writes the processor state to `out`, unencrypted. Entries are sorted, so snapshots of the
+    /// same state are identical.
+    pub fn save_snapshot<OUT: io::Write>(&self, out: OUT) -> GResult<()> {
+        self.save_snapshot_with(out, &StateEncryption::default())
+    }
+
+    /// Writes the processor state to `out`, encrypted if `encryption` has a key.
+    pub fn save_snapshot_with<OUT: io::Write>(&self, out: OUT, encryption: &StateEncryption) -> GResult<()> {
+        let mut clients_balance: Vec<_> = self.clients_balance.values().cloned().collect();
+        clients_balance.sort_by_key(|balance| balance.client);
+        let mut account_transactions = vec![];
+        let mut withdrawals = vec![];
+        let mut transaction_clients = vec![];
+        for entry in self.account_transactions.entries() {
+            let (tx_id, stored) = entry?;
+            if let Some(client) = stored.client {
+                transaction_clients.push((tx_id, client));
+            }
+            match stored.direction {
+                Direction::Deposit => account_transactions.push((tx_id, stored.amount)),
+                Direction::Withdrawal => withdrawals.push((tx_id, stored.amount)),
+            }
+        }
+        account_transactions.sort_by_key(|(tx_id, _)| *tx_id);
+        withdrawals.sort_by_key(|(tx_id, _)| *tx_id);
+        transaction_clients.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut locked_queue: Vec<_> = self
+            .locked_queue
+            .iter()
+            .map(|(client, queue)| (*client, queue.clone()))
+            .collect();
+        locked_queue.sort_by_key(|(client, _)| *client);
+        let mut idempotency_outcomes: Vec<_> = self
+            .idempotency_outcomes
+            .iter()
+            .map(|(key, outcome)| (key.clone(), outcome.clone()))
+            .collect();
+        idempotency_outcomes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut holds: Vec<_> = self.holds.iter().map(|(tx_id, hold)| (*tx_id, hold.clone())).collect();
+        holds.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut pending_deposits: Vec<_> =
+            self.pending_deposits.iter().map(|(tx_id, deposit)| (*tx_id, deposit.clone())).collect();
+        pending_deposits.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut capped_disputes: Vec<_> = self.capped_disputes.iter().map(|(tx_id, held)| (*tx_id, *held)).collect();
+        capped_disputes.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut refunded: Vec<_> = self.refunded.iter().map(|(tx_id, amount)| (*tx_id, *amount)).collect();
+        refunded.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut open_disputes: Vec<_> =
+            self.open_disputes.iter().map(|(tx_id, dispute)| (*tx_id, dispute.clone())).collect();
+        open_disputes.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut merged_accounts: Vec<_> = self.merged_accounts.iter().map(|(from, into)| (*from, *into)).collect();
+        merged_accounts.sort();
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            counters: self.counters.clone(),
+            clients_balance,
+            account_transactions,
+            withdrawals,
+            locked_queue,
+            idempotency_outcomes,
+            holds,
+            pending_deposits,
+            capped_disputes,
+            refunded,
+            run_id: self.run_id.clone(),
+            transaction_clients,
+            open_disputes,
+            merged_accounts,
+            tx_expiries: self.tx_expiries.iter().copied().collect(),
+        };
+        let json = serde_json::to_vec(&snapshot)?;
+        let mut checked = format!("crc32 {:08x} {}\n", checksum::crc32(&json), json.len()).into_bytes();
+        checked.extend_from_slice(&json);
+        let mut out = io::BufWriter::new(out);
+        out.write_all(&encryption.seal_snapshot(checked)?)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Saves a snapshot to the file at `path`. The file is replaced atomically, so a crash while
+    /// saving leaves the previous snapshot in place.
+    pub fn save_snapshot_file(&self, path: &str, encryption: &StateEncryption) -> GResult<()> {
+        let partial_path = format!("{path}.partial");
+        self.save_snapshot_with(std::fs::File::create(&partial_path)?, encryption)?;
+        std::fs::rename(partial_path, path)?;
+        Ok(())
+    }
+
+    /// Replaces the processor state with the unencrypted snapshot read from `input`. Deposit and
+    /// withdrawal amounts are added to the processor's transaction store.
+    pub fn load_snapshot<IN: io::Read>(&mut self, input: IN) -> GResult<()> {
+        self.load_snapshot_with(input, &StateEncryption::default())
+    }
+
+    /// Like `load_snapshot`, decrypting an encrypted snapshot with the key of `encryption`. With
+    /// a key, an unencrypted snapshot is only read when migrating (see
+    /// `StateEncryption::migrate_plaintext`).
+    pub fn load_snapshot_with<IN: io::Read>(&mut self, mut input: IN, encryption: &StateEncryption) -> GResult<()> {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let bytes = encryption.open_snapshot(bytes)?;
+        let snapshot: Snapshot = serde_json::from_slice(checked_json(&bytes)?)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(TxProcessorError::Parse {
+                field: "snapshot",
+                message: format!("unsupported snapshot version {}", snapshot.version),
+            });
+        }
+
+        self.counters = snapshot.counters;
+        self.clients_balance = snapshot
+            .clients_balance
+            .into_iter()
+            .map(|balance| (balance.client, balance))
+            .collect();
+        let deposits = snapshot.account_transactions.into_iter().map(|entry| (entry, Direction::Deposit));
+        let withdrawals = snapshot.withdrawals.into_iter().map(|entry| (entry, Direction::Withdrawal));
+        let clients: HashMap<TxId, ClientId> = snapshot.transaction_clients.into_iter().collect();
+        for ((tx_id, amount), direction) in deposits.chain(withdrawals) {
+            let client = clients.get(&tx_id).copied();
+            self.account_transactions.insert(tx_id, StoredTx { amount, direction, client })?;
+        }
+        self.locked_queue = snapshot.locked_queue.into_iter().collect();
+        self.idempotency_outcomes = snapshot.idempotency_outcomes.into_iter().collect();
+        self.holds = snapshot.holds.into_iter().collect();
+        self.rebuild_hold_expiries();
+        self.pending_deposits = snapshot.pending_deposits.into_iter().collect();
+        self.rebuild_clearings();
+        self.rebuild_tx_expiries(snapshot.tx_expiries);
+        self.capped_disputes = snapshot.capped_disputes.into_iter().collect();
+        self.refunded = snapshot.refunded.into_iter().collect();
+        self.open_disputes = snapshot.open_disputes.into_iter().collect();
+        self.merged_accounts = snapshot.merged_accounts.into_iter().collect();
+        // A processor without a run id of its own continues the run that saved the snapshot.
+        if self.run_id.is_none() {
+            self.run_id = snapshot.run_id;
+        }
+        Ok(())
+    }
+}
+
+/// The JSON of the snapshot `bytes`, checked against its checksum line if it has one.
+fn checked_json(bytes: &[u8]) -> GResult<&[u8]> {
+    let Some(header) = bytes.strip_prefix(b"crc32 ") else {
+        // Saved before checksums were added.
+        return Ok(bytes);
+    };
+    let corrupted = |message: String| TxProcessorError::Corrupted { what: "snapshot", message };
+    let end = header
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(|| corrupted("no JSON after the checksum line".to_string()))?;
+    let (checksum, length) = std::str::from_utf8(&header[..end])
+        .ok()
+        .and_then(|line| line.split_once(' '))
+        .ok_or_else(|| corrupted("invalid checksum line".to_string()))?;
+    let json = &header[end + 1..];
+    if length.parse() != Ok(json.len()) {
+        return Err(corrupted(format!("it is {} bytes long, expected {length}", json.len())));
+    }
+    checksum::verify("snapshot", checksum, json)?;
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_transactions_file;
+    use crate::server::parse_transaction_line;
+    use crate::tx_processor::{LockedAccountPolicy, ProcessorConfig};
+
+    #[test]
+    fn test_snapshot_round_trip() -> GResult<()> {
+        let config = ProcessorConfig {
+            locked_account_policy: LockedAccountPolicy::Queue,
+            ..Default::default()
+        };
+        let mut processor = TxProcessor::with_config(config.clone());
+        processor.process_input(read_transactions_file("tests/rejections.csv")?)?;
+        let mut keyed = read_transactions_file("tests/example.csv")?.next().unwrap()?;
+        keyed.idempotency_key = Some("key-1".to_string());
+        processor.process_transaction(&mut keyed)?;
+        assert!(!processor.locked_queue.is_empty());
+        processor.process_transaction(&mut parse_transaction_line("deposit,3,98,1")?)?;
+        let outcome = processor.process_transaction(&mut parse_transaction_line("hold,3,99,0.5")?)?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        processor.process_transaction(&mut parse_transaction_line("deposit,4,97,1")?)?;
+        processor.merge_accounts(4, 3)?;
+        processor.run_id = Some("run-1".to_string());
+
+        let mut snapshot = vec![];
+        processor.save_snapshot(&mut snapshot)?;
+        let mut restored = TxProcessor::with_config(config);
+        restored.load_snapshot(snapshot.as_slice())?;
+
+        assert_eq!(restored.clients_balance, processor.clients_balance);
+        assert_eq!(restored.counters, processor.counters);
+        assert_eq!(restored.locked_queue, processor.locked_queue);
+        assert_eq!(restored.idempotency_outcomes, processor.idempotency_outcomes);
+        assert_eq!(restored.holds, processor.holds);
+        assert_eq!(restored.open_disputes, processor.open_disputes);
+        assert_eq!(restored.merged_into(4), Some(3));
+        assert_eq!(restored.run_id.as_deref(), Some("run-1"));
+        let mut snapshot_again = vec![];
+        restored.save_snapshot(&mut snapshot_again)?;
+        assert_eq!(snapshot_again, snapshot);
+
+        // Restored deposits can still be disputed.
+        let mut dispute = keyed.clone();
+        dispute.tx_type = crate::model::TxType::Dispute;
+        dispute.idempotency_key = None;
+        assert_eq!(restored.process_transaction(&mut dispute)?, TxOutcome::Applied);
+
+        let snapshot = String::from_utf8(snapshot).unwrap();
+        let (_, json) = snapshot.split_once('\n').unwrap();
+        // Snapshots saved without checksums are still loaded.
+        TxProcessor::new().load_snapshot(json.as_bytes())?;
+        let unsupported = json.replacen("\"version\":1", "\"version\":99", 1);
+        assert!(TxProcessor::new().load_snapshot(unsupported.as_bytes()).is_err());
+
+        let truncated = &snapshot[..snapshot.len() - 10];
+        let err = TxProcessor::new().load_snapshot(truncated.as_bytes()).unwrap_err().to_string();
+        assert!(err.starts_with("corrupted snapshot: it is "), "{err}");
+        let corrupted = snapshot.replacen("\"run_id\":\"run-1\"", "\"run_id\":\"run-2\"", 1);
+        let err = TxProcessor::new().load_snapshot(corrupted.as_bytes()).unwrap_err().to_string();
+        assert!(err.starts_with("corrupted snapshot: checksum is "), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Soak-test mode: generates transactions internally at a fixed rate for a long period, tracking
+//! processing latency and memory growth.
+
+use crate::amount::Amount;
+use crate::error::TxProcessorError;
+use crate::model::{ClientId, Transaction, TxAmount, TxId, TxType};
+use crate::tx_processor::TxProcessor;
+use crate::GResult;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct SoakConfig {
+    /// Target transactions per second.
+    pub rate: u64,
+    pub duration: Duration,
+    /// Number of distinct clients the load is spread over.
+    pub clients: ClientId,
+    /// How often `run_soak` reports progress.
+    pub report_interval: Duration,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            rate: 50_000,
+            duration: Duration::from_secs(60),
+            clients: 1000,
+            report_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakReport {
+    pub elapsed: Duration,
+    pub transactions: u64,
+    pub latency_p50: Duration,
+    pub latency_p99: Duration,
+    pub latency_p999: Duration,
+    pub latency_max: Duration,
+    /// Resident memory at start and at time of the report, if it can be determined on this platform.
+    pub rss_start_kb: Option<u64>,
+    pub rss_kb: Option<u64>,
+}
+
+impl std::fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rate = self.transactions as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "elapsed: {:.1}s, transactions: {}, rate: {:.0}/s, latency p50: {:?}, p99: {:?}, p99.9: {:?}, max: {:?}",
+            self.elapsed.as_secs_f64(),
+            self.transactions,
+            rate,
+            self.latency_p50,
+            self.latency_p99,
+            self.latency_p999,
+            self.latency_max,
+        )?;
+        if let (Some(start), Some(now)) = (self.rss_start_kb, self.rss_kb) {
+            write!(f, ", rss: {now} KiB (growth {} KiB)", now as i64 - start as i64)?;
+        }
+        Ok(())
+    }
+}
+
+/// Latency histogram with power-of-two nanosecond buckets, so memory stays constant however
+/// long the run is. Percentiles are reported as the upper bound of their bucket.
+struct LatencyHistogram {
+    buckets: [u64; 64],
+    count: u64,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        Self {
+            buckets: [0; 64],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = (64 - nanos.leading_zeros() as usize).min(63);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    fn percentile(&self, percentile: f64) -> Duration {
+        let target = ((self.count as f64) * percentile).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target && seen > 0 {
+                let upper_bound = Duration::from_nanos(1u64 << bucket.min(62));
+                return upper_bound.min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Deterministic xorshift generator, the load doesn't need to be cryptographically random.
+struct LoadGenerator {
+    state: u64,
+    next_tx_id: TxId,
+    clients: ClientId,
+}
+
+impl LoadGenerator {
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn next_transaction(&mut self) -> Transaction {
+        let random = self.next_random();
+        let client = (random % self.clients.max(1) as u64) as ClientId;
+        let tx_id = self.next_tx_id;
+        self.next_tx_id = self.next_tx_id.wrapping_add(1);
+        let amount: Option<TxAmount> = Some(Amount::from_f64(((random >> 16) % 100_000) as f64 / 100.0));
+        // Mostly deposits and withdrawals, with the occasional dispute lifecycle on an earlier tx.
+        let (tx_type, tx_id, amount) = match (random >> 8) % 100 {
+            0..=59 => (TxType::Deposit, tx_id, amount),
+            60..=93 => (TxType::Withdrawal, tx_id, amount),
+            kind => {
+                let referenced = tx_id.wrapping_sub(1 + (random >> 32) as TxId % 1000);
+                let tx_type = match kind {
+                    94..=96 => TxType::Dispute,
+                    97..=98 => TxType::Resolve,
+                    _ => TxType::Chargeback,
+                };
+                (tx_type, referenced, None)
+            }
+        };
+        Transaction::new(tx_type, client, tx_id, amount)
+    }
+}
+
+/// Resident set size of the current process, in KiB. Only available on Linux (assumes 4 KiB pages).
+pub fn current_rss_kb() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(resident_pages * 4)
+}
+
+/// Runs the soak test against an in-memory `TxProcessor`, calling `on_progress` every
+/// `report_interval`, and returns the final report.
+pub fn run_soak<F: FnMut(&SoakReport)>(config: &SoakConfig, mut on_progress: F) -> GResult<SoakReport> {
+    let mut tx_processor = TxProcessor::new();
+    let mut generator = LoadGenerator {
+        state: 0x2545_F491_4F6C_DD1D,
+        next_tx_id: 1,
+        clients: config.clients,
+    };
+    let mut histogram = LatencyHistogram::new();
+    let rss_start_kb = current_rss_kb();
+    let start = Instant::now();
+    let mut next_report = config.report_interval;
+    let mut transactions: u64 = 0;
+
+    let report = |histogram: &LatencyHistogram, transactions: u64| SoakReport {
+        elapsed: start.elapsed(),
+        transactions,
+        latency_p50: histogram.percentile(0.5),
+        latency_p99: histogram.percentile(0.99),
+        latency_p999: histogram.percentile(0.999),
+        latency_max: histogram.max,
+        rss_start_kb,
+        rss_kb: current_rss_kb(),
+    };
+
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed >= config.duration {
+            break;
+        }
+        if elapsed >= next_report {
+            on_progress(&report(&histogram, transactions));
+            next_report += config.report_interval;
+        }
+
+        let due = (elapsed.as_secs_f64() * config.rate as f64) as u64;
+        if transactions >= due {
+            std::thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        for _ in transactions..due {
+            let mut tx = generator.next_transaction();
+            let tx_start = Instant::now();
+            tx_processor.process_transaction(&mut tx)?;
+            histogram.record(tx_start.elapsed());
+        }
+        transactions = due;
+    }
+
+    Ok(report(&histogram, transactions))
+}
+
+/// Parses a rate such as `50000`, `50k/s` or `1.5m/s` into transactions per second.
+pub fn parse_rate(rate: &str) -> GResult<u64> {
+    let invalid = || TxProcessorError::Parse {
+        field: "rate",
+        message: format!("invalid rate `{rate}`"),
+    };
+    let rate = rate.trim().to_ascii_lowercase();
+    let rate = rate.strip_suffix("/s").unwrap_or(&rate);
+    let (number, multiplier) = match rate.chars().last() {
+        Some('k') => (&rate[..rate.len() - 1], 1_000.0),
+        Some('m') => (&rate[..rate.len() - 1], 1_000_000.0),
+        _ => (rate, 1.0),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(invalid());
+    }
+    Ok((number * multiplier) as u64)
+}
+
+/// Parses a duration such as `90`, `90s`, `15m`, `24h` or `2d`.
+pub fn parse_duration(duration: &str) -> GResult<Duration> {
+    let invalid = || TxProcessorError::Parse {
+        field: "duration",
+        message: format!("invalid duration `{duration}`"),
+    };
+    let duration = duration.trim();
+    let (number, unit_secs) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1.0),
+        Some('m') => (&duration[..duration.len() - 1], 60.0),
+        Some('h') => (&duration[..duration.len() - 1], 3600.0),
+        Some('d') => (&duration[..duration.len() - 1], 86400.0),
+        _ => (duration, 1.0),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs_f64(number * unit_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_and_duration() -> GResult<()> {
+        assert_eq!(parse_rate("50k/s")?, 50_000);
+        assert_eq!(parse_rate("1.5M/s")?, 1_500_000);
+        assert_eq!(parse_rate("200")?, 200);
+        parse_rate("fast").unwrap_err();
+        parse_rate("-5k/s").unwrap_err();
+
+        assert_eq!(parse_duration("24h")?, Duration::from_secs(24 * 3600));
+        assert_eq!(parse_duration("15m")?, Duration::from_secs(900));
+        assert_eq!(parse_duration("1.5s")?, Duration::from_millis(1500));
+        assert_eq!(parse_duration("30")?, Duration::from_secs(30));
+        parse_duration("soon").unwrap_err();
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_soak() -> GResult<()> {
+        let config = SoakConfig {
+            rate: 20_000,
+            duration: Duration::from_millis(300),
+            clients: 10,
+            report_interval: Duration::from_millis(100),
+        };
+        let mut progress_reports = 0;
+        let report = run_soak(&config, |_| progress_reports += 1)?;
+
+        assert!(progress_reports >= 1);
+        assert!(report.transactions > 0);
+        assert!(report.transactions <= 20_000 * 300 / 1000);
+        assert!(report.latency_p50 <= report.latency_p99);
+        assert!(report.latency_p99 <= report.latency_max);
+        Ok(())
+    }
+}
@@ -0,0 +1,562 @@
+//! Storage of deposit and withdrawal amounts by transaction id, which disputes, resolves and
+//! chargebacks look up. The default is an in-memory map, which grows with every deposit and
+//! withdrawal.
+//!
+//! The rest of a processor's state (balances, holds, ...) is kept in the maps of its `Stores`,
+//! chosen at compile time: `HashStores` (the default) for speed, or `BTreeStores` for runs that
+//! iterate their state in a deterministic order.
+//!
+//! A disk-backed store is best wrapped in a `CachedTxStore`, so that the transactions of recently
+//! active accounts, which skewed workloads dispute again and again, are looked up in memory.
+
+use crate::amount::Amount;
+use crate::model::{ClientId, TxAmount, TxId};
+use crate::GResult;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Whether a stored transaction added funds to the account or removed them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Direction {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct StoredTx<A = TxAmount> {
+    pub amount: A,
+    pub direction: Direction,
+    /// Client of the transaction. Unknown for transactions restored from snapshots saved before
+    /// clients were stored.
+    pub client: Option<ClientId>,
+}
+
+/// Stored transactions by id, with amounts of type `A`. The in-memory stores take any amount
+/// type, the disk-backed ones only `TxAmount`.
+pub trait TxStore<A: Amount = TxAmount>: Send {
+    fn get(&self, tx_id: TxId) -> GResult<Option<StoredTx<A>>>;
+    fn insert(&mut self, tx_id: TxId, stored: StoredTx<A>) -> GResult<()>;
+    fn remove(&mut self, tx_id: TxId) -> GResult<()>;
+    /// All stored transactions, in no particular order.
+    fn entries(&self) -> Box<dyn Iterator<Item = GResult<(TxId, StoredTx<A>)>> + '_>;
+
+    /// Writes out transactions the store still buffers, once processing is done.
+    fn flush(&mut self) -> GResult<()> {
+        Ok(())
+    }
+}
+
+impl<A: Amount> TxStore<A> for HashMap<TxId, StoredTx<A>> {
+    fn get(&self, tx_id: TxId) -> GResult<Option<StoredTx<A>>> {
+        Ok(HashMap::get(self, &tx_id).copied())
+    }
+
+    fn insert(&mut self, tx_id: TxId, stored: StoredTx<A>) -> GResult<()> {
+        HashMap::insert(self, tx_id, stored);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: TxId) -> GResult<()> {
+        HashMap::remove(self, &tx_id);
+        Ok(())
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = GResult<(TxId, StoredTx<A>)>> + '_> {
+        Box::new(self.iter().map(|(tx_id, stored)| Ok((*tx_id, *stored))))
+    }
+}
+
+impl<A: Amount> TxStore<A> for BTreeMap<TxId, StoredTx<A>> {
+    fn get(&self, tx_id: TxId) -> GResult<Option<StoredTx<A>>> {
+        Ok(BTreeMap::get(self, &tx_id).copied())
+    }
+
+    fn insert(&mut self, tx_id: TxId, stored: StoredTx<A>) -> GResult<()> {
+        BTreeMap::insert(self, tx_id, stored);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: TxId) -> GResult<()> {
+        BTreeMap::remove(self, &tx_id);
+        Ok(())
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = GResult<(TxId, StoredTx<A>)>> + '_> {
+        Box::new(self.iter().map(|(tx_id, stored)| Ok((*tx_id, *stored))))
+    }
+}
+
+/// A write-back LRU cache of the `capacity` most recently used transactions of the `inner` store.
+/// Inserts are kept in the cache, and only written to the store when they are evicted, or when
+/// the cache is flushed. Lookups that miss are cached too, if there is room or the least recently
+/// used transaction was already written out, since lookups can't write to the store.
+///
+/// A clone shares the store of a clone-able one (ie `SledTxStore`), but starts with an empty
+/// cache, so it is meant to be taken before the cache is used.
+pub struct CachedTxStore<T> {
+    inner: T,
+    capacity: usize,
+    cache: RefCell<LruCache>,
+}
+
+#[derive(Debug, Default)]
+struct LruCache {
+    entries: HashMap<TxId, CachedTx>,
+    /// Cached transaction ids by last use.
+    recency: BTreeMap<u64, TxId>,
+    uses: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedTx {
+    stored: StoredTx,
+    /// Not written to the store yet.
+    dirty: bool,
+    last_use: u64,
+}
+
+impl LruCache {
+    fn touch(&mut self, tx_id: TxId) -> Option<StoredTx> {
+        let cached = self.entries.get_mut(&tx_id)?;
+        self.recency.remove(&cached.last_use);
+        self.uses += 1;
+        cached.last_use = self.uses;
+        self.recency.insert(self.uses, tx_id);
+        Some(cached.stored)
+    }
+
+    fn put(&mut self, tx_id: TxId, stored: StoredTx, dirty: bool) {
+        self.uses += 1;
+        let cached = CachedTx { stored, dirty, last_use: self.uses };
+        if let Some(replaced) = self.entries.insert(tx_id, cached) {
+            self.recency.remove(&replaced.last_use);
+        }
+        self.recency.insert(self.uses, tx_id);
+    }
+
+    fn least_recently_used(&self) -> Option<(TxId, CachedTx)> {
+        let (_, tx_id) = self.recency.first_key_value()?;
+        Some((*tx_id, self.entries[tx_id]))
+    }
+
+    fn evict(&mut self, tx_id: TxId) {
+        if let Some(cached) = self.entries.remove(&tx_id) {
+            self.recency.remove(&cached.last_use);
+        }
+    }
+}
+
+impl<T: TxStore> CachedTxStore<T> {
+    /// Caches up to `capacity` transactions of `inner`, none if 0.
+    pub fn new(inner: T, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            cache: RefCell::new(LruCache::default()),
+        }
+    }
+
+    /// Transactions inserted but not written to the store yet.
+    fn dirty(&self) -> HashMap<TxId, StoredTx> {
+        let cache = self.cache.borrow();
+        cache.entries.iter().filter(|(_, cached)| cached.dirty).map(|(tx_id, cached)| (*tx_id, cached.stored)).collect()
+    }
+}
+
+impl<T: TxStore + Clone> Clone for CachedTxStore<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone(), self.capacity)
+    }
+}
+
+impl<T: TxStore> TxStore for CachedTxStore<T> {
+    fn get(&self, tx_id: TxId) -> GResult<Option<StoredTx>> {
+        let mut cache = self.cache.borrow_mut();
+        if let Some(stored) = cache.touch(tx_id) {
+            return Ok(Some(stored));
+        }
+        let stored = self.inner.get(tx_id)?;
+        if let Some(stored) = stored.filter(|_| self.capacity > 0) {
+            if cache.entries.len() >= self.capacity {
+                match cache.least_recently_used() {
+                    Some((evicted, cached)) if !cached.dirty => cache.evict(evicted),
+                    _ => return Ok(Some(stored)),
+                }
+            }
+            cache.put(tx_id, stored, false);
+        }
+        Ok(stored)
+    }
+
+    fn insert(&mut self, tx_id: TxId, stored: StoredTx) -> GResult<()> {
+        if self.capacity == 0 {
+            return self.inner.insert(tx_id, stored);
+        }
+        let cache = self.cache.get_mut();
+        cache.put(tx_id, stored, true);
+        while cache.entries.len() > self.capacity {
+            let (evicted, cached) = cache.least_recently_used().expect("the cache is over capacity");
+            if cached.dirty {
+                self.inner.insert(evicted, cached.stored)?;
+            }
+            cache.evict(evicted);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, tx_id: TxId) -> GResult<()> {
+        self.cache.get_mut().evict(tx_id);
+        self.inner.remove(tx_id)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = GResult<(TxId, StoredTx)>> + '_> {
+        let dirty = self.dirty();
+        let written = self.inner.entries().filter(move |entry| !matches!(entry, Ok((tx_id, _)) if dirty.contains_key(tx_id)));
+        Box::new(written.chain(self.dirty().into_iter().map(Ok)))
+    }
+
+    fn flush(&mut self) -> GResult<()> {
+        for (tx_id, cached) in self.cache.get_mut().entries.iter_mut() {
+            if cached.dirty {
+                self.inner.insert(*tx_id, cached.stored)?;
+                cached.dirty = false;
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+/// The map types a `TxProcessor` keeps its state in, see `TxProcessorBuilder::stores`.
+pub trait Stores: Send + 'static {
+    type Map<K: Clone + Eq + Hash + Ord + Send + 'static, V: Send + 'static>: StateMap<K, V>;
+
+    /// The store of transaction amounts, unless another one is set with
+    /// `TxProcessorBuilder::tx_store` (ie a disk-backed one).
+    fn tx_store<A: Amount>() -> Box<dyn TxStore<A>>;
+}
+
+/// `HashMap`s: the fastest, but iteration order varies between runs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashStores;
+
+impl Stores for HashStores {
+    type Map<K: Clone + Eq + Hash + Ord + Send + 'static, V: Send + 'static> = HashMap<K, V>;
+
+    fn tx_store<A: Amount>() -> Box<dyn TxStore<A>> {
+        Box::new(HashMap::new())
+    }
+}
+
+/// `BTreeMap`s: state is iterated in key order (ie balances by client), so that runs over the same
+/// input are reproducible.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BTreeStores;
+
+impl Stores for BTreeStores {
+    type Map<K: Clone + Eq + Hash + Ord + Send + 'static, V: Send + 'static> = BTreeMap<K, V>;
+
+    fn tx_store<A: Amount>() -> Box<dyn TxStore<A>> {
+        Box::new(BTreeMap::new())
+    }
+}
+
+/// The map operations the processor uses, implemented by `HashMap` and `BTreeMap`.
+pub trait StateMap<K, V>: Default + Send + FromIterator<(K, V)> {
+    fn get(&self, key: &K) -> Option<&V>;
+    fn get_mut(&mut self, key: &K) -> Option<&mut V>;
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+    fn remove(&mut self, key: &K) -> Option<V>;
+    /// The value for `key`, inserted with `default` if missing.
+    fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, default: F) -> &mut V;
+    fn len(&self) -> usize;
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a;
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn keys<'a>(&'a self) -> impl Iterator<Item = &'a K>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        self.iter().map(|(key, _)| key)
+    }
+
+    fn values<'a>(&'a self) -> impl Iterator<Item = &'a V>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+impl<K: Eq + Hash + Send, V: Send> StateMap<K, V> for HashMap<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        HashMap::remove(self, key)
+    }
+
+    fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, default: F) -> &mut V {
+        self.entry(key).or_insert_with(default)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        HashMap::iter(self)
+    }
+}
+
+impl<K: Ord + Send, V: Send> StateMap<K, V> for BTreeMap<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        BTreeMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        BTreeMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, default: F) -> &mut V {
+        self.entry(key).or_insert_with(default)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        BTreeMap::iter(self)
+    }
+}
+
+#[cfg(feature = "sled")]
+pub use sled_store::SledTxStore;
+
+#[cfg(feature = "sled")]
+mod sled_store {
+    use super::*;
+
+    /// Memory sled may use for its page cache, the rest of the store stays on disk.
+    const CACHE_CAPACITY: u64 = 64 * 1024 * 1024;
+
+    /// Transaction amounts stored on disk with sled, so memory stays bounded however many
+    /// deposits there are. The directory is scratch space for one run, it is removed when the
+    /// store is dropped. Clones share the same store.
+    #[derive(Clone)]
+    pub struct SledTxStore {
+        tree: sled::Db,
+    }
+
+    impl SledTxStore {
+        pub fn open(path: &str) -> GResult<Self> {
+            let tree = sled::Config::new()
+                .path(path)
+                .temporary(true)
+                .cache_capacity(CACHE_CAPACITY)
+                .open()?;
+            Ok(Self { tree })
+        }
+    }
+
+    impl TxStore for SledTxStore {
+        fn get(&self, tx_id: TxId) -> GResult<Option<StoredTx>> {
+            let value = self.tree.get(tx_id.to_be_bytes())?;
+            Ok(value.map(|bytes| decode_stored(&bytes)))
+        }
+
+        fn insert(&mut self, tx_id: TxId, stored: StoredTx) -> GResult<()> {
+            self.tree.insert(tx_id.to_be_bytes(), encode_stored(stored))?;
+            Ok(())
+        }
+
+        fn remove(&mut self, tx_id: TxId) -> GResult<()> {
+            self.tree.remove(tx_id.to_be_bytes())?;
+            Ok(())
+        }
+
+        fn entries(&self) -> Box<dyn Iterator<Item = GResult<(TxId, StoredTx)>> + '_> {
+            Box::new(self.tree.iter().map(|entry| {
+                let (key, value) = entry?;
+                let key: [u8; 4] = key.as_ref().try_into().expect("stored keys are 4 bytes");
+                Ok((TxId::from_be_bytes(key), decode_stored(&value)))
+            }))
+        }
+
+        fn flush(&mut self) -> GResult<()> {
+            self.tree.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// A stored transaction as bytes, for the disk-backed and Redis stores: the amount as
+/// little-endian bytes, followed by a byte for the direction and, if known, the client as
+/// little-endian bytes.
+#[cfg(any(feature = "sled", feature = "redis"))]
+pub(crate) fn encode_stored(stored: StoredTx) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(11);
+    bytes.extend_from_slice(&stored.amount.to_le_bytes());
+    bytes.push(match stored.direction {
+        Direction::Deposit => 0,
+        Direction::Withdrawal => 1,
+    });
+    if let Some(client) = stored.client {
+        bytes.extend_from_slice(&client.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(any(feature = "sled", feature = "redis"))]
+pub(crate) fn decode_stored(bytes: &[u8]) -> StoredTx {
+    let (amount, rest) = bytes.split_at(8);
+    let amount: [u8; 8] = amount.try_into().expect("stored amounts are 8 bytes");
+    let (direction, client) = rest.split_at(1);
+    let direction = match direction {
+        [0] => Direction::Deposit,
+        _ => Direction::Withdrawal,
+    };
+    StoredTx {
+        amount: TxAmount::from_le_bytes(amount),
+        direction,
+        client: client.try_into().ok().map(ClientId::from_le_bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+
+    fn check_store(store: &mut dyn TxStore) -> GResult<()> {
+        let deposit = StoredTx {
+            amount: amount(10.5),
+            direction: Direction::Deposit,
+            client: Some(7),
+        };
+        let withdrawal = StoredTx {
+            amount: amount(0.25),
+            direction: Direction::Withdrawal,
+            client: None,
+        };
+        assert_eq!(store.get(1)?, None);
+        store.insert(1, deposit)?;
+        store.insert(u32::MAX, withdrawal)?;
+        assert_eq!(store.get(1)?, Some(deposit));
+        assert_eq!(store.get(u32::MAX)?, Some(withdrawal));
+        let mut entries = store.entries().collect::<GResult<Vec<_>>>()?;
+        entries.sort_by_key(|(tx_id, _)| *tx_id);
+        assert_eq!(entries, vec![(1, deposit), (u32::MAX, withdrawal)]);
+        store.remove(1)?;
+        store.remove(2)?;
+        assert_eq!(store.get(1)?, None);
+        assert_eq!(store.entries().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_map_store() -> GResult<()> {
+        check_store(&mut HashMap::new())
+    }
+
+    #[test]
+    fn test_cached_store() -> GResult<()> {
+        check_store(&mut CachedTxStore::new(HashMap::new(), 1))?;
+        check_store(&mut CachedTxStore::new(HashMap::new(), 0))?;
+
+        let deposit = |amount| StoredTx {
+            amount,
+            direction: Direction::Deposit,
+            client: Some(1),
+        };
+        let mut store = CachedTxStore::new(HashMap::new(), 2);
+        store.insert(1, deposit(amount(1.0)))?;
+        store.insert(2, deposit(amount(2.0)))?;
+        assert_eq!(store.get(1)?, Some(deposit(amount(1.0))));
+        // 2 is the least recently used, it is written back.
+        store.insert(3, deposit(amount(3.0)))?;
+        assert_eq!(store.inner, HashMap::from([(2, deposit(amount(2.0)))]));
+        // A cached lookup can only evict a transaction that was written back.
+        assert_eq!(store.get(2)?, Some(deposit(amount(2.0))));
+        assert!(!store.cache.borrow().entries.contains_key(&2));
+        store.insert(1, deposit(amount(1.5)))?;
+        assert_eq!(store.entries().count(), 3);
+        assert_eq!(store.inner.len(), 1);
+
+        store.flush()?;
+        assert_eq!(store.inner, HashMap::from([(1, deposit(amount(1.5))), (2, deposit(amount(2.0))), (3, deposit(amount(3.0)))]));
+        assert_eq!(store.get(2)?, Some(deposit(amount(2.0))));
+        assert!(store.cache.borrow().entries.contains_key(&2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_btree_stores() -> GResult<()> {
+        use crate::read_transactions_file;
+        use crate::tx_processor::TxProcessor;
+
+        let mut hashed = TxProcessor::new();
+        hashed.process_input(read_transactions_file("tests/example.csv")?)?;
+        let mut ordered = TxProcessor::builder().stores::<BTreeStores>().build();
+        let balances = ordered.process_input(read_transactions_file("tests/example.csv")?)?;
+        let clients: Vec<_> = balances.keys().copied().collect();
+        assert_eq!(clients, vec![1, 2]);
+
+        // The same state, whichever maps hold it.
+        let (mut hashed_snapshot, mut ordered_snapshot) = (vec![], vec![]);
+        hashed.save_snapshot(&mut hashed_snapshot)?;
+        ordered.save_snapshot(&mut ordered_snapshot)?;
+        assert_eq!(hashed_snapshot, ordered_snapshot);
+        Ok(())
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_sled_store() -> GResult<()> {
+        let path = std::env::temp_dir().join(format!("tx_store_test_{}", std::process::id()));
+        let mut store = SledTxStore::open(path.to_str().unwrap())?;
+        check_store(&mut store)?;
+        drop(store);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Machine-readable summary of a run, written as JSON alongside the balances, for orchestration
+//! to decide whether to promote the output: the SHA-256 of each input file and of the output,
+//! transactions by type, rejections by reason, accounts opened and locked, and timing.
+//!
+//! The summary is written once the output is complete, so a run that failed leaves none. Unlike
+//! the manifest (see `manifest`), it isn't signed.
+
+use crate::digest::FileDigest;
+use crate::model::TxType;
+use crate::{GResult, ProcessReport};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RunSummary {
+    pub engine: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    pub inputs: Vec<FileDigest>,
+    pub output: FileDigest,
+    /// Transactions processed, by type, see `ProcessReport::transactions`.
+    pub transactions: BTreeMap<TxType, u64>,
+    pub applied: u64,
+    pub queued: u64,
+    /// Rejected transactions, by reason code (see `RejectReason::code`).
+    pub rejections: BTreeMap<String, u64>,
+    pub malformed: u64,
+    /// Clients that had at least one transaction processed.
+    pub clients: u64,
+    pub accounts_created: u64,
+    pub accounts_locked: u64,
+    /// When processing started and finished, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// Time spent processing.
+    pub elapsed_ms: u64,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_millis() as u64
+}
+
+impl RunSummary {
+    /// The summary of a run that started at `started_at`, read `inputs` and wrote `output`.
+    pub fn new(started_at: SystemTime, inputs: Vec<FileDigest>, output: FileDigest, report: &ProcessReport) -> Self {
+        Self {
+            engine: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            run_id: report.run_id.clone(),
+            inputs,
+            output,
+            transactions: report.transactions.clone(),
+            applied: report.applied,
+            queued: report.queued,
+            rejections: report.rejections.iter().map(|(code, count)| (code.to_string(), *count)).collect(),
+            malformed: report.malformed,
+            clients: report.clients_touched.len() as u64,
+            accounts_created: report.accounts_created,
+            accounts_locked: report.accounts_locked,
+            started_at: unix_millis(started_at),
+            finished_at: unix_millis(SystemTime::now()),
+            elapsed_ms: report.elapsed.as_millis() as u64,
+        }
+    }
+}
+
+/// Writes the summary to the file at `path`, replaced atomically so that orchestration never reads
+/// a partial summary.
+pub fn write_summary(path: &str, summary: &RunSummary) -> GResult<()> {
+    let partial_path = format!("{path}.partial");
+    let mut out = io::BufWriter::new(std::fs::File::create(&partial_path)?);
+    serde_json::to_writer_pretty(&mut out, summary)?;
+    writeln!(out)?;
+    out.flush()?;
+    drop(out);
+    std::fs::rename(partial_path, path)?;
+    Ok(())
+}
+
+pub fn read_summary(path: &str) -> GResult<RunSummary> {
+    Ok(serde_json::from_reader(io::BufReader::new(std::fs::File::open(path)?))?)
+}
@@ -0,0 +1,281 @@
+//! Fixtures for testing code that drives a `TxProcessor` (`test-utils` feature): transaction
+//! builders, balance assertions, and `Scenario`, a small DSL that applies transactions one by one
+//! and checks outcomes and balances as it goes:
+//!
+//! ```
+//! use tx_processor::test_support::*;
+//!
+//! Scenario::new()
+//!     .tx(deposit(1, 1, 10.0))
+//!     .tx(withdrawal(1, 2, 4.0))
+//!     .rejected(withdrawal(1, 3, 7.0), "insufficient_funds")
+//!     .tx(dispute(1, 1))
+//!     .balance(1, -4.0, 10.0, false);
+//! ```
+//!
+//! Amounts are given as `f64`, whatever the amount type (see `amount`), and compared once rounded
+//! to `AMOUNT_DECIMALS`. Assertions panic, reporting the caller's location.
+
+use crate::amount::Amount;
+use crate::error::{ErrorHandler, TxProcessorError};
+use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId, TxType, AMOUNT_DECIMALS};
+use crate::tx_processor::{ProcessorConfig, TxOutcome, TxProcessor};
+use crate::GResult;
+use std::sync::{Arc, Mutex};
+
+/// `amount` as a `TxAmount`, for checks the builders and assertions here don't cover.
+pub fn amount(amount: f64) -> TxAmount {
+    TxAmount::from_f64(amount)
+}
+
+/// A transaction of `tx_type`, without idempotency key, findings or tags.
+pub fn transaction(tx_type: TxType, client: ClientId, tx_id: TxId, amount: Option<f64>) -> Transaction {
+    Transaction::new(tx_type, client, tx_id, amount.map(TxAmount::from_f64))
+}
+
+pub fn deposit(client: ClientId, tx_id: TxId, amount: f64) -> Transaction {
+    transaction(TxType::Deposit, client, tx_id, Some(amount))
+}
+
+/// A deposit that is held until it clears, see `clearing`.
+pub fn pending_deposit(client: ClientId, tx_id: TxId, amount: f64) -> Transaction {
+    Transaction {
+        pending: true,
+        ..deposit(client, tx_id, amount)
+    }
+}
+
+pub fn clear(client: ClientId, tx_id: TxId) -> Transaction {
+    transaction(TxType::Clear, client, tx_id, None)
+}
+
+pub fn withdrawal(client: ClientId, tx_id: TxId, amount: f64) -> Transaction {
+    transaction(TxType::Withdrawal, client, tx_id, Some(amount))
+}
+
+pub fn dispute(client: ClientId, tx_id: TxId) -> Transaction {
+    transaction(TxType::Dispute, client, tx_id, None)
+}
+
+pub fn resolve(client: ClientId, tx_id: TxId) -> Transaction {
+    transaction(TxType::Resolve, client, tx_id, None)
+}
+
+pub fn chargeback(client: ClientId, tx_id: TxId) -> Transaction {
+    transaction(TxType::Chargeback, client, tx_id, None)
+}
+
+pub fn hold(client: ClientId, tx_id: TxId, amount: f64) -> Transaction {
+    transaction(TxType::Hold, client, tx_id, Some(amount))
+}
+
+pub fn release(client: ClientId, tx_id: TxId) -> Transaction {
+    transaction(TxType::Release, client, tx_id, None)
+}
+
+/// Captures `amount`, or all of the hold if `None`.
+pub fn capture(client: ClientId, tx_id: TxId, amount: Option<f64>) -> Transaction {
+    transaction(TxType::Capture, client, tx_id, amount)
+}
+
+/// Refunds `amount`, or all of the withdrawal if `None`.
+pub fn refund(client: ClientId, tx_id: TxId, amount: Option<f64>) -> Transaction {
+    transaction(TxType::Refund, client, tx_id, amount)
+}
+
+pub fn settle() -> Transaction {
+    transaction(TxType::Settle, 0, 0, None)
+}
+
+pub fn freeze(client: ClientId) -> Transaction {
+    transaction(TxType::Freeze, client, 0, None)
+}
+
+pub fn unfreeze(client: ClientId) -> Transaction {
+    transaction(TxType::Unfreeze, client, 0, None)
+}
+
+/// The same transaction, with an idempotency key.
+pub fn with_key(tx: Transaction, key: &str) -> Transaction {
+    Transaction {
+        idempotency_key: Some(key.to_string()),
+        ..tx
+    }
+}
+
+/// An input record that failed to parse with `err`, as the input readers report it: `raw` on
+/// line `line`, after the header.
+pub fn malformed(line: u64, raw: &str, err: TxProcessorError) -> GResult<Transaction> {
+    Err(TxProcessorError::InvalidRecord {
+        line,
+        record: line - 1,
+        raw: raw.to_string(),
+        source: Box::new(err),
+    })
+}
+
+/// An `ErrorHandler` that collects the errors reported to it, as `<context>: <error>`.
+pub fn collected_errors() -> (ErrorHandler, Arc<Mutex<Vec<String>>>) {
+    let errors = Arc::new(Mutex::new(vec![]));
+    let collected = Arc::clone(&errors);
+    let handler: ErrorHandler = Arc::new(move |context, err| {
+        collected.lock().unwrap().push(format!("{context}: {err}"));
+    });
+    (handler, errors)
+}
+
+fn rounded(amount: TxAmount) -> TxAmount {
+    amount.round_dp(AMOUNT_DECIMALS)
+}
+
+/// Asserts the balance of `client`, an empty one if the processor hasn't seen it. The total is
+/// checked to be `available + held`. Whether the account is frozen, and its pending funds, aren't
+/// checked.
+#[track_caller]
+pub fn assert_balance(processor: &TxProcessor, client: ClientId, available: f64, held: f64, locked: bool) {
+    let actual = processor.balance_of(client).cloned().unwrap_or_else(|| ClientBalance::new_empty(client));
+    let expected = ClientBalance {
+        client,
+        available: rounded(TxAmount::from_f64(available)),
+        held: rounded(TxAmount::from_f64(held)),
+        total: rounded(TxAmount::from_f64(available + held)),
+        locked,
+        frozen: actual.frozen,
+        pending: actual.pending,
+    };
+    let actual = ClientBalance {
+        available: rounded(actual.available),
+        held: rounded(actual.held),
+        total: rounded(actual.total),
+        ..actual
+    };
+    assert_eq!(actual, expected, "balance of client {client}");
+}
+
+/// A processor that transactions are applied to one at a time, with checks in between, see the
+/// module docs.
+pub struct Scenario {
+    processor: TxProcessor,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::with_config(ProcessorConfig::default())
+    }
+
+    pub fn with_config(config: ProcessorConfig) -> Self {
+        Self::from_processor(TxProcessor::with_config(config))
+    }
+
+    /// A scenario over a processor already set up, with sinks or a store.
+    pub fn from_processor(processor: TxProcessor) -> Self {
+        Self { processor }
+    }
+
+    /// Applies `tx`, asserting that it is applied.
+    #[track_caller]
+    pub fn tx(self, tx: Transaction) -> Self {
+        self.outcome(tx, TxOutcome::Applied)
+    }
+
+    /// Applies `tx`, asserting its outcome.
+    #[track_caller]
+    pub fn outcome(mut self, mut tx: Transaction, expected: TxOutcome) -> Self {
+        match self.processor.process_transaction(&mut tx) {
+            Ok(outcome) => assert_eq!(outcome, expected, "outcome of {tx:?}"),
+            Err(err) => panic!("{tx:?} failed: {err}"),
+        }
+        self
+    }
+
+    /// Applies `tx`, asserting that it is rejected with the reason `code` (see `RejectReason::code`).
+    #[track_caller]
+    pub fn rejected(mut self, mut tx: Transaction, code: &str) -> Self {
+        match self.processor.process_transaction(&mut tx) {
+            Ok(TxOutcome::Rejected(reason)) => assert_eq!(reason.code(), code, "rejection of {tx:?}: {reason}"),
+            Ok(outcome) => panic!("{tx:?} was expected to be rejected with {code}, but was {outcome}"),
+            Err(err) => panic!("{tx:?} failed: {err}"),
+        }
+        self
+    }
+
+    /// Asserts the balance of `client`, see `assert_balance`.
+    #[track_caller]
+    pub fn balance(self, client: ClientId, available: f64, held: f64, locked: bool) -> Self {
+        assert_balance(&self.processor, client, available, held, locked);
+        self
+    }
+
+    /// Asserts whether the account of `client` is frozen.
+    #[track_caller]
+    pub fn frozen(self, client: ClientId, frozen: bool) -> Self {
+        let actual = self.processor.balance_of(client).is_some_and(|balance| balance.frozen);
+        assert_eq!(actual, frozen, "frozen account of client {client}");
+        self
+    }
+
+    /// Asserts the funds of `client` held until deposits clear.
+    #[track_caller]
+    pub fn pending(self, client: ClientId, pending: f64) -> Self {
+        let actual = self.processor.balance_of(client).map_or_else(TxAmount::default, |balance| balance.pending);
+        assert_eq!(rounded(actual), rounded(TxAmount::from_f64(pending)), "pending funds of client {client}");
+        self
+    }
+
+    /// The processor, to check anything else.
+    pub fn processor(&self) -> &TxProcessor {
+        &self.processor
+    }
+
+    pub fn into_processor(self) -> TxProcessor {
+        self.processor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RejectReason;
+    use crate::tx_processor::DisputeFundsPolicy;
+
+    #[test]
+    fn test_scenario() {
+        let scenario = Scenario::new()
+            .tx(deposit(1, 1, 10.0))
+            .tx(withdrawal(1, 2, 4.0))
+            .rejected(withdrawal(1, 3, 7.0), "insufficient_funds")
+            .tx(dispute(1, 1))
+            .balance(1, -4.0, 10.0, false)
+            .tx(chargeback(1, 1))
+            .balance(1, -4.0, 0.0, true)
+            .outcome(deposit(1, 4, 1.0), TxOutcome::Rejected(RejectReason::LockedAccount(1)))
+            .balance(2, 0.0, 0.0, false);
+        assert_eq!(scenario.processor().balances().count(), 1);
+
+        Scenario::with_config(ProcessorConfig {
+            dispute_funds_policy: DisputeFundsPolicy::Reject,
+            ..Default::default()
+        })
+        .tx(deposit(2, 1, 5.0))
+        .tx(withdrawal(2, 2, 3.0))
+        .rejected(dispute(2, 1), "insufficient_funds")
+        .tx(with_key(deposit(2, 3, 0.1), "retry"))
+        .tx(with_key(deposit(2, 3, 0.1), "retry"))
+        .tx(hold(2, 4, 1.0))
+        .balance(2, 1.1, 1.0, false)
+        .tx(capture(2, 4, Some(0.5)))
+        .balance(2, 1.6, 0.0, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "balance of client 1")]
+    fn test_assert_balance() {
+        Scenario::new().tx(deposit(1, 1, 10.0)).balance(1, 9.0, 0.0, false);
+    }
+}
@@ -1,67 +1,978 @@
-use crate::model::{ClientBalance, ClientId, Transaction, TxAmount, TxId, TxType};
+//! The processing core: `TxProcessor` applies transactions to client balances, without any I/O.
+//! Transactions come from any iterator, and what the processor records as it goes (journal,
+//! audit trail, notifications) goes to the `JournalSink`, `AuditSink` and `NotificationSink` it is
+//! given. File and CSV input, outputs and the sinks writing to files or the network live in the I/O
+//! layer (ie `input`, `output`, `sink`, `journal`, `audit`, `webhooks`), so the core can be
+//! embedded anywhere (ie WASM, FFI, async services).
+
+use crate::amount::Amount;
+use crate::clearing::{self, ClearingPeriod, PendingDeposit};
+use crate::error::{RejectReason, TxProcessorError};
+use crate::model::{BalanceUpdate, ClientBalance, ClientId, OverflowPolicy, Transaction, TxAmount, TxId, TxType};
+use crate::history::HistoryEvent;
+use crate::holds::{take_hold, Hold};
+use crate::ledger::LedgerEntry;
+use crate::settlement::{DisputeTimeoutAction, OpenDispute, Settlement};
+use crate::store::{Direction, HashStores, StateMap, Stores, StoredTx, TxStore};
+use crate::validation::{run_validators, Finding, Severity, Validator};
 use crate::GResult;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, VecDeque};
+use std::marker::PhantomData;
+use std::fmt::{self, Display, Formatter};
+use strum_macros::EnumString;
+
+/// What to do with deposits and withdrawals for an account that has been locked by a chargeback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockedAccountPolicy {
+    /// The transaction is dropped (and counted in `ProcessorCounters::locked_rejected`).
+    #[default]
+    Reject,
+    /// The transaction is kept aside in `locked_queue`, so it can be inspected or replayed later.
+    Queue,
+}
+
+/// What to do with a dispute of a deposit that is more than the client's available funds, ie
+/// because the deposit was already spent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum DisputeFundsPolicy {
+    /// The whole amount is held, leaving available funds negative.
+    #[default]
+    AllowNegative,
+    /// Only what is available is held. The resolve or chargeback then moves that capped amount.
+    Cap,
+    /// The dispute is rejected for insufficient funds.
+    Reject,
+}
+
+/// What to do with input records that can't be parsed into a transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive, serialize_all = "kebab-case")]
+pub enum ParseMode {
+    /// Processing stops with the parse error.
+    #[default]
+    Strict,
+    /// The record is skipped and counted in `ProcessorCounters::malformed`, and the first
+    /// `MAX_MALFORMED_RECORDS` are kept in `TxProcessor::malformed_records`.
+    Lenient,
+}
+
+/// Malformed records kept for reporting in lenient mode, the rest are only counted.
+pub const MAX_MALFORMED_RECORDS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalformedRecord {
+    /// Sequence number the record took in the input.
+    pub sequence: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorConfig {
+    pub locked_account_policy: LockedAccountPolicy,
+    /// Record every applied transaction per client, see `TxProcessor::client_history`.
+    pub record_history: bool,
+    /// Record a double-entry ledger of every balance change, see `ledger`.
+    pub record_ledger: bool,
+    /// Release holds automatically once this many further transactions have been processed.
+    pub hold_expiry: Option<u64>,
+    /// Clear pending deposits automatically after this period, see `clearing`.
+    pub clearing_period: Option<ClearingPeriod>,
+    /// Allow disputes of withdrawals. The disputed amount is held until the dispute is resolved,
+    /// and returned to the client on a chargeback. Otherwise only deposits can be disputed.
+    pub dispute_withdrawals: bool,
+    pub dispute_funds_policy: DisputeFundsPolicy,
+    pub parse_mode: ParseMode,
+    /// Close disputes still open after this many further transactions at the next settlement,
+    /// see `settlement`.
+    pub dispute_timeout: Option<u64>,
+    pub dispute_timeout_action: DisputeTimeoutAction,
+    /// Evict stored deposits and withdrawals this many further transactions after them, when they
+    /// can no longer be disputed, see `dispute_window`.
+    pub dispute_window: Option<u64>,
+    /// What to do when a balance update overflows, see `OverflowPolicy`.
+    pub overflow_policy: OverflowPolicy,
+}
+
+/// Running totals of what the processor has seen. Updates are checked, so that a long-lived
+/// processor reports an error instead of silently wrapping around.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct ProcessorCounters<A = TxAmount> {
+    /// Sequence number of the last transaction taken from the input.
+    pub sequence: u64,
+    /// Number of transactions that changed a client balance.
+    pub applied: u64,
+    /// Number of deposits/withdrawals not applied because the account was locked.
+    pub locked_rejected: u64,
+    /// Number of validation warnings on transactions that were still applied.
+    pub warnings: u64,
+    pub deposited_volume: A,
+    pub withdrawn_volume: A,
+    /// Missing from snapshots saved before refunds were supported.
+    #[serde(default)]
+    pub refunded_volume: A,
+    /// Number of input records skipped because they could not be parsed, see `ParseMode`.
+    #[serde(default)]
+    pub malformed: u64,
+}
+
+fn checked_increment(counter: &mut u64, name: &'static str) -> GResult<()> {
+    *counter = counter
+        .checked_add(1)
+        .ok_or(TxProcessorError::Overflow(name))?;
+    Ok(())
+}
+
+fn checked_add_volume<A: Amount>(volume: A, amount: A, name: &'static str) -> GResult<A> {
+    let new_volume = volume + amount;
+    if !new_volume.is_finite() {
+        return Err(TxProcessorError::Overflow(name));
+    }
+    Ok(new_volume)
+}
+
+/// What happened to a transaction given to the processor.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TxOutcome {
+    Applied,
+    /// Not applied yet, kept in `locked_queue` (see `LockedAccountPolicy::Queue`).
+    Queued,
+    /// Not applied, with the reason why (insufficient funds, unknown reference, locked account...).
+    Rejected(RejectReason),
+}
+
+/// Tag of a transaction whose idempotency key was seen before. It gets the outcome of the first
+/// one again, and isn't applied, notified nor audited again.
+pub const REPLAYED_TAG: &str = "replayed";
+
+impl Display for TxOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TxOutcome::Applied => write!(f, "applied"),
+            TxOutcome::Queued => write!(f, "queued"),
+            TxOutcome::Rejected(err) => write!(f, "rejected: {err}"),
+        }
+    }
+}
+
+/// Where a processor records every transaction before processing it, so that it can be
+/// recovered after a crash, see `journal::Journal`.
+pub trait JournalSink<A: Amount = TxAmount>: Send {
+    fn append(&mut self, sequence: u64, tx: &Transaction<A>) -> GResult<()>;
+
+    /// Empties the journal, once its entries are covered by a snapshot.
+    fn truncate(&mut self) -> GResult<()>;
+}
+
+/// Where a processor writes the audit trail, see `audit::AuditLog`. Output may be buffered until
+/// `flush`.
+pub trait AuditSink<A: Amount = TxAmount>: Send {
+    fn append(&mut self, record: &AuditRecord<A>) -> GResult<()>;
+
+    fn flush(&mut self) -> GResult<()>;
+}
+
+/// One transaction of the audit trail. Transactions that were not applied have zero deltas.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct AuditRecord<A = TxAmount> {
+    /// Sequence number of the transaction in the processor's input.
+    pub sequence: u64,
+    #[serde(rename = "type")]
+    pub tx_type: TxType,
+    pub client: ClientId,
+    #[serde(rename = "tx")]
+    pub tx_id: TxId,
+    pub amount: Option<A>,
+    /// `applied`, `queued` or `rejected`.
+    pub outcome: String,
+    pub reason_code: Option<String>,
+    pub reason: Option<String>,
+    pub available_delta: A,
+    pub held_delta: A,
+    /// Total balance of the client after the transaction.
+    pub total: A,
+    pub tags: Vec<String>,
+    /// See `TxProcessor::run_id`.
+    pub run_id: Option<String>,
+}
+
+impl<A: Amount> AuditRecord<A> {
+    pub(crate) fn new(
+        run_id: Option<&str>,
+        sequence: u64,
+        tx: &Transaction<A>,
+        outcome: &TxOutcome,
+        before: &ClientBalance<A>,
+        after: &ClientBalance<A>,
+    ) -> Self {
+        let (outcome, reason) = match outcome {
+            TxOutcome::Applied => ("applied", None),
+            TxOutcome::Queued => ("queued", None),
+            TxOutcome::Rejected(reason) => ("rejected", Some(reason)),
+        };
+        Self {
+            sequence,
+            tx_type: tx.tx_type,
+            client: tx.client,
+            tx_id: tx.tx_id,
+            amount: tx.amount,
+            outcome: outcome.to_string(),
+            reason_code: reason.map(|reason| reason.code().to_string()),
+            reason: reason.map(|reason| reason.to_string()),
+            available_delta: after.available - before.available,
+            held_delta: after.held - before.held,
+            total: after.total,
+            tags: tx.tags.clone(),
+            run_id: run_id.map(str::to_string),
+        }
+    }
+}
+
+/// Where a processor sends a `Notification` of each chargeback and account lock, see
+/// `webhooks::Webhooks`. Sending must not block processing for long.
+pub trait NotificationSink<A: Amount = TxAmount>: Send {
+    fn notify(&mut self, notification: &Notification<A>) -> GResult<()>;
+
+    /// Whether the sink also wants a `NotificationEvent::BalanceChanged` for every balance change,
+    /// ie to stream balances, see `kafka::KafkaNotifier`.
+    fn balance_changes(&self) -> bool {
+        false
+    }
+}
+
+/// Sends each notification to all the sinks, balance changes only to those that want them.
+impl<A: Amount> NotificationSink<A> for Vec<Box<dyn NotificationSink<A>>> {
+    fn notify(&mut self, notification: &Notification<A>) -> GResult<()> {
+        for sink in self.iter_mut() {
+            if notification.event != NotificationEvent::BalanceChanged || sink.balance_changes() {
+                sink.notify(notification)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn balance_changes(&self) -> bool {
+        self.iter().any(|sink| sink.balance_changes())
+    }
+}
 
-pub struct TxProcessor {
-    pub account_transactions: HashMap<TxId, TxAmount>,
-    pub clients_balance: HashMap<ClientId, ClientBalance>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    /// A chargeback was applied, including one closing a timed-out dispute, see `settlement`.
+    Chargeback,
+    /// A client account became locked.
+    AccountLocked,
+    /// A transaction was applied to a client's balance, or held funds were released or cleared on
+    /// their own. Only sent to sinks that ask for them, see `NotificationSink::balance_changes`.
+    BalanceChanged,
+}
+
+/// Something that happened to a client account that others need to know about.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "A: Amount")]
+pub struct Notification<A = TxAmount> {
+    pub event: NotificationEvent,
+    /// Sequence number of the transaction that caused it.
+    pub sequence: u64,
+    pub client: ClientId,
+    /// Id of the transaction that caused it, the charged back one for a chargeback.
+    #[serde(rename = "tx")]
+    pub tx_id: TxId,
+    /// Balance of the client after the transaction.
+    pub balance: ClientBalance<A>,
+    /// See `TxProcessor::run_id`.
+    pub run_id: Option<String>,
+}
+
+pub struct TxProcessor<S: Stores = HashStores, A: Amount = TxAmount> {
+    pub config: ProcessorConfig,
+    /// Amounts of applied deposits, for disputes to reference. In memory unless another store
+    /// is set with `TxProcessorBuilder::tx_store`. See `stored_transaction`.
+    pub(crate) account_transactions: Box<dyn TxStore<A>>,
+    /// See `balances` and `balance_of`.
+    pub(crate) clients_balance: S::Map<ClientId, ClientBalance<A>>,
+    pub counters: ProcessorCounters<A>,
+    pub locked_queue: S::Map<ClientId, Vec<Transaction<A>>>,
+    /// Outcome of each transaction that carried an idempotency key, returned again for retries.
+    pub idempotency_outcomes: S::Map<String, TxOutcome>,
+    /// Run in order on each transaction before it is applied, see `validation`.
+    pub validators: Vec<Box<dyn Validator<A>>>,
+    /// If set, every transaction is recorded here before it is processed, see `journal`.
+    pub journal: Option<Box<dyn JournalSink<A>>>,
+    /// Applied transactions per client, if `ProcessorConfig::record_history` is set.
+    pub history: S::Map<ClientId, Vec<HistoryEvent<A>>>,
+    /// Ledger entries of every balance change, in order, if `ProcessorConfig::record_ledger` is set.
+    pub ledger: Vec<LedgerEntry<A>>,
+    /// If set, every transaction and its outcome is written here once processed, see `audit`.
+    pub audit: Option<Box<dyn AuditSink<A>>>,
+    /// If set, chargebacks and account locks, and balance changes if it wants them, are notified
+    /// here, see `Notification`.
+    pub notifier: Option<Box<dyn NotificationSink<A>>>,
+    /// Open authorization holds by the id of the transaction that placed them, see `holds`.
+    pub holds: S::Map<TxId, Hold<A>>,
+    /// Holds that expire, by expiry sequence number, in order.
+    pub(crate) hold_expiries: VecDeque<(u64, TxId)>,
+    /// Deposits that haven't cleared yet, by id, see `clearing`.
+    pub pending_deposits: S::Map<TxId, PendingDeposit<A>>,
+    /// Pending deposits that clear on their own, by `PendingDeposit::clears_at`.
+    pub(crate) clearings: BTreeSet<(i64, TxId)>,
+    /// Latest timestamp of the transactions processed, when deposits clear after a time.
+    pub(crate) latest_timestamp: Option<i64>,
+    /// Stored transactions to evict, by eviction sequence number, in order, and the sequence
+    /// number of the last eviction scheduled for each, see `dispute_window`.
+    pub(crate) tx_expiries: VecDeque<(u64, TxId)>,
+    pub(crate) tx_expires_at: S::Map<TxId, u64>,
+    /// Amounts held for disputes that were capped by `DisputeFundsPolicy::Cap`, by disputed
+    /// transaction id.
+    pub capped_disputes: S::Map<TxId, A>,
+    /// Amount refunded so far of each withdrawal that had refunds.
+    pub refunded: S::Map<TxId, A>,
+    /// Disputes not resolved or charged back yet, by disputed transaction id.
+    pub open_disputes: S::Map<TxId, OpenDispute>,
+    /// Clients whose account was merged into another, by old id, see `merge`.
+    pub merged_accounts: S::Map<ClientId, ClientId>,
+    /// Settlements of the `settle` marker records processed, for the caller to take.
+    pub settlements: Vec<Settlement<A>>,
+    /// The first malformed records skipped in `ParseMode::Lenient`.
+    pub malformed_records: Vec<MalformedRecord>,
+    /// Identifies the run in the audit trail, snapshots and logs, so that artifacts of different
+    /// runs can be told apart.
+    pub run_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct TxProcessorBuilder<S: Stores = HashStores, A: Amount = TxAmount> {
+    config: ProcessorConfig,
+    validators: Vec<Box<dyn Validator<A>>>,
+    tx_store: Option<Box<dyn TxStore<A>>>,
+    journal: Option<Box<dyn JournalSink<A>>>,
+    audit: Option<Box<dyn AuditSink<A>>>,
+    notifier: Option<Box<dyn NotificationSink<A>>>,
+    run_id: Option<String>,
+    stores: PhantomData<S>,
+}
+
+impl<S: Stores, A: Amount> TxProcessorBuilder<S, A> {
+    pub fn config(mut self, config: ProcessorConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a validator at the end of the validation chain.
+    pub fn validator<V: Validator<A> + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Stores deposit amounts in `tx_store` instead of in memory.
+    pub fn tx_store<T: TxStore<A> + 'static>(mut self, tx_store: T) -> Self {
+        self.tx_store = Some(Box::new(tx_store));
+        self
+    }
+
+    /// Records every transaction in `journal` before processing it.
+    pub fn journal<J: JournalSink<A> + 'static>(mut self, journal: J) -> Self {
+        self.journal = Some(Box::new(journal));
+        self
+    }
+
+    /// Writes every processed transaction to `audit`.
+    pub fn audit<T: AuditSink<A> + 'static>(mut self, audit: T) -> Self {
+        self.audit = Some(Box::new(audit));
+        self
+    }
+
+    /// Notifies chargebacks and account locks to `notifier`.
+    pub fn notifier<N: NotificationSink<A> + 'static>(mut self, notifier: N) -> Self {
+        self.notifier = Some(Box::new(notifier));
+        self
+    }
+
+    pub fn run_id(mut self, run_id: impl Into<String>) -> Self {
+        self.run_id = Some(run_id.into());
+        self
+    }
+
+    /// Keeps the processor state in the maps of `T`, ie `BTreeStores` for a deterministic run.
+    pub fn stores<T: Stores>(self) -> TxProcessorBuilder<T, A> {
+        TxProcessorBuilder {
+            config: self.config,
+            validators: self.validators,
+            tx_store: self.tx_store,
+            journal: self.journal,
+            audit: self.audit,
+            notifier: self.notifier,
+            run_id: self.run_id,
+            stores: PhantomData,
+        }
+    }
+
+    pub fn build(self) -> TxProcessor<S, A> {
+        let mut tx_processor = TxProcessor::with_stores(self.config);
+        tx_processor.validators = self.validators;
+        if let Some(tx_store) = self.tx_store {
+            tx_processor.account_transactions = tx_store;
+        }
+        tx_processor.journal = self.journal;
+        tx_processor.audit = self.audit;
+        tx_processor.notifier = self.notifier;
+        tx_processor.run_id = self.run_id;
+        tx_processor
+    }
+}
+
+impl Default for TxProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TxProcessor {
     pub fn new() -> TxProcessor {
+        Self::with_config(ProcessorConfig::default())
+    }
+
+    pub fn with_config(config: ProcessorConfig) -> TxProcessor {
+        Self::with_stores(config)
+    }
+
+    pub fn builder() -> TxProcessorBuilder {
+        TxProcessorBuilder::default()
+    }
+}
+
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// A processor keeping its state in the maps of `S`, see `Stores`.
+    pub fn with_stores(config: ProcessorConfig) -> TxProcessor<S, A> {
         Self {
-            account_transactions: HashMap::new(),
-            clients_balance: HashMap::new(),
+            config,
+            account_transactions: S::tx_store(),
+            clients_balance: Default::default(),
+            counters: ProcessorCounters::default(),
+            locked_queue: Default::default(),
+            idempotency_outcomes: Default::default(),
+            validators: Vec::new(),
+            journal: None,
+            history: Default::default(),
+            ledger: Vec::new(),
+            audit: None,
+            notifier: None,
+            holds: Default::default(),
+            hold_expiries: VecDeque::new(),
+            pending_deposits: Default::default(),
+            clearings: BTreeSet::new(),
+            latest_timestamp: None,
+            tx_expiries: VecDeque::new(),
+            tx_expires_at: Default::default(),
+            capped_disputes: Default::default(),
+            refunded: Default::default(),
+            open_disputes: Default::default(),
+            merged_accounts: Default::default(),
+            settlements: Vec::new(),
+            malformed_records: Vec::new(),
+            run_id: None,
         }
     }
 
-    pub fn process_input<ITER: Iterator<Item = GResult<Transaction>>>(
+    /// Balances of all clients seen so far, in the iteration order of `S`.
+    pub fn balances(&self) -> impl Iterator<Item = &ClientBalance<A>> {
+        self.clients_balance.values()
+    }
+
+    pub fn balance_of(&self, client: ClientId) -> Option<&ClientBalance<A>> {
+        self.clients_balance.get(&client)
+    }
+
+    /// Number of clients seen so far.
+    pub fn client_count(&self) -> usize {
+        self.clients_balance.len()
+    }
+
+
+    /// The stored deposit or withdrawal with id `tx_id`, that disputes can reference.
+    pub fn stored_transaction(&self, tx_id: TxId) -> GResult<Option<StoredTx<A>>> {
+        self.account_transactions.get(tx_id)
+    }
+
+    /// All stored deposits and withdrawals, in no particular order.
+    pub fn stored_transactions(&self) -> impl Iterator<Item = GResult<(TxId, StoredTx<A>)>> + '_ {
+        self.account_transactions.entries()
+    }
+
+    /// Writes out the stored transactions that the store still buffers, see `TxStore::flush`.
+    pub fn flush_tx_store(&mut self) -> GResult<()> {
+        self.account_transactions.flush()
+    }
+
+    pub fn process_input<ITER: Iterator<Item = GResult<Transaction<A>>>>(
+        &mut self,
+        tx_iter: ITER,
+    ) -> GResult<&S::Map<ClientId, ClientBalance<A>>> {
+        self.process_input_with(tx_iter, |_, _| Ok(()))
+    }
+
+    /// Like `process_input`, but reports the outcome of each transaction to `on_outcome`.
+    pub fn process_input_with<ITER, F>(
         &mut self,
         tx_iter: ITER,
-    ) -> GResult<&HashMap<ClientId, ClientBalance>> {
+        mut on_outcome: F,
+    ) -> GResult<&S::Map<ClientId, ClientBalance<A>>>
+    where
+        ITER: Iterator<Item = GResult<Transaction<A>>>,
+        F: FnMut(&Transaction<A>, &TxOutcome) -> GResult<()>,
+    {
         for tx in tx_iter {
-            let tx = tx?;
+            let Some(mut tx) = self.parsed(tx)? else {
+                continue;
+            };
+            let outcome = self.process_transaction(&mut tx)?;
+            on_outcome(&tx, &outcome)?;
+        }
+
+        Ok(&self.clients_balance)
+    }
+
+    /// The transaction read from the input, or `None` for a malformed record skipped with
+    /// `ParseMode::Lenient`.
+    fn parsed(&mut self, tx: GResult<Transaction<A>>) -> GResult<Option<Transaction<A>>> {
+        match tx {
+            Ok(tx) => Ok(Some(tx)),
+            Err(err) if self.config.parse_mode == ParseMode::Lenient && err.is_malformed_record() => {
+                self.skip_malformed(err)?;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Counts a record that could not be parsed. It still takes a sequence number, so that the
+    /// sequence keeps matching the number of input records (ie to resume from a checkpoint).
+    fn skip_malformed(&mut self, err: TxProcessorError) -> GResult<()> {
+        checked_increment(&mut self.counters.sequence, "sequence counter")?;
+        checked_increment(&mut self.counters.malformed, "malformed counter")?;
+        if self.malformed_records.len() < MAX_MALFORMED_RECORDS {
+            self.malformed_records.push(MalformedRecord {
+                sequence: self.counters.sequence,
+                error: err.to_string(),
+            });
+        }
+        Ok(())
+    }
 
-            let client_entry = self
-                .clients_balance
-                .entry(tx.client)
-                .or_insert_with(|| ClientBalance::new_empty(tx.client));
+    /// Processes a single transaction. Transactions that can't be applied are reported as
+    /// `TxOutcome::Rejected`, an `Err` is only returned for failures that should stop processing.
+    /// Validators may modify the transaction or add findings to it.
+    pub fn process_transaction(&mut self, tx: &mut Transaction<A>) -> GResult<TxOutcome> {
+        checked_increment(&mut self.counters.sequence, "sequence counter")?;
+        if let Some(journal) = &mut self.journal {
+            journal.append(self.counters.sequence, tx)?;
+        }
+        self.release_expired_holds()?;
+        self.clear_due_deposits(tx.timestamp)?;
+        self.evict_undisputable_transactions()?;
+        if tx.tx_type == TxType::Settle {
+            let settlement = self.settle()?;
+            self.settlements.push(settlement);
+            return Ok(TxOutcome::Applied);
+        }
 
-            match tx.tx_type {
-                TxType::Deposit => {
-                    let amount = tx.amount.ok_or("amount missing")?;
-                    client_entry.add_funds(amount);
+        let balance_before = self.tracked_balance(tx.client);
+        let locked_before = self.locked_before(tx.client);
+        let outcome = self.transaction_outcome(tx, balance_before.as_ref())?;
+        if tx.has_tag(REPLAYED_TAG) {
+            return Ok(outcome);
+        }
+        if outcome == TxOutcome::Applied {
+            self.notify_applied(tx, locked_before)?;
+        }
+        if let (Some(audit), Some(before)) = (&mut self.audit, &balance_before) {
+            let after = self.clients_balance.get(&tx.client).unwrap_or(before);
+            let record = AuditRecord::new(self.run_id.as_deref(), self.counters.sequence, tx, &outcome, before, after);
+            audit.append(&record)?;
+        }
+        Ok(outcome)
+    }
+
+    fn transaction_outcome(&mut self, tx: &mut Transaction<A>, balance_before: Option<&ClientBalance<A>>) -> GResult<TxOutcome> {
+        if let Some(outcome) = tx
+            .idempotency_key
+            .as_ref()
+            .and_then(|key| self.idempotency_outcomes.get(key))
+        {
+            tx.tag(REPLAYED_TAG);
+            return Ok(outcome.clone());
+        }
+
+        let result = run_validators(&self.validators, tx)
+            .map_err(|reason| RejectReason::Invalid(reason).into())
+            .and_then(|()| self.apply_transaction(tx));
+        let outcome = match result {
+            Ok(update) => {
+                if update == BalanceUpdate::Saturated {
+                    let message = format!("balance of client {} overflowed and was saturated", tx.client);
+                    tx.findings.push(Finding::new(Severity::Warning, message));
+                    tx.tag("saturated");
                 }
-                TxType::Withdrawal => {
-                    let amount = tx.amount.ok_or("amount missing")?;
-                    client_entry.remove_funds(amount).unwrap_or_else(|_err|{
-                        // withdrawal denied due to no funds
-                    });
+                checked_increment(&mut self.counters.applied, "applied counter")?;
+                for _ in tx.findings.iter().filter(|finding| finding.severity == Severity::Warning) {
+                    checked_increment(&mut self.counters.warnings, "warnings counter")?;
                 }
-                TxType::Dispute => {
-                    if let Some(amount) = self.account_transactions.get(&tx.tx_id) {
-                        client_entry.hold_funds(*amount);
-                    }
+                if let (true, Some(before)) = (self.config.record_history, balance_before) {
+                    let after = self.clients_balance.get(&tx.client).expect("an applied transaction has a balance");
+                    let event = HistoryEvent::new(self.counters.sequence, tx, before, after);
+                    self.history.get_or_insert_with(tx.client, Vec::new).push(event);
                 }
-                TxType::Resolve => {
-                    if let Some(amount) = self.account_transactions.get(&tx.tx_id) {
-                        client_entry.resolve_funds(*amount);
-                    }
+                if let (true, Some(before)) = (self.config.record_ledger, balance_before) {
+                    self.post_ledger(tx.tx_type, tx.tx_id, tx.tags.clone(), before);
                 }
-                TxType::Chargeback => {
-                    if let Some(amount) = self.account_transactions.get(&tx.tx_id) {
-                        client_entry.chargeback_funds(*amount);
-                    }
+                TxOutcome::Applied
+            }
+            Err(TxProcessorError::Rejected(RejectReason::LockedAccount(client))) => {
+                checked_increment(&mut self.counters.locked_rejected, "locked rejected counter")?;
+                if self.config.locked_account_policy == LockedAccountPolicy::Queue {
+                    self.locked_queue.get_or_insert_with(client, Vec::new).push(tx.clone());
+                    TxOutcome::Queued
+                } else {
+                    TxOutcome::Rejected(RejectReason::LockedAccount(client))
                 }
             }
+            Err(TxProcessorError::Rejected(reason)) => TxOutcome::Rejected(reason),
+            Err(err) => return Err(err),
+        };
+        if let Some(key) = &tx.idempotency_key {
+            self.idempotency_outcomes.insert(key.clone(), outcome.clone());
+        }
+        Ok(outcome)
+    }
+
+    /// The client's balance before a transaction, when history, a ledger or an audit trail is
+    /// recorded.
+    fn tracked_balance(&self, client: ClientId) -> Option<ClientBalance<A>> {
+        if !self.config.record_history && !self.config.record_ledger && self.audit.is_none() {
+            return None;
+        }
+        let balance = self.clients_balance.get(&client).cloned();
+        Some(balance.unwrap_or_else(|| ClientBalance::new_empty(client)))
+    }
+
+    /// Whether the client's account is locked before a transaction, when notifications are sent.
+    pub(crate) fn locked_before(&self, client: ClientId) -> Option<bool> {
+        self.notifier.as_ref()?;
+        Some(self.clients_balance.get(&client).is_some_and(|balance| balance.locked))
+    }
+
+    /// Sends the notifications for an applied transaction, if any.
+    pub(crate) fn notify_applied(&mut self, tx: &Transaction<A>, locked_before: Option<bool>) -> GResult<()> {
+        let (Some(notifier), Some(locked_before)) = (&mut self.notifier, locked_before) else {
+            return Ok(());
+        };
+        let Some(balance) = self.clients_balance.get(&tx.client) else {
+            return Ok(());
+        };
+        let changed = notifier.balance_changes().then_some(NotificationEvent::BalanceChanged);
+        let chargeback = (tx.tx_type == TxType::Chargeback).then_some(NotificationEvent::Chargeback);
+        let locked = (balance.locked && !locked_before).then_some(NotificationEvent::AccountLocked);
+        for event in changed.into_iter().chain(chargeback).chain(locked) {
+            notifier.notify(&Notification {
+                event,
+                sequence: self.counters.sequence,
+                client: tx.client,
+                tx_id: tx.tx_id,
+                balance: balance.clone(),
+                run_id: self.run_id.clone(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Notifies a balance change no transaction was applied for, ie an expired hold released, to
+    /// the sinks that want balance changes. `tx_id` is the id of the funds released.
+    pub(crate) fn notify_balance_changed(&mut self, client: ClientId, tx_id: TxId) -> GResult<()> {
+        let Some(notifier) = self.notifier.as_mut().filter(|notifier| notifier.balance_changes()) else {
+            return Ok(());
+        };
+        let Some(balance) = self.clients_balance.get(&client) else {
+            return Ok(());
+        };
+        notifier.notify(&Notification {
+            event: NotificationEvent::BalanceChanged,
+            sequence: self.counters.sequence,
+            client,
+            tx_id,
+            balance: balance.clone(),
+            run_id: self.run_id.clone(),
+        })
+    }
+
+    /// Applies a transaction to its client's balance. Rejected transactions, including those whose
+    /// balance update overflows (see `OverflowPolicy`), leave the processor state unchanged.
+    pub(crate) fn apply_transaction(&mut self, tx: &Transaction<A>) -> GResult<BalanceUpdate> {
+        // Checked after validation, so that an amount rounded down to zero is rejected too.
+        if tx.amount.is_some_and(|amount| !amount.is_finite() || amount <= A::default()) {
+            return Err(RejectReason::NonPositiveAmount(tx.tx_id).into());
+        }
+        if let Some(&into) = self.merged_accounts.get(&tx.client) {
+            return Err(RejectReason::MergedAccount { client: tx.client, into }.into());
+        }
+        let disputes = matches!(tx.tx_type, TxType::Dispute | TxType::Resolve | TxType::Chargeback);
+        if disputes && self.pending_deposits.contains_key(&tx.tx_id) {
+            return Err(RejectReason::PendingDeposit(tx.tx_id).into());
+        }
+
+        let client_entry = self
+            .clients_balance
+            .get_or_insert_with(tx.client, || ClientBalance::new_empty(tx.client));
+
+        let moves_funds = matches!(
+            tx.tx_type,
+            TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Capture | TxType::Refund
+        );
+        if moves_funds && client_entry.locked {
+            return Err(RejectReason::LockedAccount(tx.client).into());
+        }
+        let takes_funds = matches!(tx.tx_type, TxType::Withdrawal | TxType::Hold | TxType::Capture);
+        if takes_funds && client_entry.frozen {
+            return Err(RejectReason::FrozenAccount(tx.client).into());
+        }
+
+        let policy = self.config.overflow_policy;
+        // Only a transaction of the same client can be disputed, resolved or charged back. It is
+        // disputed once at a time, and only resolved or charged back while disputed.
+        let disputed = self.open_disputes.contains_key(&tx.tx_id);
+        let referenced = || -> GResult<StoredTx<A>> {
+            let stored = self
+                .account_transactions
+                .get(tx.tx_id)?
+                .filter(|stored| stored.client.is_none_or(|client| client == tx.client))
+                .filter(|stored| stored.direction == Direction::Deposit || self.config.dispute_withdrawals)
+                .ok_or(RejectReason::UnknownTxReference(tx.tx_id))?;
+            match (tx.tx_type, disputed) {
+                (TxType::Dispute, true) => Err(RejectReason::AlreadyDisputed(tx.tx_id).into()),
+                (TxType::Resolve | TxType::Chargeback, false) => Err(RejectReason::NotDisputed(tx.tx_id).into()),
+                _ => Ok(stored),
+            }
+        };
 
-            match tx.tx_type {
-                TxType::Deposit => {
-                    let amount = tx.amount.ok_or("amount missing")?;
-                    self.account_transactions.insert(tx.tx_id, amount);
+        // Balances are updated before anything else, so that an overflow leaves no trace.
+        let update = match tx.tx_type {
+            TxType::Deposit => {
+                let amount = tx.amount.ok_or(TxProcessorError::MissingAmount(tx.tx_id))?;
+                let deposited_volume =
+                    checked_add_volume(self.counters.deposited_volume, amount, "deposited volume")?;
+                let update = if tx.pending {
+                    let clears_at = clearing::clears_at(self.config.clearing_period, self.counters.sequence, tx)?;
+                    let update = client_entry.add_pending_funds(amount, policy)?;
+                    if let Some(clears_at) = clears_at {
+                        self.clearings.insert((clears_at, tx.tx_id));
+                    }
+                    let deposit = PendingDeposit {
+                        client: tx.client,
+                        amount,
+                        clears_at,
+                    };
+                    self.pending_deposits.insert(tx.tx_id, deposit);
+                    update
+                } else {
+                    client_entry.add_funds(amount, policy)?
+                };
+                self.counters.deposited_volume = deposited_volume;
+                let direction = Direction::Deposit;
+                self.account_transactions.insert(tx.tx_id, StoredTx { amount, direction, client: Some(tx.client) })?;
+                update
+            }
+            TxType::Withdrawal => {
+                let amount = tx.amount.ok_or(TxProcessorError::MissingAmount(tx.tx_id))?;
+                let withdrawn_volume =
+                    checked_add_volume(self.counters.withdrawn_volume, amount, "withdrawn volume")?;
+                let update = client_entry.remove_funds(amount, policy)?;
+                self.counters.withdrawn_volume = withdrawn_volume;
+                let direction = Direction::Withdrawal;
+                self.account_transactions.insert(tx.tx_id, StoredTx { amount, direction, client: Some(tx.client) })?;
+                update
+            }
+            TxType::Dispute => match referenced()? {
+                StoredTx { amount, direction: Direction::Deposit, .. } => {
+                    let held = disputed_amount(self.config.dispute_funds_policy, client_entry.available, amount)?;
+                    let update = client_entry.hold_funds(held, policy)?;
+                    if held != amount {
+                        self.capped_disputes.insert(tx.tx_id, held);
+                    }
+                    update
+                }
+                StoredTx { amount, direction: Direction::Withdrawal, .. } => client_entry.dispute_withdrawal(amount, policy)?,
+            },
+            TxType::Resolve => match referenced()? {
+                StoredTx { amount, direction: Direction::Deposit, .. } => {
+                    let held = self.capped_disputes.get(&tx.tx_id).copied().unwrap_or(amount);
+                    let update = client_entry.resolve_funds(held, policy)?;
+                    self.capped_disputes.remove(&tx.tx_id);
+                    update
+                }
+                StoredTx { amount, direction: Direction::Withdrawal, .. } => client_entry.resolve_withdrawal(amount, policy)?,
+            },
+            TxType::Chargeback => match referenced()? {
+                StoredTx { amount, direction: Direction::Deposit, .. } => {
+                    let held = self.capped_disputes.get(&tx.tx_id).copied().unwrap_or(amount);
+                    let update = client_entry.chargeback_funds(held, policy)?;
+                    self.capped_disputes.remove(&tx.tx_id);
+                    update
+                }
+                StoredTx { amount, direction: Direction::Withdrawal, .. } => {
+                    client_entry.chargeback_withdrawal(amount, policy)?
                 }
-                _ => {}
+            },
+            TxType::Hold => {
+                let amount = tx.amount.ok_or(TxProcessorError::MissingAmount(tx.tx_id))?;
+                if self.holds.contains_key(&tx.tx_id) {
+                    return Err(RejectReason::DuplicateTx(tx.tx_id).into());
+                }
+                if client_entry.available < amount {
+                    return Err(RejectReason::InsufficientFunds.into());
+                }
+                let update = client_entry.hold_funds(amount, policy)?;
+                let expires_at = self.config.hold_expiry.map(|expiry| self.counters.sequence.saturating_add(expiry));
+                if let Some(expires_at) = expires_at {
+                    self.hold_expiries.push_back((expires_at, tx.tx_id));
+                }
+                let hold = Hold {
+                    client: tx.client,
+                    amount,
+                    expires_at,
+                };
+                self.holds.insert(tx.tx_id, hold);
+                update
+            }
+            TxType::Release => {
+                let hold_amount = self
+                    .holds
+                    .get(&tx.tx_id)
+                    .filter(|hold| hold.client == tx.client)
+                    .ok_or(RejectReason::UnknownTxReference(tx.tx_id))?
+                    .amount;
+                let update = client_entry.resolve_funds(hold_amount, policy)?;
+                take_hold(&mut self.holds, tx)?;
+                update
+            }
+            TxType::Capture => {
+                let hold_amount = self
+                    .holds
+                    .get(&tx.tx_id)
+                    .filter(|hold| hold.client == tx.client)
+                    .ok_or(RejectReason::UnknownTxReference(tx.tx_id))?
+                    .amount;
+                let amount = tx.amount.unwrap_or(hold_amount);
+                if amount > hold_amount {
+                    return Err(RejectReason::InsufficientFunds.into());
+                }
+                let withdrawn_volume =
+                    checked_add_volume(self.counters.withdrawn_volume, amount, "withdrawn volume")?;
+                let mut captured = client_entry.clone();
+                let update = captured
+                    .capture_funds(amount, policy)?
+                    .and(captured.resolve_funds(hold_amount - amount, policy)?);
+                *client_entry = captured;
+                self.counters.withdrawn_volume = withdrawn_volume;
+                take_hold(&mut self.holds, tx)?;
+                update
+            }
+            TxType::Refund => {
+                let amount = tx.amount.ok_or(TxProcessorError::MissingAmount(tx.tx_id))?;
+                // Only a withdrawal of the same client can be refunded.
+                let withdrawn = match self.account_transactions.get(tx.tx_id)? {
+                    Some(StoredTx { amount, direction: Direction::Withdrawal, client })
+                        if client.is_none_or(|client| client == tx.client) =>
+                    {
+                        amount
+                    }
+                    _ => return Err(RejectReason::UnknownTxReference(tx.tx_id).into()),
+                };
+                let refunded = self.refunded.get(&tx.tx_id).copied().unwrap_or_default() + amount;
+                if refunded > withdrawn {
+                    return Err(RejectReason::RefundExceedsWithdrawal(tx.tx_id).into());
+                }
+                let refunded_volume =
+                    checked_add_volume(self.counters.refunded_volume, amount, "refunded volume")?;
+                let update = client_entry.add_funds(amount, policy)?;
+                self.counters.refunded_volume = refunded_volume;
+                self.refunded.insert(tx.tx_id, refunded);
+                update
+            }
+            TxType::Clear => {
+                let amount = self
+                    .pending_deposits
+                    .get(&tx.tx_id)
+                    .filter(|deposit| deposit.client == tx.client)
+                    .ok_or(RejectReason::UnknownTxReference(tx.tx_id))?
+                    .amount;
+                let update = client_entry.clear_funds(amount, policy)?;
+                self.pending_deposits.remove(&tx.tx_id);
+                update
+            }
+            TxType::Freeze | TxType::Unfreeze => {
+                client_entry.frozen = tx.tx_type == TxType::Freeze;
+                BalanceUpdate::Exact
+            }
+            TxType::Settle => unreachable!("settlement markers are not applied to a client"),
+        };
+        match tx.tx_type {
+            TxType::Dispute => {
+                let dispute = OpenDispute { client: tx.client, opened_at: self.counters.sequence };
+                self.open_disputes.insert(tx.tx_id, dispute);
             }
+            TxType::Resolve | TxType::Chargeback => {
+                self.open_disputes.remove(&tx.tx_id);
+            }
+            TxType::Deposit | TxType::Withdrawal => self.schedule_eviction(tx.tx_id),
+            _ => {}
+        }
+        Ok(update)
+    }
+}
+
+/// The amount to hold for a dispute of a deposit of `amount`, see `DisputeFundsPolicy`.
+fn disputed_amount<A: Amount>(policy: DisputeFundsPolicy, available: A, amount: A) -> GResult<A> {
+    if available >= amount {
+        return Ok(amount);
+    }
+    match policy {
+        DisputeFundsPolicy::AllowNegative => Ok(amount),
+        DisputeFundsPolicy::Cap => Ok(available.max_zero()),
+        DisputeFundsPolicy::Reject => Err(RejectReason::InsufficientFunds.into()),
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: Stores, A: Amount> TxProcessor<S, A> {
+    /// Async counterpart of `process_input`, for transactions coming from an async source.
+    pub async fn process_stream<T>(&mut self, tx_stream: T) -> GResult<&S::Map<ClientId, ClientBalance<A>>>
+    where
+        T: futures::Stream<Item = GResult<Transaction<A>>>,
+    {
+        self.process_stream_with(tx_stream, |_, _| Ok(())).await
+    }
+
+    /// Async counterpart of `process_input_with`.
+    pub async fn process_stream_with<T, F>(
+        &mut self,
+        tx_stream: T,
+        mut on_outcome: F,
+    ) -> GResult<&S::Map<ClientId, ClientBalance<A>>>
+    where
+        T: futures::Stream<Item = GResult<Transaction<A>>>,
+        F: FnMut(&Transaction<A>, &TxOutcome) -> GResult<()>,
+    {
+        use futures::StreamExt;
+
+        let mut tx_stream = std::pin::pin!(tx_stream);
+        while let Some(tx) = tx_stream.next().await {
+            let Some(mut tx) = self.parsed(tx)? else {
+                continue;
+            };
+            let outcome = self.process_transaction(&mut tx)?;
+            on_outcome(&tx, &outcome)?;
         }
 
         Ok(&self.clients_balance)
@@ -71,27 +982,13 @@ impl TxProcessor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::TxId;
+    use crate::test_support::{amount, deposit, malformed, transaction, withdrawal};
 
     // Some helper functions:
 
-    fn deposit(client: ClientId, tx_id: TxId, amount: TxAmount) -> Transaction {
-        Transaction {
-            tx_type: TxType::Deposit,
-            client,
-            tx_id,
-            amount : Some(amount),
-        }
-    }
-    fn withdrawal(client: ClientId, tx_id: TxId, amount: TxAmount) -> Transaction {
-        Transaction {
-            tx_type: TxType::Withdrawal,
-            client,
-            tx_id,
-            amount : Some(amount),
-        }
-    }
     fn process_tx(tx_processor: &mut TxProcessor, transaction: Transaction) -> GResult<()> {
-        tx_processor.process_input(vec![transaction].into_iter().map(|tx| Ok(tx)))?;
+        tx_processor.process_input(vec![transaction].into_iter().map(Ok))?;
         Ok(())
     }
 
@@ -106,10 +1003,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         let mut expected_balance = ClientBalance {
             client: 1,
-            total: 100.0,
-            held: 0.0,
-            available: 100.0,
+            total: amount(100.0),
+            held: amount(0.0),
+            available: amount(100.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         };
         assert_eq!(c1_balance, &expected_balance);
 
@@ -117,8 +1016,8 @@ mod tests {
         process_tx(&mut tx_processor, deposit(1, 2, 50.0))?;
 
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
-        expected_balance.total = 150.0;
-        expected_balance.available = 150.0;
+        expected_balance.total = amount(150.0);
+        expected_balance.available = amount(150.0);
         assert_eq!(c1_balance, &expected_balance);
 
         // Test another deposit with different client.
@@ -128,10 +1027,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&client).unwrap();
         let expected_balance = ClientBalance {
             client,
-            total: 50.0,
-            held: 0.0,
-            available: 50.0,
+            total: amount(50.0),
+            held: amount(0.0),
+            available: amount(50.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         };
         assert_eq!(c1_balance, &expected_balance);
 
@@ -149,10 +1050,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         let mut expected_balance = ClientBalance {
             client: 1,
-            total: 400.0,
-            held: 0.0,
-            available: 400.0,
+            total: amount(400.0),
+            held: amount(0.0),
+            available: amount(400.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         };
         assert_eq!(c1_balance, &expected_balance);
 
@@ -166,20 +1069,15 @@ mod tests {
         // Test a 3rd withdrawal
         process_tx(&mut tx_processor, withdrawal(1, 4, 400.0))?;
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
-        expected_balance.total = 0.0;
-        expected_balance.available = 0.0;
+        expected_balance.total = amount(0.0);
+        expected_balance.available = amount(0.0);
         assert_eq!(c1_balance, &expected_balance);
 
         Ok(())
     }
 
     fn dispute(tx_type: TxType, client: ClientId, tx_id: TxId) -> Transaction {
-        Transaction {
-            tx_type,
-            client,
-            tx_id,
-            amount : None,
-        }
+        transaction(tx_type, client, tx_id, None)
     }
 
     #[test]
@@ -197,10 +1095,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         assert_eq!(c1_balance, &ClientBalance {
             client: 1,
-            total: 1500.0,
-            held: 0.0,
-            available: 1500.0,
+            total: amount(1500.0),
+            held: amount(0.0),
+            available: amount(1500.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         });
 
         Ok(())
@@ -219,10 +1119,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         assert_eq!(c1_balance, &ClientBalance {
             client: 1,
-            total: 1500.0,
-            held: 500.0,
-            available: 1500.0 - 500.0,
+            total: amount(1500.0),
+            held: amount(500.0),
+            available: amount(1500.0 - 500.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         });
 
         // Test a resolve.
@@ -231,10 +1133,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         assert_eq!(c1_balance, &ClientBalance {
             client: 1,
-            total: 1500.0,
-            held: 0.0,
-            available: 1500.0,
+            total: amount(1500.0),
+            held: amount(0.0),
+            available: amount(1500.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         });
 
         Ok(())
@@ -255,10 +1159,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         assert_eq!(c1_balance, &ClientBalance {
             client: 1,
-            total: 50.0 + 60.0 + 80.0,
-            held: 60.0 + 80.0,
-            available: 50.0,
+            total: amount(50.0 + 60.0 + 80.0),
+            held: amount(60.0 + 80.0),
+            available: amount(50.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         });
 
         // Test a resolve.
@@ -267,10 +1173,12 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         assert_eq!(c1_balance, &ClientBalance {
             client: 1,
-            total: 50.0 + 60.0 + 80.0,
-            held: 80.0,
-            available: 50.0 + 60.0,
+            total: amount(50.0 + 60.0 + 80.0),
+            held: amount(80.0),
+            available: amount(50.0 + 60.0),
             locked: false,
+            frozen: false,
+            pending: amount(0.0),
         });
 
         Ok(())
@@ -291,12 +1199,651 @@ mod tests {
         let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
         assert_eq!(c1_balance, &ClientBalance {
             client: 1,
-            total: 1000.0,
-            held: 00.0,
-            available: 1000.0,
+            total: amount(1000.0),
+            held: amount(00.0),
+            available: amount(1000.0),
+            locked: true,
+            frozen: false,
+            pending: amount(0.0),
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_withdrawal() -> GResult<()> {
+        // Without the setting, withdrawals can't be disputed.
+        let mut tx_processor = TxProcessor::new();
+        process_tx(&mut tx_processor, deposit(1, 1, 1000.0))?;
+        process_tx(&mut tx_processor, withdrawal(1, 2, 300.0))?;
+        let outcome = tx_processor.process_transaction(&mut dispute(TxType::Dispute, 1, 2))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(2)));
+
+        let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+            dispute_withdrawals: true,
+            ..Default::default()
+        });
+        process_tx(&mut tx_processor, deposit(1, 1, 1000.0))?;
+        process_tx(&mut tx_processor, withdrawal(1, 2, 300.0))?;
+        process_tx(&mut tx_processor, withdrawal(1, 3, 100.0))?;
+
+        process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 2))?;
+        process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 3))?;
+        let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
+        assert_eq!((c1_balance.available, c1_balance.held, c1_balance.total), (amount(600.0), amount(400.0), amount(1000.0)));
+
+        // A resolved dispute leaves the withdrawal in place, a chargeback returns the funds.
+        process_tx(&mut tx_processor, dispute(TxType::Resolve, 1, 3))?;
+        process_tx(&mut tx_processor, dispute(TxType::Chargeback, 1, 2))?;
+        let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
+        assert_eq!(c1_balance, &ClientBalance {
+            client: 1,
+            total: amount(900.0),
+            held: amount(0.0),
+            available: amount(900.0),
+            locked: true,
+            frozen: false,
+            pending: amount(0.0),
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_funds_policy() -> GResult<()> {
+        let spent_deposit = |policy| -> GResult<TxProcessor> {
+            let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+                dispute_funds_policy: policy,
+                ..Default::default()
+            });
+            process_tx(&mut tx_processor, deposit(1, 1, 100.0))?;
+            process_tx(&mut tx_processor, deposit(1, 2, 50.0))?;
+            process_tx(&mut tx_processor, withdrawal(1, 3, 120.0))?;
+            process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 1))?;
+            Ok(tx_processor)
+        };
+        let balance = |tx_processor: &TxProcessor| {
+            let balance = tx_processor.clients_balance.get(&1).unwrap();
+            (balance.available, balance.held, balance.total)
+        };
+
+        let tx_processor = spent_deposit(DisputeFundsPolicy::AllowNegative)?;
+        assert_eq!(balance(&tx_processor), (amount(-70.0), amount(100.0), amount(30.0)));
+
+        let tx_processor = spent_deposit(DisputeFundsPolicy::Reject)?;
+        assert_eq!(balance(&tx_processor), (amount(30.0), amount(0.0), amount(30.0)));
+
+        // The resolve or chargeback moves only the capped amount.
+        let mut tx_processor = spent_deposit(DisputeFundsPolicy::Cap)?;
+        assert_eq!(balance(&tx_processor), (amount(0.0), amount(30.0), amount(30.0)));
+        process_tx(&mut tx_processor, dispute(TxType::Chargeback, 1, 1))?;
+        assert_eq!(balance(&tx_processor), (amount(0.0), amount(0.0), amount(0.0)));
+        assert!(tx_processor.capped_disputes.is_empty());
+
+        assert_eq!("cap".parse::<DisputeFundsPolicy>(), Ok(DisputeFundsPolicy::Cap));
+        assert_eq!("allow-negative".parse::<DisputeFundsPolicy>(), Ok(DisputeFundsPolicy::AllowNegative));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "minor-units"))] // the amounts are past what `MinorUnits` holds
+    fn test_overflow_policy() -> GResult<()> {
+        // Disputing both a deposit and its withdrawal holds twice the amount.
+        let disputed_twice = |policy| -> GResult<(TxProcessor, GResult<TxOutcome>, Transaction)> {
+            let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+                dispute_withdrawals: true,
+                overflow_policy: policy,
+                ..Default::default()
+            });
+            process_tx(&mut tx_processor, deposit(1, 1, 1e308))?;
+            process_tx(&mut tx_processor, withdrawal(1, 2, 1e308))?;
+            process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 1))?;
+            let mut tx = dispute(TxType::Dispute, 1, 2);
+            let outcome = tx_processor.process_transaction(&mut tx);
+            Ok((tx_processor, outcome, tx))
+        };
+        let balance = |tx_processor: &TxProcessor| {
+            let balance = tx_processor.clients_balance.get(&1).unwrap();
+            (balance.available, balance.held, balance.total)
+        };
+
+        let (tx_processor, outcome, _) = disputed_twice(OverflowPolicy::Reject)?;
+        assert_eq!(outcome?, TxOutcome::Rejected(RejectReason::BalanceOverflow(1)));
+        assert_eq!(balance(&tx_processor), (-1e308, 1e308, 0.0));
+        assert!(!tx_processor.open_disputes.contains_key(&2));
+
+        let (tx_processor, outcome, tx) = disputed_twice(OverflowPolicy::Saturate)?;
+        assert_eq!(outcome?, TxOutcome::Applied);
+        assert_eq!(balance(&tx_processor), (-1e308, f64::MAX, 1e308));
+        assert_eq!(tx.findings[0].severity, Severity::Warning);
+        assert!(tx.has_tag("saturated"));
+        assert_eq!(tx_processor.counters.warnings, 1);
+
+        let (tx_processor, outcome, _) = disputed_twice(OverflowPolicy::Abort)?;
+        assert_eq!(outcome.unwrap_err().to_string(), "client balance overflow");
+        assert_eq!(balance(&tx_processor), (-1e308, 1e308, 0.0));
+
+        assert_eq!("saturate".parse::<OverflowPolicy>(), Ok(OverflowPolicy::Saturate));
+        Ok(())
+    }
+
+    #[test]
+    fn test_refund() -> GResult<()> {
+        let refund = |client, tx_id, amount| Transaction {
+            tx_type: TxType::Refund,
+            ..deposit(client, tx_id, amount)
+        };
+        let mut tx_processor = TxProcessor::new();
+        process_tx(&mut tx_processor, deposit(1, 1, 100.0))?;
+        process_tx(&mut tx_processor, withdrawal(1, 2, 60.0))?;
+
+        assert_eq!(tx_processor.process_transaction(&mut refund(1, 2, 40.0))?, TxOutcome::Applied);
+        assert_eq!(tx_processor.process_transaction(&mut refund(1, 2, 20.0))?, TxOutcome::Applied);
+        // Refunds are bounded by the withdrawal, and only withdrawals can be refunded.
+        let outcome = tx_processor.process_transaction(&mut refund(1, 2, 0.5))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::RefundExceedsWithdrawal(2)));
+        let outcome = tx_processor.process_transaction(&mut refund(1, 1, 10.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(1)));
+
+        assert_eq!(tx_processor.clients_balance.get(&1).unwrap().available, amount(100.0));
+        assert_eq!(tx_processor.counters.refunded_volume, amount(60.0));
+        assert_eq!(tx_processor.counters.deposited_volume, amount(100.0));
+
+        // Nor can another client's withdrawal.
+        process_tx(&mut tx_processor, deposit(2, 3, 10.0))?;
+        process_tx(&mut tx_processor, withdrawal(2, 4, 5.0))?;
+        let outcome = tx_processor.process_transaction(&mut refund(1, 4, 5.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(4)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_of_another_client() {
+        use crate::test_support::{chargeback, dispute, resolve, Scenario};
+
+        // Client 2 can't dispute, resolve nor charge back a deposit of client 1.
+        Scenario::new()
+            .tx(deposit(1, 1, 100.0))
+            .tx(deposit(2, 2, 10.0))
+            .rejected(dispute(2, 1), "unknown_tx_reference")
+            .balance(2, 10.0, 0.0, false)
+            .tx(dispute(1, 1))
+            .rejected(resolve(2, 1), "unknown_tx_reference")
+            .rejected(chargeback(2, 1), "unknown_tx_reference")
+            .balance(1, 0.0, 100.0, false)
+            .balance(2, 10.0, 0.0, false)
+            .tx(chargeback(1, 1))
+            .balance(1, 0.0, 0.0, true);
+    }
+
+    #[test]
+    fn test_dispute_state() {
+        use crate::test_support::{chargeback, dispute, resolve, Scenario};
+
+        // Resolves and chargebacks need an open dispute, and a transaction is disputed once at a time.
+        Scenario::new()
+            .tx(deposit(1, 1, 100.0))
+            .rejected(resolve(1, 1), "not_disputed")
+            .rejected(chargeback(1, 1), "not_disputed")
+            .balance(1, 100.0, 0.0, false)
+            .tx(dispute(1, 1))
+            .rejected(dispute(1, 1), "already_disputed")
+            .balance(1, 0.0, 100.0, false)
+            .tx(resolve(1, 1))
+            .rejected(resolve(1, 1), "not_disputed")
+            .balance(1, 100.0, 0.0, false)
+            // Disputed again once resolved.
+            .tx(dispute(1, 1))
+            .tx(chargeback(1, 1))
+            .rejected(chargeback(1, 1), "not_disputed")
+            .balance(1, 0.0, 0.0, true);
+    }
+
+    #[test]
+    fn test_non_positive_amount() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+        process_tx(&mut tx_processor, deposit(1, 1, 100.0))?;
+        for (tx_id, amount) in [(2, -100.0), (3, 0.0), (4, f64::NAN), (5, f64::INFINITY)] {
+            let outcome = tx_processor.process_transaction(&mut deposit(1, tx_id, amount))?;
+            assert_eq!(outcome, TxOutcome::Rejected(RejectReason::NonPositiveAmount(tx_id)));
+        }
+        let outcome = tx_processor.process_transaction(&mut withdrawal(1, 6, -50.0))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::NonPositiveAmount(6)));
+
+        let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
+        assert_eq!((c1_balance.available, c1_balance.total), (amount(100.0), amount(100.0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_locked_account() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+
+        process_tx(&mut tx_processor, deposit(1, 1, 1000.0))?;
+        process_tx(&mut tx_processor, deposit(1, 2, 500.0))?;
+        process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 2))?;
+        process_tx(&mut tx_processor, dispute(TxType::Chargeback, 1, 2))?;
+
+        // Test deposits and withdrawals are rejected once locked.
+        process_tx(&mut tx_processor, deposit(1, 3, 200.0))?;
+        process_tx(&mut tx_processor, withdrawal(1, 4, 100.0))?;
+
+        let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
+        assert_eq!(c1_balance, &ClientBalance {
+            client: 1,
+            total: amount(1000.0),
+            held: amount(0.0),
+            available: amount(1000.0),
             locked: true,
+            frozen: false,
+            pending: amount(0.0),
+        });
+        assert_eq!(tx_processor.counters.locked_rejected, 2);
+        assert!(tx_processor.locked_queue.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_locked_account_queue() -> GResult<()> {
+        let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+            locked_account_policy: LockedAccountPolicy::Queue,
+            ..Default::default()
+        });
+
+        process_tx(&mut tx_processor, deposit(1, 1, 1000.0))?;
+        process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 1))?;
+        process_tx(&mut tx_processor, dispute(TxType::Chargeback, 1, 1))?;
+        process_tx(&mut tx_processor, deposit(1, 2, 200.0))?;
+
+        let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
+        assert_eq!(c1_balance.total, amount(0.0));
+        assert_eq!(tx_processor.counters.locked_rejected, 1);
+        assert_eq!(tx_processor.locked_queue.get(&1).unwrap(), &vec![deposit(1, 2, 200.0)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_frozen_account() {
+        use crate::test_support::*;
+
+        // Funds can't leave a frozen account, but deposits and disputes still apply.
+        Scenario::new()
+            .tx(deposit(1, 1, 100.0))
+            .tx(hold(1, 2, 10.0))
+            .tx(freeze(1))
+            .frozen(1, true)
+            .rejected(withdrawal(1, 3, 5.0), "frozen_account")
+            .rejected(hold(1, 4, 5.0), "frozen_account")
+            .rejected(capture(1, 2, None), "frozen_account")
+            .tx(release(1, 2))
+            .tx(deposit(1, 5, 20.0))
+            .tx(dispute(1, 5))
+            .tx(resolve(1, 5))
+            .balance(1, 120.0, 0.0, false)
+            .tx(freeze(1))
+            .tx(unfreeze(1))
+            .frozen(1, false)
+            .tx(withdrawal(1, 6, 5.0))
+            .balance(1, 115.0, 0.0, false)
+            // A frozen account can still be locked by a chargeback, and unfrozen after.
+            .tx(freeze(1))
+            .tx(dispute(1, 5))
+            .tx(chargeback(1, 5))
+            .tx(unfreeze(1))
+            .frozen(1, false)
+            .rejected(withdrawal(1, 7, 5.0), "locked_account")
+            .balance(1, 95.0, 0.0, true);
+
+        assert_eq!("unfreeze".parse::<TxType>(), Ok(TxType::Unfreeze));
+    }
+
+    #[test]
+    fn test_counters() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+
+        process_tx(&mut tx_processor, deposit(1, 1, 100.0))?;
+        process_tx(&mut tx_processor, withdrawal(1, 2, 30.0))?;
+        // Not applied: not enough funds, and bad reference.
+        process_tx(&mut tx_processor, withdrawal(1, 3, 300.0))?;
+        process_tx(&mut tx_processor, dispute(TxType::Dispute, 1, 666))?;
+
+        assert_eq!(tx_processor.counters, ProcessorCounters {
+            sequence: 4,
+            applied: 2,
+            locked_rejected: 0,
+            warnings: 0,
+            deposited_volume: amount(100.0),
+            withdrawn_volume: amount(30.0),
+            refunded_volume: amount(0.0),
+            malformed: 0,
+        });
+
+        // Test overflow is reported instead of wrapping.
+        tx_processor.counters.sequence = u64::MAX;
+        let err = process_tx(&mut tx_processor, deposit(1, 4, 1.0)).unwrap_err();
+        assert_eq!(err.to_string(), "sequence counter overflow");
+
+        tx_processor.counters.sequence = 0;
+        let headroom = TxAmount::max_finite() - tx_processor.counters.deposited_volume;
+        let largest = |tx_id| Transaction { amount: Some(headroom), ..deposit(1, tx_id, 0.0) };
+        process_tx(&mut tx_processor, largest(5)).unwrap();
+        process_tx(&mut tx_processor, largest(6)).unwrap_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mode() -> GResult<()> {
+        let parse_error = |field, message: &str| TxProcessorError::Parse { field, message: message.to_string() };
+        let input = || {
+            vec![
+                Ok(deposit(1, 1, 10.0)),
+                malformed(3, "deposit,x,2,10", parse_error("client", "invalid digit found in string")),
+                Ok(withdrawal(1, 3, 2.0)),
+                malformed(5, "bogus,1,4,1", parse_error("type", "unknown transaction type `bogus`")),
+                malformed(6, "deposit,1,5,", TxProcessorError::MissingAmount(5)),
+            ]
+            .into_iter()
+        };
+        let err = TxProcessor::new().process_input(input()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3 (record 2): invalid `client` field: invalid digit found in string, in `deposit,x,2,10`"
+        );
+
+        let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+            parse_mode: ParseMode::Lenient,
+            ..Default::default()
+        });
+        tx_processor.process_input(input())?;
+        assert_eq!(tx_processor.clients_balance[&1].available, amount(8.0));
+        assert_eq!((tx_processor.counters.sequence, tx_processor.counters.malformed), (5, 3));
+        let skipped: Vec<_> = tx_processor.malformed_records.iter().map(|record| record.sequence).collect();
+        assert_eq!(skipped, vec![2, 4, 5]);
+        assert_eq!(
+            tx_processor.malformed_records[2].error,
+            "line 6 (record 5): amount missing for transaction 5, in `deposit,1,5,`"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_outcomes() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+
+        let outcome = tx_processor.process_transaction(&mut deposit(1, 1, 100.0))?;
+        assert!(matches!(outcome, TxOutcome::Applied));
+
+        let outcome = tx_processor.process_transaction(&mut withdrawal(1, 2, 300.0))?;
+        assert!(matches!(outcome, TxOutcome::Rejected(RejectReason::InsufficientFunds)));
+
+        let outcome = tx_processor.process_transaction(&mut dispute(TxType::Dispute, 1, 666))?;
+        assert!(matches!(outcome, TxOutcome::Rejected(RejectReason::UnknownTxReference(666))));
+
+        tx_processor.process_transaction(&mut dispute(TxType::Dispute, 1, 1))?;
+        tx_processor.process_transaction(&mut dispute(TxType::Chargeback, 1, 1))?;
+        let outcome = tx_processor.process_transaction(&mut deposit(1, 3, 10.0))?;
+        assert!(matches!(outcome, TxOutcome::Rejected(RejectReason::LockedAccount(1))));
+
+        // Test outcomes are reported for each input transaction.
+        let input = vec![deposit(2, 4, 10.0), withdrawal(2, 5, 20.0)];
+        let mut outcomes = vec![];
+        tx_processor.process_input_with(input.into_iter().map(Ok), |tx, outcome| {
+            outcomes.push((tx.tx_id, outcome.to_string()));
+            Ok(())
+        })?;
+        assert_eq!(outcomes, vec![(4, "applied".to_string()), (5, "rejected: not enough funds".to_string())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_idempotency_key() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+
+        let keyed = |mut tx: Transaction, key: &str| {
+            tx.idempotency_key = Some(key.to_string());
+            tx
+        };
+
+        let outcome = tx_processor.process_transaction(&mut keyed(deposit(1, 1, 100.0), "a"))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        let outcome = tx_processor.process_transaction(&mut keyed(withdrawal(1, 2, 500.0), "b"))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::InsufficientFunds));
+
+        // Test retries get the original outcome, and are not applied again.
+        let outcome = tx_processor.process_transaction(&mut keyed(deposit(1, 1, 100.0), "a"))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        tx_processor.process_transaction(&mut deposit(1, 3, 400.0))?;
+        let outcome = tx_processor.process_transaction(&mut keyed(withdrawal(1, 2, 500.0), "b"))?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::InsufficientFunds));
+
+        // Test the same tx id with a new key is processed.
+        let outcome = tx_processor.process_transaction(&mut keyed(withdrawal(1, 2, 500.0), "c"))?;
+        assert_eq!(outcome, TxOutcome::Applied);
+
+        let c1_balance = tx_processor.clients_balance.get(&1).unwrap();
+        assert_eq!(c1_balance.total, amount(0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validators() -> GResult<()> {
+        use crate::validation::{AmountSchema, Finding, MaxAmount, RoundAmount, Verdict};
+
+        let mut tx_processor = TxProcessor::builder()
+            .validator(AmountSchema)
+            .validator(MaxAmount(amount(1000.0)))
+            .validator(|tx: &mut Transaction| {
+                if tx.tx_type == TxType::Withdrawal && tx.client == 2 {
+                    Verdict::Annotate("watched client".to_string())
+                } else {
+                    Verdict::Accept
+                }
+            })
+            .build();
+
+        let outcome = tx_processor.process_transaction(&mut deposit(1, 1, 5000.0))?;
+        assert_eq!(
+            outcome,
+            TxOutcome::Rejected(RejectReason::Invalid("amount exceeds maximum of 1000".to_string()))
+        );
+
+        // Test missing amount is a rejection rather than an error when the schema is validated.
+        let mut missing_amount = deposit(1, 2, 0.0);
+        missing_amount.amount = None;
+        let outcome = tx_processor.process_transaction(&mut missing_amount)?;
+        assert_eq!(outcome, TxOutcome::Rejected(RejectReason::Invalid("amount missing".to_string())));
+
+        process_tx(&mut tx_processor, deposit(2, 3, 100.0))?;
+        let mut tx = withdrawal(2, 4, 10.0);
+        let outcome = tx_processor.process_transaction(&mut tx)?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        assert_eq!(tx.findings, vec![Finding::new(Severity::Info, "watched client")]);
+        assert_eq!(tx_processor.counters.warnings, 0);
+
+        let mut tx = withdrawal(2, 5, 10.123456);
+        tx_processor.validators.push(Box::new(RoundAmount { decimals: 2 }));
+        let outcome = tx_processor.process_transaction(&mut tx)?;
+        assert_eq!(outcome, TxOutcome::Applied);
+        assert_eq!(tx.amount, Some(amount(10.12)));
+        assert_eq!(tx_processor.counters.warnings, 1);
+
+        assert!(tx_processor.clients_balance.get(&1).is_none_or(|balance| balance.total == amount(0.0)));
+        assert_eq!(tx_processor.clients_balance.get(&2).unwrap().total, amount(100.0 - 10.0 - 10.12));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accessors() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+        process_tx(&mut tx_processor, deposit(1, 1, 100.0))?;
+        process_tx(&mut tx_processor, withdrawal(2, 2, 10.0))?;
+
+        assert_eq!(tx_processor.client_count(), 2);
+        assert_eq!(tx_processor.balance_of(1).map(|balance| balance.total), Some(amount(100.0)));
+        assert_eq!(tx_processor.balance_of(3), None);
+        assert_eq!(tx_processor.balances().map(|balance| balance.total).sum::<TxAmount>(), amount(100.0));
+        let stored = StoredTx { amount: amount(100.0), direction: Direction::Deposit, client: Some(1) };
+        assert_eq!(tx_processor.stored_transaction(1)?, Some(stored));
+        // The rejected withdrawal wasn't stored.
+        assert_eq!(tx_processor.stored_transaction(2)?, None);
+        assert_eq!(tx_processor.stored_transactions().count(), 1);
+        Ok(())
+    }
+
+    /// Journal and audit trail kept in memory, shared with the test.
+    #[derive(Default, Clone)]
+    struct Recorded(std::sync::Arc<std::sync::Mutex<(Vec<u64>, Vec<AuditRecord>)>>);
+
+    impl JournalSink for Recorded {
+        fn append(&mut self, sequence: u64, _tx: &Transaction) -> GResult<()> {
+            self.0.lock().unwrap().0.push(sequence);
+            Ok(())
+        }
+
+        fn truncate(&mut self) -> GResult<()> {
+            self.0.lock().unwrap().0.clear();
+            Ok(())
+        }
+    }
+
+    impl AuditSink for Recorded {
+        fn append(&mut self, record: &AuditRecord) -> GResult<()> {
+            self.0.lock().unwrap().1.push(record.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> GResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_replays_not_notified() -> GResult<()> {
+        let (recorded, notified) = (Recorded::default(), Notified::default());
+        let mut tx_processor = TxProcessor::builder().audit(recorded.clone()).notifier(notified.clone()).build();
+        tx_processor.process_transaction(&mut deposit(1, 1, 100.0))?;
+        tx_processor.process_transaction(&mut dispute(TxType::Dispute, 1, 1))?;
+        let mut chargeback = dispute(TxType::Chargeback, 1, 1);
+        chargeback.idempotency_key = Some("cb".to_string());
+        let mut retry = chargeback.clone();
+
+        assert_eq!(tx_processor.process_transaction(&mut chargeback)?, TxOutcome::Applied);
+        assert!(!chargeback.has_tag(REPLAYED_TAG));
+        assert_eq!(tx_processor.process_transaction(&mut retry)?, TxOutcome::Applied);
+        assert!(retry.has_tag(REPLAYED_TAG));
+        let events = notified.0.lock().unwrap().clone();
+        assert_eq!(events, vec![(NotificationEvent::Chargeback, 1), (NotificationEvent::AccountLocked, 1)]);
+        assert_eq!(recorded.0.lock().unwrap().1.len(), 3);
+        assert_eq!(tx_processor.counters.applied, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_sinks() -> GResult<()> {
+        let recorded = Recorded::default();
+        let mut tx_processor = TxProcessor::builder().journal(recorded.clone()).audit(recorded.clone()).build();
+        let input = vec![deposit(1, 1, 100.0), withdrawal(1, 2, 300.0)];
+        tx_processor.process_input(input.into_iter().map(Ok))?;
+
+        let (journal, audit) = &*recorded.0.lock().unwrap();
+        assert_eq!(journal, &vec![1, 2]);
+        let outcomes: Vec<_> = audit.iter().map(|record| record.outcome.as_str()).collect();
+        assert_eq!(outcomes, vec!["applied", "rejected"]);
+        Ok(())
+    }
+
+    /// Events notified, and whether balance changes are wanted.
+    #[derive(Default, Clone)]
+    struct Notified(std::sync::Arc<std::sync::Mutex<Vec<(NotificationEvent, TxId)>>>, bool);
+
+    impl NotificationSink for Notified {
+        fn notify(&mut self, notification: &Notification) -> GResult<()> {
+            self.0.lock().unwrap().push((notification.event, notification.tx_id));
+            Ok(())
+        }
+
+        fn balance_changes(&self) -> bool {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_balance_change_notifications() -> GResult<()> {
+        let (balances, alerts) = (Notified(Default::default(), true), Notified::default());
+        let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+            hold_expiry: Some(1),
+            ..Default::default()
         });
+        let sinks: Vec<Box<dyn NotificationSink>> = vec![Box::new(balances.clone()), Box::new(alerts.clone())];
+        tx_processor.notifier = Some(Box::new(sinks));
+        let input = vec![
+            deposit(1, 1, 100.0),
+            crate::test_support::hold(1, 2, 30.0),
+            deposit(1, 3, 1.0),
+            // The hold expires before this one.
+            deposit(1, 4, 1.0),
+            withdrawal(1, 5, 500.0),
+            crate::test_support::dispute(1, 1),
+            crate::test_support::chargeback(1, 1),
+        ];
+        tx_processor.process_input(input.into_iter().map(Ok))?;
+
+        use NotificationEvent::*;
+        // The rejected withdrawal changes nothing, and the expired hold is released by its id.
+        let changed = [1, 2, 3, 2, 4, 1, 1].map(|tx_id| (BalanceChanged, tx_id));
+        let expected = [&changed[..], &[(Chargeback, 1), (AccountLocked, 1)]].concat();
+        assert_eq!(*balances.0.lock().unwrap(), expected);
+        assert_eq!(*alerts.0.lock().unwrap(), vec![(Chargeback, 1), (AccountLocked, 1)]);
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_process_stream() -> GResult<()> {
+        let mut tx_processor = TxProcessor::new();
+        let input = vec![deposit(1, 1, 100.0), withdrawal(1, 2, 30.0), withdrawal(1, 3, 300.0)];
 
+        let mut outcomes = vec![];
+        let stream = futures::stream::iter(input.into_iter().map(Ok));
+        let balances = futures::executor::block_on(tx_processor.process_stream_with(stream, |_, outcome| {
+            outcomes.push(outcome.clone());
+            Ok(())
+        }))?;
+
+        assert_eq!(balances.get(&1).unwrap().total, amount(70.0));
+        assert_eq!(outcomes, vec![
+            TxOutcome::Applied,
+            TxOutcome::Applied,
+            TxOutcome::Rejected(RejectReason::InsufficientFunds),
+        ]);
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_process_stream_lenient() -> GResult<()> {
+        let malformed = || TxProcessorError::Parse {
+            field: "client",
+            message: "invalid digit found in string".to_string(),
+        };
+        let input = || futures::stream::iter([Ok(deposit(1, 1, 100.0)), Err(malformed()), Ok(withdrawal(1, 3, 30.0))]);
+
+        let err = futures::executor::block_on(TxProcessor::new().process_stream(input())).err();
+        assert_eq!(err.map(|err| err.to_string()), Some(malformed().to_string()));
+
+        // The malformed record in the middle is skipped, as with `process_input`.
+        let mut tx_processor = TxProcessor::with_config(ProcessorConfig {
+            parse_mode: ParseMode::Lenient,
+            ..Default::default()
+        });
+        futures::executor::block_on(tx_processor.process_stream(input()))?;
+        assert_eq!(tx_processor.clients_balance[&1].available, amount(70.0));
+        assert_eq!((tx_processor.counters.sequence, tx_processor.counters.malformed), (3, 1));
+        assert_eq!(tx_processor.malformed_records[0].sequence, 2);
         Ok(())
     }
 }
@@ -0,0 +1,370 @@
+//! Reading input files with io_uring, for batch hosts with fast NVMe disks where the standard
+//! reads, one blocking syscall per buffer, leave the disk idle while records are parsed. Requires
+//! the `io-uring` feature, on Linux.
+//!
+//! `UringReader` keeps `QUEUE_DEPTH` reads of `BLOCK_SIZE` bytes submitted ahead of the parser, so
+//! that the next blocks are being read while the current one is parsed. Where io_uring isn't
+//! available (other platforms, builds without the feature, or kernels and sandboxes that refuse
+//! it), files are read with the standard reader. `run_read_benchmark` compares both.
+
+use crate::model::{ClientId, TxId, TxType};
+use crate::GResult;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+/// Size of each read submitted to the ring.
+pub const BLOCK_SIZE: usize = 1024 * 1024;
+/// Reads kept in flight.
+pub const QUEUE_DEPTH: usize = 4;
+
+/// Opens the file at `path` for reading, with a `UringReader` where io_uring is available.
+pub fn open_file(path: &str) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Ok(reader) = UringReader::new(file.try_clone()?) {
+        return Ok(Box::new(reader));
+    }
+    Ok(Box::new(file))
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub use ring::UringReader;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod ring {
+    use super::{BLOCK_SIZE, QUEUE_DEPTH};
+    use io_uring::{opcode, types, IoUring};
+    use std::collections::VecDeque;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::AsRawFd;
+
+    /// A buffer and the read submitted into it.
+    struct Slot {
+        buffer: Box<[u8]>,
+        offset: u64,
+        requested: usize,
+        /// Result of the read once completed, bytes read or a negated errno.
+        result: Option<i32>,
+        /// Bytes read, while they are being returned.
+        filled: Option<usize>,
+    }
+
+    /// Reads a file sequentially, with `QUEUE_DEPTH` reads ahead in flight, see the module docs.
+    pub struct UringReader {
+        ring: IoUring,
+        file: File,
+        slots: Vec<Slot>,
+        /// Slots in file order, the front one is being returned.
+        queue: VecDeque<usize>,
+        /// Bytes of the front slot already returned.
+        consumed: usize,
+        next_offset: u64,
+        in_flight: usize,
+        eof: bool,
+    }
+
+    impl UringReader {
+        /// Fails if io_uring isn't available, ie it is disabled in the kernel or the sandbox.
+        pub fn new(file: File) -> io::Result<Self> {
+            let mut reader = Self {
+                ring: IoUring::new(QUEUE_DEPTH as u32)?,
+                file,
+                slots: Vec::with_capacity(QUEUE_DEPTH),
+                queue: VecDeque::with_capacity(QUEUE_DEPTH),
+                consumed: 0,
+                next_offset: 0,
+                in_flight: 0,
+                eof: false,
+            };
+            for slot in 0..QUEUE_DEPTH {
+                reader.slots.push(Slot {
+                    buffer: vec![0; BLOCK_SIZE].into_boxed_slice(),
+                    offset: 0,
+                    requested: 0,
+                    result: None,
+                    filled: None,
+                });
+                reader.submit(slot, reader.next_offset, BLOCK_SIZE)?;
+                reader.next_offset += BLOCK_SIZE as u64;
+                reader.queue.push_back(slot);
+            }
+            Ok(reader)
+        }
+
+        pub fn open(path: &str) -> io::Result<Self> {
+            Self::new(File::open(path)?)
+        }
+
+        fn submit(&mut self, slot: usize, offset: u64, len: usize) -> io::Result<()> {
+            let fd = types::Fd(self.file.as_raw_fd());
+            let target = &mut self.slots[slot];
+            target.offset = offset;
+            target.requested = len;
+            target.result = None;
+            let entry = opcode::Read::new(fd, target.buffer.as_mut_ptr(), len as u32)
+                .offset(offset)
+                .build()
+                .user_data(slot as u64);
+            // SAFETY: the buffer is owned by the reader, which doesn't touch it until the read
+            // completes, and waits for the reads in flight before it is dropped.
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            self.in_flight += 1;
+            self.ring.submit()?;
+            Ok(())
+        }
+
+        /// Waits for at least one read to complete.
+        fn complete(&mut self) -> io::Result<()> {
+            self.ring.submit_and_wait(1)?;
+            let completed: Vec<_> = self.ring.completion().map(|cqe| (cqe.user_data() as usize, cqe.result())).collect();
+            for (slot, result) in completed {
+                self.slots[slot].result = Some(result);
+                self.in_flight -= 1;
+            }
+            Ok(())
+        }
+
+        /// Bytes read into `slot`, once its read completed. A failed read is submitted again, so
+        /// that the reader can be retried.
+        fn filled(&mut self, slot: usize) -> io::Result<usize> {
+            if let Some(filled) = self.slots[slot].filled {
+                return Ok(filled);
+            }
+            let result = loop {
+                match self.slots[slot].result.take() {
+                    Some(result) => break result,
+                    None => self.complete()?,
+                }
+            };
+            if result < 0 {
+                let Slot { offset, requested, .. } = self.slots[slot];
+                self.submit(slot, offset, requested)?;
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            self.slots[slot].filled = Some(result as usize);
+            Ok(result as usize)
+        }
+    }
+
+    impl Read for UringReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.eof || buf.is_empty() {
+                return Ok(0);
+            }
+            let front = *self.queue.front().expect("slots are queued again once returned");
+            let filled = self.filled(front)?;
+            if filled == 0 {
+                self.eof = true;
+                return Ok(0);
+            }
+            let copied = (filled - self.consumed).min(buf.len());
+            buf[..copied].copy_from_slice(&self.slots[front].buffer[self.consumed..self.consumed + copied]);
+            self.consumed += copied;
+            if self.consumed == filled {
+                self.consumed = 0;
+                let slot = &mut self.slots[front];
+                slot.filled = None;
+                let (offset, requested) = (slot.offset, slot.requested);
+                if filled < requested {
+                    // A short read: the rest of the block is read before the next slots.
+                    self.submit(front, offset + filled as u64, requested - filled)?;
+                } else {
+                    self.queue.pop_front();
+                    self.submit(front, self.next_offset, BLOCK_SIZE)?;
+                    self.next_offset += BLOCK_SIZE as u64;
+                    self.queue.push_back(front);
+                }
+            }
+            Ok(copied)
+        }
+    }
+
+    impl Drop for UringReader {
+        fn drop(&mut self) {
+            // The kernel may still write into the buffers of the reads in flight.
+            while self.in_flight > 0 {
+                if self.complete().is_err() {
+                    // The buffers are leaked rather than freed under a pending read.
+                    std::mem::forget(std::mem::take(&mut self.slots));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Writes a transactions CSV of `rows` records over `clients` clients: mostly deposits and
+/// withdrawals, with some disputes, resolves and chargebacks of earlier deposits. The same
+/// arguments always give the same file.
+pub fn write_synthetic_csv<OUT: Write>(out: OUT, rows: u64, clients: ClientId) -> GResult<()> {
+    let mut out = io::BufWriter::with_capacity(BLOCK_SIZE, out);
+    writeln!(out, "type, client, tx, amount")?;
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    for row in 0..rows {
+        // xorshift, as the load doesn't need to be cryptographically random.
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let tx_id = (row + 1) as TxId;
+        let client = (tx_id as u64 % clients.max(1) as u64) as ClientId;
+        let cents = (state >> 16) % 1_000_000 + 1;
+        let amount = format_args!("{}.{:02}", cents / 100, cents % 100);
+        match (state >> 8) % 100 {
+            0..=59 => writeln!(out, "deposit, {client}, {tx_id}, {amount}")?,
+            60..=93 => writeln!(out, "withdrawal, {client}, {tx_id}, {amount}")?,
+            kind => {
+                // A transaction of the same client, as ids and clients go round together.
+                let referenced = tx_id.saturating_sub(clients.max(1) as TxId * (1 + (state >> 32) % 10) as TxId);
+                let tx_type = match kind {
+                    94..=96 => TxType::Dispute,
+                    97..=98 => TxType::Resolve,
+                    _ => TxType::Chargeback,
+                };
+                writeln!(out, "{tx_type}, {client}, {referenced},")?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Input of `run_read_benchmark`.
+#[derive(Debug, Clone)]
+pub struct ReadBenchConfig {
+    pub rows: u64,
+    pub clients: ClientId,
+    /// Where the synthetic input is written, a file in the temporary directory if not set.
+    pub path: Option<String>,
+}
+
+impl Default for ReadBenchConfig {
+    fn default() -> Self {
+        Self {
+            rows: 10_000_000,
+            clients: 10_000,
+            path: None,
+        }
+    }
+}
+
+/// Time taken to read the same file with the standard reader and with io_uring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadBenchReport {
+    pub bytes: u64,
+    pub standard: std::time::Duration,
+    pub io_uring: std::time::Duration,
+}
+
+impl ReadBenchReport {
+    /// How many times faster io_uring reads are.
+    pub fn speedup(&self) -> f64 {
+        self.standard.as_secs_f64() / self.io_uring.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for ReadBenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rate = |elapsed: std::time::Duration| self.bytes as f64 / 1e6 / elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "bytes: {}, standard: {:.2}s ({:.0} MB/s), io_uring: {:.2}s ({:.0} MB/s), speedup: {:.1}x",
+            self.bytes,
+            self.standard.as_secs_f64(),
+            rate(self.standard),
+            self.io_uring.as_secs_f64(),
+            rate(self.io_uring),
+            self.speedup(),
+        )
+    }
+}
+
+/// Reads a synthetic input (see `write_synthetic_csv`) and parses its transactions, with
+/// the standard reader and with a `UringReader`, checking that both read the same transactions.
+/// The input file is removed afterwards, unless its path was given.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub fn run_read_benchmark(config: &ReadBenchConfig) -> GResult<ReadBenchReport> {
+    use crate::input::read_transactions_csv;
+    use std::time::{Duration, Instant};
+
+    let path = match &config.path {
+        Some(path) => path.clone(),
+        None => std::env::temp_dir()
+            .join(format!("tx_processor_read_bench_{}.csv", std::process::id()))
+            .to_string_lossy()
+            .to_string(),
+    };
+    write_synthetic_csv(File::create(&path)?, config.rows, config.clients)?;
+    let run = |reader: Box<dyn Read>| -> GResult<(Duration, u64)> {
+        let started = Instant::now();
+        let mut transactions = 0;
+        for tx in read_transactions_csv(reader) {
+            tx?;
+            transactions += 1;
+        }
+        Ok((started.elapsed(), transactions))
+    };
+    let result = (|| {
+        let standard = run(Box::new(File::open(&path)?))?;
+        let io_uring = run(Box::new(UringReader::open(&path)?))?;
+        Ok::<_, crate::error::TxProcessorError>((standard, io_uring))
+    })();
+    let bytes = std::fs::metadata(&path).map(|metadata| metadata.len());
+    if config.path.is_none() {
+        let _ = std::fs::remove_file(&path);
+    }
+    let ((standard, standard_count), (io_uring, io_uring_count)) = result?;
+    if standard_count != io_uring_count {
+        return Err(io::Error::other("io_uring reads differ from the standard ones").into());
+    }
+    Ok(ReadBenchReport {
+        bytes: bytes?,
+        standard,
+        io_uring,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads all of `reader` with odd-sized reads, that straddle the blocks.
+    fn read_all(mut reader: impl Read) -> GResult<Vec<u8>> {
+        let mut read = vec![];
+        let mut buffer = vec![0; 100_003];
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => break,
+                n => read.extend_from_slice(&buffer[..n]),
+            }
+        }
+        assert_eq!(reader.read(&mut buffer)?, 0);
+        Ok(read)
+    }
+
+    #[test]
+    fn test_open_file() -> GResult<()> {
+        let path = std::env::temp_dir().join(format!("tx_uring_test_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        // Spans several blocks, and ends in the middle of one.
+        let content: Vec<u8> = (0..BLOCK_SIZE * QUEUE_DEPTH * 2 + 1234).map(|at| (at % 251) as u8).collect();
+        std::fs::write(path, &content)?;
+        assert!(read_all(open_file(path)?)? == content);
+
+        // Kernels or sandboxes may refuse io_uring, the reader is only checked where it isn't.
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if UringReader::open(path).is_ok() {
+            assert!(read_all(UringReader::open(path)?)? == content);
+            // Dropped with reads in flight.
+            let mut reader = UringReader::open(path)?;
+            let mut start = vec![0; 10];
+            reader.read_exact(&mut start)?;
+            assert_eq!(start, content[..10]);
+            drop(reader);
+            assert!(read_all(UringReader::open("/dev/null")?)?.is_empty());
+        }
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
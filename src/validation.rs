@@ -0,0 +1,208 @@
+//! Pluggable validation run on each transaction before it is applied. Validators run in the
+//! order they were added to the `TxProcessorBuilder`, and each can accept, annotate, warn about,
+//! transform (by modifying the transaction in place) or reject the transaction. Validators can
+//! also label transactions with `Transaction::tag`.
+
+use crate::amount::Amount;
+use crate::model::{Transaction, TxAmount, TxType};
+use strum_macros::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, serde::Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Informational note, ie a harmless normalisation.
+    Info,
+    /// The transaction is applied, but flagged for review.
+    Warning,
+    /// The transaction is rejected.
+    Error,
+}
+
+/// A note or warning attached to a transaction that was accepted, or an error that rejects it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// Continue with the next validator.
+    Accept,
+    /// Continue with the next validator, adding an `Info` finding to the transaction.
+    Annotate(String),
+    /// Continue with the next validator, adding a `Warning` finding to the transaction.
+    Warn(String),
+    /// Stop validating, the transaction is rejected with this reason (an `Error` finding).
+    Reject(String),
+}
+
+/// Validates transactions with amounts of type `A`, see `amount`.
+pub trait Validator<A: Amount = TxAmount>: Send {
+    fn validate(&self, tx: &mut Transaction<A>) -> Verdict;
+}
+
+/// Any closure can be used as a custom validator.
+impl<A: Amount, F> Validator<A> for F
+where
+    F: Fn(&mut Transaction<A>) -> Verdict + Send,
+{
+    fn validate(&self, tx: &mut Transaction<A>) -> Verdict {
+        self(tx)
+    }
+}
+
+/// Runs the validators in order, returning the rejection reason of the first one that rejects.
+/// A transaction that comes with an `Error` finding already, ie from its reader, is rejected with
+/// it.
+pub fn run_validators<A: Amount>(validators: &[Box<dyn Validator<A>>], tx: &mut Transaction<A>) -> Result<(), String> {
+    if let Some(error) = tx.findings.iter().find(|finding| finding.severity == Severity::Error) {
+        return Err(error.message.clone());
+    }
+    for validator in validators {
+        match validator.validate(tx) {
+            Verdict::Accept => {}
+            Verdict::Annotate(note) => tx.findings.push(Finding::new(Severity::Info, note)),
+            Verdict::Warn(warning) => tx.findings.push(Finding::new(Severity::Warning, warning)),
+            Verdict::Reject(reason) => return Err(reason),
+        }
+    }
+    Ok(())
+}
+
+/// Schema check: deposits, withdrawals, holds and refunds must have an amount, captures may have one, other
+/// types must not (a stray amount is dropped and noted).
+pub struct AmountSchema;
+
+impl<A: Amount> Validator<A> for AmountSchema {
+    fn validate(&self, tx: &mut Transaction<A>) -> Verdict {
+        match (tx.tx_type, tx.amount) {
+            (TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Refund, None) => {
+                Verdict::Reject("amount missing".to_string())
+            }
+            (TxType::Deposit | TxType::Withdrawal | TxType::Hold | TxType::Refund | TxType::Capture, Some(_))
+            | (_, None) => Verdict::Accept,
+            (_, Some(_)) => {
+                tx.amount = None;
+                Verdict::Annotate(format!("amount ignored for {}", tx.tx_type))
+            }
+        }
+    }
+}
+
+/// Rounds amounts to a number of decimal places, with a warning when that changes the amount.
+pub struct RoundAmount {
+    pub decimals: u32,
+}
+
+impl<A: Amount> Validator<A> for RoundAmount {
+    fn validate(&self, tx: &mut Transaction<A>) -> Verdict {
+        let Some(amount) = tx.amount else {
+            return Verdict::Accept;
+        };
+        let rounded = amount.round_dp(self.decimals);
+        if rounded == amount {
+            return Verdict::Accept;
+        }
+        let decimals = amount.to_string().split_once('.').map_or(0, |(_, fraction)| fraction.len());
+        tx.amount = Some(rounded);
+        tx.tag("rounded");
+        Verdict::Warn(format!("amount has {decimals} decimal places, rounded"))
+    }
+}
+
+/// Business rule: rejects deposits and withdrawals above a maximum amount.
+pub struct MaxAmount<A = TxAmount>(pub A);
+
+impl<A: Amount> Validator<A> for MaxAmount<A> {
+    fn validate(&self, tx: &mut Transaction<A>) -> Verdict {
+        match tx.amount {
+            Some(amount) if amount > self.0 => Verdict::Reject(format!("amount exceeds maximum of {}", self.0)),
+            _ => Verdict::Accept,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_run_validators() {
+        let validators: Vec<Box<dyn Validator>> = vec![
+            Box::new(AmountSchema),
+            Box::new(MaxAmount(amount(1000.0))),
+            Box::new(|tx: &mut Transaction| {
+                if tx.client == 666 {
+                    Verdict::Reject("blocked client".to_string())
+                } else {
+                    Verdict::Accept
+                }
+            }),
+            Box::new(|tx: &mut Transaction| {
+                if tx.amount.is_some_and(|value| value >= amount(500.0)) {
+                    tx.tag("high-risk");
+                }
+                Verdict::Accept
+            }),
+        ];
+
+        let mut tx = deposit(1, 1, 10.0);
+        assert_eq!(run_validators(&validators, &mut tx), Ok(()));
+        assert!(tx.findings.is_empty());
+        assert!(tx.tags.is_empty());
+
+        let mut tx = deposit(1, 1, 600.0);
+        assert_eq!(run_validators(&validators, &mut tx), Ok(()));
+        assert!(tx.has_tag("high-risk"));
+
+        let mut tx = transaction(TxType::Dispute, 1, 1, Some(10.0));
+        assert_eq!(run_validators(&validators, &mut tx), Ok(()));
+        assert_eq!(tx.amount, None);
+        assert_eq!(tx.findings, vec![Finding::new(Severity::Info, "amount ignored for dispute")]);
+
+        let mut tx = transaction(TxType::Withdrawal, 1, 1, None);
+        assert_eq!(run_validators(&validators, &mut tx), Err("amount missing".to_string()));
+
+        let mut tx = withdrawal(1, 1, 5000.0);
+        assert_eq!(
+            run_validators(&validators, &mut tx),
+            Err("amount exceeds maximum of 1000".to_string())
+        );
+
+        let mut tx = deposit(1, 1, 10.0);
+        tx.client = 666;
+        assert_eq!(run_validators(&validators, &mut tx), Err("blocked client".to_string()));
+    }
+
+    #[test]
+    #[cfg(not(feature = "minor-units"))] // `MinorUnits` already have four decimal places at most
+    fn test_round_amount() {
+        let validators: Vec<Box<dyn Validator>> = vec![Box::new(RoundAmount { decimals: 4 })];
+
+        let mut tx = deposit(1, 1, 1.123456);
+        assert_eq!(run_validators(&validators, &mut tx), Ok(()));
+        assert_eq!(tx.amount, Some(amount(1.1235)));
+        assert_eq!(tx.tags, vec!["rounded".to_string()]);
+        assert_eq!(
+            tx.findings,
+            vec![Finding::new(Severity::Warning, "amount has 6 decimal places, rounded")]
+        );
+
+        let mut tx = deposit(1, 1, 1.1234);
+        assert_eq!(run_validators(&validators, &mut tx), Ok(()));
+        assert_eq!(tx.amount, Some(amount(1.1234)));
+        assert!(tx.findings.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+//! Trial balance: recomputes client totals and money flows from the ledger (see `ledger`), and
+//! checks them against the balances and volume counters of the processor, and that the sum of all
+//! client totals is deposits minus withdrawals minus chargebacks. Refunds and the withdrawals in
+//! dispute are added in, as they change client totals too.
+
+use crate::amount::Amount;
+use crate::ledger::Account;
+use crate::model::{ClientId, TxAmount, TxType, AMOUNT_DECIMALS};
+use crate::store::Stores;
+use crate::tx_processor::TxProcessor;
+use crate::{GResult, ProcessOptions};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// A difference found by `trial_balance`. Amounts are compared to `AMOUNT_DECIMALS` places.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Discrepancy {
+    /// The total of a client differs from the one recomputed from its postings.
+    Client {
+        client: ClientId,
+        total: TxAmount,
+        recomputed: TxAmount,
+    },
+    /// A volume counter of the processor differs from the one recomputed from the postings.
+    Volume {
+        counter: &'static str,
+        counted: TxAmount,
+        recomputed: TxAmount,
+    },
+    /// The sum of all client totals differs from what the money flows add up to.
+    Totals { clients_total: TxAmount, expected: TxAmount },
+}
+
+impl Display for Discrepancy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Client { client, total, recomputed } => {
+                write!(f, "client {client}: total is {total}, but {recomputed} from the ledger")
+            }
+            Discrepancy::Volume { counter, counted, recomputed } => {
+                write!(f, "{counter}: counted {counted}, but {recomputed} from the ledger")
+            }
+            Discrepancy::Totals { clients_total, expected } => {
+                write!(f, "client totals add up to {clients_total}, but the money flows to {expected}")
+            }
+        }
+    }
+}
+
+/// Money flows recomputed from the ledger, with the discrepancies found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrialBalance {
+    pub deposits: TxAmount,
+    /// Includes captured holds.
+    pub withdrawals: TxAmount,
+    pub chargebacks: TxAmount,
+    pub refunds: TxAmount,
+    /// Withdrawals disputed and not resolved, held for their return to the client.
+    pub disputed_withdrawals: TxAmount,
+    pub clients_total: TxAmount,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl TrialBalance {
+    /// What the sum of all client totals should be.
+    pub fn expected_total(&self) -> TxAmount {
+        self.deposits - self.withdrawals - self.chargebacks + self.refunds + self.disputed_withdrawals
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+fn differs(a: TxAmount, b: TxAmount) -> bool {
+    a.round_dp(AMOUNT_DECIMALS) != b.round_dp(AMOUNT_DECIMALS)
+}
+
+/// Checks the balances of `processor` against its ledger, see the module docs. The ledger must
+/// have been recorded from the start, see `ProcessorConfig::record_ledger`.
+pub fn trial_balance<S: Stores>(processor: &TxProcessor<S>) -> TrialBalance {
+    let mut trial = TrialBalance::default();
+    let mut recomputed = BTreeMap::<ClientId, TxAmount>::new();
+    for entry in &processor.ledger {
+        for posting in &entry.postings {
+            match (posting.account, entry.tx_type) {
+                // Client accounts are liabilities, credited by what the client is owed.
+                (Account::Available(client) | Account::Held(client), _) => {
+                    *recomputed.entry(client).or_default() -= posting.amount;
+                }
+                (_, TxType::Deposit) => trial.deposits += posting.amount,
+                (_, TxType::Withdrawal | TxType::Capture) => trial.withdrawals -= posting.amount,
+                (_, TxType::Refund) => trial.refunds += posting.amount,
+                (_, TxType::Chargeback) => trial.chargebacks -= posting.amount,
+                (_, TxType::Dispute | TxType::Resolve) => trial.disputed_withdrawals += posting.amount,
+                // Holds, releases, clearings and settlements only move funds between a client's
+                // accounts.
+                (_, TxType::Hold | TxType::Release | TxType::Clear | TxType::Settle | TxType::Freeze | TxType::Unfreeze) => {}
+            }
+        }
+    }
+
+    let mut balances: Vec<_> = processor.balances().collect();
+    balances.sort_by_key(|balance| balance.client);
+    for balance in balances {
+        trial.clients_total += balance.total;
+        let recomputed = recomputed.get(&balance.client).copied().unwrap_or_default();
+        if differs(balance.total, recomputed) {
+            trial.discrepancies.push(Discrepancy::Client {
+                client: balance.client,
+                total: balance.total,
+                recomputed,
+            });
+        }
+    }
+
+    let counters = &processor.counters;
+    let volumes = [
+        ("deposited volume", counters.deposited_volume, trial.deposits),
+        ("withdrawn volume", counters.withdrawn_volume, trial.withdrawals),
+        ("refunded volume", counters.refunded_volume, trial.refunds),
+    ];
+    for (counter, counted, recomputed) in volumes {
+        if differs(counted, recomputed) {
+            trial.discrepancies.push(Discrepancy::Volume { counter, counted, recomputed });
+        }
+    }
+    let expected = trial.expected_total();
+    if differs(trial.clients_total, expected) {
+        trial.discrepancies.push(Discrepancy::Totals {
+            clients_total: trial.clients_total,
+            expected,
+        });
+    }
+    trial
+}
+
+/// Processes the files at `paths` with a ledger, as `process_files_and_output` would with
+/// `options`, and checks the result.
+pub fn verify_files(paths: &[String], options: &ProcessOptions) -> GResult<TrialBalance> {
+    let mut tx_processor = crate::build_processor(options, crate::open_tx_store(options)?);
+    tx_processor.config.record_ledger = true;
+    let range = options.record_range();
+    let transactions = crate::read_transactions_files(paths).take(range.end as usize).skip(range.start as usize);
+    tx_processor.process_input(transactions)?;
+    Ok(trial_balance(&tx_processor))
+}
+
+/// Writes the money flows, their sum and the discrepancies, one per line.
+pub fn write_trial_balance<OUT: io::Write>(mut out: OUT, trial: &TrialBalance) -> GResult<()> {
+    let round = |amount: TxAmount| amount.round_dp(AMOUNT_DECIMALS);
+    writeln!(out, "deposits: {}", round(trial.deposits))?;
+    writeln!(out, "withdrawals: {}", round(trial.withdrawals))?;
+    writeln!(out, "chargebacks: {}", round(trial.chargebacks))?;
+    writeln!(out, "refunds: {}", round(trial.refunds))?;
+    writeln!(out, "disputed withdrawals: {}", round(trial.disputed_withdrawals))?;
+    writeln!(out, "expected total: {}", round(trial.expected_total()))?;
+    writeln!(out, "client totals: {}", round(trial.clients_total))?;
+    for discrepancy in &trial.discrepancies {
+        writeln!(out, "discrepancy: {discrepancy}")?;
+    }
+    if trial.is_balanced() {
+        writeln!(out, "balanced")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use crate::tx_processor::ProcessorConfig;
+
+    #[test]
+    fn test_trial_balance() -> GResult<()> {
+        let mut processor = TxProcessor::with_config(ProcessorConfig {
+            record_ledger: true,
+            dispute_withdrawals: true,
+            ..Default::default()
+        });
+        let input = "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,4\ndeposit,2,3,5\ndispute,2,3,\n\
+            chargeback,2,3,\nrefund,1,2,1\nwithdrawal,1,4,2\ndispute,1,4,\nhold,1,5,1\ncapture,1,5,0.5\n";
+        processor.process_input(crate::read_transactions_csv(input.as_bytes()))?;
+
+        let trial = trial_balance(&processor);
+        assert_eq!(trial.discrepancies, vec![]);
+        let flows = (trial.deposits, trial.withdrawals, trial.chargebacks, trial.refunds, trial.disputed_withdrawals);
+        assert_eq!(flows, (amount(15.0), amount(6.5), amount(5.0), amount(1.0), amount(2.0)));
+        assert_eq!(trial.clients_total, amount(6.5));
+
+        // A balance changed behind the ledger's back.
+        processor.clients_balance.get_mut(&1).unwrap().total += amount(1.0);
+        let trial = trial_balance(&processor);
+        assert_eq!(trial.discrepancies, vec![
+            Discrepancy::Client { client: 1, total: amount(7.5), recomputed: amount(6.5) },
+            Discrepancy::Totals { clients_total: amount(7.5), expected: amount(6.5) },
+        ]);
+        let mut out = vec![];
+        write_trial_balance(&mut out, &trial)?;
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.ends_with(
+            "client totals: 7.5\n\
+             discrepancy: client 1: total is 7.5, but 6.5 from the ledger\n\
+             discrepancy: client totals add up to 7.5, but the money flows to 6.5\n"
+        ));
+        Ok(())
+    }
+}
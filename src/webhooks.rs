@@ -0,0 +1,293 @@
+//! Webhooks: a `NotificationSink` that POSTs each notification as JSON to HTTP endpoints, ie for
+//! the risk team to hear of chargebacks and locked accounts as they happen.
+//!
+//! Deliveries happen on a background thread, so a slow endpoint doesn't hold up processing. A
+//! delivery that fails (connection error or non-2xx response) is retried, waiting twice as long
+//! each time; once out of attempts the notification is appended to the dead-letter file, as a JSON
+//! line with the endpoint and the last error. Dropping `Webhooks` waits for the pending
+//! deliveries.
+//!
+//! Only plain `http://` endpoints are supported, TLS is left to a local proxy.
+
+use crate::error::{ErrorHandler, TxProcessorError};
+use crate::tx_processor::{Notification, NotificationSink};
+use crate::GResult;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// Endpoints every notification is sent to, as `http://host[:port][/path]`.
+    pub urls: Vec<String>,
+    /// Deliveries tried per endpoint before a notification is dead-lettered.
+    pub max_attempts: u32,
+    /// Wait before the first retry.
+    pub retry_delay: Duration,
+    /// Connection, write and read timeout of a delivery.
+    pub timeout: Duration,
+    /// If set, undeliverable notifications are appended to a file at this path, otherwise they are
+    /// only reported.
+    pub dead_letter_path: Option<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            max_attempts: 3,
+            retry_delay: Duration::from_millis(500),
+            timeout: Duration::from_secs(5),
+            dead_letter_path: None,
+        }
+    }
+}
+
+/// An endpoint of `WebhookConfig::urls`.
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    url: String,
+    /// `host:port`, to connect to.
+    address: String,
+    host: String,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> GResult<Self> {
+        let invalid = |message: &str| TxProcessorError::Parse {
+            field: "webhook_url",
+            message: format!("`{url}` {message}"),
+        };
+        let rest = url.strip_prefix("http://").ok_or_else(|| invalid("is not an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid("has no host"));
+        }
+        let (host, address) = match authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => (host, authority.to_string()),
+            Some(_) => return Err(invalid("has an invalid port")),
+            None => (authority, format!("{authority}:80")),
+        };
+        Ok(Self {
+            url: url.to_string(),
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Sends notifications to webhooks, see the module docs.
+pub struct Webhooks {
+    sender: Option<Sender<Notification>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    /// Starts the delivery thread, which reports failed deliveries to `on_error`. Fails if an
+    /// endpoint URL is invalid.
+    pub fn start(config: WebhookConfig, on_error: ErrorHandler) -> GResult<Self> {
+        let endpoints = config.urls.iter().map(|url| Endpoint::parse(url)).collect::<GResult<Vec<_>>>()?;
+        let (sender, receiver) = mpsc::channel::<Notification>();
+        let worker = thread::Builder::new().name("webhooks".to_string()).spawn(move || {
+            for notification in receiver {
+                let payload = match serde_json::to_string(&notification) {
+                    Ok(payload) => payload,
+                    Err(err) => {
+                        on_error("Failed to serialize webhook notification", &err.into());
+                        continue;
+                    }
+                };
+                for endpoint in &endpoints {
+                    if let Err(err) = deliver_with_retries(endpoint, &payload, &config) {
+                        let failure = io::Error::other(err.clone()).into();
+                        on_error(&format!("Webhook {} failed, dead-lettering", endpoint.url), &failure);
+                        if let Err(err) = dead_letter(&config, endpoint, &notification, &err) {
+                            on_error("Failed to dead-letter webhook notification", &err);
+                        }
+                    }
+                }
+            }
+        })?;
+        Ok(Self {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+}
+
+impl NotificationSink for Webhooks {
+    fn notify(&mut self, notification: &Notification) -> GResult<()> {
+        if let Some(sender) = &self.sender {
+            // The worker only stops once the sender is dropped.
+            let _ = sender.send(notification.clone());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Webhooks {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn deliver_with_retries(endpoint: &Endpoint, payload: &str, config: &WebhookConfig) -> Result<(), String> {
+    let mut delay = config.retry_delay;
+    let mut attempt = 1;
+    loop {
+        match deliver(endpoint, payload, config.timeout) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= config.max_attempts => return Err(err),
+            Err(_) => {
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// POSTs `payload` to `endpoint`, succeeding on a 2xx response.
+fn deliver(endpoint: &Endpoint, payload: &str, timeout: Duration) -> Result<(), String> {
+    let address = endpoint
+        .address
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or_else(|| format!("{} doesn't resolve", endpoint.address))?;
+    let post = || -> io::Result<String> {
+        let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_read_timeout(Some(timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{payload}",
+            endpoint.path,
+            endpoint.host,
+            payload.len()
+        )?;
+        stream.flush()?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        Ok(status)
+    };
+    let status = post().map_err(|err| err.to_string())?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') && code.len() == 3 => Ok(()),
+        Some(code) => Err(format!("HTTP status {code}")),
+        None => Err("no HTTP response".to_string()),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct DeadLetter<'a> {
+    url: &'a str,
+    error: &'a str,
+    notification: &'a Notification,
+}
+
+fn dead_letter(config: &WebhookConfig, endpoint: &Endpoint, notification: &Notification, error: &str) -> GResult<()> {
+    let Some(path) = &config.dead_letter_path else {
+        return Ok(());
+    };
+    let line = serde_json::to_string(&DeadLetter {
+        url: &endpoint.url,
+        error,
+        notification,
+    })?;
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{line}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::collected_errors;
+    use crate::tx_processor::TxProcessor;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Answers `statuses` in turn, one connection each, and returns the bodies received.
+    fn endpoint(statuses: Vec<u16>) -> GResult<(String, JoinHandle<Vec<String>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/hooks", listener.local_addr()?);
+        let server = thread::spawn(move || {
+            let mut bodies = vec![];
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                write!(&stream, "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\n\r\n").unwrap();
+            }
+            bodies
+        });
+        Ok((url, server))
+    }
+
+    #[test]
+    fn test_webhooks() -> GResult<()> {
+        // The first delivery fails once, the second one for good.
+        let (url, server) = endpoint(vec![503, 200, 500, 500])?;
+        let dead_letter_path = std::env::temp_dir().join("tx_processor_test_webhooks_dead_letter.jsonl");
+        let _ = std::fs::remove_file(&dead_letter_path);
+        let (on_error, errors) = collected_errors();
+        let config = WebhookConfig {
+            urls: vec![url.clone()],
+            max_attempts: 2,
+            retry_delay: Duration::from_millis(1),
+            dead_letter_path: Some(dead_letter_path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let webhooks = Webhooks::start(config, on_error)?;
+        let mut processor = TxProcessor::builder().notifier(webhooks).run_id("run-1").build();
+        let input = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nchargeback,1,1,\n";
+        processor.process_input(crate::read_transactions_csv(input.as_bytes()))?;
+        drop(processor);
+
+        let bodies = server.join().unwrap();
+        let chargeback = r#"{"event":"chargeback","sequence":4,"client":1,"tx":1,"#;
+        let locked = r#"{"event":"account_locked","sequence":4,"client":1,"tx":1,"#;
+        assert!(bodies[0].starts_with(chargeback) && bodies[1].starts_with(chargeback), "{bodies:?}");
+        assert!(bodies[2].starts_with(locked), "{bodies:?}");
+        assert!(bodies[0].ends_with(r#""locked":true},"run_id":"run-1"}"#), "{bodies:?}");
+
+        let dead_letters = std::fs::read_to_string(&dead_letter_path)?;
+        let expected = format!(r#"{{"url":"{url}","error":"HTTP status 500","notification":{}}}"#, bodies[3]);
+        assert_eq!(dead_letters, format!("{expected}\n"));
+        let failure = format!("Webhook {url} failed, dead-lettering: I/O error: HTTP status 500");
+        assert_eq!(*errors.lock().unwrap(), vec![failure]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint = Endpoint::parse("http://localhost:8080/risk/hooks").unwrap();
+        assert_eq!((endpoint.address.as_str(), endpoint.host.as_str()), ("localhost:8080", "localhost"));
+        assert_eq!(endpoint.path, "/risk/hooks");
+        assert_eq!(Endpoint::parse("http://example.com").unwrap().address, "example.com:80");
+        assert!(Endpoint::parse("https://example.com/").is_err());
+        assert!(Endpoint::parse("http://example.com:x/").is_err());
+    }
+}
@@ -1,15 +1,622 @@
-use tx_processor::process_file_and_output;
+use tx_processor::{
+    expand_paths, process_file_and_output, process_files_and_output, process_reader_and_output, ProcessOptions,
+};
 
 #[test]
 fn main_test() {
     let file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/example.csv");
 
     let mut output = vec![];
-    process_file_and_output(file, &mut output).unwrap();
+    process_file_and_output(file, &mut output, &ProcessOptions::default()).unwrap();
 
     let output = String::from_utf8(output).unwrap();
-    assert!(output.starts_with("client, available, held, total, locked"));
-    assert!(output.contains("\n1, 127.9, 0, 127.9, false"));
-    assert!(output.contains("\n2, 0, 80, 80, false"));
+    assert!(output.starts_with("client,available,held,total,locked"));
+    assert!(output.contains("\n1,127.9,0,127.9,false"));
+    assert!(output.contains("\n2,0,80,80,false"));
 
 }
+
+#[test]
+fn sorted_output_test() {
+    let file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/example.csv");
+    let options = ProcessOptions {
+        sort_by_client: true,
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_file_and_output(file, &mut output, &options).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,127.9,0,127.9,false\n2,0,80,80,false\n");
+}
+
+#[test]
+fn sharded_output_test() {
+    let file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/example.csv");
+    let options = ProcessOptions {
+        sort_by_client: true,
+        shards: Some(4),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_file_and_output(file, &mut output, &options).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,127.9,0,127.9,false\n2,0,80,80,false\n");
+}
+
+/// A large input over many clients, with disputes, retries and references across clients, from a
+/// fixed seed.
+fn generate_input(records: u32) -> String {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move |bound: u32| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % u64::from(bound)) as u32
+    };
+    let mut input = "type,client,tx,amount,idempotency_key\n".to_string();
+    for tx in 1..=records {
+        let client = next(500) + 1;
+        let line = match next(20) {
+            0..=8 => format!("deposit,{client},{tx},{}.{},", next(1000), next(100)),
+            9..=12 => format!("withdrawal,{client},{tx},{}.{},", next(300), next(100)),
+            13..=15 => format!("dispute,{client},{},,", next(tx) + 1),
+            16 => format!("resolve,{client},{},,", next(tx) + 1),
+            17 => format!("chargeback,{client},{},,", next(tx) + 1),
+            // A retry, possibly of another client's key.
+            _ => format!("deposit,{client},{},1,key-{}", next(tx) + 1, next(50)),
+        };
+        input.push_str(&line);
+        input.push('\n');
+    }
+    input
+}
+
+#[test]
+fn sharded_determinism_test() {
+    let path = std::env::temp_dir().join(format!("tx_processor_determinism_test_{}.csv", std::process::id()));
+    std::fs::write(&path, generate_input(100_000)).unwrap();
+    let path = path.to_str().unwrap();
+
+    let output = |shards, output_format: &str| {
+        let options = ProcessOptions {
+            shards: Some(shards),
+            output_format: output_format.parse().unwrap(),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        process_file_and_output(path, &mut output, &options).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    for output_format in ["csv", "json"] {
+        let expected = output(1, output_format);
+        for shards in [3, 8] {
+            assert!(output(shards, output_format) == expected, "{shards} shards, {output_format} output");
+        }
+    }
+    let expected = output(1, "csv");
+    assert_eq!(expected.lines().count(), 501);
+    let clients: Vec<u32> = expected.lines().skip(1).map(|line| line.split(',').next().unwrap().parse().unwrap()).collect();
+    assert!(clients.windows(2).all(|pair| pair[0] < pair[1]));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reader_input_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5.5\n";
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &ProcessOptions::default()).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,5.5,0,5.5,false\n");
+}
+
+#[test]
+fn multiple_files_test() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
+    let paths = expand_paths(&[format!("{dir}/exam*.csv"), format!("{dir}/rejections.csv")]).unwrap();
+    assert_eq!(paths, vec![format!("{dir}/example.csv"), format!("{dir}/rejections.csv")]);
+    let options = ProcessOptions {
+        sort_by_client: true,
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+
+    // Client 1 deposits in both files, and is locked by the second one.
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,127.9,0,127.9,true\n2,0,80,80,false\n");
+}
+
+#[test]
+fn tx_id_collisions_test() {
+    let dir = std::env::temp_dir();
+    let day_1 = dir.join("tx_processor_collisions_test_1.csv");
+    let day_2 = dir.join("tx_processor_collisions_test_2.csv");
+    std::fs::write(&day_1, "type,client,tx,amount\ndeposit,1,1,10\n").unwrap();
+    std::fs::write(&day_2, "type,client,tx,amount\ndeposit,2,1,7\ndispute,2,1,\n").unwrap();
+    let paths = [&day_1, &day_2].map(|path| path.to_str().unwrap().to_string());
+    let mut options = ProcessOptions {
+        sort_by_client: true,
+        tx_id_collisions: "namespace".parse().unwrap(),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,10,0,10,false\n2,0,7,7,false\n");
+
+    // The dispute of client 2 references the deposit of client 1, and is rejected.
+    options.tx_id_collisions = "reject".parse().unwrap();
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,10,0,10,false\n2,0,0,0,false\n");
+}
+
+#[test]
+fn ordering_test() {
+    let dir = std::env::temp_dir();
+    let day_1 = dir.join("tx_processor_ordering_test_1.csv");
+    let day_2 = dir.join("tx_processor_ordering_test_2.csv");
+    std::fs::write(&day_1, "type,client,tx,amount,timestamp\ndeposit,1,1,10,2024-05-02T09:00:00Z\n").unwrap();
+    std::fs::write(
+        &day_2,
+        "type,client,tx,amount,timestamp\ndeposit,1,2,5,2024-05-01T09:00:00Z\nwithdrawal,1,3,12,2024-05-01T10:00:00Z\n",
+    )
+    .unwrap();
+    // Concatenated in the wrong order.
+    let paths = [&day_1, &day_2].map(|path| path.to_str().unwrap().to_string());
+    let mut options = ProcessOptions {
+        ordering: "check".parse().unwrap(),
+        ..Default::default()
+    };
+
+    let err = process_files_and_output(&paths, &mut vec![], &options).unwrap_err().to_string();
+    assert!(err.starts_with(&format!("record 1 of `{}` is out of order", paths[1])), "{err}");
+
+    // Processed in input order.
+    options.ordering = "warn".parse().unwrap();
+    let mut output = vec![];
+    let report = process_files_and_output(&paths, &mut output, &options).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,3,0,3,false\n");
+    assert_eq!(report.warnings.len(), 1);
+    assert!(report.warnings[0].starts_with(&format!("record 1 of `{}` to record 2 of", paths[1])), "{:?}", report.warnings);
+
+    // Re-sorted, the withdrawal comes before the deposit of the first file, without the funds for it.
+    options.ordering = "resort:2".parse().unwrap();
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,15,0,15,false\n");
+}
+
+#[test]
+fn clearing_test() {
+    let path = std::env::temp_dir().join("tx_processor_clearing_test.csv");
+    std::fs::write(
+        &path,
+        "type,client,tx,amount,pending\ndeposit,1,1,10,true\ndeposit,2,2,4,true\nclear,2,2,,\nwithdrawal,2,3,1,\n",
+    )
+    .unwrap();
+    let paths = [path.to_str().unwrap().to_string()];
+    let mut options = ProcessOptions {
+        sort_by_client: true,
+        ..Default::default()
+    };
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,0,10,10,false\n2,3,0,3,false\n"
+    );
+
+    // The deposit of client 1 clears once 2 further records were processed, the one of client 2
+    // was cleared before.
+    options.clearing_period = Some("2".parse().unwrap());
+    options.output_format = "json".parse().unwrap();
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(!output.contains("pending"), "{output}");
+    options.clearing_period = Some("3".parse().unwrap());
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("\"pending\":10.0"), "{output}");
+}
+
+#[test]
+fn rejected_report_test() {
+    let file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/rejections.csv");
+    let report_path = std::env::temp_dir().join("tx_processor_rejected_report_test.csv");
+    let options = ProcessOptions {
+        rejected_report_path: Some(report_path.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    let summary = process_file_and_output(file, &mut output, &options).unwrap();
+    assert_eq!((summary.total(), summary.applied), (6, 3));
+    assert_eq!(
+        summary.rejections.into_iter().collect::<Vec<_>>(),
+        vec![("insufficient_funds", 1), ("locked_account", 1), ("unknown_tx_reference", 1)]
+    );
+
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        report,
+        "type,client,tx,amount,reason,tags
+withdrawal,1,2,500,not enough funds,
+dispute,1,7,,unknown transaction reference 7,
+deposit,1,5,10,account 1 is locked,
+"
+    );
+}
+
+#[test]
+#[cfg(not(feature = "minor-units"))] // `MinorUnits` already have four decimal places at most
+fn warnings_report_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5.123456\ndeposit, 1, 2, 1.5\n";
+    let report_path = std::env::temp_dir().join("tx_processor_warnings_report_test.csv");
+    let options = ProcessOptions {
+        warnings_report_path: Some(report_path.to_str().unwrap().to_string()),
+        round_amount_decimals: Some(4),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,6.6235,0,6.6235,false\n");
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        report,
+        "type,client,tx,amount,warning,tags\ndeposit,1,1,5.1235,\"amount has 6 decimal places, rounded\",rounded\n"
+    );
+}
+
+#[test]
+fn history_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 2, 2\nwithdrawal, 1, 3, 9\n";
+    let history_path = std::env::temp_dir().join("tx_processor_history_test.csv");
+    let options = ProcessOptions {
+        history_path: Some(history_path.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+
+    let history = std::fs::read_to_string(&history_path).unwrap();
+    assert_eq!(
+        history,
+        "client,sequence,type,tx,amount,available_delta,held_delta,available,held,total,locked,tags\n\
+         1,1,deposit,1,5,5,0,5,0,5,false,\n\
+         1,2,withdrawal,2,2,-2,0,3,0,3,false,\n"
+    );
+}
+
+#[test]
+fn negative_balances_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 2, 2\ndispute, 1, 1,\ndeposit, 2, 3, 1\n";
+    let report_path = std::env::temp_dir().join("tx_processor_negative_balances_test.csv");
+    let history_path = std::env::temp_dir().join("tx_processor_negative_balances_history_test.csv");
+    let mut options = ProcessOptions {
+        negative_balances_path: Some(report_path.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(report, "client,available,held,total,locked,sequence,type,tx\n1,-2,5,3,false,,,\n");
+
+    options.history_path = Some(history_path.to_str().unwrap().to_string());
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(report, "client,available,held,total,locked,sequence,type,tx\n1,-2,5,3,false,3,dispute,1\n");
+}
+
+#[test]
+fn manifest_test() {
+    let dir = std::env::temp_dir();
+    let manifest_path = dir.join("tx_processor_manifest_test.json").to_str().unwrap().to_string();
+    let key_path = dir.join("tx_processor_manifest_test.key").to_str().unwrap().to_string();
+    std::fs::write(&key_path, "secret\n").unwrap();
+    let options = ProcessOptions {
+        manifest_path: Some(manifest_path.clone()),
+        manifest_key_path: Some(key_path.clone()),
+        run_id: Some("nightly-1".to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    let result = process_file_and_output("tests/example.csv", &mut output, &options);
+    #[cfg(not(feature = "manifest"))]
+    assert!(result.unwrap_err().to_string().contains("`manifest` feature"));
+    #[cfg(feature = "manifest")]
+    {
+        use tx_processor::manifest::{read_key_file, read_manifest};
+        result.unwrap();
+        let manifest = read_manifest(&manifest_path).unwrap();
+        manifest.verify(&read_key_file(&key_path).unwrap()).unwrap();
+        assert_eq!(manifest.manifest.output.bytes, output.len() as u64);
+        assert_eq!(manifest.manifest.inputs[0].path.as_deref(), Some("tests/example.csv"));
+        assert_eq!((manifest.manifest.records.transactions, manifest.manifest.clients), (5, 2));
+        assert!(manifest.verify(b"other").is_err());
+    }
+}
+
+#[test]
+fn summary_test() {
+    use tx_processor::summary::read_summary;
+
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
+    let paths = vec![format!("{dir}/example.csv"), format!("{dir}/rejections.csv")];
+    let summary_path = std::env::temp_dir().join("tx_processor_summary_test.json");
+    let summary_path = summary_path.to_str().unwrap().to_string();
+    let options = ProcessOptions {
+        summary_path: Some(summary_path.clone()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_files_and_output(&paths, &mut output, &options).unwrap();
+    let summary = read_summary(&summary_path).unwrap();
+    assert_eq!(summary.inputs.len(), 2);
+    assert_eq!(summary.inputs[1].path.as_deref(), Some(paths[1].as_str()));
+    assert_eq!(summary.inputs[1].bytes, std::fs::metadata(&paths[1]).unwrap().len());
+    // Hashed while processing, as if the file had been read again.
+    assert_eq!(summary.inputs[0], tx_processor::digest::digest_file(&paths[0]).unwrap());
+    assert_eq!(summary.output.bytes, output.len() as u64);
+    assert_eq!(summary.transactions.values().sum::<u64>(), 11);
+    assert_eq!(summary.applied, 8);
+    let rejections: Vec<_> = summary.rejections.iter().map(|(code, count)| (code.as_str(), *count)).collect();
+    assert_eq!(rejections, [("insufficient_funds", 1), ("locked_account", 1), ("unknown_tx_reference", 1)]);
+    assert_eq!((summary.clients, summary.accounts_created, summary.accounts_locked), (2, 2, 1));
+    assert!(summary.run_id.is_some() && summary.started_at <= summary.finished_at);
+
+    let json = std::fs::read_to_string(&summary_path).unwrap();
+    assert!(json.contains("\"deposit\": 5"), "{json}");
+    assert!(!std::path::Path::new(&format!("{summary_path}.partial")).exists());
+}
+
+#[test]
+fn record_range_test() {
+    let file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/example.csv");
+    let audit_path = std::env::temp_dir().join("tx_processor_record_range_test.csv");
+    let options = ProcessOptions {
+        sort_by_client: true,
+        skip: 1,
+        limit: Some(3),
+        audit_path: Some(audit_path.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    let report = process_file_and_output(file, &mut output, &options).unwrap();
+    assert_eq!(report.total(), 3);
+    // Without the first deposit, the withdrawal is rejected, and the dispute isn't read.
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,30,0,30,false\n2,80,0,80,false\n");
+    // Sequence numbers are positions in the whole input.
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let sequences: Vec<_> = audit.lines().skip(1).map(|line| line.split(',').next().unwrap()).collect();
+    assert_eq!(sequences, ["2", "3", "4"]);
+}
+
+#[test]
+fn audit_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 2, 9\n";
+    let audit_path = std::env::temp_dir().join("tx_processor_audit_test.csv");
+    let options = ProcessOptions {
+        audit_path: Some(audit_path.to_str().unwrap().to_string()),
+        run_id: Some("nightly-1".to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    assert_eq!(
+        audit,
+        "sequence,type,client,tx,amount,outcome,reason_code,reason,available_delta,held_delta,total,tags,run_id\n\
+         1,deposit,1,1,5,applied,,,5,0,5,,nightly-1\n\
+         2,withdrawal,1,2,9,rejected,insufficient_funds,not enough funds,0,0,5,,nightly-1\n"
+    );
+}
+
+#[test]
+fn amount_precision_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.23456\ndeposit, 1, 2, 0.1\ndeposit, 1, 3, 0.2\n";
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &ProcessOptions::default()).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,1.5346,0,1.5346,false\n");
+}
+
+#[test]
+fn checkpoint_resume_test() {
+    let input = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/rejections.csv")).unwrap();
+    let dir = std::env::temp_dir();
+    let checkpoint_path = dir.join("tx_processor_checkpoint_resume_test.json");
+    let report_path = dir.join("tx_processor_checkpoint_resume_test_rejected.csv");
+    let _ = std::fs::remove_file(&checkpoint_path);
+    let options = ProcessOptions {
+        rejected_report_path: Some(report_path.to_str().unwrap().to_string()),
+        checkpoint_path: Some(checkpoint_path.to_str().unwrap().to_string()),
+        checkpoint_every: Some(2),
+        resume: true,
+        ..Default::default()
+    };
+
+    // A first run that stops after the first 3 records, as if it crashed.
+    let partial_input: String = input.lines().take(4).map(|line| format!("{line}\n")).collect();
+    process_reader_and_output(partial_input.as_bytes(), &mut vec![], &options).unwrap();
+
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,0,0,0,true\n");
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        report,
+        "type,client,tx,amount,reason,tags
+withdrawal,1,2,500,not enough funds,
+dispute,1,7,,unknown transaction reference 7,
+deposit,1,5,10,account 1 is locked,
+"
+    );
+}
+
+#[test]
+fn settlement_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5\nhold, 1, 2, 2\nsettle, 0, 0,\nhold, 1, 3, 1\n";
+    let dir = std::env::temp_dir();
+    let report_path = dir.join("tx_processor_settlement_test.csv");
+    let snapshot_dir = dir.join("tx_processor_settlement_test");
+    std::fs::create_dir_all(&snapshot_dir).unwrap();
+    let options = ProcessOptions {
+        settle_at_end: true,
+        settlement_report_path: Some(report_path.to_str().unwrap().to_string()),
+        settlement_snapshot_dir: Some(snapshot_dir.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    let mut output = vec![];
+    let report = process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+
+    // Both holds were released, by the marker and at the end.
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output, "client,available,held,total,locked\n1,5,0,5,false\n");
+    assert_eq!(report.clients_touched.len(), 1);
+    let settlements = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        settlements,
+        "sequence,event,client,tx,amount\n\
+         3,released_hold,1,2,2\n3,settled,,,5\n\
+         4,released_hold,1,3,1\n4,settled,,,5\n"
+    );
+    assert!(snapshot_dir.join("settlement-3.json").exists());
+    assert!(snapshot_dir.join("settlement-4.json").exists());
+}
+
+#[test]
+fn trailer_test() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5\ndeposit, 1, 2, 1\ntrailer, 3, 6\n";
+
+    let err = process_reader_and_output(input.as_bytes(), &mut vec![], &ProcessOptions::default()).unwrap_err();
+    assert_eq!(err.to_string(), "trailer expects 3 records totalling 6, found 2 totalling 6");
+
+    let options = ProcessOptions {
+        trailer_mismatch: tx_processor::input::TrailerPolicy::Warn,
+        ..Default::default()
+    };
+    let mut output = vec![];
+    let report = process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,6,0,6,false\n");
+    assert_eq!(report.warnings, ["trailer expects 3 records totalling 6, found 2 totalling 6"]);
+}
+
+#[test]
+fn fixed_width_test() {
+    let input = "deposit   0000100000001  10.0\nwithdrawal0000100000002   2.5\ndeposit   0000200000003   1.0\n";
+    let options = ProcessOptions {
+        input_format: tx_processor::input::InputFormat::FixedWidth,
+        ..Default::default()
+    };
+    let err = process_reader_and_output(input.as_bytes(), &mut vec![], &options).unwrap_err();
+    assert_eq!(err.to_string(), "invalid `fixed_width_layout` field: a layout is required for fixed-width input");
+
+    let options = ProcessOptions {
+        fixed_width_layout: Some("type=0..10,client=10..15,tx=15..23,amount=23..29".parse().unwrap()),
+        sort_by_client: true,
+        ..options
+    };
+    let mut output = vec![];
+    process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n2,1,0,1,false\n"
+    );
+}
+
+#[test]
+fn dead_letter_test() {
+    let input = "type,client,tx,amount\ndeposit,1,1,5\ndeposit,x,2,1\nwithdrawal,1,3,1\n\"deposit\",1,4,\"1,5\"\n";
+    let dead_letter_path = std::env::temp_dir().join("tx_processor_dead_letter_test.csv");
+    let options = ProcessOptions {
+        dead_letter_path: Some(dead_letter_path.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+    let err = process_reader_and_output(input.as_bytes(), &mut vec![], &options).unwrap_err();
+    assert_eq!(err.to_string(), "invalid `dead_letter` field: a dead-letter file requires lenient parsing");
+
+    let options = ProcessOptions {
+        parse_mode: tx_processor::tx_processor::ParseMode::Lenient,
+        ..options
+    };
+    let mut output = vec![];
+    let report = process_reader_and_output(input.as_bytes(), &mut output, &options).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,4,0,4,false\n");
+    let skipped: Vec<_> = report.malformed_records.iter().map(|record| record.sequence).collect();
+    assert_eq!((report.malformed, skipped), (2, vec![2, 4]));
+    let dead_letters = std::fs::read_to_string(&dead_letter_path).unwrap();
+    let amount_error = if cfg!(feature = "minor-units") {
+        "\"invalid `amount` field: invalid decimal amount `1,5`\""
+    } else {
+        "invalid `amount` field: invalid float literal"
+    };
+    assert_eq!(
+        dead_letters,
+        format!(
+            "line,error,raw\n\
+             3,invalid `client` field: invalid digit found in string,\"deposit,x,2,1\"\n\
+             5,{amount_error},\"deposit,1,4,\"\"1,5\"\"\"\n"
+        )
+    );
+}
+
+#[test]
+fn encoding_test() {
+    let expected = "client,available,held,total,locked\n1,3.5,0,3.5,false\n";
+    let input = b"\xef\xbb\xbftype,client,tx,amount\r\ndeposit,1,1,1.5\r\ndeposit,1,2,2\r\n";
+    let mut output = vec![];
+    process_reader_and_output(&input[..], &mut output, &ProcessOptions::default()).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+    let input = b"\xef\xbb\xbfdeposit   00001000000011.5\r\ndeposit   0000100000002   2\r\n";
+    let options = ProcessOptions {
+        input_format: tx_processor::input::InputFormat::FixedWidth,
+        fixed_width_layout: Some("type=0..10,client=10..15,tx=15..23,amount=23..27".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut output = vec![];
+    process_reader_and_output(&input[..], &mut output, &options).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+    let input = b"type,client,tx,amount,idempotency_key\ndeposit,1,1,3.5,caf\xe9\n";
+    let err = process_reader_and_output(&input[..], &mut vec![], &ProcessOptions::default()).unwrap_err();
+    assert!(matches!(err, tx_processor::error::TxProcessorError::Csv(_)), "{err:?}");
+    #[cfg(feature = "encoding")]
+    {
+        let options = ProcessOptions {
+            input_encoding: "latin1".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut output = vec![];
+        process_reader_and_output(&input[..], &mut output, &options).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}